@@ -0,0 +1,2 @@
+// Bindings for the ATECC608A secure element through esp-cryptoauthlib
+#include "cryptoauthlib.h"
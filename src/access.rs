@@ -0,0 +1,563 @@
+use std::mem;
+use std::sync::mpsc::Sender;
+use std::time::{Instant, SystemTime};
+
+use doorsys_protocol::{Audit, CodeType};
+use serde::Serialize;
+
+use crate::alarm::Alarm;
+use crate::audit::{Action, AuditEvent, AuditQueue, Source};
+use crate::card::Normalizer;
+use crate::channel::Channels;
+use crate::config::PinConfig;
+use crate::door::DoorCommand;
+use crate::duress::Duress;
+use crate::feedback::Feedback;
+use crate::mqtt::Outbox;
+use crate::privacy::{self, Redacted};
+use crate::readout::Readout;
+use crate::rules::{Requirement, Rules};
+use crate::scan::ScanGuard;
+use crate::schedule::{Mode, Scheduler};
+use crate::stale::{StaleGuard, StalePolicy, Uplink};
+use crate::temporary::TemporaryCodes;
+use crate::user::UserDB;
+use crate::vms::{Reason, Vms};
+use crate::walktest::{Observation, WalkTest};
+
+const DEFAULT_MAX_PIN_LENGTH: usize = 8;
+/// Longest pin that still fits in an i32
+const PIN_LENGTH_LIMIT: usize = 9;
+const STAR_KEY: u8 = 0x0A;
+const HASH_KEY: u8 = 0x0B;
+
+/// Converts a key press sequence into an integer
+fn keys_to_int(keys: &[u8]) -> i32 {
+    keys.iter()
+        .cloned()
+        .rev()
+        .enumerate()
+        .fold(0, |acc, (i, num)| acc + 10i32.pow(i as u32) * num as i32)
+}
+
+/// Published to doorsys/grant/{device_id} after every grant so
+/// an elevator controller or lighting system can react
+#[derive(Serialize, Debug)]
+pub struct Grant {
+    pub code: i32,
+    pub user_id: Option<u32>,
+    pub route: Option<u16>,
+    pub timestamp: SystemTime,
+}
+
+/// Access decision logic fed by the packets read from the wiegand reader.
+/// It keeps track of the pin being typed and, when a rule requires
+/// card and pin, of the card waiting for its pin.
+pub struct Access {
+    user_db: UserDB,
+    rules: Rules,
+    scheduler: Scheduler,
+    door_tx: Sender<DoorCommand>,
+    audit_tx: Sender<AuditEvent>,
+    chime_tx: Option<Sender<()>>,
+    feedback_tx: Sender<Feedback>,
+    keys: Vec<u8>,
+    min_pin_length: usize,
+    max_pin_length: usize,
+    pending_card: Option<i32>,
+    scan_guard: Option<ScanGuard>,
+    normalizer: Option<Normalizer>,
+    grant_tx: Option<Outbox<Grant>>,
+    grant_output_tx: Option<Sender<()>>,
+    floor_tx: Option<Sender<u16>>,
+    temporary_codes: Option<TemporaryCodes>,
+    stale_guard: Option<StaleGuard>,
+    audit_queue: Option<AuditQueue>,
+    channels: Option<Channels>,
+    alarm: Option<Alarm>,
+    vms: Option<Vms>,
+    duress: Option<Duress>,
+    walk_test: WalkTest,
+    uplink: Option<Uplink>,
+    /// Set by a star on an empty sequence, the master code and a hash
+    /// that follow ask for the readout
+    readout_armed: bool,
+    /// Door of the reader, tagged on every audit
+    door: u8,
+    /// Time the last packet was decoded, where the door latency starts
+    decoded_at: Instant,
+}
+
+impl Access {
+    pub fn new(
+        user_db: UserDB,
+        rules: Rules,
+        scheduler: Scheduler,
+        door_tx: Sender<DoorCommand>,
+        audit_tx: Sender<AuditEvent>,
+        chime_tx: Option<Sender<()>>,
+        feedback_tx: Sender<Feedback>,
+    ) -> Self {
+        Access {
+            user_db,
+            rules,
+            scheduler,
+            door_tx,
+            audit_tx,
+            chime_tx,
+            feedback_tx,
+            keys: Vec::with_capacity(DEFAULT_MAX_PIN_LENGTH),
+            min_pin_length: 0,
+            max_pin_length: DEFAULT_MAX_PIN_LENGTH,
+            pending_card: None,
+            scan_guard: None,
+            normalizer: None,
+            grant_tx: None,
+            grant_output_tx: None,
+            floor_tx: None,
+            temporary_codes: None,
+            stale_guard: None,
+            audit_queue: None,
+            channels: None,
+            alarm: None,
+            vms: None,
+            duress: None,
+            walk_test: WalkTest::default(),
+            uplink: None,
+            readout_armed: false,
+            door: 0,
+            decoded_at: Instant::now(),
+        }
+    }
+
+    /// Enables the detection of card scanning attacks
+    pub fn with_scan_guard(mut self, scan_guard: ScanGuard) -> Self {
+        self.scan_guard = Some(scan_guard);
+        self
+    }
+
+    /// Rewrites card numbers before they are looked up
+    pub fn with_normalizer(mut self, normalizer: Normalizer) -> Self {
+        self.normalizer = Some(normalizer);
+        self
+    }
+
+    /// Publishes every grant with the data attached to the credential
+    pub fn with_grant_publisher(mut self, grant_tx: Outbox<Grant>) -> Self {
+        self.grant_tx = Some(grant_tx);
+        self
+    }
+
+    /// Pulses an output when a credential with a route is granted
+    pub fn with_grant_output(mut self, grant_output_tx: Sender<()>) -> Self {
+        self.grant_output_tx = Some(grant_output_tx);
+        self
+    }
+
+    /// Energizes the floor relays selected by the credential route
+    pub fn with_floor_outputs(mut self, floor_tx: Sender<u16>) -> Self {
+        self.floor_tx = Some(floor_tx);
+        self
+    }
+
+    /// Accepts single use pins on top of the user database
+    pub fn with_temporary_codes(mut self, temporary_codes: TemporaryCodes) -> Self {
+        self.temporary_codes = Some(temporary_codes);
+        self
+    }
+
+    /// Changes the policy once the device is offline for a while
+    pub fn with_stale_guard(mut self, stale_guard: StaleGuard) -> Self {
+        self.stale_guard = Some(stale_guard);
+        self
+    }
+
+    /// Stops granting access when the audit queue policy requires it
+    pub fn with_audit_queue(mut self, audit_queue: AuditQueue) -> Self {
+        self.audit_queue = Some(audit_queue);
+        self
+    }
+
+    /// Rejects the credentials of disabled channels
+    pub fn with_channels(mut self, channels: Channels) -> Self {
+        self.channels = Some(channels);
+        self
+    }
+
+    /// Lets the master code acknowledge the alarms from the keypad
+    pub fn with_alarm(mut self, alarm: Alarm) -> Self {
+        self.alarm = Some(alarm);
+        self
+    }
+
+    /// Asks the video management system for a snapshot on denials and duress
+    pub fn with_vms(mut self, vms: Vms) -> Self {
+        self.vms = Some(vms);
+        self
+    }
+
+    /// Opens the door for duress pins and raises an alert
+    pub fn with_duress(mut self, duress: Duress) -> Self {
+        self.duress = Some(duress);
+        self
+    }
+
+    /// Reports every key, card and decision while the walk test runs and
+    /// lets the master code followed by a star toggle it
+    pub fn with_walk_test(mut self, walk_test: WalkTest) -> Self {
+        self.walk_test = walk_test;
+        self
+    }
+
+    /// Lets a star, the master code and a hash read out the wifi and mqtt
+    /// status and the size of the user database on the buzzer
+    pub fn with_readout(mut self, uplink: Uplink) -> Self {
+        self.uplink = Some(uplink);
+        self
+    }
+
+    /// Tags the audits of a reader other than the first one
+    pub fn with_door(mut self, door: u8) -> Self {
+        self.door = door;
+        self
+    }
+
+    /// Limits the number of digits accepted in a pin
+    pub fn with_pin_length(mut self, config: &PinConfig) -> Self {
+        self.max_pin_length = config.max_digits.min(PIN_LENGTH_LIMIT);
+        self.min_pin_length = config.min_digits.min(self.max_pin_length);
+        self.keys = Vec::with_capacity(self.max_pin_length);
+        self
+    }
+
+    pub fn key(&mut self, key: u8) {
+        self.decoded_at = Instant::now();
+        let shown = (!privacy::enabled()).then_some(key);
+        self.walk_test.observe(Observation::Key(shown));
+        let readout_armed = mem::take(&mut self.readout_armed);
+        if key == STAR_KEY && self.is_master_code(&self.keys) {
+            log::info!("Master code, toggling the walk test");
+            self.keys.clear();
+            self.walk_test.toggle();
+        } else if key == HASH_KEY && readout_armed && self.is_master_code(&self.keys) {
+            log::info!("Master code, reading out the status");
+            self.keys.clear();
+            self.readout();
+        } else if key == HASH_KEY && self.keys.is_empty() {
+            // A hash without a pin works as the doorbell
+            crate::ring_chime(&self.chime_tx);
+        } else if key == HASH_KEY && self.keys.len() < self.min_pin_length {
+            let pin = keys_to_int(&self.keys);
+            log::warn!("pin sequence is too short {:?}", Redacted(&self.keys));
+            self.keys.clear();
+            self.send_audit(pin, CodeType::Pin, Action::ShortPin, false);
+            self.feedback(Feedback::ShortPin);
+        } else if key == HASH_KEY {
+            let pin = keys_to_int(&self.keys);
+            self.keys.clear();
+            self.pin(pin);
+        } else if key == STAR_KEY {
+            log::info!("Cancel sequence");
+            self.readout_armed = self.keys.is_empty() && self.pending_card.is_none();
+            self.keys.clear();
+            self.pending_card = None;
+            self.feedback(Feedback::Deny);
+        } else if self.keys.len() == self.max_pin_length {
+            log::warn!("pin sequence is too big {:?}", Redacted(&self.keys));
+            self.keys.clear();
+            self.feedback(Feedback::Deny);
+        } else {
+            self.keys.push(key);
+            self.readout_armed = readout_armed;
+        }
+    }
+
+    fn is_master_code(&self, keys: &[u8]) -> bool {
+        !keys.is_empty()
+            && self
+                .alarm
+                .as_ref()
+                .is_some_and(|alarm| alarm.is_master_code(keys_to_int(keys)))
+    }
+
+    fn readout(&self) {
+        let Some(uplink) = &self.uplink else {
+            return;
+        };
+        let readout = Readout::take(&self.user_db, uplink);
+        log::info!("Reading out {:?}", readout);
+        self.feedback(Feedback::Readout(readout));
+    }
+
+    /// Credentials are still validated and audited during a lockdown
+    /// but they never open the door
+    fn locked_down(&self) -> bool {
+        let locked_down = self.scheduler.mode() == Mode::LockedDown;
+        if locked_down {
+            log::warn!("door is locked down");
+        }
+        locked_down
+    }
+
+    fn stale_policy(&self) -> Option<StalePolicy> {
+        self.stale_guard.as_ref().and_then(|guard| guard.policy())
+    }
+
+    /// Credentials are still validated and audited while stale
+    /// but they never open the door if the policy denies all
+    fn stale_denied(&self) -> bool {
+        let denied = self.stale_policy() == Some(StalePolicy::DenyAll);
+        if denied {
+            log::warn!("user database is stale");
+        }
+        denied
+    }
+
+    /// Grants are refused while they can't be audited
+    fn audit_blocked(&self) -> bool {
+        let blocked = self
+            .audit_queue
+            .as_ref()
+            .is_some_and(|queue| !queue.granting());
+        if blocked {
+            log::warn!("audit queue is full");
+        }
+        blocked
+    }
+
+    fn requirement(&self) -> Requirement {
+        match self.stale_policy() {
+            Some(StalePolicy::Relaxed) => Requirement::Any,
+            _ => self.rules.requirement(),
+        }
+    }
+
+    fn pin(&mut self, pin: i32) {
+        if let Some(alarm) = self
+            .alarm
+            .as_ref()
+            .filter(|alarm| alarm.is_master_code(pin))
+        {
+            log::info!("Master code, acknowledging the alarms");
+            alarm.acknowledge();
+            // The master code itself is never recorded
+            self.send_audit(0, CodeType::Pin, Action::AlarmAcknowledged, true);
+            self.feedback(Feedback::Grant);
+            return;
+        }
+        if self.channels.as_ref().is_some_and(|c| !c.pin_enabled()) {
+            log::warn!("pin channel disabled, ignoring pin {}", Redacted(pin));
+            self.send_audit(pin, CodeType::Pin, Action::ChannelDisabled, false);
+            self.feedback(Feedback::Deny);
+            return;
+        }
+        let duress = self
+            .duress
+            .as_ref()
+            .and_then(|duress| duress.user_pin(&self.user_db, pin));
+        // Audited and granted as the user, the duress is in the action
+        let pin = duress.unwrap_or(pin);
+        let known = self.user_db.contains(pin);
+        let temporary = !known
+            && self.stale_policy() != Some(StalePolicy::PermanentOnly)
+            && self
+                .temporary_codes
+                .as_ref()
+                .is_some_and(|codes| codes.contains(pin));
+        let valid = (known || temporary)
+            && !self.locked_down()
+            && !self.stale_denied()
+            && !self.audit_blocked();
+        log::info!("Valid pin {}: {}", Redacted(pin), valid);
+        let success = match self.requirement() {
+            Requirement::Any | Requirement::PinOnly => valid,
+            Requirement::CardOnly => {
+                log::warn!("pin not accepted at this time");
+                false
+            }
+            Requirement::CardAndPin => match self.pending_card.take() {
+                Some(rfid) => {
+                    self.audit(rfid, CodeType::Fob, valid);
+                    valid
+                }
+                None => {
+                    log::warn!("card required before pin");
+                    false
+                }
+            },
+        };
+        if let (Some(user_pin), Some(duress)) = (duress, &self.duress) {
+            duress.raise(&self.user_db, user_pin);
+            self.send_audit(pin, CodeType::Pin, Action::Duress, success);
+        } else if temporary {
+            self.temporary(pin, success);
+        } else {
+            self.audit(pin, CodeType::Pin, success);
+        }
+        // Neither a duress nor a visitor means the staff has arrived
+        self.finish(pin, success, duress.is_none() && !temporary);
+    }
+
+    pub fn card(&mut self, rfid: i32) {
+        self.decoded_at = Instant::now();
+        let shown = (!privacy::enabled()).then_some(rfid);
+        self.walk_test.observe(Observation::Card(shown));
+        self.keys.clear();
+        let rfid = match &self.normalizer {
+            Some(normalizer) => normalizer.apply(rfid),
+            None => rfid,
+        };
+        if self.channels.as_ref().is_some_and(|c| !c.card_enabled()) {
+            log::warn!("card channel disabled, ignoring rfid {}", Redacted(rfid));
+            self.send_audit(rfid, CodeType::Fob, Action::ChannelDisabled, false);
+            self.feedback(Feedback::Deny);
+            return;
+        }
+        if self.scan_guard.as_ref().is_some_and(|guard| guard.locked()) {
+            log::warn!("reader locked, ignoring rfid {}", Redacted(rfid));
+            self.audit(rfid, CodeType::Fob, false);
+            self.feedback(Feedback::Lockout);
+            return;
+        }
+        let known = self.user_db.contains(rfid);
+        if let (false, Some(guard)) = (known, &mut self.scan_guard) {
+            guard.denied();
+        }
+        let valid = known && !self.locked_down() && !self.stale_denied() && !self.audit_blocked();
+        log::info!("Valid rfid {}: {}", Redacted(rfid), valid);
+        match self.requirement() {
+            Requirement::CardAndPin if valid => {
+                log::info!("Card {} waiting for pin", Redacted(rfid));
+                self.pending_card = Some(rfid);
+            }
+            Requirement::PinOnly => {
+                log::warn!("card not accepted at this time");
+                self.audit(rfid, CodeType::Fob, false);
+                self.finish(rfid, false, false);
+            }
+            _ => {
+                self.audit(rfid, CodeType::Fob, valid);
+                self.finish(rfid, valid, true);
+            }
+        }
+    }
+
+    /// Called when no packets were received for a while
+    /// to cancel any incomplete sequence
+    pub fn timeout(&mut self) {
+        self.readout_armed = false;
+        if !self.keys.is_empty() {
+            log::warn!("incomplete pin sequence {:?}", Redacted(&self.keys));
+            self.keys.clear();
+            self.feedback(Feedback::Timeout);
+        }
+        if let Some(rfid) = self.pending_card.take() {
+            log::warn!("pin not entered for card {}", Redacted(rfid));
+            self.audit(rfid, CodeType::Fob, false);
+            self.feedback(Feedback::Timeout);
+        }
+    }
+
+    /// Temporary codes are audited apart and removed once used
+    fn temporary(&self, pin: i32, success: bool) {
+        self.send_audit(pin, CodeType::Pin, Action::TemporaryAccess, success);
+        if let (true, Some(codes)) = (success, &self.temporary_codes) {
+            log::info!("Temporary code {} used", Redacted(pin));
+            if let Err(e) = codes.consume(pin) {
+                log::error!("error removing temporary code: {}", e);
+            }
+        }
+    }
+
+    fn audit(&self, code: i32, code_type: CodeType, success: bool) {
+        self.send_audit(code, code_type, Action::Access, success);
+    }
+
+    fn send_audit(&self, code: i32, code_type: CodeType, action: Action, success: bool) {
+        let audit = Audit {
+            code,
+            code_type,
+            timestamp: SystemTime::now(),
+            success,
+        };
+        let suspicious = self
+            .scan_guard
+            .as_ref()
+            .is_some_and(|guard| guard.suspicious());
+        let reason = match action {
+            Action::Duress => Some(Reason::Duress),
+            _ if !success => Some(Reason::Deny),
+            _ => None,
+        };
+        let correlation_id = self
+            .vms
+            .as_ref()
+            .zip(reason)
+            .map(|(vms, reason)| vms.trigger(reason, Source::Reader));
+        let event = AuditEvent {
+            audit,
+            source: Source::Reader,
+            action,
+            suspicious,
+            stale: self.stale_policy().is_some(),
+            correlation_id,
+            door: self.door,
+        };
+        self.walk_test
+            .observe(Observation::Decision { action, success });
+        if let Err(e) = self.audit_tx.send(event) {
+            log::error!("error sending audit record: {}", e);
+        }
+    }
+
+    fn finish(&self, code: i32, success: bool, arrival: bool) {
+        let feedback = if success {
+            let grant = DoorCommand::Grant {
+                decoded_at: self.decoded_at,
+                arrival,
+            };
+            self.door_tx.send(grant).unwrap();
+            self.grant(code);
+            Feedback::Grant
+        } else if self.scheduler.mode() == Mode::LockedDown {
+            Feedback::LockdownDeny
+        } else {
+            Feedback::Deny
+        };
+        self.feedback(feedback);
+    }
+
+    /// Notifies the secondary systems about the grant
+    fn grant(&self, code: i32) {
+        let credential = self.user_db.credential(code).unwrap_or_default();
+        if let (Some(_), Some(output_tx)) = (credential.route, &self.grant_output_tx) {
+            if let Err(e) = output_tx.send(()) {
+                log::error!("error pulsing grant output: {}", e);
+            }
+        }
+        if let (Some(floors), Some(floor_tx)) = (credential.route, &self.floor_tx) {
+            log::info!("Enabling floors {:#b}", floors);
+            if let Err(e) = floor_tx.send(floors) {
+                log::error!("error enabling floors: {}", e);
+            }
+        }
+        if let Some(grant_tx) = &self.grant_tx {
+            let grant = Grant {
+                code,
+                user_id: credential.user_id,
+                route: credential.route,
+                timestamp: SystemTime::now(),
+            };
+            if let Err(e) = grant_tx.send(grant) {
+                log::error!("error sending grant: {}", e);
+            }
+        }
+    }
+
+    /// Plays a sound on the keypad
+    fn feedback(&self, feedback: Feedback) {
+        if let Err(e) = self.feedback_tx.send(feedback) {
+            log::warn!("error playing feedback: {}", e);
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use std::ptr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use esp_idf_svc::hal::adc::ADC1;
+use esp_idf_svc::sys::{
+    adc_atten_t, adc_atten_t_ADC_ATTEN_DB_11, adc_bitwidth_t, adc_bitwidth_t_ADC_BITWIDTH_DEFAULT,
+    adc_cali_create_scheme_curve_fitting, adc_cali_curve_fitting_config_t, adc_cali_handle_t,
+    adc_cali_raw_to_voltage, adc_channel_t, adc_oneshot_chan_cfg_t, adc_oneshot_config_channel,
+    adc_oneshot_io_to_channel, adc_oneshot_new_unit, adc_oneshot_read, adc_oneshot_unit_handle_t,
+    adc_oneshot_unit_init_cfg_t, adc_unit_t, adc_unit_t_ADC_UNIT_1, esp,
+};
+
+/// Full range up to about 2.5V
+const ATTENUATION: adc_atten_t = adc_atten_t_ADC_ATTEN_DB_11;
+const BITWIDTH: adc_bitwidth_t = adc_bitwidth_t_ADC_BITWIDTH_DEFAULT;
+
+struct Unit {
+    handle: adc_oneshot_unit_handle_t,
+    calibration: adc_cali_handle_t,
+}
+
+// Both handles are only used behind the mutex
+unsafe impl Send for Unit {}
+
+/// Oneshot adc1 unit shared by the strike current sense and the supervised
+/// inputs, each on its own channel. Reads are serialized by the unit.
+#[derive(Clone)]
+pub struct Adc(Arc<Mutex<Unit>>);
+
+impl Adc {
+    /// Takes the peripheral so nothing else drives the unit
+    pub fn new(_adc: ADC1) -> anyhow::Result<Self> {
+        let init = adc_oneshot_unit_init_cfg_t {
+            unit_id: adc_unit_t_ADC_UNIT_1,
+            ..Default::default()
+        };
+        let mut handle = ptr::null_mut();
+        esp!(unsafe { adc_oneshot_new_unit(&init, &mut handle) })?;
+        let scheme = adc_cali_curve_fitting_config_t {
+            unit_id: adc_unit_t_ADC_UNIT_1,
+            atten: ATTENUATION,
+            bitwidth: BITWIDTH,
+            ..Default::default()
+        };
+        let mut calibration = ptr::null_mut();
+        esp!(unsafe { adc_cali_create_scheme_curve_fitting(&scheme, &mut calibration) })?;
+        Ok(Adc(Arc::new(Mutex::new(Unit {
+            handle,
+            calibration,
+        }))))
+    }
+
+    /// Configures the channel of the gpio, only gpio0 to gpio4 are on adc1
+    pub fn channel(&self, gpio: i32) -> anyhow::Result<AdcChannel> {
+        let mut unit: adc_unit_t = 0;
+        let mut channel: adc_channel_t = 0;
+        esp!(unsafe { adc_oneshot_io_to_channel(gpio, &mut unit, &mut channel) })
+            .with_context(|| format!("gpio{} is not an analog pin", gpio))?;
+        if unit != adc_unit_t_ADC_UNIT_1 {
+            anyhow::bail!("gpio{} is not on adc1", gpio);
+        }
+        let config = adc_oneshot_chan_cfg_t {
+            atten: ATTENUATION,
+            bitwidth: BITWIDTH,
+        };
+        let handle = self.0.lock().unwrap().handle;
+        esp!(unsafe { adc_oneshot_config_channel(handle, channel, &config) })?;
+        Ok(AdcChannel {
+            adc: self.clone(),
+            channel,
+        })
+    }
+}
+
+/// Analog pin of the shared unit
+pub struct AdcChannel {
+    adc: Adc,
+    channel: adc_channel_t,
+}
+
+impl AdcChannel {
+    /// Calibrated voltage of the pin in millivolts
+    pub fn read_mv(&self) -> anyhow::Result<u16> {
+        let unit = self.adc.0.lock().unwrap();
+        let mut raw = 0;
+        esp!(unsafe { adc_oneshot_read(unit.handle, self.channel, &mut raw) })?;
+        let mut mv = 0;
+        esp!(unsafe { adc_cali_raw_to_voltage(unit.calibration, raw, &mut mv) })?;
+        Ok(mv as u16)
+    }
+}
@@ -0,0 +1,307 @@
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::gpio::{AnyOutputPin, PinDriver};
+use serde::Serialize;
+
+use crate::alert::{Alert, Category, Severity};
+use crate::audio::Audio;
+use crate::config::AlarmConfig;
+use crate::mqtt::Outbox;
+use crate::webhook::{Kind, Notifier};
+use crate::zone::{Trigger, Zone};
+
+/// Sounder kept on by the wiring test
+const TEST_PULSE: Duration = Duration::from_secs(1);
+
+/// Conditions that sound the local alarm
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlarmKind {
+    /// Door left open past the configured limit
+    HeldOpen,
+    /// Door opened while locked, without a grant or exit request
+    ForcedOpen,
+}
+
+impl AlarmKind {
+    fn severity(&self) -> Severity {
+        match self {
+            AlarmKind::HeldOpen => Severity::Warning,
+            AlarmKind::ForcedOpen => Severity::Critical,
+        }
+    }
+}
+
+/// Change reported for an alarm
+#[derive(Serialize, Debug, Clone, Copy)]
+pub enum Transition {
+    Raised,
+    /// The condition is gone, sent only when the alarm does not latch
+    Cleared,
+    /// Silenced by the master code or a remote command
+    Acknowledged,
+}
+
+/// Published to doorsys/alarm/{device_id} on every transition
+#[derive(Serialize, Debug)]
+pub struct AlarmEvent {
+    pub kind: AlarmKind,
+    pub transition: Transition,
+}
+
+enum AlarmCommand {
+    Raise(AlarmKind),
+    Clear(AlarmKind),
+    Acknowledge,
+    /// Pulses the sounder for the wiring test
+    Test,
+}
+
+/// Handle used to raise and acknowledge the alarms
+#[derive(Clone)]
+pub struct Alarm {
+    tx: Sender<AlarmCommand>,
+    master_code: Option<i32>,
+    sounder: bool,
+}
+
+impl Alarm {
+    pub fn raise(&self, kind: AlarmKind) {
+        self.send(AlarmCommand::Raise(kind));
+    }
+
+    /// The condition went away, a latching alarm keeps sounding
+    pub fn clear(&self, kind: AlarmKind) {
+        self.send(AlarmCommand::Clear(kind));
+    }
+
+    /// Silences the sounder and resets every active alarm
+    pub fn acknowledge(&self) {
+        self.send(AlarmCommand::Acknowledge);
+    }
+
+    /// Pulses the sounder, false when there is none
+    pub fn test_sounder(&self) -> bool {
+        if self.sounder {
+            self.send(AlarmCommand::Test);
+        }
+        self.sounder
+    }
+
+    /// Checks if the pin typed on the keypad is the master code
+    pub fn is_master_code(&self, pin: i32) -> bool {
+        self.master_code == Some(pin)
+    }
+
+    fn send(&self, cmd: AlarmCommand) {
+        if let Err(e) = self.tx.send(cmd) {
+            log::error!("error sending alarm command: {}", e);
+        }
+    }
+}
+
+/// Spawns the thread driving the sounder. A latching alarm keeps the
+/// sounder on after the condition is gone, until it is acknowledged.
+pub fn setup_alarm(
+    config: &AlarmConfig,
+    alert_tx: Outbox<Alert>,
+    event_tx: Outbox<AlarmEvent>,
+    notifier: Notifier,
+    audio: Audio,
+    zone: Zone,
+) -> anyhow::Result<Alarm> {
+    let mut sounder = match config.pin {
+        Some(pin) => {
+            let mut driver = PinDriver::output(unsafe { AnyOutputPin::new(pin) })?;
+            driver.set_low()?;
+            Some(driver)
+        }
+        None => None,
+    };
+    let latch = config.latch;
+    let forced_open_sounder = config.forced_open_sounder;
+
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut active: Vec<AlarmKind> = Vec::new();
+        let publish = |kind, transition| {
+            if let Err(e) = event_tx.send(AlarmEvent { kind, transition }) {
+                log::error!("error sending alarm event: {}", e);
+            }
+        };
+        for cmd in rx {
+            match cmd {
+                AlarmCommand::Raise(kind) => {
+                    if active.contains(&kind) {
+                        continue;
+                    }
+                    active.push(kind);
+                    let detail = format!("{:?} alarm raised", kind);
+                    if let Err(e) =
+                        alert_tx.send(Alert::new(kind.severity(), Category::Security, &detail))
+                    {
+                        log::error!("error sending alert: {}", e);
+                    }
+                    match kind {
+                        AlarmKind::HeldOpen => notifier.notify(Kind::HeldOpen, detail),
+                        AlarmKind::ForcedOpen => {
+                            notifier.notify(Kind::ForcedOpen, detail);
+                            zone.request(Trigger::ForcedOpen);
+                        }
+                    }
+                    audio.alarm(kind);
+                    publish(kind, Transition::Raised);
+                }
+                AlarmCommand::Clear(kind) => {
+                    if latch || !active.contains(&kind) {
+                        continue;
+                    }
+                    active.retain(|k| *k != kind);
+                    log::info!("{:?} alarm cleared", kind);
+                    publish(kind, Transition::Cleared);
+                }
+                AlarmCommand::Acknowledge => {
+                    log::info!("Alarm acknowledged {:?}", active);
+                    for kind in active.drain(..) {
+                        publish(kind, Transition::Acknowledged);
+                    }
+                }
+                AlarmCommand::Test => {
+                    // Back to the alarm state right after
+                    if let Some(driver) = &mut sounder {
+                        if let Err(e) = driver.set_high() {
+                            log::error!("error driving the sounder: {}", e);
+                        }
+                        thread::sleep(TEST_PULSE);
+                    }
+                }
+            }
+            if let Some(driver) = &mut sounder {
+                let sounding = active
+                    .iter()
+                    .any(|kind| *kind != AlarmKind::ForcedOpen || forced_open_sounder);
+                let result = if sounding {
+                    driver.set_high()
+                } else {
+                    driver.set_low()
+                };
+                if let Err(e) = result {
+                    log::error!("error driving the sounder: {}", e);
+                }
+            }
+        }
+    });
+
+    Ok(Alarm {
+        tx,
+        master_code: config.master_code,
+        sounder: config.pin.is_some(),
+    })
+}
+
+/// Times how long the door stays open, used by the door thread
+pub struct HeldOpen {
+    limit: Duration,
+    alarm: Alarm,
+    opened: Option<Instant>,
+    raised: bool,
+}
+
+impl HeldOpen {
+    pub fn new(limit: Duration, alarm: Alarm) -> Self {
+        HeldOpen {
+            limit,
+            alarm,
+            opened: None,
+            raised: false,
+        }
+    }
+
+    /// Door contact changed, true when the door is open
+    pub fn contact(&mut self, open: bool) {
+        if !open {
+            self.opened = None;
+            if self.raised {
+                self.raised = false;
+                self.alarm.clear(AlarmKind::HeldOpen);
+            }
+        } else if self.opened.is_none() {
+            self.opened = Some(Instant::now());
+        }
+    }
+
+    pub fn set_limit(&mut self, limit: Duration) {
+        self.limit = limit;
+    }
+
+    /// Restarts the count for a door that is still open,
+    /// used when a scheduled unlock ends
+    pub fn restart(&mut self) {
+        if self.opened.is_some() {
+            self.opened = Some(Instant::now());
+        }
+    }
+
+    /// When the alarm must be raised if the door stays open
+    pub fn deadline(&self) -> Option<Instant> {
+        match self.raised {
+            true => None,
+            false => self.opened.map(|opened| opened + self.limit),
+        }
+    }
+
+    /// Raises the alarm once the deadline has passed
+    pub fn check(&mut self) {
+        if self
+            .deadline()
+            .is_some_and(|deadline| deadline <= Instant::now())
+        {
+            log::warn!("Door held open for more than {:?}", self.limit);
+            self.raised = true;
+            self.alarm.raise(AlarmKind::HeldOpen);
+        }
+    }
+}
+
+/// Watches the door contact for a door opened while the relay is locked,
+/// used by the door thread. An opening right after the relay is released
+/// or locked again is expected and ignored.
+pub struct ForcedOpen {
+    grace: Duration,
+    alarm: Alarm,
+    raised: bool,
+}
+
+impl ForcedOpen {
+    pub fn new(grace: Duration, alarm: Alarm) -> Self {
+        ForcedOpen {
+            grace,
+            alarm,
+            raised: false,
+        }
+    }
+
+    pub fn set_grace(&mut self, grace: Duration) {
+        self.grace = grace;
+    }
+
+    /// Door contact changed, true when the door is open. `released` is when
+    /// the relay was last locked again, None if it never was since boot.
+    pub fn contact(&mut self, open: bool, unlocked: bool, released: Option<Instant>) {
+        if !open {
+            if self.raised {
+                self.raised = false;
+                self.alarm.clear(AlarmKind::ForcedOpen);
+            }
+            return;
+        }
+        let expected = released.is_some_and(|released| released.elapsed() < self.grace);
+        if unlocked || expected || self.grace.is_zero() {
+            return;
+        }
+        log::warn!("Door forced open");
+        self.raised = true;
+        self.alarm.raise(AlarmKind::ForcedOpen);
+    }
+}
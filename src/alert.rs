@@ -0,0 +1,112 @@
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use serde::Serialize;
+
+use crate::mqtt::{self, MqttClient, Stamped};
+
+/// Most recent alerts kept until the publisher is attached
+const PENDING_ALERTS: usize = 32;
+const RELAY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Kind of problem being reported
+#[derive(Serialize, Debug, Clone, Copy)]
+pub enum Category {
+    HardwareFault,
+    /// The relay could not be driven, the door may not lock or unlock
+    ActuatorFault,
+    Security,
+    /// Flash storage running out, writes such as a user sync may fail
+    StorageFault,
+    /// Not enough heap for a payload, it was dropped
+    MemoryFault,
+}
+
+/// How soon someone has to act on the alert
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Worth a look, e.g. a rejected command
+    Info,
+    /// Needs attention soon, e.g. maintenance
+    Warning,
+    /// Needs attention now, the door may be compromised or stuck
+    Critical,
+}
+
+/// Alert raised by one of the subsystems that requires attention.
+/// The severity comes last so backends decoding older alerts still work.
+#[derive(Serialize, Debug)]
+pub struct Alert {
+    pub category: Category,
+    pub detail: String,
+    pub timestamp: SystemTime,
+    pub severity: Severity,
+}
+
+impl Alert {
+    pub fn new(severity: Severity, category: Category, detail: impl Into<String>) -> Self {
+        let detail = detail.into();
+        match severity {
+            Severity::Info => log::info!("alert {:?}: {}", category, detail),
+            Severity::Warning => log::warn!("alert {:?}: {}", category, detail),
+            Severity::Critical => log::error!("alert {:?}: {}", category, detail),
+        }
+        Alert {
+            category,
+            detail,
+            timestamp: SystemTime::now(),
+            severity,
+        }
+    }
+}
+
+/// Takes the alerts from boot, before the broker is known, so they are
+/// drained even when the device ends up offline or the boot fails
+pub struct AlertRelay(Sender<Sender<Stamped<Alert>>>);
+
+/// Spawns the thread holding the alerts until the publisher is attached,
+/// only the most recent ones are kept meanwhile
+pub fn setup_alert_relay(alert_rx: Receiver<Stamped<Alert>>) -> AlertRelay {
+    let (attach_tx, attach_rx) = mpsc::channel::<Sender<Stamped<Alert>>>();
+    thread::spawn(move || {
+        let mut pending = VecDeque::with_capacity(PENDING_ALERTS);
+        let publisher = loop {
+            if let Ok(publisher) = attach_rx.try_recv() {
+                break publisher;
+            }
+            match alert_rx.recv_timeout(RELAY_INTERVAL) {
+                Ok(alert) => {
+                    if pending.len() == PENDING_ALERTS {
+                        pending.pop_front();
+                    }
+                    pending.push_back(alert);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        };
+        for alert in pending.into_iter().chain(alert_rx) {
+            if let Err(e) = publisher.send(alert) {
+                log::error!("error relaying alert: {}", e);
+            }
+        }
+    });
+    AlertRelay(attach_tx)
+}
+
+/// Publishes the relayed alerts to doorsys/alert/{device_id}
+pub fn setup_alert_publisher(
+    device_id: &str,
+    mqtt_client: Arc<Mutex<MqttClient>>,
+    relay: AlertRelay,
+) {
+    let topic = mqtt::topic(&format!("alert/{device_id}"));
+    let (alert_tx, alert_rx) = mpsc::channel();
+    mqtt::setup_publisher(topic, false, mqtt_client, alert_rx);
+    if let Err(e) = relay.0.send(alert_tx) {
+        log::error!("error attaching the alert publisher: {}", e);
+    }
+}
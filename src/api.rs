@@ -0,0 +1,140 @@
+use std::sync::mpsc::Sender;
+use std::time::{Duration, SystemTime};
+
+use embedded_svc::http::server::Request;
+use esp_idf_svc::http::server::{Configuration, EspHttpConnection, EspHttpServer};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::io::Write;
+
+use crate::audit::{Action, AuditEvent, Source};
+use crate::command::Command;
+use crate::config::ApiConfig;
+use crate::crypto;
+use crate::management::Origin;
+use crate::schedule::{Mode, Override, Scheduler};
+
+const DEFAULT_LOCKDOWN_MINUTES: u64 = 60;
+
+type HttpRequest<'a, 'r> = Request<&'a mut EspHttpConnection<'r>>;
+
+/// Starts the local http api used by integrators without mqtt.
+/// Every request must carry the configured token as a bearer token.
+///
+/// - GET /status: current mode and firmware version as json
+/// - POST /open: opens the door momentarily
+/// - POST /lockdown?minutes=60: rejects every credential for a while
+/// - DELETE /lockdown: lifts the lockdown and any other override
+pub fn setup_api(
+    config: &ApiConfig,
+    scheduler: Scheduler,
+    cmd_tx: Sender<(Origin, Command)>,
+    audit_tx: Sender<AuditEvent>,
+) -> anyhow::Result<()> {
+    let mut server = EspHttpServer::new(&Configuration {
+        http_port: config.port,
+        ..Default::default()
+    })?;
+
+    let token = format!("Bearer {}", config.token);
+    let version = crate::built_info::GIT_VERSION.unwrap_or(crate::built_info::PKG_VERSION);
+
+    let status_token = token.clone();
+    server.fn_handler("/status", Method::Get, move |req| -> anyhow::Result<()> {
+        if !authorized(&req, &status_token) {
+            req.into_status_response(401)?;
+            return Ok(());
+        }
+        let body = format!(
+            r#"{{"mode":"{:?}","version":"{}"}}"#,
+            scheduler.mode(),
+            version
+        );
+        req.into_response(200, None, &[("Content-Type", "application/json")])?
+            .write_all(body.as_bytes())?;
+        Ok(())
+    })?;
+
+    let open_token = token.clone();
+    let open_cmd_tx = cmd_tx.clone();
+    let open_audit_tx = audit_tx.clone();
+    server.fn_handler("/open", Method::Post, move |req| -> anyhow::Result<()> {
+        let authorized = authorized(&req, &open_token);
+        audit(&open_audit_tx, Action::Open, authorized);
+        if !authorized {
+            req.into_status_response(401)?;
+            return Ok(());
+        }
+        open_cmd_tx.send((Origin::Http, Command::Open(0)))?;
+        req.into_ok_response()?;
+        Ok(())
+    })?;
+
+    let lockdown_token = token.clone();
+    let lockdown_cmd_tx = cmd_tx.clone();
+    let lockdown_audit_tx = audit_tx.clone();
+    server.fn_handler(
+        "/lockdown",
+        Method::Post,
+        move |req| -> anyhow::Result<()> {
+            let authorized = authorized(&req, &lockdown_token);
+            audit(&lockdown_audit_tx, Action::LockDown, authorized);
+            if !authorized {
+                req.into_status_response(401)?;
+                return Ok(());
+            }
+            let minutes = query_param(req.uri(), "minutes")
+                .and_then(|minutes| minutes.parse().ok())
+                .unwrap_or(DEFAULT_LOCKDOWN_MINUTES);
+            let start = SystemTime::now();
+            let window = Override {
+                mode: Mode::LockedDown,
+                start,
+                end: start + Duration::from_secs(minutes * 60),
+            };
+            lockdown_cmd_tx.send((Origin::Http, Command::Override(window)))?;
+            req.into_ok_response()?;
+            Ok(())
+        },
+    )?;
+
+    server.fn_handler(
+        "/lockdown",
+        Method::Delete,
+        move |req| -> anyhow::Result<()> {
+            let authorized = authorized(&req, &token);
+            audit(&audit_tx, Action::Release, authorized);
+            if !authorized {
+                req.into_status_response(401)?;
+                return Ok(());
+            }
+            cmd_tx.send((Origin::Http, Command::ClearOverrides))?;
+            req.into_ok_response()?;
+            Ok(())
+        },
+    )?;
+
+    log::info!("Http api listening on port {}", config.port);
+    // The server runs until reboot
+    std::mem::forget(server);
+    Ok(())
+}
+
+fn authorized(req: &HttpRequest, token: &str) -> bool {
+    req.header("Authorization")
+        .is_some_and(|header| crypto::constant_time_eq(header.as_bytes(), token.as_bytes()))
+}
+
+fn audit(audit_tx: &Sender<AuditEvent>, action: Action, success: bool) {
+    if let Err(e) = audit_tx.send(AuditEvent::remote(Source::Http, action, success)) {
+        log::error!("error sending audit record: {}", e);
+    }
+}
+
+fn query_param<'a>(uri: &'a str, name: &str) -> Option<&'a str> {
+    let (_, query) = uri.split_once('?')?;
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
@@ -0,0 +1,43 @@
+use std::ptr;
+use std::sync::Mutex;
+
+use esp_idf_svc::sys::cryptoauthlib::{
+    atcab_init, atcab_sha_hmac, cfg_ateccx08a_i2c_default, ATCA_SUCCESS, SHA_MODE_TARGET_OUT_ONLY,
+};
+
+/// Serializes the access to the chip from the firmware threads
+static ATECC: Mutex<bool> = Mutex::new(false);
+
+/// Initializes the ATECC608A on the I2C bus configured in sdkconfig
+/// if it hasn't been initialized yet
+fn init(initialized: &mut bool) -> anyhow::Result<()> {
+    if !*initialized {
+        let status = unsafe { atcab_init(ptr::addr_of_mut!(cfg_ateccx08a_i2c_default)) };
+        if status != ATCA_SUCCESS as i32 {
+            anyhow::bail!("error initializing ATECC608A: {:#x}", status);
+        }
+        *initialized = true;
+    }
+    Ok(())
+}
+
+/// Computes the HMAC-SHA256 of data with the key stored in a slot
+/// of the secure element, so the key itself never leaves the chip
+pub fn hmac_sha256(slot: u16, data: &[u8]) -> anyhow::Result<[u8; 32]> {
+    let mut initialized = ATECC.lock().unwrap();
+    init(&mut initialized)?;
+    let mut digest = [0; 32];
+    let status = unsafe {
+        atcab_sha_hmac(
+            data.as_ptr(),
+            data.len(),
+            slot,
+            digest.as_mut_ptr(),
+            SHA_MODE_TARGET_OUT_ONLY as u8,
+        )
+    };
+    if status != ATCA_SUCCESS as i32 {
+        anyhow::bail!("ATECC608A hmac failure: {:#x}", status);
+    }
+    Ok(digest)
+}
@@ -0,0 +1,131 @@
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+use esp_idf_svc::hal::gpio::{AnyIOPin, AnyInputPin, AnyOutputPin};
+use esp_idf_svc::hal::uart::{config::Config, UartDriver, UART1};
+use esp_idf_svc::hal::units::Hertz;
+use serde::Deserialize;
+
+use crate::alarm::AlarmKind;
+use crate::config::AudioConfig;
+use crate::feedback::Feedback;
+
+const BAUD_RATE: u32 = 9600;
+const MAX_VOLUME: u8 = 30;
+const CMD_VOLUME: u8 = 0x06;
+/// Plays a file from a numbered folder of the SD card
+const CMD_PLAY_FOLDER: u8 = 0x0F;
+
+/// Clip number played for each event, from 1 to 255 as in `001.mp3`.
+/// Events without a clip keep the buzzer pattern.
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(default)]
+pub struct Clips {
+    pub grant: Option<u8>,
+    pub deny: Option<u8>,
+    pub timeout: Option<u8>,
+    pub lockout: Option<u8>,
+    pub lockdown_deny: Option<u8>,
+    pub enrollment: Option<u8>,
+    pub short_pin: Option<u8>,
+    pub held_open: Option<u8>,
+    pub walk_test: Option<u8>,
+    pub forced_open: Option<u8>,
+}
+
+impl Clips {
+    fn feedback(&self, feedback: Feedback) -> Option<u8> {
+        match feedback {
+            Feedback::Grant => self.grant,
+            Feedback::Deny => self.deny,
+            Feedback::Timeout => self.timeout,
+            Feedback::Lockout => self.lockout,
+            Feedback::LockdownDeny => self.lockdown_deny,
+            Feedback::Enrollment => self.enrollment,
+            Feedback::ShortPin => self.short_pin,
+            Feedback::WalkTest => self.walk_test,
+            // Only beeps can carry the status
+            Feedback::Readout(_) => None,
+        }
+    }
+
+    fn alarm(&self, kind: AlarmKind) -> Option<u8> {
+        match kind {
+            AlarmKind::HeldOpen => self.held_open,
+            AlarmKind::ForcedOpen => self.forced_open,
+        }
+    }
+}
+
+/// Handle used to play the spoken prompts.
+/// Does nothing when created with default.
+#[derive(Clone, Default)]
+pub struct Audio {
+    tx: Option<Sender<u8>>,
+    clips: Clips,
+}
+
+impl Audio {
+    /// Plays the clip of the feedback, false when it has none
+    /// and the buzzer must be used instead
+    pub fn feedback(&self, feedback: Feedback) -> bool {
+        self.play(self.clips.feedback(feedback))
+    }
+
+    pub fn alarm(&self, kind: AlarmKind) {
+        self.play(self.clips.alarm(kind));
+    }
+
+    fn play(&self, clip: Option<u8>) -> bool {
+        let (Some(tx), Some(clip)) = (&self.tx, clip) else {
+            return false;
+        };
+        if let Err(e) = tx.send(clip) {
+            log::error!("error sending audio clip: {}", e);
+            return false;
+        }
+        true
+    }
+}
+
+/// Command frame of the DFPlayer Mini serial protocol
+fn frame(cmd: u8, param: u16) -> [u8; 10] {
+    let [high, low] = param.to_be_bytes();
+    let body = [0xFF, 0x06, cmd, 0x00, high, low];
+    let sum = body.iter().fold(0u16, |sum, byte| sum + *byte as u16);
+    let [check_high, check_low] = 0u16.wrapping_sub(sum).to_be_bytes();
+    [
+        0x7E, body[0], body[1], body[2], body[3], body[4], body[5], check_high, check_low, 0xEF,
+    ]
+}
+
+/// Spawns the thread driving a DFPlayer Mini over uart1. The clips are read
+/// from the configured folder, so each language can live in its own folder.
+pub fn setup_audio(uart: UART1, config: &AudioConfig) -> anyhow::Result<Audio> {
+    let mut driver = UartDriver::new(
+        uart,
+        unsafe { AnyOutputPin::new(config.tx_pin) },
+        unsafe { AnyInputPin::new(config.rx_pin) },
+        Option::<AnyIOPin>::None,
+        Option::<AnyIOPin>::None,
+        &Config::default().baudrate(Hertz(BAUD_RATE)),
+    )?;
+    driver.write(&frame(CMD_VOLUME, config.volume.min(MAX_VOLUME) as u16))?;
+    let folder = config.folder;
+
+    let (tx, rx) = mpsc::channel::<u8>();
+    thread::spawn(move || {
+        for clip in rx {
+            log::debug!("Playing clip {} of folder {}", clip, folder);
+            let param = u16::from_be_bytes([folder, clip]);
+            if let Err(e) = driver.write(&frame(CMD_PLAY_FOLDER, param)) {
+                log::warn!("error playing clip: {}", e);
+            }
+        }
+    });
+
+    Ok(Audio {
+        tx: Some(tx),
+        clips: config.clips,
+    })
+}
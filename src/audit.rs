@@ -0,0 +1,561 @@
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use doorsys_protocol::{Audit, CodeType};
+use esp_idf_svc::mqtt::client::QoS;
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+use serde::{Deserialize, Serialize};
+
+use crate::config::AuditConfig;
+use crate::crypto;
+use crate::mqtt::{self, MqttClient};
+use crate::privacy::Redacted;
+use crate::protocol;
+use crate::schedule::LocalTime;
+use crate::stale::Uplink;
+use crate::storage::{self, Area};
+use crate::webhook::{Kind, Notifier};
+
+const CHAIN_KEY: &str = "audit_chain";
+/// Columns of [`AuditEvent::csv`]
+pub const CSV_HEADER: &str =
+    "timestamp,action,source,code_type,code,success,suspicious,stale,correlation_id,door";
+/// First protocol with the audits chained in audit records
+const CHAINED_PROTOCOL: u16 = 2;
+/// First protocol with the door in the audit records
+const DOOR_PROTOCOL: u16 = 3;
+/// First protocol with the gap action
+const GAP_PROTOCOL: u16 = 4;
+/// How often the queue is checked while the broker is down
+const DRAIN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Interface the audited action came from
+#[derive(Serialize, Debug, Clone, Copy)]
+pub enum Source {
+    Reader,
+    Http,
+    Modbus,
+    ExitButton,
+    /// Generated by the controller itself
+    Controller,
+}
+
+/// Action being audited
+#[derive(Serialize, Debug, Clone, Copy)]
+pub enum Action {
+    /// Credential presented to the reader
+    Access,
+    /// Door opened momentarily
+    Open,
+    LockDown,
+    /// Lockdown lifted
+    Release,
+    /// Door kept unlocked for a while
+    Hold,
+    /// Single use code presented to the reader
+    TemporaryAccess,
+    /// Pin shorter than the minimum length, rejected without a lookup
+    ShortPin,
+    /// Credential presented on a disabled channel, pin or card
+    ChannelDisabled,
+    /// Gap marker of the backends before the gap action,
+    /// the code holds the number of audits dropped
+    AuditsLost,
+    /// Alarms silenced with the master code
+    AlarmAcknowledged,
+    /// Door opened with a duress pin, the code is the user's own pin
+    Duress,
+    /// Marks a gap, holds the number of audits dropped because the queue
+    /// was full
+    Gap(u32),
+}
+
+/// Audit generated by the access logic along with the context around it
+pub struct AuditEvent {
+    pub audit: Audit,
+    pub source: Source,
+    pub action: Action,
+    /// Raised while a card scanning attack is being detected
+    pub suspicious: bool,
+    /// Generated while the user database was stale
+    pub stale: bool,
+    /// Id of the snapshot trigger sent to the video management system
+    pub correlation_id: Option<u32>,
+    /// Door the audit is for, 0 unless it came from the second reader
+    pub door: u8,
+}
+
+impl AuditEvent {
+    /// Audit for an action requested through an interface other than the reader.
+    /// There is no credential involved so the code is always zero.
+    pub fn remote(source: Source, action: Action, success: bool) -> Self {
+        AuditEvent {
+            audit: Audit {
+                code: 0,
+                code_type: CodeType::Pin,
+                timestamp: SystemTime::now(),
+                success,
+            },
+            source,
+            action,
+            suspicious: false,
+            stale: false,
+            correlation_id: None,
+            door: 0,
+        }
+    }
+
+    fn lost(count: u32) -> Self {
+        AuditEvent::remote(Source::Controller, Action::Gap(count), false)
+    }
+
+    /// Gap marker for the backends that don't know the gap action
+    fn legacy_gap(&self) -> Option<Self> {
+        let Action::Gap(count) = self.action else {
+            return None;
+        };
+        let mut event = self.duplicate();
+        event.action = Action::AuditsLost;
+        event.audit.code = count.try_into().unwrap_or(i32::MAX);
+        Some(event)
+    }
+
+    /// Copy handed to each additional sink
+    fn duplicate(&self) -> Self {
+        AuditEvent {
+            audit: copy_audit(&self.audit),
+            source: self.source,
+            action: self.action,
+            suspicious: self.suspicious,
+            stale: self.stale,
+            correlation_id: self.correlation_id,
+            door: self.door,
+        }
+    }
+
+    /// Capture time in unix seconds
+    pub fn unix_time(&self) -> u64 {
+        self.audit
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs())
+    }
+
+    pub fn code_type(&self) -> &'static str {
+        match self.audit.code_type {
+            CodeType::Pin => "pin",
+            CodeType::Fob => "card",
+        }
+    }
+
+    /// Line with the columns of [`CSV_HEADER`], without the line break
+    pub fn csv(&self) -> String {
+        format!(
+            "{},{:?},{:?},{},{},{},{},{},{},{}",
+            self.unix_time(),
+            self.action,
+            self.source,
+            self.code_type(),
+            self.audit.code,
+            self.audit.success,
+            self.suspicious,
+            self.stale,
+            self.correlation_id
+                .map_or_else(String::new, |id| id.to_string()),
+            self.door
+        )
+    }
+}
+
+fn copy_audit(audit: &Audit) -> Audit {
+    Audit {
+        code: audit.code,
+        code_type: match audit.code_type {
+            CodeType::Pin => CodeType::Pin,
+            CodeType::Fob => CodeType::Fob,
+        },
+        timestamp: audit.timestamp,
+        success: audit.success,
+    }
+}
+
+/// What happens to new audits once the queue is full
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Keeps the most recent audits
+    #[default]
+    DropOldest,
+    /// Keeps the audits from when the broker went down
+    DropNewest,
+    /// Like drop newest but the reader also stops granting access,
+    /// so no grant goes unrecorded
+    StopGranting,
+}
+
+struct QueueData {
+    events: VecDeque<AuditEvent>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    /// Audits dropped since the last marker was published
+    lost: u32,
+}
+
+/// Bounded queue holding the audits while their sink is unreachable.
+/// The mqtt one is shared with the access logic to enforce the stop
+/// granting policy.
+#[derive(Clone)]
+pub struct AuditQueue(Arc<Mutex<QueueData>>);
+
+impl AuditQueue {
+    pub fn new(config: &AuditConfig) -> Self {
+        AuditQueue::with_capacity(config.queue_size, config.overflow)
+    }
+
+    pub fn with_capacity(capacity: usize, policy: OverflowPolicy) -> Self {
+        let capacity = capacity.max(1);
+        AuditQueue(Arc::new(Mutex::new(QueueData {
+            events: VecDeque::with_capacity(capacity),
+            capacity,
+            policy,
+            lost: 0,
+        })))
+    }
+
+    /// False while grants can't be audited
+    pub fn granting(&self) -> bool {
+        let data = self.0.lock().unwrap();
+        data.policy != OverflowPolicy::StopGranting || data.events.len() < data.capacity
+    }
+
+    /// True once every audit was handed to the sink
+    pub fn is_empty(&self) -> bool {
+        let data = self.0.lock().unwrap();
+        data.events.is_empty() && data.lost == 0
+    }
+
+    fn push(&self, event: AuditEvent) {
+        let mut data = self.0.lock().unwrap();
+        if data.events.len() == data.capacity {
+            data.lost = data.lost.saturating_add(1);
+            if data.lost == 1 {
+                log::warn!("audit queue full, applying {:?}", data.policy);
+            }
+            if data.policy != OverflowPolicy::DropOldest {
+                return;
+            }
+            data.events.pop_front();
+        }
+        data.events.push_back(event);
+    }
+
+    /// Copies of the queued audits, oldest first, left in the queue
+    pub fn snapshot(&self) -> Vec<AuditEvent> {
+        let data = self.0.lock().unwrap();
+        data.events.iter().map(AuditEvent::duplicate).collect()
+    }
+
+    /// Takes the oldest audit, preceded by a marker if any was lost
+    fn pop(&self) -> Option<AuditEvent> {
+        let mut data = self.0.lock().unwrap();
+        if data.lost > 0 {
+            log::warn!("{} audits lost", data.lost);
+            let lost = data.lost;
+            data.lost = 0;
+            return Some(AuditEvent::lost(lost));
+        }
+        data.events.pop_front()
+    }
+
+    /// Puts back an audit the sink failed to take, so it goes out first
+    fn requeue(&self, event: AuditEvent) {
+        let mut data = self.0.lock().unwrap();
+        if data.events.len() == data.capacity {
+            data.lost = data.lost.saturating_add(1);
+            return;
+        }
+        data.events.push_front(event);
+    }
+}
+
+/// Audit published with its position in the chain.
+/// The audit comes first so backends unaware of the chain can still decode it.
+#[derive(Serialize)]
+pub struct AuditRecord {
+    pub audit: Audit,
+    /// Incremented for every record, a gap means records were lost
+    pub seq: u64,
+    /// SHA-256 of the previous encoded record, zeroes for the first one
+    pub prev_hash: [u8; 32],
+    pub suspicious: bool,
+    pub source: Source,
+    pub action: Action,
+    pub site: Option<String>,
+    pub stale: bool,
+    /// When the record was chained and published, the audit
+    /// timestamp is the capture time. Only once the clock is synchronized.
+    pub published: Option<SystemTime>,
+    /// Same id as the snapshot trigger sent to the video management system
+    pub correlation_id: Option<u32>,
+    pub door: u8,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct ChainHead {
+    seq: u64,
+    hash: [u8; 32],
+}
+
+/// Hash chain linking every audit record to the one before it.
+/// The head is persisted in nvs so the chain survives reboots.
+pub struct AuditChain {
+    nvs: EspNvs<NvsDefault>,
+    head: ChainHead,
+    site: Option<String>,
+}
+
+impl AuditChain {
+    pub fn new(
+        nvs_part: EspNvsPartition<NvsDefault>,
+        site: Option<String>,
+    ) -> anyhow::Result<Self> {
+        let nvs = EspNvs::new(nvs_part, "doorsys", true)?;
+        let mut buf = [0; 64];
+        let head = match nvs.get_raw(CHAIN_KEY, &mut buf)? {
+            Some(slice) => postcard::from_bytes(slice).context("error decoding audit chain")?,
+            None => ChainHead::default(),
+        };
+        log::info!("Audit chain at sequence {}", head.seq);
+        Ok(AuditChain { nvs, head, site })
+    }
+
+    /// Links the audit to the chain and returns the encoded record
+    pub fn append(&mut self, event: &AuditEvent) -> anyhow::Result<Vec<u8>> {
+        let record = AuditRecord {
+            audit: copy_audit(&event.audit),
+            seq: self.head.seq + 1,
+            prev_hash: self.head.hash,
+            suspicious: event.suspicious,
+            source: event.source,
+            action: event.action,
+            site: self.site.clone(),
+            stale: event.stale,
+            published: LocalTime::now().map(|_| SystemTime::now()),
+            correlation_id: event.correlation_id,
+            door: event.door,
+        };
+        let buffer = postcard::to_allocvec(&record).context("encoding failure")?;
+        let head = ChainHead {
+            seq: record.seq,
+            hash: crypto::sha256(&buffer)?,
+        };
+        let buf = postcard::to_allocvec(&head).context("encoding failure")?;
+        self.nvs.set_raw(CHAIN_KEY, &buf).context("nvs failure")?;
+        storage::record_write(Area::Other, buf.len());
+        self.head = head;
+        Ok(buffer)
+    }
+}
+
+/// Destination of the audit trail
+pub trait AuditSink: Send + 'static {
+    fn name(&self) -> &'static str;
+
+    /// False while the destination is known to be unreachable
+    fn ready(&self) -> bool {
+        true
+    }
+
+    /// Delivers one audit, on error it stays queued and
+    /// is retried first on the next drain
+    fn write(&mut self, event: &AuditEvent) -> anyhow::Result<()>;
+}
+
+/// Publishes chained audit records to doorsys/audit/{device_id}.
+/// Audits are chained once published. Backends that announced
+/// the first protocol get the bare audits instead. Backends that
+/// don't know about the door get the second one on its own topic,
+/// those that don't know the gap action get the count as code.
+pub struct MqttSink {
+    topic: String,
+    mqtt_client: Arc<Mutex<MqttClient>>,
+    chain: AuditChain,
+    uplink: Uplink,
+}
+
+impl MqttSink {
+    pub fn new(
+        device_id: &str,
+        mqtt_client: Arc<Mutex<MqttClient>>,
+        chain: AuditChain,
+        uplink: Uplink,
+    ) -> Self {
+        MqttSink {
+            topic: mqtt::topic(&format!("audit/{device_id}")),
+            mqtt_client,
+            chain,
+            uplink,
+        }
+    }
+}
+
+impl AuditSink for MqttSink {
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+
+    fn ready(&self) -> bool {
+        self.uplink.connected()
+    }
+
+    fn write(&mut self, event: &AuditEvent) -> anyhow::Result<()> {
+        let legacy = if protocol::negotiated() < GAP_PROTOCOL {
+            event.legacy_gap()
+        } else {
+            None
+        };
+        let event = legacy.as_ref().unwrap_or(event);
+        let buffer = if protocol::negotiated() < CHAINED_PROTOCOL {
+            postcard::to_allocvec(&event.audit).context("encoding failure")?
+        } else {
+            self.chain.append(event).context("error chaining audit")?
+        };
+        let topic = if event.door != 0 && protocol::negotiated() < DOOR_PROTOCOL {
+            Cow::Owned(format!("{}/{}", self.topic, event.door))
+        } else {
+            Cow::Borrowed(&self.topic)
+        };
+        // The record is in the chain now, the gap shows if it never arrives
+        if let Err(e) =
+            self.mqtt_client
+                .lock()
+                .unwrap()
+                .enqueue(&topic, QoS::AtLeastOnce, false, &buffer)
+        {
+            log::error!("error sending audit: {}", e);
+        }
+        Ok(())
+    }
+}
+
+/// Sink attached once it can be created, the mqtt one once the broker is
+/// configured. Audits stay queued until then, so the overflow policy also
+/// applies while the device runs offline.
+pub struct PendingSink<S>(Arc<Mutex<Option<S>>>);
+
+impl<S> Clone for PendingSink<S> {
+    fn clone(&self) -> Self {
+        PendingSink(self.0.clone())
+    }
+}
+
+impl<S> Default for PendingSink<S> {
+    fn default() -> Self {
+        PendingSink(Arc::new(Mutex::new(None)))
+    }
+}
+
+impl<S> PendingSink<S> {
+    pub fn attach(&self, sink: S) {
+        *self.0.lock().unwrap() = Some(sink);
+    }
+}
+
+impl<S: AuditSink> AuditSink for PendingSink<S> {
+    fn name(&self) -> &'static str {
+        self.0
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or("pending", |sink| sink.name())
+    }
+
+    fn ready(&self) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|sink| sink.ready())
+    }
+
+    fn write(&mut self, event: &AuditEvent) -> anyhow::Result<()> {
+        match self.0.lock().unwrap().as_mut() {
+            Some(sink) => sink.write(event),
+            None => anyhow::bail!("sink not attached yet"),
+        }
+    }
+}
+
+/// Spawns the thread feeding the sink from its own queue.
+/// Audits are queued while the sink is not ready or failing.
+pub fn setup_audit_sink(mut sink: impl AuditSink, queue: AuditQueue) -> Sender<AuditEvent> {
+    let (tx, rx) = mpsc::channel::<AuditEvent>();
+    thread::spawn(move || loop {
+        match rx.recv_timeout(DRAIN_INTERVAL) {
+            Ok(event) => queue.push(event),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if !sink.ready() {
+            continue;
+        }
+        while let Some(event) = queue.pop() {
+            if let Err(e) = sink.write(&event) {
+                log::warn!("error writing audit to {}: {:?}", sink.name(), e);
+                queue.requeue(event);
+                break;
+            }
+        }
+    });
+    tx
+}
+
+/// Hands every audit to each sink, the first one gets the original
+pub fn setup_audit_publisher(
+    notifier: Notifier,
+    audit_rx: Receiver<AuditEvent>,
+    sinks: Vec<Sender<AuditEvent>>,
+) {
+    thread::spawn(move || {
+        for event in audit_rx {
+            notify(&notifier, &event);
+            let Some((first, others)) = sinks.split_first() else {
+                continue;
+            };
+            for sink in others {
+                if let Err(e) = sink.send(event.duplicate()) {
+                    log::error!("error sending audit to sink: {}", e);
+                }
+            }
+            if let Err(e) = first.send(event) {
+                log::error!("error sending audit to sink: {}", e);
+            }
+        }
+    });
+}
+
+/// Notifies the denials and duress as soon as they happen
+fn notify(notifier: &Notifier, event: &AuditEvent) {
+    if matches!(event.action, Action::Duress) {
+        let detail = format!("duress from {:?} on door {}", event.source, event.door);
+        notifier.notify(Kind::Duress, detail);
+        return;
+    }
+    if event.audit.success {
+        return;
+    }
+    let detail = format!(
+        "{:?} denied from {:?} on door {} with {} {}",
+        event.action,
+        event.source,
+        event.door,
+        event.code_type(),
+        Redacted(event.audit.code)
+    );
+    notifier.notify(Kind::Deny, detail);
+}
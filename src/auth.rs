@@ -0,0 +1,258 @@
+use std::collections::VecDeque;
+use std::mem;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+use esp_idf_svc::sys::esp_random;
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::{self, Secret};
+use crate::schedule::LocalTime;
+use crate::storage::{self, Area};
+
+/// How far the message timestamp can be from the device clock
+const MAX_CLOCK_SKEW_SECS: u64 = 300;
+/// Number of recent nonces remembered to detect replays
+const NONCE_CACHE_SIZE: usize = 64;
+const COUNTER_KEY: &str = "cmd_counter";
+const BROADCAST_COUNTER_KEY: &str = "bcast_counter";
+/// Newest message timestamp accepted over mqtt and from the peers
+pub const MQTT_FLOOR_KEY: &str = "mqtt_floor";
+pub const PEER_FLOOR_KEY: &str = "peer_floor";
+/// Replay protection kept in nvs, a restore never lowers it
+pub const REPLAY_KEYS: [&str; 4] = [
+    COUNTER_KEY,
+    BROADCAST_COUNTER_KEY,
+    MQTT_FLOOR_KEY,
+    PEER_FLOOR_KEY,
+];
+
+/// Envelope carrying a payload signed with the device secret.
+/// The mac is the HMAC-SHA256 of the topic, the timestamp and the nonce
+/// (both little endian) and the payload, concatenated in this order.
+#[derive(Serialize, Deserialize)]
+struct Signed<'a> {
+    /// Seconds since the unix epoch
+    timestamp: u64,
+    nonce: u32,
+    payload: &'a [u8],
+    mac: [u8; 32],
+}
+
+/// Privileges granted by the key that signed a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Day to day operations such as opening the door
+    Operator,
+    /// Changes to the configuration and destructive operations
+    Admin,
+}
+
+/// Verifies incoming messages when a secret is configured.
+/// Without a secret every message is accepted as is.
+/// When an admin secret is configured only messages signed with it are
+/// granted the admin level, otherwise the secret grants every level.
+pub struct Authenticator {
+    secret: Option<Secret>,
+    admin_secret: Option<Secret>,
+    seen: VecDeque<(u64, u32)>,
+    /// Messages older than this can't be told apart from replays,
+    /// they were sent before a restart or left the nonce cache
+    floor: u64,
+    /// Newest timestamp accepted
+    latest: u64,
+    /// Where the newest accepted timestamp is kept across restarts
+    nvs: Option<(EspNvs<NvsDefault>, &'static str)>,
+}
+
+impl Authenticator {
+    pub fn new(secret: Option<Secret>, admin_secret: Option<Secret>) -> Self {
+        Authenticator {
+            secret,
+            admin_secret,
+            seen: VecDeque::with_capacity(NONCE_CACHE_SIZE),
+            floor: 0,
+            latest: 0,
+            nvs: None,
+        }
+    }
+
+    /// Keeps the newest accepted timestamp in nvs under the key so
+    /// messages accepted before a restart are not accepted again,
+    /// even while the clock is not set
+    pub fn with_floor(
+        mut self,
+        nvs_part: EspNvsPartition<NvsDefault>,
+        key: &'static str,
+    ) -> anyhow::Result<Self> {
+        let nvs = EspNvs::new(nvs_part, "doorsys", true)?;
+        let latest = nvs.get_u64(key)?.unwrap_or(0);
+        log::info!("Newest {} message from {}", key, latest);
+        self.floor = latest + 1;
+        self.latest = latest;
+        self.nvs = Some((nvs, key));
+        Ok(self)
+    }
+
+    /// Checks the signature and freshness of the message
+    /// and returns the authenticated payload with its level
+    pub fn verify<'a>(&mut self, topic: &str, data: &'a [u8]) -> anyhow::Result<(Level, &'a [u8])> {
+        if self.secret.is_none() && self.admin_secret.is_none() {
+            return Ok((Level::Admin, data));
+        }
+        let signed: Signed = postcard::from_bytes(data)?;
+        let level = self.level(topic, &signed)?;
+
+        // Freshness can only be verified once sntp has set the clock
+        if LocalTime::now().is_some() {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            if now.abs_diff(signed.timestamp) > MAX_CLOCK_SKEW_SECS {
+                anyhow::bail!("stale message from {}", signed.timestamp);
+            }
+        }
+
+        if signed.timestamp < self.floor {
+            anyhow::bail!(
+                "message from {} older than {}",
+                signed.timestamp,
+                self.floor
+            );
+        }
+        let id = (signed.timestamp, signed.nonce);
+        if self.seen.contains(&id) {
+            anyhow::bail!("replayed nonce {}", signed.nonce);
+        }
+        if signed.timestamp > self.latest {
+            if let Some((nvs, key)) = &mut self.nvs {
+                nvs.set_u64(key, signed.timestamp)?;
+                storage::record_write(Area::Other, mem::size_of::<u64>());
+            }
+            self.latest = signed.timestamp;
+        }
+        if self.seen.len() == NONCE_CACHE_SIZE {
+            if let Some((timestamp, _)) = self.seen.pop_front() {
+                self.floor = self.floor.max(timestamp + 1);
+            }
+        }
+        self.seen.push_back(id);
+
+        Ok((level, signed.payload))
+    }
+
+    /// Checks only the signature of retained messages, they are delivered
+    /// again on every connection so freshness and nonces don't apply
+    pub fn verify_retained<'a>(
+        &self,
+        topic: &str,
+        data: &'a [u8],
+    ) -> anyhow::Result<(Level, &'a [u8])> {
+        if self.secret.is_none() && self.admin_secret.is_none() {
+            return Ok((Level::Admin, data));
+        }
+        let signed: Signed = postcard::from_bytes(data)?;
+        let level = self.level(topic, &signed)?;
+        Ok((level, signed.payload))
+    }
+
+    /// Finds which secret signed the message
+    fn level(&self, topic: &str, signed: &Signed) -> anyhow::Result<Level> {
+        let message = signed_message(topic, signed.timestamp, signed.nonce, signed.payload);
+        if signed_by(&self.admin_secret, &message, &signed.mac)? {
+            Ok(Level::Admin)
+        } else if signed_by(&self.secret, &message, &signed.mac)? {
+            if self.admin_secret.is_some() {
+                Ok(Level::Operator)
+            } else {
+                Ok(Level::Admin)
+            }
+        } else {
+            anyhow::bail!("invalid signature");
+        }
+    }
+}
+
+/// Wraps the payload in a signed envelope, used by
+/// the messages this device sends to its peers
+pub fn sign(secret: &Secret, topic: &str, payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let nonce = unsafe { esp_random() };
+    let message = signed_message(topic, timestamp, nonce, payload);
+    let signed = Signed {
+        timestamp,
+        nonce,
+        payload,
+        mac: secret.hmac_sha256(&message)?,
+    };
+    Ok(postcard::to_allocvec(&signed)?)
+}
+
+/// Data covered by the mac
+fn signed_message(topic: &str, timestamp: u64, nonce: u32, payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(topic.len() + 12 + payload.len());
+    message.extend_from_slice(topic.as_bytes());
+    message.extend_from_slice(&timestamp.to_le_bytes());
+    message.extend_from_slice(&nonce.to_le_bytes());
+    message.extend_from_slice(payload);
+    message
+}
+
+/// Checks if the mac was produced with the given secret
+fn signed_by(secret: &Option<Secret>, message: &[u8], mac: &[u8; 32]) -> anyhow::Result<bool> {
+    match secret {
+        Some(secret) => Ok(crypto::constant_time_eq(&secret.hmac_sha256(message)?, mac)),
+        None => Ok(false),
+    }
+}
+
+/// Keeps track of the last accepted command counter in nvs so a recorded
+/// command can't be replayed later, not even after a reboot. Broadcasts
+/// are counted apart, they are shared by the devices of the site.
+pub struct ReplayGuard {
+    nvs: EspNvs<NvsDefault>,
+    last: u64,
+    last_broadcast: u64,
+}
+
+impl ReplayGuard {
+    pub fn new(nvs_part: EspNvsPartition<NvsDefault>) -> anyhow::Result<Self> {
+        let nvs = EspNvs::new(nvs_part, "doorsys", true)?;
+        let last = nvs.get_u64(COUNTER_KEY)?.unwrap_or(0);
+        let last_broadcast = nvs.get_u64(BROADCAST_COUNTER_KEY)?.unwrap_or(0);
+        log::info!(
+            "Last command counter {}, broadcast {}",
+            last,
+            last_broadcast
+        );
+        Ok(ReplayGuard {
+            nvs,
+            last,
+            last_broadcast,
+        })
+    }
+
+    /// Accepts the counter only if it is greater than the last one seen
+    pub fn check(&mut self, counter: u64) -> anyhow::Result<()> {
+        if counter <= self.last {
+            anyhow::bail!("stale command counter {} <= {}", counter, self.last);
+        }
+        self.nvs.set_u64(COUNTER_KEY, counter)?;
+        storage::record_write(Area::Other, mem::size_of::<u64>());
+        self.last = counter;
+        Ok(())
+    }
+
+    /// Same as check for the counter of the broadcasts
+    pub fn check_broadcast(&mut self, counter: u64) -> anyhow::Result<()> {
+        if counter <= self.last_broadcast {
+            anyhow::bail!(
+                "stale broadcast counter {} <= {}",
+                counter,
+                self.last_broadcast
+            );
+        }
+        self.nvs.set_u64(BROADCAST_COUNTER_KEY, counter)?;
+        storage::record_write(Area::Other, mem::size_of::<u64>());
+        self.last_broadcast = counter;
+        Ok(())
+    }
+}
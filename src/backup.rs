@@ -0,0 +1,333 @@
+use std::ffi::{c_char, CStr, CString};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use std::{mem, ptr, thread};
+
+use anyhow::Context;
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+use esp_idf_svc::sys::{
+    esp, esp_err_t, esp_random, esp_restart, nvs_entry_find, nvs_entry_info, nvs_entry_info_t,
+    nvs_entry_next, nvs_iterator_t, nvs_release_iterator, nvs_type_t, nvs_type_t_NVS_TYPE_ANY,
+    nvs_type_t_NVS_TYPE_BLOB, nvs_type_t_NVS_TYPE_STR, nvs_type_t_NVS_TYPE_U64,
+    ESP_ERR_NVS_NOT_FOUND, ESP_OK, NVS_DEFAULT_PART_NAME,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::REPLAY_KEYS;
+use crate::crypto::{self, Secret, GCM_NONCE_SIZE, GCM_TAG_SIZE};
+use crate::heap;
+use crate::http_client;
+use crate::management::{Change, ManagementLog, Origin};
+use crate::mqtt::Outbox;
+use crate::storage::{self, Area};
+
+/// Every namespace the firmware writes to, the wifi driver keeps its own
+const NAMESPACES: [&str; 2] = ["doorsys", "config"];
+const MAGIC: &[u8; 4] = b"DSBK";
+/// Label the backup key is derived from with the admin secret
+const KEY_LABEL: &[u8] = b"doorsys backup";
+const CHUNK_SIZE: usize = 4096;
+/// Largest backup made or restored, it is held in memory along with
+/// its decrypted copy
+const MAX_BACKUP_SIZE: usize = 64 * 1024;
+/// Size of an nvs entry, values take whole entries
+const NVS_ENTRY_SIZE: usize = 32;
+/// Largest part of a blob nvs keeps in a single page
+const NVS_BLOB_CHUNK: usize = 4000;
+const RESTART_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Deserialize, Debug)]
+enum Value {
+    U64(u64),
+    Str(String),
+    Blob(Vec<u8>),
+}
+
+impl Value {
+    /// Nvs entries taken by the value, with the headers of its blob chunks
+    fn nvs_entries(&self) -> usize {
+        match self {
+            Value::U64(_) => 1,
+            Value::Str(value) => 1 + (value.len() + 1).div_ceil(NVS_ENTRY_SIZE),
+            Value::Blob(value) => {
+                let chunks = value.len().div_ceil(NVS_BLOB_CHUNK).max(1);
+                1 + chunks + value.len().div_ceil(NVS_ENTRY_SIZE)
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Entry {
+    namespace: String,
+    key: String,
+    value: Value,
+}
+
+impl Entry {
+    fn is_replay_key(namespace: &str, key: &str) -> bool {
+        namespace == "doorsys" && REPLAY_KEYS.contains(&key)
+    }
+}
+
+/// Copy of everything kept in nvs: configuration and secrets, user database,
+/// rules and schedules, command counter, audit chain and nvs wear
+#[derive(Serialize, Deserialize, Debug)]
+struct Snapshot {
+    version: String,
+    taken: SystemTime,
+    entries: Vec<Entry>,
+}
+
+/// Part of an encrypted backup published to doorsys/backup/{device_id}.
+/// The data of every part with the same id, in index order, makes up the
+/// file accepted by the restore command.
+#[derive(Serialize, Debug)]
+pub struct BackupChunk {
+    pub id: u32,
+    pub index: u16,
+    pub total: u16,
+    pub data: Vec<u8>,
+}
+
+/// Keys of the namespace along with their type
+fn keys(namespace: &str) -> anyhow::Result<Vec<(String, nvs_type_t)>> {
+    let namespace = CString::new(namespace)?;
+    let mut keys = Vec::new();
+    let mut iterator: nvs_iterator_t = ptr::null_mut();
+    let mut ret = unsafe {
+        nvs_entry_find(
+            NVS_DEFAULT_PART_NAME.as_ptr() as *const c_char,
+            namespace.as_ptr(),
+            nvs_type_t_NVS_TYPE_ANY,
+            &mut iterator,
+        )
+    };
+    while ret == ESP_OK as esp_err_t {
+        let mut info: nvs_entry_info_t = unsafe { mem::zeroed() };
+        ret = unsafe { nvs_entry_info(iterator, &mut info) };
+        if ret != ESP_OK as esp_err_t {
+            break;
+        }
+        let key = unsafe { CStr::from_ptr(info.key.as_ptr()) };
+        keys.push((key.to_string_lossy().into_owned(), info.type_));
+        ret = unsafe { nvs_entry_next(&mut iterator) };
+    }
+    unsafe { nvs_release_iterator(iterator) };
+    if ret != ESP_ERR_NVS_NOT_FOUND as esp_err_t {
+        esp!(ret)?;
+    }
+    Ok(keys)
+}
+
+fn read_entries(
+    nvs_part: &EspNvsPartition<NvsDefault>,
+    namespace: &str,
+    entries: &mut Vec<Entry>,
+) -> anyhow::Result<()> {
+    let nvs = EspNvs::new(nvs_part.clone(), namespace, true)?;
+    for (key, kind) in keys(namespace)? {
+        let value = match kind {
+            nvs_type_t_NVS_TYPE_U64 => nvs.get_u64(&key)?.map(Value::U64),
+            nvs_type_t_NVS_TYPE_STR => {
+                let mut buf = vec![0; nvs.str_len(&key)?.unwrap_or(0)];
+                nvs.get_str(&key, &mut buf)?
+                    .map(|value| Value::Str(value.to_owned()))
+            }
+            nvs_type_t_NVS_TYPE_BLOB => {
+                let mut buf = vec![0; nvs.blob_len(&key)?.unwrap_or(0)];
+                nvs.get_raw(&key, &mut buf)?
+                    .map(|value| Value::Blob(value.to_vec()))
+            }
+            _ => {
+                log::warn!(
+                    "Leaving {}/{} of type {} out of the backup",
+                    namespace,
+                    key,
+                    kind
+                );
+                None
+            }
+        };
+        if let Some(value) = value {
+            entries.push(Entry {
+                namespace: namespace.to_owned(),
+                key,
+                value,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Exports and restores the state of the device so a replacement
+/// controller picks up where a failed one stopped
+pub struct StateBackup {
+    nvs_part: EspNvsPartition<NvsDefault>,
+    /// The admin secret, backups can't be made without it
+    secret: Option<Secret>,
+    chunk_tx: Mutex<Outbox<BackupChunk>>,
+}
+
+impl StateBackup {
+    pub fn new(
+        nvs_part: EspNvsPartition<NvsDefault>,
+        secret: Option<Secret>,
+        chunk_tx: Outbox<BackupChunk>,
+    ) -> Self {
+        StateBackup {
+            nvs_part,
+            secret,
+            chunk_tx: Mutex::new(chunk_tx),
+        }
+    }
+
+    fn key(&self) -> anyhow::Result<[u8; 32]> {
+        let secret = self
+            .secret
+            .as_ref()
+            .context("backups are encrypted with the admin secret, none is set")?;
+        secret.hmac_sha256(KEY_LABEL)
+    }
+
+    /// Encrypts the snapshot of nvs and publishes it in chunks.
+    /// Returns the number of entries exported.
+    pub fn export(&self) -> anyhow::Result<usize> {
+        let key = self.key()?;
+        let mut entries = Vec::new();
+        for namespace in NAMESPACES {
+            read_entries(&self.nvs_part, namespace, &mut entries)?;
+        }
+        let count = entries.len();
+        let snapshot = Snapshot {
+            version: crate::built_info::PKG_VERSION.to_owned(),
+            taken: SystemTime::now(),
+            entries,
+        };
+        // Only two copies are ever held, the entries go once encoded
+        let payload = postcard::to_allocvec(&snapshot).context("encoding failure")?;
+        drop(snapshot);
+        if payload.len() > MAX_BACKUP_SIZE - MAGIC.len() - GCM_NONCE_SIZE - GCM_TAG_SIZE {
+            anyhow::bail!("backup of {} bytes too big to restore", payload.len());
+        }
+        let mut nonce = [0; GCM_NONCE_SIZE];
+        crypto::fill_random(&mut nonce);
+        heap::reserve(2 * payload.len(), "backup")?;
+        let ciphertext = crypto::aes_gcm_encrypt(&key, &nonce, &payload)?;
+        drop(payload);
+        let mut file = Vec::with_capacity(MAGIC.len() + nonce.len() + ciphertext.len());
+        file.extend_from_slice(MAGIC);
+        file.extend_from_slice(&nonce);
+        file.extend_from_slice(&ciphertext);
+        drop(ciphertext);
+
+        let id = unsafe { esp_random() };
+        let total = file.chunks(CHUNK_SIZE).len();
+        let total = u16::try_from(total).context("backup too big")?;
+        let chunk_tx = self.chunk_tx.lock().unwrap();
+        for (index, data) in file.chunks(CHUNK_SIZE).enumerate() {
+            let chunk = BackupChunk {
+                id,
+                index: index as u16,
+                total,
+                data: data.to_vec(),
+            };
+            chunk_tx.send(chunk)?;
+        }
+        log::info!("Backup {} of {} entries in {} chunks", id, count, total);
+        Ok(count)
+    }
+
+    /// Replaces the whole state with the backup at the url. Nothing is
+    /// touched unless the backup decrypts and nvs has room for all of it,
+    /// every entry is written before the keys left out of the backup are
+    /// removed. The replay counters only ever go up, commands captured
+    /// since the backup was taken stay rejected. Returns the number of entries restored, the device must
+    /// restart to pick them up.
+    pub fn restore(&self, url: &str) -> anyhow::Result<usize> {
+        let key = self.key()?;
+        let file = http_client::download(url, MAX_BACKUP_SIZE, |_, _| {})?;
+        let header = MAGIC.len() + GCM_NONCE_SIZE;
+        if file.len() < header || &file[..MAGIC.len()] != MAGIC {
+            anyhow::bail!("not a backup file");
+        }
+        let nonce: [u8; GCM_NONCE_SIZE] = file[MAGIC.len()..header].try_into()?;
+        heap::reserve(file.len(), "backup restore")?;
+        let payload = crypto::aes_gcm_decrypt(&key, &nonce, &file[header..])?;
+        drop(file);
+        heap::reserve(payload.len(), "backup restore")?;
+        let snapshot: Snapshot = postcard::from_bytes(&payload).context("error decoding backup")?;
+        drop(payload);
+        log::info!(
+            "Restoring {} entries from version {}",
+            snapshot.entries.len(),
+            snapshot.version
+        );
+
+        let needed: usize = snapshot
+            .entries
+            .iter()
+            .map(|entry| entry.value.nvs_entries())
+            .sum();
+        let free = storage::nvs_stats()?.free_entries;
+        if needed > free {
+            anyhow::bail!("backup needs {} nvs entries, {} are free", needed, free);
+        }
+        for entry in &snapshot.entries {
+            let mut nvs = EspNvs::new(self.nvs_part.clone(), &entry.namespace, true)?;
+            let bytes = match &entry.value {
+                Value::U64(value) => {
+                    let value = match nvs.get_u64(&entry.key)? {
+                        Some(current) if Entry::is_replay_key(&entry.namespace, &entry.key) => {
+                            current.max(*value)
+                        }
+                        _ => *value,
+                    };
+                    nvs.set_u64(&entry.key, value)?;
+                    mem::size_of::<u64>()
+                }
+                Value::Str(value) => {
+                    nvs.set_str(&entry.key, value)?;
+                    value.len()
+                }
+                Value::Blob(value) => {
+                    nvs.set_raw(&entry.key, value)?;
+                    value.len()
+                }
+            };
+            storage::record_write(Area::Config, bytes);
+        }
+        for namespace in NAMESPACES {
+            let mut nvs = EspNvs::new(self.nvs_part.clone(), namespace, true)?;
+            for (key, _) in keys(namespace)? {
+                let restored = snapshot
+                    .entries
+                    .iter()
+                    .any(|entry| entry.namespace == namespace && entry.key == key);
+                if !restored && !Entry::is_replay_key(namespace, &key) {
+                    nvs.remove(&key)?;
+                }
+            }
+        }
+        Ok(snapshot.entries.len())
+    }
+}
+
+/// Restores the backup in the background then restarts into it.
+/// The door keeps working from the current state meanwhile.
+pub fn restore(backup: Arc<StateBackup>, url: String, origin: Origin, management: ManagementLog) {
+    thread::spawn(move || {
+        log::warn!("Restoring the device state");
+        let result = backup.restore(&url);
+        let count = *result.as_ref().unwrap_or(&0);
+        let result = result.map(|_| ());
+        if let Err(e) = &result {
+            log::error!("Error restoring the device state {:?}", e);
+        }
+        management.record(origin, Change::Restore, count, &result);
+        if result.is_ok() {
+            thread::sleep(RESTART_DELAY);
+            unsafe { esp_restart() };
+        }
+    });
+}
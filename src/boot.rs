@@ -0,0 +1,78 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::mqtt::Outbox;
+
+/// Milestones of the boot sequence. The tags are stable and new stages are
+/// appended, the order they were reached in is the one of `Progress::stages`
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Nvs partition taken and settings read
+    Nvs,
+    UserDb {
+        codes: usize,
+    },
+    Wifi,
+    /// Every subsystem was started
+    Ready,
+    /// First connection to the broker
+    Mqtt,
+    /// Reader and door working with the credentials in flash,
+    /// reached before any network setup
+    Door,
+    /// Interrupts of the first reader registered, reached from its thread
+    Reader,
+}
+
+/// Published retained to doorsys/boot/{device_id} every time a stage is
+/// reached so the last message shows where a stuck device stopped
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Progress {
+    /// Stages reached with the milliseconds since boot
+    pub stages: Vec<(Stage, u32)>,
+}
+
+/// Keeps track of the boot progress. The updates are queued until
+/// the publisher is started once the mqtt client is available.
+#[derive(Clone)]
+pub struct BootProgress {
+    started: Instant,
+    progress: Arc<Mutex<Progress>>,
+    progress_tx: Outbox<Progress>,
+}
+
+impl BootProgress {
+    pub fn new(progress_tx: Outbox<Progress>) -> Self {
+        BootProgress {
+            started: Instant::now(),
+            progress: Arc::default(),
+            progress_tx,
+        }
+    }
+
+    /// Records the stage, stages already reached are ignored
+    pub fn reached(&self, stage: Stage) {
+        let mut progress = self.progress.lock().unwrap();
+        if progress.stages.iter().any(|(reached, _)| *reached == stage) {
+            return;
+        }
+        let elapsed = self.started.elapsed().as_millis() as u32;
+        log::info!("Boot stage {:?} reached after {}ms", stage, elapsed);
+        progress.stages.push((stage, elapsed));
+        if let Err(e) = self.progress_tx.send(progress.clone()) {
+            log::warn!("error sending boot progress: {}", e);
+        }
+    }
+
+    pub fn has_reached(&self, stage: Stage) -> bool {
+        let progress = self.progress.lock().unwrap();
+        progress.stages.iter().any(|(reached, _)| *reached == stage)
+    }
+
+    /// Time since power-on
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
@@ -0,0 +1,88 @@
+use serde::Deserialize;
+
+use crate::config::CardConfig;
+
+/// Bits in the card number of a 26 bit frame, facility code included
+const CARD_BITS: u32 = 24;
+const CARD_MASK: u32 = (1 << CARD_BITS) - 1;
+
+/// Site specific scrambling found on legacy cards, undone
+/// before any other rewrite. Results are kept within 24 bits.
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(tag = "algorithm", rename_all = "snake_case")]
+pub enum Transform {
+    /// Exclusive or with a fixed key
+    Xor { key: u32 },
+    /// Rotates the bits to the left
+    Rotate { bits: u32 },
+    /// Adds a fixed value
+    Offset { value: u32 },
+    /// Multiplies by a factor, it must be odd so no two cards collide
+    Multiply { factor: u32 },
+}
+
+impl Transform {
+    fn apply(&self, value: u32) -> u32 {
+        let value = match *self {
+            Transform::Xor { key } => value ^ key,
+            Transform::Rotate { bits } => {
+                let bits = bits % CARD_BITS;
+                value << bits | value >> ((CARD_BITS - bits) % CARD_BITS)
+            }
+            Transform::Offset { value: offset } => value.wrapping_add(offset),
+            Transform::Multiply { factor } => value.wrapping_mul(factor),
+        };
+        value & CARD_MASK
+    }
+}
+
+/// Rewrites the card numbers read from the reader to the format
+/// used by other systems, such as the HR database the users come from
+pub struct Normalizer {
+    transforms: Vec<Transform>,
+    reverse_bytes: bool,
+    reverse_bits: bool,
+    strip_facility: bool,
+    /// Power of ten used to keep the last digits
+    modulus: Option<u32>,
+}
+
+impl Normalizer {
+    pub fn new(config: &CardConfig) -> Self {
+        for transform in &config.transforms {
+            if let Transform::Multiply { factor } = transform {
+                if factor % 2 == 0 {
+                    log::warn!("even card multiplier {} maps cards together", factor);
+                }
+            }
+        }
+        Normalizer {
+            transforms: config.transforms.clone(),
+            reverse_bytes: config.reverse_bytes,
+            reverse_bits: config.reverse_bits,
+            strip_facility: config.strip_facility,
+            modulus: config.digits.and_then(|digits| 10u32.checked_pow(digits)),
+        }
+    }
+
+    /// Transformations are applied in the same order as the fields
+    pub fn apply(&self, rfid: i32) -> i32 {
+        let mut value = self
+            .transforms
+            .iter()
+            .fold(rfid as u32, |value, transform| transform.apply(value));
+        if self.reverse_bytes {
+            value = value.swap_bytes() >> (32 - CARD_BITS);
+        }
+        if self.reverse_bits {
+            value = value.reverse_bits() >> (32 - CARD_BITS);
+        }
+        if self.strip_facility {
+            value &= 0xFFFF;
+        }
+        if let Some(modulus) = self.modulus {
+            value %= modulus;
+        }
+        value as i32
+    }
+}
@@ -0,0 +1,124 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+use esp_idf_svc::sys::esp_restart;
+use serde::{Deserialize, Serialize};
+
+use crate::crypto;
+use crate::stale::Uplink;
+use crate::storage::{self, Area};
+
+const ACTIVE_KEY: &str = "certs";
+const STAGED_KEY: &str = "certs_staged";
+/// Time given to staged certificates to connect to the broker
+const TRIAL_TIMEOUT: Duration = Duration::from_secs(180);
+const TRIAL_POLL: Duration = Duration::from_secs(1);
+
+/// PEM encoded certificates used by the mqtt TLS connection.
+/// They take precedence over the client certificate in the settings.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Certificates {
+    /// Authority the broker certificate must be signed by
+    pub ca: Option<String>,
+    pub client_cert: Option<String>,
+    /// Client key, not needed when the ATECC608A holds it
+    pub private_key: Option<String>,
+}
+
+impl Certificates {
+    /// Parses every certificate and checks the key matches the client certificate
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(ca) = &self.ca {
+            crypto::check_certificate(ca, None).context("ca")?;
+        }
+        match (&self.client_cert, &self.private_key) {
+            (Some(cert), key) => {
+                crypto::check_certificate(cert, key.as_deref()).context("client certificate")?
+            }
+            (None, Some(_)) => anyhow::bail!("private key without a client certificate"),
+            (None, None) => {}
+        }
+        Ok(())
+    }
+}
+
+/// Certificates installed at runtime. New certificates are staged and only
+/// become active once the device reconnects to the broker with them,
+/// otherwise it restarts with the previous ones.
+#[derive(Clone)]
+pub struct CertStore(Arc<Mutex<EspNvs<NvsDefault>>>);
+
+impl CertStore {
+    pub fn new(nvs_part: EspNvsPartition<NvsDefault>) -> anyhow::Result<Self> {
+        let nvs = EspNvs::new(nvs_part, "doorsys", true)?;
+        Ok(CertStore(Arc::new(Mutex::new(nvs))))
+    }
+
+    /// Certificates to connect with, the staged ones when they are
+    /// on trial, along with the trial flag
+    pub fn load(&self) -> anyhow::Result<(Option<Certificates>, bool)> {
+        let nvs = self.0.lock().unwrap();
+        if let Some(staged) = read(&nvs, STAGED_KEY)? {
+            log::info!("Trying the staged certificates");
+            return Ok((Some(staged), true));
+        }
+        Ok((read(&nvs, ACTIVE_KEY)?, false))
+    }
+
+    /// Validates and stages the certificates for the next boot
+    pub fn stage(&self, certificates: &Certificates) -> anyhow::Result<()> {
+        certificates.validate()?;
+        let buf = postcard::to_allocvec(certificates).context("encoding failure")?;
+        let mut nvs = self.0.lock().unwrap();
+        nvs.set_raw(STAGED_KEY, &buf).context("nvs failure")?;
+        storage::record_write(Area::Config, buf.len());
+        Ok(())
+    }
+
+    /// Promotes the staged certificates once the broker is reached with them
+    /// or discards them and restarts when the trial times out
+    pub fn confirm(&self, uplink: Uplink) {
+        let store = self.clone();
+        thread::spawn(move || {
+            let started = Instant::now();
+            while !uplink.connected() {
+                if started.elapsed() > TRIAL_TIMEOUT {
+                    log::error!("Staged certificates failed to connect, rolling back");
+                    if let Err(e) = store.0.lock().unwrap().remove(STAGED_KEY) {
+                        log::error!("error discarding staged certificates: {}", e);
+                    }
+                    unsafe { esp_restart() };
+                }
+                thread::sleep(TRIAL_POLL);
+            }
+            if let Err(e) = store.promote() {
+                log::error!("error promoting staged certificates: {}", e);
+            }
+        });
+    }
+
+    fn promote(&self) -> anyhow::Result<()> {
+        let mut nvs = self.0.lock().unwrap();
+        let staged = read(&nvs, STAGED_KEY)?.context("no staged certificates")?;
+        let buf = postcard::to_allocvec(&staged).context("encoding failure")?;
+        nvs.set_raw(ACTIVE_KEY, &buf).context("nvs failure")?;
+        storage::record_write(Area::Config, buf.len());
+        nvs.remove(STAGED_KEY)?;
+        log::info!("Staged certificates are now active");
+        Ok(())
+    }
+}
+
+fn read(nvs: &EspNvs<NvsDefault>, key: &str) -> anyhow::Result<Option<Certificates>> {
+    let blob_size = nvs.blob_len(key)?.unwrap_or(0);
+    let mut buf = vec![0; blob_size];
+    match nvs.get_raw(key, &mut buf)? {
+        Some(slice) => Ok(Some(
+            postcard::from_bytes(slice).context("error decoding certificates")?,
+        )),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,66 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+use serde::{Deserialize, Serialize};
+
+use crate::config::ChannelConfig;
+use crate::storage::{self, Area};
+
+const CHANNELS_KEY: &str = "channels";
+
+/// Credential channels of the reader that can be disabled on their own,
+/// e.g. pins during a pin leak incident while cards keep working
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct ChannelState {
+    pub pin: bool,
+    pub card: bool,
+}
+
+/// Channel state persisted in nvs so a disabled channel stays
+/// disabled after a reboot. The settings are used until it is changed.
+#[derive(Clone)]
+pub struct Channels(Arc<Mutex<ChannelData>>);
+
+struct ChannelData {
+    nvs: EspNvs<NvsDefault>,
+    state: ChannelState,
+}
+
+impl Channels {
+    pub fn new(
+        nvs_part: EspNvsPartition<NvsDefault>,
+        config: Option<&ChannelConfig>,
+    ) -> anyhow::Result<Self> {
+        let nvs = EspNvs::new(nvs_part, "doorsys", true)?;
+        let mut buf = [0; 8];
+        let state = match nvs.get_raw(CHANNELS_KEY, &mut buf)? {
+            Some(slice) => postcard::from_bytes(slice).context("error decoding channels")?,
+            None => ChannelState {
+                pin: config.map_or(true, |config| config.pin),
+                card: config.map_or(true, |config| config.card),
+            },
+        };
+        log::info!("Reader channels {:?}", state);
+        Ok(Channels(Arc::new(Mutex::new(ChannelData { nvs, state }))))
+    }
+
+    pub fn set(&self, state: ChannelState) -> anyhow::Result<()> {
+        let mut data = self.0.lock().unwrap();
+        let buf = postcard::to_allocvec(&state).context("encoding failure")?;
+        data.nvs
+            .set_raw(CHANNELS_KEY, &buf)
+            .context("nvs failure")?;
+        storage::record_write(Area::Config, buf.len());
+        data.state = state;
+        Ok(())
+    }
+
+    pub fn pin_enabled(&self) -> bool {
+        self.0.lock().unwrap().state.pin
+    }
+
+    pub fn card_enabled(&self) -> bool {
+        self.0.lock().unwrap().state.card
+    }
+}
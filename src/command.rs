@@ -0,0 +1,546 @@
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use esp_idf_svc::sys::{
+    esp, esp_restart, heap_caps_get_free_size, heap_caps_get_largest_free_block, nvs_flash_erase,
+    MALLOC_CAP_DEFAULT,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::alarm::Alarm;
+use crate::auth::Level;
+use crate::backup::{self, StateBackup};
+use crate::certs::{CertStore, Certificates};
+use crate::channel::{ChannelState, Channels};
+use crate::config::DoorsysConfig;
+use crate::cron::{Job, Jobs};
+use crate::door::{DoorCommand, DoorMode};
+use crate::management::{Change, ManagementLog, Origin};
+use crate::mqtt::Outbox;
+use crate::ota;
+use crate::power::Power;
+use crate::privacy::Redacted;
+use crate::ratelimit::OpenLimit;
+use crate::reload::SettingsBus;
+use crate::rules::{Requirement, Rule, Rules};
+use crate::schedule::{Holiday, Holidays, Mode, Override, Scheduler, TimeWindow};
+use crate::storage;
+use crate::sync::SyncRequest;
+use crate::temporary::{TemporaryCode, TemporaryCodes};
+use crate::user::{Credential, UserDB};
+use crate::walktest::WalkTest;
+use crate::wiring::WiringTest;
+
+/// Time given for the diagnostics to be published before a restart
+const RESTART_DELAY: Duration = Duration::from_secs(2);
+
+/// Message received on doorsys/cmd/{device_id}.
+/// The counter must increase with every command, unix time in
+/// milliseconds is a good choice, otherwise the command is rejected.
+#[derive(Deserialize, Debug)]
+pub struct CommandMessage {
+    pub counter: u64,
+    pub command: Command,
+}
+
+/// Message received on doorsys/broadcast, addressed to every device
+/// of the site. Its counter is kept apart from the device commands.
+#[derive(Deserialize, Debug)]
+pub struct BroadcastMessage {
+    pub counter: u64,
+    pub command: BroadcastCommand,
+}
+
+/// Commands accepted on the broadcast topic
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub enum BroadcastCommand {
+    Lockdown,
+    ClearLockdown,
+    ResendState,
+}
+
+impl From<BroadcastCommand> for Command {
+    fn from(command: BroadcastCommand) -> Self {
+        match command {
+            BroadcastCommand::Lockdown => Command::SetLockdown(true),
+            BroadcastCommand::ClearLockdown => Command::SetLockdown(false),
+            BroadcastCommand::ResendState => Command::ResendState,
+        }
+    }
+}
+
+/// Commands addressed to a single device
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Command {
+    /// Opens the door momentarily, 0 is the first door
+    Open(u8),
+    /// Pulses the chime output
+    Chime,
+    /// Replaces the access rules
+    SetRules(Vec<Rule>),
+    /// Replaces the holiday calendar
+    SetHolidays(Vec<Holiday>),
+    /// Replaces the windows where the door is kept unlocked
+    SetUnlockSchedule(Vec<TimeWindow>),
+    /// Adds a temporary override on top of the schedule
+    Override(Override),
+    /// Removes all overrides returning to the normal schedule
+    ClearOverrides,
+    /// Erases every setting and user then restarts
+    FactoryReset,
+    /// Replaces the user database with a file downloaded over https
+    SyncUsers(SyncRequest),
+    /// Replaces the scheduled jobs
+    SetJobs(Vec<Job>),
+    Reboot,
+    /// Attaches the user id and routing data to an existing code
+    SetCredential {
+        code: i32,
+        credential: Credential,
+    },
+    /// Publishes the status of a single code to doorsys/query/{device_id}
+    CheckCode(i32),
+    /// Installs a single use pin valid until it expires
+    AddTemporaryCode(TemporaryCode),
+    /// Rewrites the user database blobs and publishes the memory diagnostics
+    /// to doorsys/diag/{device_id}/memory. Restarts when the heap
+    /// fragmentation is above the given percentage.
+    Compact {
+        restart_above: Option<u8>,
+    },
+    /// Enables or disables the pin and card channels of the reader
+    SetChannels(ChannelState),
+    /// Publishes again every retained topic so a rebuilt
+    /// backend can repopulate its cache
+    ResendState,
+    /// Silences the local sounder and resets the latched alarms
+    AcknowledgeAlarm,
+    /// Replaces the mqtt TLS certificates. They are validated and staged,
+    /// then the device restarts and keeps them only if it reconnects.
+    InstallCertificates(Certificates),
+    /// Pulses every output in turn and publishes the input levels
+    /// to doorsys/wiring/{device_id}
+    WiringTest,
+    /// Rejects every credential until cleared
+    SetLockdown(bool),
+    /// Replaces the configuration file, same as the one uploaded at
+    /// provisioning, and reloads the behavior settings in place
+    ReloadSettings(String),
+    /// Installs the firmware image downloaded over https and restarts into
+    /// it. The previous image comes back if the new one is not healthy.
+    UpdateFirmware(String),
+    /// Switches the low power profile on or off until the next restart
+    SetLowPower(bool),
+    /// Publishes an encrypted backup of the whole state
+    /// to doorsys/backup/{device_id}
+    ExportState,
+    /// Replaces the whole state with the backup downloaded over https
+    /// and restarts
+    RestoreState(String),
+    /// Locks the door down, holds it open or returns it to the schedule
+    SetDoorMode(DoorMode),
+    /// Starts or stops the installer walk test, reported to
+    /// doorsys/walktest/{device_id}
+    WalkTest(bool),
+    /// Runs the command once the instant is reached, used to spread the
+    /// broadcasts. Never received from the backend.
+    #[serde(skip)]
+    Deferred(Instant, Box<Command>),
+}
+
+/// Answer to the check code command, everything needed to tell
+/// why a credential is not working without a site visit
+#[derive(Serialize, Debug)]
+pub struct CodeStatus {
+    pub code: i32,
+    pub exists: bool,
+    pub credential: Option<Credential>,
+    /// Credentials required by the access rules right now
+    pub requirement: Requirement,
+    pub mode: Mode,
+    pub timestamp: SystemTime,
+}
+
+/// Share of the free heap outside the largest block, in percent
+pub fn fragmentation(free: usize, largest_free_block: usize) -> u8 {
+    100 - (largest_free_block * 100)
+        .checked_div(free)
+        .unwrap_or(100)
+        .min(100) as u8
+}
+
+/// Answer to the compact command
+#[derive(Serialize, Debug)]
+pub struct MemoryDiagnostics {
+    pub free_heap: usize,
+    pub largest_free_block: usize,
+    /// Share of the free heap outside the largest block, in percent
+    pub fragmentation: u8,
+    pub nvs_used_entries: usize,
+    pub nvs_free_entries: usize,
+    /// False if the user database failed to be written again
+    pub compacted: bool,
+    pub restarting: bool,
+    pub timestamp: SystemTime,
+}
+
+impl MemoryDiagnostics {
+    fn read(compacted: bool, restart_above: Option<u8>) -> Self {
+        let (free_heap, largest_free_block) = unsafe {
+            (
+                heap_caps_get_free_size(MALLOC_CAP_DEFAULT),
+                heap_caps_get_largest_free_block(MALLOC_CAP_DEFAULT),
+            )
+        };
+        let fragmentation = fragmentation(free_heap, largest_free_block);
+        let (nvs_used_entries, nvs_free_entries) = match storage::nvs_stats() {
+            Ok(stats) => (stats.used_entries, stats.free_entries),
+            Err(e) => {
+                log::warn!("error reading nvs stats {}", e);
+                (0, 0)
+            }
+        };
+        MemoryDiagnostics {
+            free_heap,
+            largest_free_block,
+            fragmentation,
+            nvs_used_entries,
+            nvs_free_entries,
+            compacted,
+            restarting: restart_above.is_some_and(|threshold| fragmentation > threshold),
+            timestamp: SystemTime::now(),
+        }
+    }
+}
+
+impl Command {
+    /// Authorization level needed to execute the command
+    pub fn level(&self) -> Level {
+        match self {
+            Command::Open(_)
+            | Command::Chime
+            | Command::Override(_)
+            | Command::ClearOverrides
+            | Command::CheckCode(_)
+            | Command::AddTemporaryCode(_)
+            | Command::SetChannels(_)
+            | Command::ResendState
+            | Command::AcknowledgeAlarm
+            | Command::SetLockdown(_)
+            | Command::SetDoorMode(_) => Level::Operator,
+            Command::SetRules(_)
+            | Command::SetHolidays(_)
+            | Command::SetUnlockSchedule(_)
+            | Command::FactoryReset
+            | Command::SyncUsers(_)
+            | Command::SetJobs(_)
+            | Command::Reboot
+            | Command::SetCredential { .. }
+            | Command::Compact { .. }
+            | Command::InstallCertificates(_)
+            | Command::WiringTest
+            | Command::ReloadSettings(_)
+            | Command::UpdateFirmware(_)
+            | Command::SetLowPower(_)
+            | Command::ExportState
+            | Command::RestoreState(_)
+            | Command::WalkTest(_) => Level::Admin,
+            Command::Deferred(_, command) => command.level(),
+        }
+    }
+}
+
+/// Everything the commands act upon
+pub struct Executor {
+    pub user_db: UserDB,
+    pub rules: Rules,
+    pub holidays: Holidays,
+    pub scheduler: Scheduler,
+    pub jobs: Jobs,
+    /// Every door by index
+    pub door_txs: Vec<Sender<DoorCommand>>,
+    pub chime_tx: Option<Sender<()>>,
+    pub sync_tx: Sender<(Origin, SyncRequest)>,
+    pub query_tx: Outbox<CodeStatus>,
+    pub memory_tx: Outbox<MemoryDiagnostics>,
+    pub temporary_codes: TemporaryCodes,
+    pub channels: Channels,
+    pub resend_tx: Sender<()>,
+    pub alarm: Alarm,
+    pub management: ManagementLog,
+    pub cert_store: CertStore,
+    pub wiring: WiringTest,
+    pub walk_test: WalkTest,
+    pub config_store: Mutex<DoorsysConfig>,
+    pub settings_bus: SettingsBus,
+    pub open_limit: Mutex<OpenLimit>,
+    pub power: Power,
+    pub backup: Arc<StateBackup>,
+    /// Public key firmware images are signed with
+    pub firmware_key: Option<String>,
+}
+
+impl Executor {
+    fn execute(&self, origin: Origin, cmd: Command) {
+        match cmd {
+            Command::Open(door) => {
+                log::info!("Remote open of door {}", door);
+                let Some(door_tx) = self.door_txs.get(door as usize) else {
+                    log::warn!("Remote open of unknown door {}", door);
+                    return;
+                };
+                if !self.open_limit.lock().unwrap().allow() {
+                    return;
+                }
+                if let Err(e) = door_tx.send(DoorCommand::Open) {
+                    log::error!("error sending door command: {}", e);
+                }
+            }
+            Command::Chime => crate::ring_chime(&self.chime_tx),
+            Command::SetRules(new_rules) => {
+                log::info!("Updating {} access rules", new_rules.len());
+                let count = new_rules.len();
+                let result = self.rules.set(new_rules);
+                if let Err(e) = &result {
+                    log::error!("Error updating rules {}", e);
+                }
+                self.management
+                    .record(origin, Change::Rules, count, &result);
+            }
+            Command::SetHolidays(new_holidays) => {
+                log::info!("Updating {} holidays", new_holidays.len());
+                let count = new_holidays.len();
+                let result = self.holidays.set(new_holidays);
+                if let Err(e) = &result {
+                    log::error!("Error updating holidays {}", e);
+                }
+                self.management
+                    .record(origin, Change::Holidays, count, &result);
+            }
+            Command::SetUnlockSchedule(windows) => {
+                log::info!("Updating {} unlock windows", windows.len());
+                let count = windows.len();
+                let result = self.scheduler.set_unlock_windows(windows);
+                if let Err(e) = &result {
+                    log::error!("Error updating unlock schedule {}", e);
+                }
+                self.management
+                    .record(origin, Change::UnlockSchedule, count, &result);
+            }
+            Command::Override(window) => {
+                log::info!("Adding schedule override {:?}", window);
+                self.scheduler.add_override(window);
+                self.management
+                    .record(origin, Change::Overrides, 1, &Ok(()));
+            }
+            Command::ClearOverrides => {
+                log::info!("Clearing schedule overrides");
+                self.scheduler.clear_overrides();
+                self.management
+                    .record(origin, Change::Overrides, 0, &Ok(()));
+            }
+            Command::FactoryReset => {
+                log::warn!("Factory reset requested");
+                self.management
+                    .record(origin, Change::FactoryReset, 0, &Ok(()));
+                // Gives the management event a chance to be published
+                thread::sleep(RESTART_DELAY);
+                if let Err(e) = esp!(unsafe { nvs_flash_erase() }) {
+                    log::error!("Error erasing nvs {}", e);
+                }
+                unsafe { esp_restart() };
+            }
+            Command::SyncUsers(request) => {
+                if let Err(e) = self.sync_tx.send((origin, request)) {
+                    log::error!("Error starting user sync {}", e);
+                }
+            }
+            Command::SetJobs(jobs) => {
+                log::info!("Updating {} scheduled jobs", jobs.len());
+                let count = jobs.len();
+                let result = self.jobs.set(jobs);
+                if let Err(e) = &result {
+                    log::error!("Error updating jobs {}", e);
+                }
+                self.management.record(origin, Change::Jobs, count, &result);
+            }
+            Command::Reboot => {
+                log::warn!("Reboot requested");
+                unsafe { esp_restart() };
+            }
+            Command::SetCredential { code, credential } => {
+                log::info!("Updating credential {}", Redacted(code));
+                let result = self.user_db.set_credential(code, credential);
+                if let Err(e) = &result {
+                    log::error!("Error updating credential {}", e);
+                }
+                self.management
+                    .record(origin, Change::Credential, 1, &result);
+            }
+            Command::CheckCode(code) => {
+                let status = CodeStatus {
+                    code,
+                    exists: self.user_db.contains(code),
+                    credential: self.user_db.credential(code),
+                    requirement: self.rules.requirement(),
+                    mode: self.scheduler.mode(),
+                    timestamp: SystemTime::now(),
+                };
+                log::info!("Code {} exists: {}", Redacted(code), status.exists);
+                if let Err(e) = self.query_tx.send(status) {
+                    log::error!("Error sending code status {}", e);
+                }
+            }
+            Command::AddTemporaryCode(temp) => {
+                log::info!(
+                    "Adding temporary code {} until {}",
+                    Redacted(temp.code),
+                    temp.expires
+                );
+                let result = self.temporary_codes.add(temp);
+                if let Err(e) = &result {
+                    log::error!("Error adding temporary code {}", e);
+                }
+                self.management
+                    .record(origin, Change::TemporaryCode, 1, &result);
+            }
+            Command::ResendState => {
+                if let Err(e) = self.resend_tx.send(()) {
+                    log::error!("Error requesting state resend {}", e);
+                }
+            }
+            Command::AcknowledgeAlarm => {
+                log::info!("Remote alarm acknowledge");
+                self.alarm.acknowledge();
+            }
+            Command::InstallCertificates(certificates) => {
+                log::info!("Installing mqtt certificates");
+                let result = self.cert_store.stage(&certificates);
+                if let Err(e) = &result {
+                    log::error!("Error installing certificates {}", e);
+                }
+                self.management
+                    .record(origin, Change::Certificates, 0, &result);
+                if result.is_ok() {
+                    log::warn!("Restarting to try the new certificates");
+                    thread::sleep(RESTART_DELAY);
+                    unsafe { esp_restart() };
+                }
+            }
+            Command::SetLockdown(lockdown) => {
+                log::info!("Remote lockdown {}", lockdown);
+                if self.scheduler.set_lockdown(lockdown) {
+                    let count = usize::from(lockdown);
+                    self.management
+                        .record(origin, Change::Lockdown, count, &Ok(()));
+                }
+            }
+            Command::SetDoorMode(mode) => {
+                log::info!("Remote door mode {:?}", mode);
+                if self.scheduler.set_door_mode(mode) {
+                    self.management
+                        .record(origin, Change::DoorMode, mode as usize, &Ok(()));
+                }
+            }
+            Command::ReloadSettings(file) => {
+                log::info!("Reloading settings");
+                let result = self.config_store.lock().unwrap().store_settings(&file);
+                let result = result.map(|settings| self.settings_bus.publish(settings));
+                if let Err(e) = &result {
+                    log::error!("Error reloading settings {}", e);
+                }
+                self.management
+                    .record(origin, Change::Settings, file.len(), &result);
+            }
+            Command::UpdateFirmware(url) => ota::update(
+                url,
+                self.firmware_key.clone(),
+                origin,
+                self.management.clone(),
+            ),
+            Command::SetLowPower(low_power) => {
+                log::info!("Setting low power {}", low_power);
+                let result = self.power.set(low_power);
+                if let Err(e) = &result {
+                    log::error!("Error setting low power {}", e);
+                }
+                let count = usize::from(low_power);
+                self.management
+                    .record(origin, Change::Power, count, &result);
+            }
+            Command::ExportState => {
+                log::info!("Exporting the device state");
+                if let Err(e) = self.backup.export() {
+                    log::error!("Error exporting the device state {:?}", e);
+                }
+            }
+            Command::RestoreState(url) => {
+                backup::restore(self.backup.clone(), url, origin, self.management.clone())
+            }
+            Command::WiringTest => {
+                log::info!("Starting the wiring test");
+                self.wiring.run();
+            }
+            Command::WalkTest(true) => self.walk_test.start(),
+            Command::WalkTest(false) => self.walk_test.stop(),
+            // Held by the command thread until due
+            Command::Deferred(_, cmd) => self.execute(origin, *cmd),
+            Command::SetChannels(state) => {
+                log::info!("Updating reader channels {:?}", state);
+                let result = self.channels.set(state);
+                if let Err(e) = &result {
+                    log::error!("Error updating reader channels {}", e);
+                }
+                self.management.record(origin, Change::Channels, 0, &result);
+            }
+            Command::Compact { restart_above } => {
+                log::info!("Rewriting user database");
+                let compacted = match self.user_db.rewrite() {
+                    Ok(()) => true,
+                    Err(e) => {
+                        log::error!("Error rewriting user database {}", e);
+                        false
+                    }
+                };
+                let diagnostics = MemoryDiagnostics::read(compacted, restart_above);
+                log::info!("Memory diagnostics {:?}", diagnostics);
+                let restarting = diagnostics.restarting;
+                if let Err(e) = self.memory_tx.send(diagnostics) {
+                    log::error!("Error sending memory diagnostics {}", e);
+                }
+                if restarting {
+                    log::warn!("Heap fragmented, restarting");
+                    thread::sleep(RESTART_DELAY);
+                    unsafe { esp_restart() };
+                }
+            }
+        }
+    }
+}
+
+/// Executes the commands received from the mqtt broker
+/// and the other interfaces
+pub fn setup_commands(cmd_rx: Receiver<(Origin, Command)>, executor: Executor) {
+    thread::spawn(move || {
+        let mut deferred: Vec<(Instant, Origin, Command)> = Vec::new();
+        loop {
+            let received = match deferred.iter().map(|(at, _, _)| *at).min() {
+                Some(at) => cmd_rx.recv_timeout(at.saturating_duration_since(Instant::now())),
+                None => cmd_rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match received {
+                Ok((origin, Command::Deferred(at, cmd))) => deferred.push((at, origin, *cmd)),
+                Ok((origin, cmd)) => executor.execute(origin, cmd),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            let now = Instant::now();
+            while let Some(index) = deferred.iter().position(|(at, _, _)| *at <= now) {
+                let (_, origin, cmd) = deferred.remove(index);
+                executor.execute(origin, cmd);
+            }
+        }
+    });
+}
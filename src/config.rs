@@ -1,21 +1,52 @@
 use core::str;
-use std::io::{Read, Write};
+use std::ffi::c_char;
+use std::io::{ErrorKind, Read, Write};
 use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use esp_idf_svc::sys::{
+    esp, esp_partition_find_first, esp_partition_read, esp_partition_subtype_t,
+    esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+};
 
 use esp_idf_svc::wifi::{BlockingWifi, ClientConfiguration, Configuration};
 use esp_idf_svc::{
     nvs::{EspNvs, EspNvsPartition, NvsDefault},
     wifi::{AuthMethod, EspWifi},
 };
-use serde::{Deserialize, Serialize};
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::audio::Clips;
+use crate::audit::OverflowPolicy;
+use crate::card::Transform;
+use crate::console;
+use crate::exit::LongPress;
+use crate::feedback::FeedbackPattern;
+use crate::input::Role;
+use crate::network::TimeoutAction;
+use crate::schedule::TimeWindow;
+use crate::smartconfig;
+use crate::stale::StalePolicy;
+use crate::storage::{self, Area};
+use crate::webhook::Kind;
+use crate::wiegand::{Completion, Edge, LinePull};
+use crate::zone::Trigger;
 
 #[derive(Deserialize, Debug)]
 struct Config {
-    wifi: WifiConfig,
+    /// Only left out of pre-provisioned files, the network then
+    /// comes from the EspTouch app over SmartConfig
+    wifi: Option<WifiConfig>,
     mqtt: MqttConfig,
+    /// PEM public key firmware images are signed with, only taken when the
+    /// device is provisioned so the settings can't replace it
+    firmware_key: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 struct WifiConfig {
     ssid: String,
     password: String,
@@ -29,6 +60,879 @@ pub struct MqttConfig {
     pub password: String,
 }
 
+/// Optional behaviour settings read from the same TOML file as the
+/// wifi and mqtt sections. Every section is optional so config files
+/// written for older firmware versions keep working.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Settings {
+    /// POSIX timezone used by schedules e.g. "EST5EDT,M3.2.0,M11.1.0"
+    pub timezone: Option<String>,
+    /// Masks pins and card numbers in the logs
+    pub privacy: bool,
+    /// Unlock windows wait for the first valid credential of the day
+    pub first_card_in: bool,
+    /// Building the device belongs to, included in topics and audits
+    pub site: Option<String>,
+    pub watchdog: Option<WatchdogConfig>,
+    pub strike: Option<StrikeConfig>,
+    pub chime: Option<ChimeConfig>,
+    pub motion: Option<MotionConfig>,
+    pub security: Option<SecurityConfig>,
+    pub scan_guard: Option<ScanGuardConfig>,
+    pub api: Option<ApiConfig>,
+    pub webhook: Option<WebhookConfig>,
+    pub modbus: Option<ModbusConfig>,
+    pub influx: Option<InfluxConfig>,
+    pub exit: Option<ExitConfig>,
+    pub contact: Option<ContactConfig>,
+    pub feedback: Option<FeedbackConfig>,
+    pub wiegand: Option<WiegandConfig>,
+    pub card: Option<CardConfig>,
+    pub selftest: Option<SelfTestConfig>,
+    pub grant: Option<GrantConfig>,
+    pub pin: Option<PinConfig>,
+    pub stale: Option<StaleConfig>,
+    pub peer: Option<PeerConfig>,
+    pub audit: Option<AuditConfig>,
+    pub burst: Option<BurstConfig>,
+    pub elevator: Option<ElevatorConfig>,
+    pub led: Option<LedConfig>,
+    pub channels: Option<ChannelConfig>,
+    pub alarm: Option<AlarmConfig>,
+    pub relay: Option<RelayConfig>,
+    pub storage: Option<StorageConfig>,
+    pub provisioning: Option<ProvisioningConfig>,
+    pub audio: Option<AudioConfig>,
+    pub health: Option<HealthConfig>,
+    pub reboot: Option<RebootConfig>,
+    pub broadcast: Option<BroadcastConfig>,
+    pub remote_open: Option<RemoteOpenConfig>,
+    pub vms: Option<VmsConfig>,
+    pub duress: Option<DuressConfig>,
+    pub ota: Option<OtaConfig>,
+    pub power: Option<PowerConfig>,
+    pub discovery: Option<DiscoveryConfig>,
+    pub door: Option<DoorConfig>,
+    pub zone: Option<ZoneConfig>,
+    pub second_door: Option<SecondDoorConfig>,
+    /// Extra dry contact inputs on top of the dedicated sections
+    pub inputs: Vec<InputConfig>,
+}
+
+/// External hardware watchdog fed by strobing a gpio
+#[derive(Deserialize, Debug)]
+pub struct WatchdogConfig {
+    pub pin: i32,
+    #[serde(default = "default_strobe_ms")]
+    pub strobe_ms: u64,
+}
+
+fn default_strobe_ms() -> u64 {
+    500
+}
+
+/// Strike current sensing, the shunt amplifier output must be wired to gpio3
+#[derive(Deserialize, Debug)]
+pub struct StrikeConfig {
+    pub min_mv: u16,
+    #[serde(default = "default_settle_ms")]
+    pub settle_ms: u64,
+}
+
+fn default_settle_ms() -> u64 {
+    200
+}
+
+/// Limits how long the config server waits on a device without wifi
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct ProvisioningConfig {
+    pub timeout_minutes: u64,
+    pub on_timeout: TimeoutAction,
+}
+
+impl Default for ProvisioningConfig {
+    fn default() -> Self {
+        ProvisioningConfig {
+            timeout_minutes: 10,
+            on_timeout: TimeoutAction::default(),
+        }
+    }
+}
+
+/// Health reporting detail by time of day, every metric is reported
+/// inside the windows and only the heap outside of them
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct HealthConfig {
+    /// Local time windows, e.g. business hours
+    pub verbose_windows: Vec<TimeWindow>,
+    pub verbose_minutes: u64,
+    pub minimal_minutes: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            verbose_windows: Vec::new(),
+            verbose_minutes: 1,
+            minimal_minutes: 15,
+        }
+    }
+}
+
+/// Weekly preventive reboot, disabled when absent.
+/// Days is a bit mask where bit 0 is sunday and bit 6 is saturday,
+/// minute is the start of the window in minutes since midnight.
+#[derive(Deserialize, Debug)]
+pub struct RebootConfig {
+    pub days: u8,
+    #[serde(deserialize_with = "minute_of_day")]
+    pub minute: u16,
+    #[serde(default = "default_window_minutes")]
+    pub window_minutes: u16,
+    /// Longest wait for the pending audits to be published
+    #[serde(default = "default_flush_secs")]
+    pub flush_secs: u64,
+}
+
+fn default_window_minutes() -> u16 {
+    60
+}
+
+fn default_flush_secs() -> u64 {
+    30
+}
+
+/// Commands sent to every device on doorsys/broadcast,
+/// accepted with the defaults when absent
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct BroadcastConfig {
+    /// Each device waits a random delay up to this before running the command
+    pub jitter_secs: u64,
+}
+
+impl Default for BroadcastConfig {
+    fn default() -> Self {
+        BroadcastConfig { jitter_secs: 10 }
+    }
+}
+
+/// Rate limit of the remote open commands, enabled with the defaults when absent
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RemoteOpenConfig {
+    pub max_opens: usize,
+    pub window_secs: u64,
+}
+
+impl Default for RemoteOpenConfig {
+    fn default() -> Self {
+        RemoteOpenConfig {
+            max_opens: 6,
+            window_secs: 60,
+        }
+    }
+}
+
+/// Health check of a new firmware, enabled with the defaults when absent
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct OtaConfig {
+    /// Time given to a new image to reach the broker before it is rolled back
+    pub confirm_minutes: u64,
+}
+
+impl Default for OtaConfig {
+    fn default() -> Self {
+        OtaConfig {
+            confirm_minutes: 10,
+        }
+    }
+}
+
+/// Low power profile for installs running on batteries
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PowerConfig {
+    /// Profile at boot, switched at runtime with the SetLowPower command
+    pub low_power: bool,
+    pub cpu_mhz: i32,
+    /// Shortest interval between health reports
+    pub health_minutes: u64,
+}
+
+impl Default for PowerConfig {
+    fn default() -> Self {
+        PowerConfig {
+            low_power: false,
+            cpu_mhz: 80,
+            health_minutes: 15,
+        }
+    }
+}
+
+/// Broker lookup over DNS-SD when the mqtt url is "auto"
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+    /// Time to wait for the broker to answer the query
+    pub timeout_secs: u64,
+    /// Used when no broker answers
+    pub fallback_url: Option<String>,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            timeout_secs: 5,
+            fallback_url: None,
+        }
+    }
+}
+
+/// Watch over the free nvs entries, enabled with the defaults when absent
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct StorageConfig {
+    /// Free entries below which the user database is compacted
+    /// and an alert raised if it doesn't help
+    pub min_free_entries: usize,
+    pub check_minutes: u64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            min_free_entries: 64,
+            check_minutes: 10,
+        }
+    }
+}
+
+/// Door relay protection
+#[derive(Deserialize, Debug)]
+pub struct RelayConfig {
+    /// Minimum time the relay stays de-energized between actuations
+    pub min_off_ms: u64,
+}
+
+/// Momentary unlock, enabled with the defaults when absent
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DoorConfig {
+    /// Time the relay stays energized after a grant or a remote open
+    #[serde(deserialize_with = "non_zero")]
+    pub unlock_secs: u64,
+}
+
+impl Default for DoorConfig {
+    fn default() -> Self {
+        DoorConfig { unlock_secs: 4 }
+    }
+}
+
+/// Output pulsed by the doorbell or the remote chime command
+#[derive(Deserialize, Debug)]
+pub struct ChimeConfig {
+    pub pin: i32,
+    #[serde(default = "default_pulse_ms")]
+    pub pulse_ms: u64,
+}
+
+fn default_pulse_ms() -> u64 {
+    500
+}
+
+/// PIR sensor input, optionally pulsing an output to arm a camera or light
+#[derive(Deserialize, Debug)]
+pub struct MotionConfig {
+    pub pin: i32,
+    #[serde(default)]
+    pub active_low: bool,
+    #[serde(default = "default_debounce_ms")]
+    pub debounce_ms: u64,
+    pub trigger_pin: Option<i32>,
+    #[serde(default = "default_pulse_ms")]
+    pub trigger_ms: u64,
+}
+
+fn default_debounce_ms() -> u64 {
+    50
+}
+
+/// Once a secret is set, user and command messages must be signed with it
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct SecurityConfig {
+    /// Hex encoded shared secret
+    pub secret: Option<String>,
+    /// ATECC608A slot holding the shared secret, takes precedence over `secret`
+    pub secret_slot: Option<u16>,
+    /// Hex encoded secret required for admin commands
+    pub admin_secret: Option<String>,
+    /// ATECC608A slot holding the admin secret
+    pub admin_secret_slot: Option<u16>,
+    /// Uses the ATECC608A private key for the mqtt TLS client certificate
+    pub secure_element: bool,
+    /// PEM encoded mqtt TLS client certificate
+    pub client_cert: Option<String>,
+}
+
+/// Detection of card numbers being brute forced on the reader
+#[derive(Deserialize, Debug)]
+pub struct ScanGuardConfig {
+    /// Unknown cards within the window that raise the alert
+    #[serde(deserialize_with = "non_zero")]
+    pub max_denials: usize,
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    /// Cards are ignored for this long after an alert, zero keeps the reader enabled
+    #[serde(default)]
+    pub lockout_secs: u64,
+}
+
+fn default_window_secs() -> u64 {
+    60
+}
+
+/// Local http api for integrations that don't speak mqtt
+#[derive(Deserialize, Debug)]
+pub struct ApiConfig {
+    /// Bearer token required on every request
+    pub token: String,
+    #[serde(default = "default_api_port")]
+    pub port: u16,
+}
+
+fn default_api_port() -> u16 {
+    80
+}
+
+/// Http endpoint notified of selected events, e.g. a Slack incoming webhook
+#[derive(Deserialize, Debug)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Value of the Authorization header
+    pub authorization: Option<String>,
+    pub events: Vec<Kind>,
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+}
+
+fn default_retries() -> u32 {
+    5
+}
+
+/// Modbus TCP server for building automation
+#[derive(Deserialize, Debug)]
+pub struct ModbusConfig {
+    #[serde(default = "default_modbus_port")]
+    pub port: u16,
+    /// Allows the unlock coil to be written
+    #[serde(default)]
+    pub allow_writes: bool,
+}
+
+fn default_modbus_port() -> u16 {
+    502
+}
+
+/// InfluxDB or Telegraf http endpoint receiving the health metrics
+/// directly instead of through mqtt
+#[derive(Deserialize, Debug)]
+pub struct InfluxConfig {
+    /// Write url including the database or org and bucket query parameters
+    pub url: String,
+    /// Value of the Authorization header e.g. "Token ..."
+    pub authorization: Option<String>,
+}
+
+/// Request to exit button, a short press opens the door momentarily
+#[derive(Deserialize, Debug)]
+pub struct ExitConfig {
+    pub pin: i32,
+    #[serde(default = "default_true")]
+    pub active_low: bool,
+    #[serde(default = "default_debounce_ms")]
+    pub debounce_ms: u64,
+    /// Presses at least this long trigger the long press action
+    #[serde(default = "default_long_press_ms")]
+    pub long_press_ms: u64,
+    #[serde(default)]
+    pub long_press: LongPress,
+    /// How long the door is kept unlocked by the hold action
+    #[serde(default = "default_hold_minutes")]
+    pub hold_minutes: u64,
+    pub eol: Option<EolConfig>,
+}
+
+/// Door contact, active while the door is open
+#[derive(Deserialize, Debug)]
+pub struct ContactConfig {
+    pub pin: i32,
+    #[serde(default)]
+    pub active_low: bool,
+    #[serde(default = "default_debounce_ms")]
+    pub debounce_ms: u64,
+    /// Locks the door as soon as it closes instead of waiting the full unlock time
+    #[serde(default = "default_true")]
+    pub relock_on_close: bool,
+    pub eol: Option<EolConfig>,
+}
+
+/// End-of-line resistors of a supervised input, wired to an adc1 pin
+/// (gpio0 to gpio4). From the lowest voltage up the line is shorted,
+/// in one contact state, in the other, then cut.
+#[derive(Deserialize, Debug, Clone)]
+pub struct EolConfig {
+    /// Below this the line is shorted
+    pub short_mv: u16,
+    /// Between the two contact states, the lower one is active when
+    /// the input is active low
+    pub split_mv: u16,
+    /// From this the line is cut
+    pub cut_mv: u16,
+}
+
+/// Keypad buzzer patterns, alternating on and off durations in milliseconds
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct FeedbackConfig {
+    pub grant: Option<FeedbackPattern>,
+    pub deny: Option<FeedbackPattern>,
+    pub timeout: Option<FeedbackPattern>,
+    pub lockout: Option<FeedbackPattern>,
+    pub lockdown_deny: Option<FeedbackPattern>,
+    pub enrollment: Option<FeedbackPattern>,
+    pub short_pin: Option<FeedbackPattern>,
+    pub walk_test: Option<FeedbackPattern>,
+}
+
+/// DFPlayer Mini audio module playing spoken prompts instead of beeps
+#[derive(Deserialize, Debug)]
+pub struct AudioConfig {
+    /// Wired to the RX line of the module
+    pub tx_pin: i32,
+    /// Wired to the TX line of the module, its replies are not used
+    pub rx_pin: i32,
+    /// From 0 to 30
+    #[serde(default = "default_volume")]
+    pub volume: u8,
+    /// SD card folder holding the clips, e.g. one per language
+    #[serde(default = "default_folder")]
+    pub folder: u8,
+    #[serde(default)]
+    pub clips: Clips,
+}
+
+fn default_volume() -> u8 {
+    20
+}
+
+fn default_folder() -> u8 {
+    1
+}
+
+/// Card number normalization, applied before the lookup and the audit
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct CardConfig {
+    /// Applied in order to the raw card number before everything else
+    pub transforms: Vec<Transform>,
+    /// Swaps the order of the three bytes of the card number
+    pub reverse_bytes: bool,
+    /// Mirrors the 24 bits of the card number
+    pub reverse_bits: bool,
+    /// Drops the 8 bit facility code keeping the 16 bit card number
+    pub strip_facility: bool,
+    /// Keeps only the last digits of the card number
+    pub digits: Option<u32>,
+}
+
+/// Notifies secondary systems such as an elevator controller after a grant
+#[derive(Deserialize, Debug)]
+pub struct GrantConfig {
+    /// Publishes every grant to doorsys/grant/{device_id}
+    #[serde(default)]
+    pub publish: bool,
+    /// Output pulsed when a credential with a route is granted
+    pub pin: Option<i32>,
+    #[serde(default = "default_pulse_ms")]
+    pub pulse_ms: u64,
+}
+
+/// Snapshot triggers for the video management system
+#[derive(Deserialize, Debug)]
+pub struct VmsConfig {
+    /// Output pulsed along with every trigger
+    pub pin: Option<i32>,
+    #[serde(default = "default_pulse_ms")]
+    pub pulse_ms: u64,
+}
+
+/// Duress pins, a user's pin plus the offset. There is no default
+/// offset, a small one would take a mistyped pin as duress.
+#[derive(Deserialize, Debug)]
+pub struct DuressConfig {
+    pub offset: i32,
+}
+
+/// Controllers locked down together when one of them raises a trigger
+#[derive(Deserialize, Debug)]
+pub struct ZoneConfig {
+    pub id: String,
+    /// Alerts that request the lockdown, when empty the lockdowns
+    /// requested by the others are still honored
+    #[serde(default = "default_zone_triggers")]
+    pub triggers: Vec<Trigger>,
+}
+
+fn default_zone_triggers() -> Vec<Trigger> {
+    vec![Trigger::Duress, Trigger::ForcedOpen]
+}
+
+/// Door driven by the same controller with its own reader and relay.
+/// Its audits are tagged with door 1, the first door being door 0.
+#[derive(Deserialize, Debug)]
+pub struct SecondDoorConfig {
+    pub relay_pin: i32,
+    pub d0_pin: i32,
+    pub d1_pin: i32,
+    /// Led and buzzer line of the second reader,
+    /// it shares the one of the first reader when absent
+    pub feedback_pin: Option<i32>,
+    /// Contact of the second door, with the held and forced open alarms
+    pub contact: Option<ContactConfig>,
+}
+
+impl SecondDoorConfig {
+    /// Every gpio driven or read by the door
+    pub fn pins(&self) -> Vec<i32> {
+        [
+            Some(self.relay_pin),
+            Some(self.d0_pin),
+            Some(self.d1_pin),
+            self.feedback_pin,
+            self.contact.as_ref().map(|config| config.pin),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+}
+
+/// Number of digits accepted in a pin
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct PinConfig {
+    pub min_digits: usize,
+    pub max_digits: usize,
+}
+
+impl Default for PinConfig {
+    fn default() -> Self {
+        PinConfig {
+            min_digits: 0,
+            max_digits: 8,
+        }
+    }
+}
+
+/// Policy applied after the device is offline for a while
+#[derive(Deserialize, Debug)]
+pub struct StaleConfig {
+    pub after_hours: u64,
+    #[serde(default)]
+    pub policy: StalePolicy,
+}
+
+/// Dry contact input configured by its role
+#[derive(Deserialize, Debug)]
+pub struct InputConfig {
+    pub pin: i32,
+    pub role: Role,
+    #[serde(default)]
+    pub active_low: bool,
+    #[serde(default = "default_debounce_ms")]
+    pub debounce_ms: u64,
+    pub eol: Option<EolConfig>,
+}
+
+/// Reader channels enabled until changed by a command
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct ChannelConfig {
+    pub pin: bool,
+    pub card: bool,
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        ChannelConfig {
+            pin: true,
+            card: true,
+        }
+    }
+}
+
+/// Status blink on the led line of the reader
+#[derive(Deserialize, Debug)]
+pub struct LedConfig {
+    pub pin: i32,
+    /// Most readers light the led when the line is pulled low
+    #[serde(default = "default_true")]
+    pub active_low: bool,
+}
+
+/// Floor relays of an elevator access board
+#[derive(Deserialize, Debug)]
+pub struct ElevatorConfig {
+    /// Output of each floor, the first one is bit 0 of the mask
+    pub pins: Vec<i32>,
+    #[serde(default = "default_pulse_ms")]
+    pub pulse_ms: u64,
+}
+
+/// Local sounder for the door alarms
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct AlarmConfig {
+    /// Sounder output, driven high while an alarm is active
+    pub pin: Option<i32>,
+    /// Keeps the sounder on until acknowledged, even after the door closes
+    pub latch: bool,
+    pub held_open_secs: u64,
+    /// Pin that acknowledges the alarms when typed on the keypad
+    pub master_code: Option<i32>,
+    /// Time after the relay is released or locked again during which the
+    /// door opening is expected, zero disables the forced open alarm
+    pub forced_open_secs: u64,
+    /// Drives the sounder for a forced door, it is still reported without
+    pub forced_open_sounder: bool,
+}
+
+impl Default for AlarmConfig {
+    fn default() -> Self {
+        AlarmConfig {
+            pin: None,
+            latch: true,
+            held_open_secs: 60,
+            master_code: None,
+            forced_open_secs: 10,
+            forced_open_sounder: true,
+        }
+    }
+}
+
+/// Keeps the door unlocked across rapid consecutive grants
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct BurstConfig {
+    /// Grants within the window that start the burst
+    pub grants: usize,
+    pub window_secs: u64,
+    /// Time the door stays unlocked after each grant in the burst
+    pub hold_secs: u64,
+}
+
+impl Default for BurstConfig {
+    fn default() -> Self {
+        BurstConfig {
+            grants: 3,
+            window_secs: 30,
+            hold_secs: 10,
+        }
+    }
+}
+
+/// Audit queue used while the broker is unreachable
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AuditConfig {
+    /// Number of audits kept in memory, by each sink
+    pub queue_size: usize,
+    pub overflow: OverflowPolicy,
+    /// Destinations written on top of mqtt
+    pub sinks: Vec<SinkConfig>,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            queue_size: 200,
+            overflow: OverflowPolicy::default(),
+            sinks: Vec::new(),
+        }
+    }
+}
+
+/// Additional audit destination, each one with its own queue
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SinkConfig {
+    /// RFC 5424 messages over udp
+    Syslog {
+        host: String,
+        #[serde(default = "default_syslog_port")]
+        port: u16,
+    },
+    /// Json posted to the url
+    Webhook {
+        url: String,
+        /// Value of the Authorization header
+        authorization: Option<String>,
+    },
+    /// Csv lines appended to a file on an sd card wired to spi
+    SdCard {
+        sclk_pin: i32,
+        mosi_pin: i32,
+        miso_pin: i32,
+        cs_pin: i32,
+        #[serde(default = "default_sd_file")]
+        file: String,
+    },
+}
+
+fn default_syslog_port() -> u16 {
+    514
+}
+
+fn default_sd_file() -> String {
+    "audit.csv".to_owned()
+}
+
+/// Gossip with the other controllers on the same network
+#[derive(Deserialize, Debug)]
+pub struct PeerConfig {
+    /// Hex encoded key shared by every controller of the site
+    pub key: String,
+    #[serde(default = "default_peer_port")]
+    pub port: u16,
+    /// Peer addresses, the changes are broadcast when empty
+    #[serde(default)]
+    pub peers: Vec<String>,
+}
+
+fn default_peer_port() -> u16 {
+    4210
+}
+
+/// Boot self-test options
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct SelfTestConfig {
+    /// Opens the door momentarily to test the relay driver,
+    /// only enable it where a brief unlock on boot is acceptable
+    pub relay: bool,
+}
+
+/// Wiegand reader options
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct WiegandConfig {
+    /// Publishes the bit timing of every frame for installer diagnostics
+    pub capture: bool,
+    pub pull: LinePull,
+    pub edge: Edge,
+    /// Unknown packets are counted and reported once per window
+    pub unknown_report_minutes: u64,
+    pub completion: Completion,
+    /// Window in which a frame identical to the previous one is dropped
+    pub dedup_ms: u64,
+    /// Interval of the interrupt self-check, zero disables it
+    pub isr_check_seconds: u64,
+}
+
+impl Default for WiegandConfig {
+    fn default() -> Self {
+        WiegandConfig {
+            capture: false,
+            pull: LinePull::default(),
+            edge: Edge::default(),
+            unknown_report_minutes: 5,
+            completion: Completion::default(),
+            dedup_ms: 100,
+            isr_check_seconds: 10,
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Rejects zero, which would silently disable the feature the value sizes
+fn non_zero<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default + PartialEq,
+{
+    let value = T::deserialize(deserializer)?;
+    if value == T::default() {
+        return Err(D::Error::custom("zero is not allowed"));
+    }
+    Ok(value)
+}
+
+/// Minutes since midnight, a later minute would never come
+fn minute_of_day<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+    let minute = u16::deserialize(deserializer)?;
+    if minute >= 24 * 60 {
+        return Err(D::Error::custom(format!(
+            "minute {} is past the end of the day",
+            minute
+        )));
+    }
+    Ok(minute)
+}
+
+fn default_long_press_ms() -> u64 {
+    3000
+}
+
+fn default_hold_minutes() -> u64 {
+    5
+}
+
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const FIRMWARE_KEY: &str = "fw_key";
+/// Network received over SmartConfig, the file has none
+const SMARTCONFIG_WIFI_KEY: &str = "sc_wifi";
+
+/// Label of the partition holding the configuration written at manufacturing time
+const PROVISION_LABEL: &[u8] = b"provision\0";
+const PROVISION_SUBTYPE: esp_partition_subtype_t = 0x40;
+
+/// Reads the configuration file from the provision partition.
+/// The file is stored as plain text and ends at the first erased byte.
+fn read_provision_partition() -> anyhow::Result<Option<String>> {
+    let partition = unsafe {
+        esp_partition_find_first(
+            esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+            PROVISION_SUBTYPE,
+            PROVISION_LABEL.as_ptr() as *const c_char,
+        )
+    };
+    if partition.is_null() {
+        return Ok(None);
+    }
+    let size = unsafe { (*partition).size } as usize;
+    let mut buf = vec![0; size];
+    esp!(unsafe { esp_partition_read(partition, 0, buf.as_mut_ptr().cast(), size) })?;
+    let len = buf
+        .iter()
+        .position(|&b| b == 0xFF || b == 0)
+        .unwrap_or(size);
+    if len == 0 {
+        return Ok(None);
+    }
+    buf.truncate(len);
+    Ok(Some(String::from_utf8(buf)?))
+}
+
 /// Struct that keeps track of the configurations of the firmware.
 /// It is also capable to start the configuration socket for config updates
 pub struct DoorsysConfig {
@@ -52,55 +956,315 @@ impl DoorsysConfig {
         anyhow::bail!("No mqtt config found");
     }
 
-    /// Run the config server on port 23 and wait for new connections
-    /// Once a valid configuration is uploaded this method will apply
-    /// the configs, close the socket and return.
-    /// This is meant to be ran only during the first boot if not previous configs are found
-    pub fn run_config_server(&mut self, wifi: &mut BlockingWifi<EspWifi>) -> anyhow::Result<()> {
+    /// PEM public key firmware images are signed with, none until provisioned
+    /// with one
+    pub fn read_firmware_key(&self) -> anyhow::Result<Option<String>> {
+        let blob_size = self.nvs.blob_len(FIRMWARE_KEY)?.unwrap_or(0);
+        let mut buf = vec![0; blob_size];
+        match self.nvs.get_raw(FIRMWARE_KEY, &mut buf)? {
+            Some(slice) => Ok(Some(str::from_utf8(slice)?.to_owned())),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads the settings stored alongside the last applied configuration.
+    /// Devices provisioned before settings existed will get the defaults.
+    pub fn read_settings(&self) -> anyhow::Result<Settings> {
+        let blob_size = self.nvs.blob_len("settings")?.unwrap_or(0);
+        let mut buf = vec![0; blob_size];
+        match self.nvs.get_raw("settings", &mut buf)? {
+            Some(slice) => Ok(toml::from_str(str::from_utf8(slice)?)?),
+            None => {
+                log::info!("No settings found, using defaults");
+                Ok(Settings::default())
+            }
+        }
+    }
+
+    /// Applies the configuration baked into the provision partition, if any,
+    /// so pre-provisioned devices skip the config server on first boot.
+    /// A file without a wifi section waits for the network to be sent by the
+    /// EspTouch app over SmartConfig, for as long as the timeout allows.
+    /// An invalid file or no network received falls back to the config server.
+    pub fn apply_provisioned(
+        &mut self,
+        wifi: &mut BlockingWifi<EspWifi>,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<bool> {
+        let file = match read_provision_partition() {
+            Ok(Some(file)) => file,
+            Ok(None) => return Ok(false),
+            Err(e) => {
+                log::error!("Error reading the provision partition: {}", e);
+                return Ok(false);
+            }
+        };
+        log::info!("Applying configuration from the provision partition");
+        if let Ok(Config { wifi: None, .. }) = toml::from_str::<Config>(&file) {
+            return self.apply_smartconfig(wifi, &file, timeout);
+        }
+        match self.store(&file) {
+            Ok(wifi_config) => {
+                connect(wifi, wifi_config)?;
+                Ok(true)
+            }
+            Err(e) => {
+                log::error!("Error parsing provisioned configuration: {}", e);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Run the config server on port 23 and wait for new connections.
+    /// The serial console is watched at the same time for installers without
+    /// network tools. Once a valid configuration is uploaded this method will
+    /// apply the configs, close the socket and return.
+    /// This is meant to be ran only during the first boot if not previous configs are found.
+    /// Returns false if nothing was uploaded before the timeout.
+    pub fn run_config_server(
+        &mut self,
+        wifi: &mut BlockingWifi<EspWifi>,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<bool> {
         let listener = TcpListener::bind("0.0.0.0:23")?;
+        listener.set_nonblocking(true)?;
+        let console_rx = console::setup_console_provisioning();
+        let started = Instant::now();
         // accept connections and process them serially
-        for stream_res in listener.incoming() {
-            log::info!("New connection: {:?}", stream_res);
-            match stream_res {
-                Ok(mut stream) => {
-                    if let Err(e) = self.apply_config(&mut stream, wifi) {
-                        log::error!("Error parsing configuration: {}", e);
-                        writeln!(stream, "Error parsing configuration {}", e)?;
-                    } else {
+        loop {
+            if timeout.is_some_and(|timeout| started.elapsed() > timeout) {
+                return Ok(false);
+            }
+            match listener.accept() {
+                Ok((mut stream, addr)) => {
+                    log::info!("New connection: {}", addr);
+                    stream.set_nonblocking(false)?;
+                    match self.apply_config(&mut stream, wifi, timeout) {
                         // Close config server and continue with boot proccess
-                        break;
+                        Ok(true) => break,
+                        Ok(false) => {}
+                        Err(e) => {
+                            log::error!("Error parsing configuration: {}", e);
+                            writeln!(stream, "Error parsing configuration {}", e)?;
+                        }
                     }
                 }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
                 Err(e) => {
                     log::error!("Error: {}", e);
                 }
             }
+            if let Ok(file) = console_rx.try_recv() {
+                log::info!("New config from the console");
+                match self.apply_file(wifi, &file, timeout) {
+                    Ok(true) => {
+                        println!("OK");
+                        break;
+                    }
+                    Ok(false) => println!("ERR no network received"),
+                    Err(e) => {
+                        log::error!("Error parsing configuration: {}", e);
+                        println!("ERR {}", e);
+                    }
+                }
+            }
+            thread::sleep(CONFIG_POLL_INTERVAL);
         }
+        Ok(true)
+    }
+
+    /// Stores the file and joins its network. A file without a wifi section
+    /// waits for the network from the EspTouch app, false if none arrived.
+    fn apply_file(
+        &mut self,
+        wifi: &mut BlockingWifi<EspWifi>,
+        file: &str,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<bool> {
+        let config: Config = toml::from_str(file)?;
+        if config.wifi.is_none() {
+            return self.apply_smartconfig(wifi, file, timeout);
+        }
+        let wifi_config = self.store(file)?;
+        connect(wifi, wifi_config)?;
+        Ok(true)
+    }
+
+    fn apply_smartconfig(
+        &mut self,
+        wifi: &mut BlockingWifi<EspWifi>,
+        file: &str,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<bool> {
+        let config: Config = toml::from_str(file)?;
+        // A blank device has no settings yet, the ones of the file apply
+        let settings: Settings = toml::from_str(file)?;
+        let timeout = timeout.or_else(|| {
+            let config = settings.provisioning?;
+            Some(Duration::from_secs(config.timeout_minutes * 60))
+        });
+        // SmartConfig listens in station mode, the config server needs the hotspot back
+        let hotspot = wifi.get_configuration()?;
+        connect(wifi, ClientConfiguration::default())?;
+        let Some(credentials) = smartconfig::receive_credentials(timeout)? else {
+            wifi.stop()?;
+            wifi.set_configuration(&hotspot)?;
+            wifi.start()?;
+            return Ok(false);
+        };
+        let wifi_config = WifiConfig {
+            auth: auth_method(wifi, &credentials.ssid, &credentials.password),
+            ssid: credentials.ssid,
+            password: credentials.password,
+        };
+        if let Err(e) = self
+            .persist(&config, file)
+            .and_then(|_| self.persist_firmware_key(&config))
+            .and_then(|_| self.persist_smartconfig_wifi(&wifi_config))
+        {
+            log::error!("Error storing provisioned configuration: {}", e);
+            return Ok(false);
+        }
+        // Joined without a restart so SmartConfig can tell the app
+        wifi.set_configuration(&Configuration::Client(client_configuration(&wifi_config)?))?;
+        Ok(true)
+    }
+
+    /// Kept apart from the file, a reload of the settings never touches it
+    fn persist_smartconfig_wifi(&mut self, wifi_config: &WifiConfig) -> anyhow::Result<()> {
+        let payload = postcard::to_allocvec(wifi_config)?;
+        self.nvs.set_raw(SMARTCONFIG_WIFI_KEY, &payload)?;
+        storage::record_write(Area::Config, payload.len());
         Ok(())
     }
 
+    /// Connects with the wifi credentials of the stored configuration.
+    /// Returns false when there is none.
+    pub fn connect_stored(&self, wifi: &mut BlockingWifi<EspWifi>) -> anyhow::Result<bool> {
+        let blob_size = self.nvs.blob_len("settings")?.unwrap_or(0);
+        let mut buf = vec![0; blob_size];
+        let Some(slice) = self.nvs.get_raw("settings", &mut buf)? else {
+            return Ok(false);
+        };
+        let config: Config = toml::from_str(str::from_utf8(slice)?)?;
+        let mut buf = [0; 256];
+        let wifi_config = match config.wifi {
+            Some(wifi_config) => wifi_config,
+            None => match self.nvs.get_raw(SMARTCONFIG_WIFI_KEY, &mut buf)? {
+                Some(slice) => postcard::from_bytes(slice)?,
+                None => return Ok(false),
+            },
+        };
+        log::info!("Retrying the stored wifi network {}", wifi_config.ssid);
+        connect(wifi, client_configuration(&wifi_config)?)?;
+        Ok(true)
+    }
+
     fn apply_config(
         &mut self,
         stream: &mut TcpStream,
         wifi: &mut BlockingWifi<EspWifi>,
-    ) -> anyhow::Result<()> {
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<bool> {
         let mut file = String::new();
         stream.read_to_string(&mut file)?;
         log::info!("New config\n{}", file);
         let config: Config = toml::from_str(&file)?;
+        if config.wifi.is_none() {
+            // The hotspot goes down while SmartConfig listens
+            writeln!(
+                stream,
+                "Success! Waiting for the network from the EspTouch app"
+            )?;
+            return self.apply_smartconfig(wifi, &file, timeout);
+        }
+        let wifi_config = self.store(&file)?;
+        writeln!(stream, "Success! Appying configs")?;
+        connect(wifi, wifi_config)?;
+        Ok(true)
+    }
+
+    /// Replaces the stored configuration file and returns its settings.
+    /// The wifi and mqtt sections are only used on the next restart.
+    pub fn store_settings(&mut self, file: &str) -> anyhow::Result<Settings> {
+        let config: Config = toml::from_str(file)?;
+        self.persist(&config, file)
+    }
+
+    /// Persists the mqtt config and settings returning the wifi configuration
+    fn store(&mut self, file: &str) -> anyhow::Result<ClientConfiguration> {
+        let config: Config = toml::from_str(file)?;
+        let wifi = config.wifi.as_ref().context("missing wifi section")?;
+        let wifi_config = client_configuration(wifi)?;
+        self.persist(&config, file)?;
+        self.persist_firmware_key(&config)?;
+        Ok(wifi_config)
+    }
+
+    /// Kept under its own key, a reload of the settings never touches it
+    fn persist_firmware_key(&mut self, config: &Config) -> anyhow::Result<()> {
+        if let Some(key) = &config.firmware_key {
+            self.nvs.set_raw(FIRMWARE_KEY, key.as_bytes())?;
+            storage::record_write(Area::Config, key.len());
+        }
+        Ok(())
+    }
+
+    fn persist(&mut self, config: &Config, file: &str) -> anyhow::Result<Settings> {
+        // Validates the settings before persisting anything
+        let settings: Settings = toml::from_str(file)?;
         let payload = postcard::to_allocvec(&config.mqtt)?;
         self.nvs.set_raw("mqtt", &payload)?;
+        self.nvs.set_raw("settings", file.as_bytes())?;
+        storage::record_write(Area::Config, payload.len() + file.len());
+        Ok(settings)
+    }
+}
 
-        let wifi_config = ClientConfiguration {
-            ssid: config.wifi.ssid.as_str().try_into().unwrap(),
-            password: config.wifi.password.as_str().try_into().unwrap(),
-            auth_method: config.wifi.auth,
-            ..Default::default()
-        };
-        writeln!(stream, "Success! Appying configs")?;
-        wifi.stop()?;
-        wifi.set_configuration(&Configuration::Client(wifi_config))?;
-        wifi.start()?;
-        Ok(())
+fn client_configuration(wifi: &WifiConfig) -> anyhow::Result<ClientConfiguration> {
+    Ok(ClientConfiguration {
+        ssid: wifi
+            .ssid
+            .as_str()
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("ssid too long"))?,
+        password: wifi
+            .password
+            .as_str()
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("wifi password too long"))?,
+        auth_method: wifi.auth,
+        ..Default::default()
+    })
+}
+
+/// Security of the network as seen by a scan. When it isn't found, any
+/// WPA2 or newer network is joined, or an open one without a password.
+fn auth_method(wifi: &mut BlockingWifi<EspWifi>, ssid: &str, password: &str) -> AuthMethod {
+    let found = match wifi.scan() {
+        Ok(access_points) => access_points
+            .into_iter()
+            .find(|ap| ap.ssid.as_str() == ssid)
+            .and_then(|ap| ap.auth_method),
+        Err(e) => {
+            log::warn!("Error scanning for {}: {}", ssid, e);
+            None
+        }
+    };
+    match found {
+        Some(auth_method) => auth_method,
+        None if password.is_empty() => AuthMethod::None,
+        None => AuthMethod::WPA2Personal,
     }
 }
+
+/// Leaves AP mode and switches to the configured network
+fn connect(
+    wifi: &mut BlockingWifi<EspWifi>,
+    wifi_config: ClientConfiguration,
+) -> anyhow::Result<()> {
+    wifi.stop()?;
+    wifi.set_configuration(&Configuration::Client(wifi_config))?;
+    wifi.start()?;
+    Ok(())
+}
@@ -0,0 +1,135 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, ErrorKind};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+
+use crate::audit::{self, AuditQueue};
+use crate::crypto;
+
+/// Line that ends a file sent over the console, followed by its sha256 in hex
+const CHECKSUM_PREFIX: &str = "sha256:";
+/// Largest file accepted, the same order of the settings stored in nvs
+const MAX_FILE_SIZE: usize = 16 * 1024;
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Reads configuration files pasted on the serial console.
+/// The file must be followed by a `sha256:<hex>` line with its digest,
+/// files that don't match are rejected so a garbled paste is never applied.
+pub fn setup_console_provisioning() -> Receiver<String> {
+    let (file_tx, file_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut file = String::new();
+        read_lines(|line| {
+            match line.trim().strip_prefix(CHECKSUM_PREFIX) {
+                Some(checksum) => {
+                    match verify(&file, checksum) {
+                        Ok(()) => {
+                            if file_tx.send(file.clone()).is_err() {
+                                // Provisioning is over
+                                return false;
+                            }
+                        }
+                        Err(e) => println!("ERR {}", e),
+                    }
+                    file.clear();
+                }
+                None if file.len() + line.len() > MAX_FILE_SIZE => {
+                    println!("ERR file is too large");
+                    file.clear();
+                }
+                None => {
+                    // Terminals may send crlf, the checksum is over lf endings
+                    file.push_str(line.trim_end_matches(['\r', '\n']));
+                    file.push('\n');
+                }
+            }
+            true
+        });
+    });
+    file_rx
+}
+
+/// Answers the investigation commands typed on the serial console,
+/// for when the network or the backend are down:
+///
+/// - `audits`: dumps the sd card archive as csv, or the pending
+///   audits when there is no archive
+/// - `audits pending`: dumps the audits not published yet as csv
+///
+/// The dump ends with an `OK` line, failures print `ERR` and the reason.
+pub fn setup_console_commands(queue: AuditQueue, archive: Option<String>) {
+    thread::spawn(move || {
+        read_lines(|line| {
+            let result = match line.split_whitespace().collect::<Vec<_>>()[..] {
+                ["audits"] => match &archive {
+                    Some(path) => dump_archive(path),
+                    None => dump_pending(&queue),
+                },
+                ["audits", "pending"] => dump_pending(&queue),
+                [] => return true,
+                _ => Err(anyhow::anyhow!("unknown command")),
+            };
+            match result {
+                Ok(()) => println!("OK"),
+                Err(e) => println!("ERR {}", e),
+            }
+            true
+        });
+    });
+}
+
+fn dump_archive(path: &str) -> anyhow::Result<()> {
+    let file = File::open(path)?;
+    for line in BufReader::new(file).lines() {
+        println!("{}", line?);
+    }
+    Ok(())
+}
+
+fn dump_pending(queue: &AuditQueue) -> anyhow::Result<()> {
+    println!("{}", audit::CSV_HEADER);
+    for event in queue.snapshot() {
+        println!("{}", event.csv());
+    }
+    Ok(())
+}
+
+/// Calls the handler with every line read from the console
+/// until it returns false
+fn read_lines(mut handler: impl FnMut(&str) -> bool) {
+    let stdin = io::stdin();
+    let mut line = String::new();
+    loop {
+        // The console may be non blocking, keeps what was read so far
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => {
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            Ok(_) if !line.ends_with('\n') => continue,
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            Err(e) => {
+                log::warn!("console read error: {}", e);
+                line.clear();
+                continue;
+            }
+        }
+        if !handler(&line) {
+            return;
+        }
+        line.clear();
+    }
+}
+
+fn verify(file: &str, checksum: &str) -> anyhow::Result<()> {
+    let expected = crypto::decode_hex(checksum.trim())?;
+    if !crypto::constant_time_eq(&crypto::sha256(file.as_bytes())?, &expected) {
+        anyhow::bail!("checksum mismatch");
+    }
+    Ok(())
+}
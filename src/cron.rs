@@ -0,0 +1,98 @@
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Context;
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+use serde::{Deserialize, Serialize};
+
+use crate::command::Command;
+use crate::management::Origin;
+use crate::schedule::LocalTime;
+use crate::storage::{self, Area};
+
+const JOBS_KEY: &str = "jobs";
+const CRON_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Command executed every selected day at a given time.
+/// Days is a bit mask where bit 0 is sunday and bit 6 is saturday,
+/// minute is the number of minutes since midnight.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Job {
+    pub days: u8,
+    pub minute: u16,
+    pub command: Command,
+}
+
+impl Job {
+    fn due(&self, now: &LocalTime) -> bool {
+        self.days & (1 << now.weekday) != 0 && self.minute == now.minutes
+    }
+}
+
+/// Scheduled jobs persisted in nvs
+#[derive(Clone)]
+pub struct Jobs(Arc<Mutex<JobsData>>);
+
+struct JobsData {
+    nvs: EspNvs<NvsDefault>,
+    jobs: Vec<Job>,
+}
+
+impl Jobs {
+    pub fn new(nvs_part: EspNvsPartition<NvsDefault>) -> anyhow::Result<Self> {
+        let nvs = EspNvs::new(nvs_part, "doorsys", true)?;
+        let blob_size = nvs.blob_len(JOBS_KEY)?.unwrap_or(0);
+        let mut buf = vec![0; blob_size];
+        let jobs = match nvs.get_raw(JOBS_KEY, &mut buf)? {
+            Some(slice) => postcard::from_bytes(slice).context("error decoding jobs")?,
+            None => Vec::new(),
+        };
+        log::info!("Loaded {} scheduled jobs", jobs.len());
+        Ok(Jobs(Arc::new(Mutex::new(JobsData { nvs, jobs }))))
+    }
+
+    /// Replaces the scheduled jobs and persists them to flash
+    pub fn set(&self, jobs: Vec<Job>) -> anyhow::Result<()> {
+        let mut data = self.0.lock().unwrap();
+        let buf = postcard::to_allocvec(&jobs).context("encoding failure")?;
+        data.nvs.set_raw(JOBS_KEY, &buf).context("nvs failure")?;
+        storage::record_write(Area::Config, buf.len());
+        data.jobs = jobs;
+        Ok(())
+    }
+
+    fn due(&self, now: &LocalTime) -> Vec<Command> {
+        let data = self.0.lock().unwrap();
+        data.jobs
+            .iter()
+            .filter(|job| job.due(now))
+            .map(|job| job.command.clone())
+            .collect()
+    }
+}
+
+/// Checks the jobs periodically and dispatches the due commands
+/// to the command thread. Nothing runs until the clock is synchronized.
+pub fn setup_cron(jobs: Jobs, cmd_tx: Sender<(Origin, Command)>) {
+    thread::spawn(move || {
+        // Minute of the week the jobs last ran, avoids running twice in the same minute
+        let mut last_run = None;
+        loop {
+            if let Some(now) = LocalTime::now() {
+                let minute = (now.weekday, now.minutes);
+                if last_run != Some(minute) {
+                    last_run = Some(minute);
+                    for command in jobs.due(&now) {
+                        log::info!("Running scheduled {:?}", command);
+                        if let Err(e) = cmd_tx.send((Origin::Cron, command)) {
+                            log::error!("error dispatching scheduled command: {}", e);
+                        }
+                    }
+                }
+            }
+            thread::sleep(CRON_INTERVAL);
+        }
+    });
+}
@@ -0,0 +1,301 @@
+use core::ffi::c_void;
+use core::{mem, str};
+
+use esp_idf_svc::sys::{
+    esp_fill_random, mbedtls_cipher_id_t_MBEDTLS_CIPHER_ID_AES, mbedtls_gcm_auth_decrypt,
+    mbedtls_gcm_context, mbedtls_gcm_crypt_and_tag, mbedtls_gcm_free, mbedtls_gcm_init,
+    mbedtls_gcm_setkey, mbedtls_md, mbedtls_md_hmac, mbedtls_md_info_from_type,
+    mbedtls_md_type_t_MBEDTLS_MD_SHA256, mbedtls_pk_check_pair, mbedtls_pk_context,
+    mbedtls_pk_free, mbedtls_pk_init, mbedtls_pk_parse_key, mbedtls_pk_parse_public_key,
+    mbedtls_pk_verify, mbedtls_sha256_context, mbedtls_sha256_finish, mbedtls_sha256_free,
+    mbedtls_sha256_init, mbedtls_sha256_starts, mbedtls_sha256_update, mbedtls_x509_crt,
+    mbedtls_x509_crt_free, mbedtls_x509_crt_init, mbedtls_x509_crt_parse, MBEDTLS_GCM_ENCRYPT,
+};
+
+use crate::atecc;
+
+/// Key used to sign and verify messages
+pub enum Secret {
+    /// Key stored in the settings
+    Key(Vec<u8>),
+    /// Key kept in a slot of the ATECC608A
+    SecureElement { slot: u16 },
+}
+
+impl Secret {
+    /// Builds the secret from the settings, the slot takes precedence over the hex key
+    pub fn from_settings(slot: Option<u16>, hex: Option<&str>) -> anyhow::Result<Option<Self>> {
+        match (slot, hex) {
+            (Some(slot), _) => Ok(Some(Secret::SecureElement { slot })),
+            (None, Some(hex)) => Ok(Some(Secret::Key(decode_hex(hex)?))),
+            (None, None) => Ok(None),
+        }
+    }
+
+    pub fn hmac_sha256(&self, data: &[u8]) -> anyhow::Result<[u8; 32]> {
+        match self {
+            Secret::Key(key) => hmac_sha256(key, data),
+            Secret::SecureElement { slot } => atecc::hmac_sha256(*slot, data),
+        }
+    }
+}
+
+/// HMAC-SHA256 using the mbedtls implementation bundled with esp-idf
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> anyhow::Result<[u8; 32]> {
+    let mut output = [0; 32];
+    let ret = unsafe {
+        let info = mbedtls_md_info_from_type(mbedtls_md_type_t_MBEDTLS_MD_SHA256);
+        mbedtls_md_hmac(
+            info,
+            key.as_ptr(),
+            key.len(),
+            data.as_ptr(),
+            data.len(),
+            output.as_mut_ptr(),
+        )
+    };
+    if ret != 0 {
+        anyhow::bail!("hmac failure: {}", ret);
+    }
+    Ok(output)
+}
+
+/// SHA-256 digest using mbedtls
+pub fn sha256(data: &[u8]) -> anyhow::Result<[u8; 32]> {
+    let mut output = [0; 32];
+    let ret = unsafe {
+        let info = mbedtls_md_info_from_type(mbedtls_md_type_t_MBEDTLS_MD_SHA256);
+        mbedtls_md(info, data.as_ptr(), data.len(), output.as_mut_ptr())
+    };
+    if ret != 0 {
+        anyhow::bail!("sha256 failure: {}", ret);
+    }
+    Ok(output)
+}
+
+/// SHA-256 of data too big to be kept in memory, fed in parts
+pub struct Sha256(mbedtls_sha256_context);
+
+impl Sha256 {
+    pub fn new() -> anyhow::Result<Self> {
+        let mut hasher = Sha256(unsafe { mem::zeroed() });
+        let ret = unsafe {
+            mbedtls_sha256_init(&mut hasher.0);
+            mbedtls_sha256_starts(&mut hasher.0, 0)
+        };
+        if ret != 0 {
+            anyhow::bail!("sha256 failure: {}", ret);
+        }
+        Ok(hasher)
+    }
+
+    pub fn update(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let ret = unsafe { mbedtls_sha256_update(&mut self.0, data.as_ptr(), data.len()) };
+        if ret != 0 {
+            anyhow::bail!("sha256 failure: {}", ret);
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> anyhow::Result<[u8; 32]> {
+        let mut output = [0; 32];
+        let ret = unsafe { mbedtls_sha256_finish(&mut self.0, output.as_mut_ptr()) };
+        if ret != 0 {
+            anyhow::bail!("sha256 failure: {}", ret);
+        }
+        Ok(output)
+    }
+}
+
+impl Drop for Sha256 {
+    fn drop(&mut self) {
+        unsafe { mbedtls_sha256_free(&mut self.0) };
+    }
+}
+
+/// Verifies the signature of a SHA-256 digest with the PEM public key,
+/// RSA PKCS#1 v1.5 or ECDSA depending on the key
+pub fn verify_signature(
+    public_key: &str,
+    digest: &[u8; 32],
+    signature: &[u8],
+) -> anyhow::Result<()> {
+    // mbedtls expects the PEM length to include the nul terminator
+    let key = format!("{public_key}\0");
+    unsafe {
+        let mut pk: mbedtls_pk_context = mem::zeroed();
+        mbedtls_pk_init(&mut pk);
+        let mut ret = mbedtls_pk_parse_public_key(&mut pk, key.as_ptr(), key.len());
+        if ret == 0 {
+            ret = mbedtls_pk_verify(
+                &mut pk,
+                mbedtls_md_type_t_MBEDTLS_MD_SHA256,
+                digest.as_ptr(),
+                digest.len(),
+                signature.as_ptr(),
+                signature.len(),
+            );
+        }
+        mbedtls_pk_free(&mut pk);
+        if ret != 0 {
+            anyhow::bail!("invalid signature: {}", ret);
+        }
+    }
+    Ok(())
+}
+
+/// Size of the authentication tag appended by [`aes_gcm_encrypt`]
+pub const GCM_TAG_SIZE: usize = 16;
+pub const GCM_NONCE_SIZE: usize = 12;
+
+/// AES-256-GCM encryption, returns the ciphertext followed by the tag
+pub fn aes_gcm_encrypt(
+    key: &[u8; 32],
+    nonce: &[u8; GCM_NONCE_SIZE],
+    data: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let mut output = vec![0; data.len() + GCM_TAG_SIZE];
+    let (ciphertext, tag) = output.split_at_mut(data.len());
+    unsafe {
+        let mut ctx: mbedtls_gcm_context = mem::zeroed();
+        mbedtls_gcm_init(&mut ctx);
+        let mut ret = mbedtls_gcm_setkey(
+            &mut ctx,
+            mbedtls_cipher_id_t_MBEDTLS_CIPHER_ID_AES,
+            key.as_ptr(),
+            256,
+        );
+        if ret == 0 {
+            ret = mbedtls_gcm_crypt_and_tag(
+                &mut ctx,
+                MBEDTLS_GCM_ENCRYPT as i32,
+                data.len(),
+                nonce.as_ptr(),
+                nonce.len(),
+                core::ptr::null(),
+                0,
+                data.as_ptr(),
+                ciphertext.as_mut_ptr(),
+                GCM_TAG_SIZE,
+                tag.as_mut_ptr(),
+            );
+        }
+        mbedtls_gcm_free(&mut ctx);
+        if ret != 0 {
+            anyhow::bail!("encryption failure: {}", ret);
+        }
+    }
+    Ok(output)
+}
+
+/// AES-256-GCM decryption of the ciphertext followed by the tag,
+/// fails when the tag doesn't match
+pub fn aes_gcm_decrypt(
+    key: &[u8; 32],
+    nonce: &[u8; GCM_NONCE_SIZE],
+    data: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    if data.len() < GCM_TAG_SIZE {
+        anyhow::bail!("ciphertext too short");
+    }
+    let (ciphertext, tag) = data.split_at(data.len() - GCM_TAG_SIZE);
+    let mut output = vec![0; ciphertext.len()];
+    unsafe {
+        let mut ctx: mbedtls_gcm_context = mem::zeroed();
+        mbedtls_gcm_init(&mut ctx);
+        let mut ret = mbedtls_gcm_setkey(
+            &mut ctx,
+            mbedtls_cipher_id_t_MBEDTLS_CIPHER_ID_AES,
+            key.as_ptr(),
+            256,
+        );
+        if ret == 0 {
+            ret = mbedtls_gcm_auth_decrypt(
+                &mut ctx,
+                ciphertext.len(),
+                nonce.as_ptr(),
+                nonce.len(),
+                core::ptr::null(),
+                0,
+                tag.as_ptr(),
+                GCM_TAG_SIZE,
+                ciphertext.as_ptr(),
+                output.as_mut_ptr(),
+            );
+        }
+        mbedtls_gcm_free(&mut ctx);
+        if ret != 0 {
+            anyhow::bail!("decryption failure: {}", ret);
+        }
+    }
+    Ok(output)
+}
+
+/// Fills the buffer from the hardware rng
+pub fn fill_random(buf: &mut [u8]) {
+    unsafe { esp_fill_random(buf.as_mut_ptr() as *mut c_void, buf.len()) };
+}
+
+/// Checks that the PEM certificate, or chain, can be parsed and that the
+/// private key, when given, can be parsed and matches the first certificate
+pub fn check_certificate(cert: &str, private_key: Option<&str>) -> anyhow::Result<()> {
+    // mbedtls expects the PEM length to include the nul terminator
+    let cert = format!("{cert}\0");
+    unsafe {
+        let mut crt: mbedtls_x509_crt = mem::zeroed();
+        mbedtls_x509_crt_init(&mut crt);
+        let mut ret = mbedtls_x509_crt_parse(&mut crt, cert.as_ptr(), cert.len());
+        if ret == 0 {
+            if let Some(private_key) = private_key {
+                ret = check_private_key(&mut crt, private_key);
+            }
+        }
+        mbedtls_x509_crt_free(&mut crt);
+        if ret != 0 {
+            anyhow::bail!("invalid certificate or key: {}", ret);
+        }
+    }
+    Ok(())
+}
+
+unsafe fn check_private_key(crt: &mut mbedtls_x509_crt, private_key: &str) -> i32 {
+    let key = format!("{private_key}\0");
+    let mut pk: mbedtls_pk_context = mem::zeroed();
+    mbedtls_pk_init(&mut pk);
+    let mut ret = mbedtls_pk_parse_key(
+        &mut pk,
+        key.as_ptr(),
+        key.len(),
+        core::ptr::null(),
+        0,
+        Some(random),
+        core::ptr::null_mut(),
+    );
+    if ret == 0 {
+        ret = mbedtls_pk_check_pair(&crt.private_pk, &pk, Some(random), core::ptr::null_mut());
+    }
+    mbedtls_pk_free(&mut pk);
+    ret
+}
+
+/// Random generator handed to mbedtls, backed by the hardware rng
+unsafe extern "C" fn random(_: *mut c_void, output: *mut u8, len: usize) -> i32 {
+    esp_fill_random(output as *mut c_void, len);
+    0
+}
+
+/// Compares two digests without leaking where they differ
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Decodes a hex string such as the secret in the settings file
+pub fn decode_hex(hex: &str) -> anyhow::Result<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        anyhow::bail!("hex string must have an even length");
+    }
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| Ok(u8::from_str_radix(str::from_utf8(pair)?, 16)?))
+        .collect()
+}
@@ -0,0 +1,114 @@
+use std::ffi::{c_char, CStr};
+use std::net::Ipv4Addr;
+use std::ptr;
+
+use anyhow::Context;
+use esp_idf_svc::sys::{
+    esp, mdns_free, mdns_init, mdns_query_ptr, mdns_query_results_free, mdns_result_t,
+    ESP_ERR_INVALID_STATE, ESP_IPADDR_TYPE_V4,
+};
+
+use crate::config::DiscoveryConfig;
+
+/// Mqtt url that asks for the broker to be discovered
+pub const AUTO: &str = "auto";
+const SERVICE: &[u8] = b"_mqtt\0";
+const PROTO: &[u8] = b"_tcp\0";
+/// Brokers are only looked at in the order they answer, the first wins
+const MAX_RESULTS: usize = 4;
+const MQTTS_PORT: u16 = 8883;
+
+/// Broker announced on the local network
+#[derive(Debug)]
+struct Broker {
+    host: String,
+    port: u16,
+}
+
+impl Broker {
+    fn url(&self) -> String {
+        let scheme = if self.port == MQTTS_PORT {
+            "mqtts"
+        } else {
+            "mqtt"
+        };
+        format!("{}://{}:{}", scheme, self.host, self.port)
+    }
+}
+
+/// Resolves the "auto" url to the first broker announcing _mqtt._tcp over
+/// DNS-SD, or to the fallback url when none answers. Wifi must be up.
+pub fn broker_url(config: &DiscoveryConfig) -> anyhow::Result<String> {
+    match query(config.timeout_secs as u32 * 1000) {
+        Ok(Some(broker)) => {
+            let url = broker.url();
+            log::info!("Discovered mqtt broker {}", url);
+            Ok(url)
+        }
+        result => {
+            if let Err(e) = result {
+                log::error!("error discovering the mqtt broker: {:?}", e);
+            }
+            let url = config
+                .fallback_url
+                .clone()
+                .context("no mqtt broker discovered and no fallback url")?;
+            log::warn!("No mqtt broker discovered, using {}", url);
+            Ok(url)
+        }
+    }
+}
+
+fn query(timeout_ms: u32) -> anyhow::Result<Option<Broker>> {
+    let initialized = unsafe { mdns_init() };
+    // Invalid state means mdns was already running
+    if initialized != ESP_ERR_INVALID_STATE as i32 {
+        esp!(initialized)?;
+    }
+    let mut results: *mut mdns_result_t = ptr::null_mut();
+    let queried = esp!(unsafe {
+        mdns_query_ptr(
+            SERVICE.as_ptr() as *const c_char,
+            PROTO.as_ptr() as *const c_char,
+            timeout_ms,
+            MAX_RESULTS,
+            &mut results,
+        )
+    });
+    let broker = queried.map(|_| unsafe { first_broker(results) });
+    unsafe {
+        mdns_query_results_free(results);
+        mdns_free();
+    }
+    Ok(broker?)
+}
+
+/// Prefers the ipv4 address of the answer so nothing else has to be
+/// resolved, the .local host name is the last resort
+unsafe fn first_broker(mut result: *mut mdns_result_t) -> Option<Broker> {
+    while let Some(current) = result.as_ref() {
+        result = current.next;
+        if current.port == 0 {
+            continue;
+        }
+        let mut addr = current.addr;
+        while let Some(ip) = addr.as_ref() {
+            if ip.addr.type_ == ESP_IPADDR_TYPE_V4 as u8 {
+                let octets = ip.addr.u_addr.ip4.addr.to_le_bytes();
+                return Some(Broker {
+                    host: Ipv4Addr::from(octets).to_string(),
+                    port: current.port,
+                });
+            }
+            addr = ip.next;
+        }
+        if !current.hostname.is_null() {
+            let hostname = CStr::from_ptr(current.hostname).to_string_lossy();
+            return Some(Broker {
+                host: format!("{}.local", hostname),
+                port: current.port,
+            });
+        }
+    }
+    None
+}
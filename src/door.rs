@@ -1,21 +1,389 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use std::{mem, thread};
+
 use esp_idf_svc::hal::gpio::{Output, OutputPin, PinDriver};
+use serde::{Deserialize, Serialize};
+
+use crate::adc::AdcChannel;
+use crate::config::{BurstConfig, StrikeConfig};
+use crate::mqtt::Outbox;
+use crate::schedule::Mode;
+use crate::walktest::{Observation, WalkTest};
+
+/// Requests handled by the door thread
+#[derive(Debug, Clone, Copy)]
+pub enum DoorCommand {
+    /// Unlocks the door momentarily
+    Open,
+    /// Unlocks the door momentarily for a credential decoded at the instant,
+    /// arrival when it releases the first card in unlock windows
+    Grant { decoded_at: Instant, arrival: bool },
+    /// Switches the mode of the door thread
+    Mode(DoorMode),
+    /// Door contact changed, true when the door is open
+    Contact(bool),
+    /// Fire panel asserted or cleared, the door stays released until cleared
+    FireRelease(bool),
+}
+
+/// State of the door thread, it decides what the open requests do
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DoorMode {
+    /// Opens momentarily on request
+    #[default]
+    Normal,
+    /// Keeps the relay de-energized and ignores every open request
+    LockedDown,
+    /// Keeps the relay energized
+    HeldOpen,
+}
+
+impl From<Mode> for DoorMode {
+    fn from(mode: Mode) -> Self {
+        match mode {
+            Mode::Normal => DoorMode::Normal,
+            Mode::Unlocked => DoorMode::HeldOpen,
+            Mode::LockedDown => DoorMode::LockedDown,
+        }
+    }
+}
+
+/// Time taken by the door to react to the granted credentials
+/// since the last call to [`Latency::take`]
+#[derive(Debug, Clone, Copy)]
+pub struct Latency {
+    pub count: u32,
+    pub last_ms: u32,
+    pub max_ms: u32,
+    pub total_ms: u64,
+}
+
+impl Latency {
+    const fn new() -> Self {
+        Latency {
+            count: 0,
+            last_ms: 0,
+            max_ms: 0,
+            total_ms: 0,
+        }
+    }
+
+    fn record(&mut self, latency: Duration) {
+        let ms = latency.as_millis().min(u32::MAX as u128) as u32;
+        self.count += 1;
+        self.last_ms = ms;
+        self.max_ms = self.max_ms.max(ms);
+        self.total_ms += ms as u64;
+    }
+
+    pub fn avg_ms(&self) -> u64 {
+        match self.count {
+            0 => 0,
+            count => self.total_ms / count as u64,
+        }
+    }
+
+    /// Reads the relay and door contact latencies and restarts both
+    pub fn take() -> (Self, Self) {
+        let relay = mem::replace(&mut *RELAY_LATENCY.lock().unwrap(), Latency::new());
+        let contact = mem::replace(&mut *CONTACT_LATENCY.lock().unwrap(), Latency::new());
+        (relay, contact)
+    }
+}
+
+/// From the credential decode to the relay energized
+static RELAY_LATENCY: Mutex<Latency> = Mutex::new(Latency::new());
+/// From the credential decode to the door contact reporting the door open
+static CONTACT_LATENCY: Mutex<Latency> = Mutex::new(Latency::new());
+
+pub fn record_relay_latency(latency: Duration) {
+    RELAY_LATENCY.lock().unwrap().record(latency);
+}
+
+pub fn record_contact_latency(latency: Duration) {
+    CONTACT_LATENCY.lock().unwrap().record(latency);
+}
+
+/// Physical position of the door reported by the reed switch
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Position {
+    Closed,
+    Open,
+}
+
+/// Position change published, retained, to doorsys/door/{device_id}/position
+#[derive(Serialize, Debug)]
+pub struct PositionEvent {
+    pub position: Position,
+    pub timestamp: SystemTime,
+}
+
+/// Published, retained, to doorsys/emergency/{device_id} when the fire panel
+/// releases the doors and again when it is cleared
+#[derive(Serialize, Debug)]
+pub struct EmergencyEvent {
+    pub released: bool,
+    pub timestamp: SystemTime,
+}
+
+/// Zero until the sensor reports, then one plus the position
+static POSITION: AtomicU8 = AtomicU8::new(0);
+static OPENINGS: AtomicU32 = AtomicU32::new(0);
+
+/// Door position and openings since the last call to [`SensorStats::take`]
+#[derive(Debug)]
+pub struct SensorStats {
+    /// None without a door sensor
+    pub position: Option<Position>,
+    pub openings: u32,
+}
+
+impl SensorStats {
+    pub fn take() -> Self {
+        let position = match POSITION.load(Ordering::Relaxed) {
+            0 => None,
+            1 => Some(Position::Closed),
+            _ => Some(Position::Open),
+        };
+        SensorStats {
+            position,
+            openings: OPENINGS.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+/// Door position sensor on the contact input, tells a door that was only
+/// unlocked from one that was actually opened
+pub struct Sensor {
+    position_tx: Outbox<PositionEvent>,
+}
+
+impl Sensor {
+    /// Publishes the position read when the input was set up right away
+    pub fn new(open: bool, position_tx: Outbox<PositionEvent>) -> Self {
+        let sensor = Sensor { position_tx };
+        sensor.update(open);
+        sensor
+    }
+
+    /// Records a debounced change of the contact
+    pub fn update(&self, open: bool) {
+        let position = if open {
+            Position::Open
+        } else {
+            Position::Closed
+        };
+        let previous = POSITION.swap(position as u8 + 1, Ordering::Relaxed);
+        if previous == position as u8 + 1 {
+            return;
+        }
+        if position == Position::Open {
+            OPENINGS.fetch_add(1, Ordering::Relaxed);
+        }
+        let event = PositionEvent {
+            position,
+            timestamp: SystemTime::now(),
+        };
+        if let Err(e) = self.position_tx.send(event) {
+            log::error!("error sending door position: {}", e);
+        }
+    }
+}
+
+/// Relay state published to doorsys/door/{device_id}
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DoorState {
+    Locked,
+    Unlocked,
+    /// The relay driver failed, the actual state is unknown
+    Fault,
+}
+
+/// Relay state shared with the interfaces that report it
+#[derive(Clone, Default)]
+pub struct DoorStatus(Arc<AtomicU8>);
+
+impl DoorStatus {
+    pub fn state(&self) -> DoorState {
+        match self.0.load(Ordering::Relaxed) {
+            0 => DoorState::Locked,
+            1 => DoorState::Unlocked,
+            _ => DoorState::Fault,
+        }
+    }
+
+    pub fn unlocked(&self) -> bool {
+        self.state() == DoorState::Unlocked
+    }
+
+    fn set(&self, state: DoorState) {
+        self.0.store(state as u8, Ordering::Relaxed);
+    }
+}
 
 /// Simple container to encapsulate the door logic
 pub struct Door<'d, T: OutputPin> {
     driver: PinDriver<'d, T, Output>,
+    status: DoorStatus,
+    state_tx: Outbox<DoorState>,
+    /// Time the relay must rest between actuations
+    min_off: Duration,
+    closed_at: Option<Instant>,
+    walk_test: WalkTest,
 }
 
 impl<T: OutputPin> Door<'_, T> {
-    pub fn new(pin: T) -> anyhow::Result<Self> {
+    pub fn new(pin: T, status: DoorStatus, state_tx: Outbox<DoorState>) -> anyhow::Result<Self> {
         let driver = PinDriver::output(pin)?;
-        Ok(Door { driver })
+        // Retained so the current state is known right after boot
+        if let Err(e) = state_tx.send(status.state()) {
+            log::error!("error sending door state: {}", e);
+        }
+        Ok(Door {
+            driver,
+            status,
+            state_tx,
+            min_off: Duration::ZERO,
+            closed_at: None,
+            walk_test: WalkTest::default(),
+        })
+    }
+
+    /// Keeps the relay de-energized for at least `min_off` after each
+    /// actuation so rapid grants don't chatter it or overheat the strike
+    pub fn with_min_off(mut self, min_off: Duration) -> Self {
+        self.set_min_off(min_off);
+        self
+    }
+
+    /// Reports every relay actuation while the walk test runs
+    pub fn with_walk_test(mut self, walk_test: WalkTest) -> Self {
+        self.walk_test = walk_test;
+        self
+    }
+
+    pub fn set_min_off(&mut self, min_off: Duration) {
+        self.min_off = min_off;
+    }
+
+    /// When the relay was last locked again after being energized
+    pub fn locked_at(&self) -> Option<Instant> {
+        self.closed_at
+    }
+
+    /// When the relay is done resting after the last actuation,
+    /// None when it can be energized right away
+    pub fn resting_until(&self) -> Option<Instant> {
+        let rest_until = self.closed_at? + self.min_off;
+        (rest_until > Instant::now()).then_some(rest_until)
     }
 
+    /// Energizes the relay right away, callers wait for `resting_until`
+    /// unless the door must be released regardless
     pub fn open(&mut self) -> anyhow::Result<()> {
-        Ok(self.driver.set_high()?)
+        let result = self.driver.set_high();
+        self.update(result.is_ok(), DoorState::Unlocked);
+        Ok(result?)
     }
 
     pub fn close(&mut self) -> anyhow::Result<()> {
-        Ok(self.driver.set_low()?)
+        let result = self.driver.set_low();
+        if self.status.unlocked() {
+            self.closed_at = Some(Instant::now());
+        }
+        self.update(result.is_ok(), DoorState::Locked);
+        Ok(result?)
+    }
+
+    /// Records the new state and publishes it when it changes
+    fn update(&self, ok: bool, state: DoorState) {
+        let state = if ok { state } else { DoorState::Fault };
+        if self.status.state() == state {
+            return;
+        }
+        self.walk_test.observe(Observation::Relay(state));
+        self.status.set(state);
+        if let Err(e) = self.state_tx.send(state) {
+            log::error!("error sending door state: {}", e);
+        }
+    }
+}
+
+/// Detects rapid entry sequences, such as a shift change, where the relay is
+/// kept energized across consecutive grants instead of clicking for each one.
+/// Every grant is still audited on its own.
+pub struct Burst {
+    grants: usize,
+    window: Duration,
+    hold: Duration,
+    recent: VecDeque<Instant>,
+}
+
+impl Burst {
+    pub fn new(config: &BurstConfig) -> Self {
+        let grants = config.grants.max(2);
+        Burst {
+            grants,
+            window: Duration::from_secs(config.window_secs),
+            hold: Duration::from_secs(config.hold_secs),
+            recent: VecDeque::with_capacity(grants),
+        }
+    }
+
+    /// Records an open and returns how long the door must be kept
+    /// unlocked when enough of them happened within the window
+    pub fn open(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        while self
+            .recent
+            .front()
+            .is_some_and(|first| now.duration_since(*first) > self.window)
+        {
+            self.recent.pop_front();
+        }
+        if self.recent.len() == self.grants {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(now);
+        (self.recent.len() == self.grants).then_some(self.hold)
+    }
+}
+
+/// Measures the strike current through a shunt amplifier wired to gpio3.
+/// Used to detect a broken wire or a dead coil when the door is unlocked.
+pub struct CurrentSense {
+    channel: AdcChannel,
+    min_mv: u16,
+    settle: Duration,
+}
+
+impl CurrentSense {
+    pub const GPIO: i32 = 3;
+
+    pub fn new(channel: AdcChannel, config: &StrikeConfig) -> Self {
+        CurrentSense {
+            channel,
+            min_mv: config.min_mv,
+            settle: Duration::from_millis(config.settle_ms),
+        }
+    }
+
+    /// Waits for the current to settle and checks if the strike is drawing
+    /// the expected current. Must be called right after the door is opened.
+    pub fn check(&mut self) -> anyhow::Result<()> {
+        thread::sleep(self.settle);
+        let mv = self.channel.read_mv()?;
+        log::info!("Strike current sense: {}mV", mv);
+        if mv < self.min_mv {
+            anyhow::bail!(
+                "strike reading {}mV is below the expected {}mV",
+                mv,
+                self.min_mv
+            );
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,56 @@
+use crate::alert::{Alert, Category, Severity};
+use crate::config::DuressConfig;
+use crate::mqtt::Outbox;
+use crate::privacy::Redacted;
+use crate::user::UserDB;
+use crate::zone::{Trigger, Zone};
+
+/// Pins entered under duress, a user's pin plus the offset. They open the
+/// door as usual so nothing shows to whoever is forcing the user.
+pub struct Duress {
+    offset: i32,
+    alert_tx: Outbox<Alert>,
+    zone: Zone,
+}
+
+impl Duress {
+    pub fn new(config: &DuressConfig, alert_tx: Outbox<Alert>) -> Self {
+        Duress {
+            offset: config.offset,
+            alert_tx,
+            zone: Zone::default(),
+        }
+    }
+
+    /// Locks down the other controllers of the zone on every duress pin
+    pub fn with_zone(mut self, zone: Zone) -> Self {
+        self.zone = zone;
+        self
+    }
+
+    /// Pin of the user entering the duress pin, None for any other pin.
+    /// A pin that belongs to a user is never taken as duress.
+    pub fn user_pin(&self, user_db: &UserDB, pin: i32) -> Option<i32> {
+        if self.offset == 0 || user_db.contains(pin) {
+            return None;
+        }
+        pin.checked_sub(self.offset)
+            .filter(|user_pin| user_db.contains(*user_pin))
+    }
+
+    /// Alerts with the user id rather than the pin, alerts are not redacted
+    pub fn raise(&self, user_db: &UserDB, user_pin: i32) {
+        log::warn!("Duress pin entered by {}", Redacted(user_pin));
+        let detail = match user_db.credential(user_pin).and_then(|c| c.user_id) {
+            Some(user_id) => format!("duress pin entered by user {}", user_id),
+            None => "duress pin entered".to_owned(),
+        };
+        if let Err(e) =
+            self.alert_tx
+                .send(Alert::new(Severity::Critical, Category::Security, detail))
+        {
+            log::error!("error sending alert: {}", e);
+        }
+        self.zone.request(Trigger::Duress);
+    }
+}
@@ -0,0 +1,100 @@
+use std::sync::mpsc::Sender;
+use std::time::{Duration, SystemTime};
+
+use serde::Deserialize;
+
+use crate::audit::{Action, AuditEvent, Source};
+use crate::config::ExitConfig;
+use crate::door::DoorCommand;
+use crate::input::InputEvent;
+use crate::schedule::{Mode, Override, Scheduler};
+
+/// What a long press of the exit button does
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LongPress {
+    /// Keeps the door unlocked for a while, e.g. for deliveries
+    #[default]
+    Hold,
+    /// Same as a short press
+    Open,
+    /// Does nothing
+    Ignore,
+}
+
+/// Request to exit (REX) button, tells short presses from long presses
+/// once the button is released
+pub struct ExitButton {
+    door_tx: Sender<DoorCommand>,
+    scheduler: Scheduler,
+    audit_tx: Sender<AuditEvent>,
+    long_press: LongPress,
+    long_press_duration: Duration,
+    hold_duration: Duration,
+    pressed_at: Option<SystemTime>,
+}
+
+impl ExitButton {
+    pub fn new(
+        config: &ExitConfig,
+        door_tx: Sender<DoorCommand>,
+        scheduler: Scheduler,
+        audit_tx: Sender<AuditEvent>,
+    ) -> Self {
+        ExitButton {
+            door_tx,
+            scheduler,
+            audit_tx,
+            long_press: config.long_press,
+            long_press_duration: Duration::from_millis(config.long_press_ms),
+            hold_duration: Duration::from_secs(config.hold_minutes * 60),
+            pressed_at: None,
+        }
+    }
+
+    pub fn event(&mut self, event: &InputEvent) {
+        if event.active {
+            self.pressed_at = Some(event.timestamp);
+            return;
+        }
+        let Some(pressed_at) = self.pressed_at.take() else {
+            return;
+        };
+        let held = event
+            .timestamp
+            .duration_since(pressed_at)
+            .unwrap_or_default();
+        let long_press = held >= self.long_press_duration;
+        log::info!("Exit button released after {:?}", held);
+        match (long_press, self.long_press) {
+            (true, LongPress::Hold) => self.hold(),
+            (true, LongPress::Ignore) => log::info!("Exit button long press ignored"),
+            _ => self.open(),
+        }
+    }
+
+    fn open(&self) {
+        if let Err(e) = self.door_tx.send(DoorCommand::Open) {
+            log::error!("error sending door command: {}", e);
+        }
+        self.audit(Action::Open);
+    }
+
+    fn hold(&self) {
+        log::info!("Holding door unlocked for {:?}", self.hold_duration);
+        let start = SystemTime::now();
+        self.scheduler.add_override(Override {
+            mode: Mode::Unlocked,
+            start,
+            end: start + self.hold_duration,
+        });
+        self.audit(Action::Hold);
+    }
+
+    fn audit(&self, action: Action) {
+        let event = AuditEvent::remote(Source::ExitButton, action, true);
+        if let Err(e) = self.audit_tx.send(event) {
+            log::error!("error sending audit record: {}", e);
+        }
+    }
+}
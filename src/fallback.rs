@@ -0,0 +1,143 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::c_char;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use esp_idf_svc::sys::{
+    esp, esp_partition_erase_range, esp_partition_find_first, esp_partition_read,
+    esp_partition_subtype_t, esp_partition_t, esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+    esp_partition_write,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::crypto;
+use crate::schedule::LocalTime;
+use crate::user::{Credential, UserDB};
+
+const FALLBACK_LABEL: &[u8] = b"fallback\0";
+const FALLBACK_SUBTYPE: esp_partition_subtype_t = 0x41;
+const MAGIC: &[u8; 4] = b"DSFB";
+/// Magic, payload length and payload hash
+const HEADER_SIZE: usize = 4 + 4 + 32;
+const SECTOR_SIZE: usize = 4096;
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Copy of the user database kept outside nvs, used
+/// when the database can't be loaded at boot
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Snapshot {
+    pub codes: BTreeSet<i32>,
+    pub credentials: BTreeMap<i32, Credential>,
+    /// Unix time the snapshot was taken
+    pub taken: u64,
+}
+
+fn partition() -> Option<*const esp_partition_t> {
+    let partition = unsafe {
+        esp_partition_find_first(
+            esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+            FALLBACK_SUBTYPE,
+            FALLBACK_LABEL.as_ptr() as *const c_char,
+        )
+    };
+    (!partition.is_null()).then_some(partition)
+}
+
+/// Reads the snapshot, None when there is no fallback partition
+/// or nothing was written to it yet
+pub fn read() -> anyhow::Result<Option<Snapshot>> {
+    let Some(partition) = partition() else {
+        return Ok(None);
+    };
+    let mut header = [0; HEADER_SIZE];
+    esp!(unsafe { esp_partition_read(partition, 0, header.as_mut_ptr().cast(), HEADER_SIZE) })?;
+    if &header[..4] != MAGIC {
+        return Ok(None);
+    }
+    let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let size = unsafe { (*partition).size } as usize;
+    if HEADER_SIZE + len > size {
+        anyhow::bail!("fallback snapshot length {} out of bounds", len);
+    }
+    let mut payload = vec![0; len];
+    esp!(unsafe { esp_partition_read(partition, HEADER_SIZE, payload.as_mut_ptr().cast(), len) })?;
+    if crypto::sha256(&payload)? != header[8..] {
+        anyhow::bail!("fallback snapshot hash mismatch");
+    }
+    let snapshot = postcard::from_bytes(&payload).context("error decoding fallback snapshot")?;
+    Ok(Some(snapshot))
+}
+
+fn write(snapshot: &Snapshot) -> anyhow::Result<()> {
+    let partition = partition().context("no fallback partition")?;
+    let payload = postcard::to_allocvec(snapshot).context("encoding failure")?;
+    let mut buf = Vec::with_capacity(HEADER_SIZE + payload.len());
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crypto::sha256(&payload)?);
+    buf.extend_from_slice(&payload);
+    let size = unsafe { (*partition).size } as usize;
+    if buf.len() > size {
+        anyhow::bail!("fallback snapshot of {} bytes doesn't fit", buf.len());
+    }
+    let erase = buf.len().div_ceil(SECTOR_SIZE) * SECTOR_SIZE;
+    unsafe {
+        esp!(esp_partition_erase_range(partition, 0, erase))?;
+        esp!(esp_partition_write(
+            partition,
+            0,
+            buf.as_ptr().cast(),
+            buf.len()
+        ))?;
+    }
+    Ok(())
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Spawns the thread writing the snapshot once a week. It waits for the clock
+/// so the age of the snapshot is known and does nothing without the partition.
+pub fn setup_snapshots(user_db: UserDB) {
+    if partition().is_none() {
+        return;
+    }
+    thread::spawn(move || {
+        let mut taken = match read() {
+            Ok(snapshot) => snapshot.map_or(0, |snapshot| snapshot.taken),
+            Err(e) => {
+                log::error!("error reading fallback snapshot: {:?}", e);
+                0
+            }
+        };
+        loop {
+            let now = unix_time();
+            if LocalTime::now().is_some()
+                && now.saturating_sub(taken) >= SNAPSHOT_INTERVAL.as_secs()
+            {
+                let (codes, credentials) = user_db.export();
+                let snapshot = Snapshot {
+                    codes,
+                    credentials,
+                    taken: now,
+                };
+                match write(&snapshot) {
+                    Ok(()) => {
+                        log::info!(
+                            "Fallback snapshot of {} codes written",
+                            snapshot.codes.len()
+                        );
+                        taken = now;
+                    }
+                    Err(e) => log::error!("error writing fallback snapshot: {:?}", e),
+                }
+            }
+            thread::sleep(CHECK_INTERVAL);
+        }
+    });
+}
@@ -0,0 +1,152 @@
+use std::borrow::Cow;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use esp_idf_svc::hal::gpio::{AnyOutputPin, PinDriver};
+use serde::Deserialize;
+
+use crate::audio::Audio;
+use crate::config::{FeedbackConfig, Settings};
+use crate::readout::Readout;
+
+/// Event signaled to the user on the keypad buzzer
+#[derive(Debug, Clone, Copy)]
+pub enum Feedback {
+    Grant,
+    Deny,
+    /// Pin sequence not completed in time
+    Timeout,
+    /// Reader locked after a scanning attack
+    Lockout,
+    /// Valid credential rejected during a lockdown
+    LockdownDeny,
+    /// New user added
+    Enrollment,
+    /// Pin shorter than the minimum length
+    ShortPin,
+    /// Anything observed during the walk test
+    WalkTest,
+    /// Status requested from the keypad, its pattern comes from the status
+    Readout(Readout),
+}
+
+/// Alternating on and off durations in milliseconds, starting with on
+#[derive(Deserialize, Debug, Clone)]
+pub struct FeedbackPattern(pub Vec<u16>);
+
+impl FeedbackPattern {
+    fn new(durations: &[u16]) -> Self {
+        FeedbackPattern(durations.to_vec())
+    }
+}
+
+/// Pattern for every feedback, each one can be overridden in the settings
+struct Patterns {
+    grant: FeedbackPattern,
+    deny: FeedbackPattern,
+    timeout: FeedbackPattern,
+    lockout: FeedbackPattern,
+    lockdown_deny: FeedbackPattern,
+    enrollment: FeedbackPattern,
+    short_pin: FeedbackPattern,
+    walk_test: FeedbackPattern,
+}
+
+impl Patterns {
+    fn new(config: Option<&FeedbackConfig>) -> Self {
+        let get = |pattern: Option<&FeedbackPattern>, default: &[u16]| {
+            pattern
+                .cloned()
+                .unwrap_or_else(|| FeedbackPattern::new(default))
+        };
+        Patterns {
+            grant: get(config.and_then(|c| c.grant.as_ref()), &[800]),
+            deny: get(
+                config.and_then(|c| c.deny.as_ref()),
+                &[100, 100, 100, 100, 100, 100, 100, 100],
+            ),
+            timeout: get(config.and_then(|c| c.timeout.as_ref()), &[300, 100, 300]),
+            lockout: get(
+                config.and_then(|c| c.lockout.as_ref()),
+                &[50, 50, 50, 50, 50, 50, 50, 50, 50, 50, 50, 50],
+            ),
+            lockdown_deny: get(
+                config.and_then(|c| c.lockdown_deny.as_ref()),
+                &[1000, 200, 1000],
+            ),
+            enrollment: get(
+                config.and_then(|c| c.enrollment.as_ref()),
+                &[100, 100, 100, 100, 600],
+            ),
+            short_pin: get(
+                config.and_then(|c| c.short_pin.as_ref()),
+                &[300, 100, 100, 100, 100],
+            ),
+            walk_test: get(
+                config.and_then(|c| c.walk_test.as_ref()),
+                &[600, 150, 600, 150, 600],
+            ),
+        }
+    }
+
+    fn pattern(&self, feedback: Feedback) -> Cow<FeedbackPattern> {
+        let pattern = match feedback {
+            Feedback::Grant => &self.grant,
+            Feedback::Deny => &self.deny,
+            Feedback::Timeout => &self.timeout,
+            Feedback::Lockout => &self.lockout,
+            Feedback::LockdownDeny => &self.lockdown_deny,
+            Feedback::Enrollment => &self.enrollment,
+            Feedback::ShortPin => &self.short_pin,
+            Feedback::WalkTest => &self.walk_test,
+            Feedback::Readout(readout) => return Cow::Owned(readout.pattern()),
+        };
+        Cow::Borrowed(pattern)
+    }
+}
+
+/// Spawns the thread driving the keypad buzzer, which is active low.
+/// Feedback with an audio clip is spoken instead. New patterns
+/// received on the settings bus apply from the next feedback.
+pub fn setup_feedback(
+    pin: AnyOutputPin,
+    config: Option<&FeedbackConfig>,
+    audio: Audio,
+    settings_rx: Receiver<Arc<Settings>>,
+) -> anyhow::Result<Sender<Feedback>> {
+    let mut driver = PinDriver::output_od(pin)?;
+    driver.set_high()?;
+    let mut patterns = Patterns::new(config);
+
+    let (feedback_tx, feedback_rx) = mpsc::channel::<Feedback>();
+    thread::spawn(move || {
+        for feedback in feedback_rx {
+            if let Some(settings) = settings_rx.try_iter().last() {
+                log::info!("Reloading the feedback patterns");
+                patterns = Patterns::new(settings.feedback.as_ref());
+            }
+            if audio.feedback(feedback) {
+                continue;
+            }
+            log::debug!("Playing feedback {:?}", feedback);
+            let pattern = patterns.pattern(feedback);
+            for (i, duration) in pattern.0.iter().enumerate() {
+                let result = if i % 2 == 0 {
+                    driver.set_low()
+                } else {
+                    driver.set_high()
+                };
+                if let Err(e) = result {
+                    log::warn!("error playing feedback: {}", e);
+                }
+                thread::sleep(Duration::from_millis(*duration as u64));
+            }
+            if let Err(e) = driver.set_high() {
+                log::warn!("error playing feedback: {}", e);
+            }
+        }
+    });
+    Ok(feedback_tx)
+}
@@ -0,0 +1,52 @@
+use std::sync::Mutex;
+
+use esp_idf_svc::sys::{
+    heap_caps_get_free_size, heap_caps_get_largest_free_block, MALLOC_CAP_DEFAULT,
+};
+
+use crate::alert::{Alert, Category, Severity};
+use crate::mqtt::Outbox;
+
+/// Heap left to the door, wifi and mqtt whatever the payload
+const MARGIN: usize = 24 * 1024;
+/// Taken by a TLS session, for the downloads streamed to flash
+pub const TLS_SESSION: usize = 40 * 1024;
+/// Bytes in memory for each byte of a postcard encoded list of codes,
+/// the decoded list plus the tree built from it
+pub const DECODE_EXPANSION: usize = 4;
+
+static ALERT_TX: Mutex<Option<Outbox<Alert>>> = Mutex::new(None);
+
+/// Alerts are only raised once this is set, rejections before are only logged
+pub fn set_alert_tx(alert_tx: Outbox<Alert>) {
+    *ALERT_TX.lock().unwrap() = Some(alert_tx);
+}
+
+/// Checks `bytes` can be allocated for `what` while keeping the margin
+/// free. The allocation must fit in the largest free block since a failed
+/// one aborts the firmware. A rejection raises an alert and the caller
+/// drops the work, the sender may try again later.
+pub fn reserve(bytes: usize, what: &str) -> anyhow::Result<()> {
+    let (free, largest_free_block) = unsafe {
+        (
+            heap_caps_get_free_size(MALLOC_CAP_DEFAULT),
+            heap_caps_get_largest_free_block(MALLOC_CAP_DEFAULT),
+        )
+    };
+    if bytes <= largest_free_block && bytes.saturating_add(MARGIN) <= free {
+        return Ok(());
+    }
+    let detail = format!(
+        "{} rejected, {} bytes needed with {} free and {} in the largest block",
+        what, bytes, free, largest_free_block
+    );
+    if let Some(alert_tx) = ALERT_TX.lock().unwrap().as_ref() {
+        let alert = Alert::new(Severity::Warning, Category::MemoryFault, detail.clone());
+        if let Err(e) = alert_tx.send(alert) {
+            log::error!("error sending alert: {}", e);
+        }
+    } else {
+        log::warn!("{}", detail);
+    }
+    anyhow::bail!(detail)
+}
@@ -0,0 +1,82 @@
+use embedded_svc::http::client::Client;
+use esp_idf_svc::http::client::{Configuration, EspHttpConnection};
+use esp_idf_svc::io::{Read, Write};
+use esp_idf_svc::sys::esp_crt_bundle_attach;
+
+use crate::heap;
+
+/// Posts the body to the url, https urls are verified against the
+/// certificate bundle shipped with esp-idf
+pub fn post(
+    url: &str,
+    content_type: &str,
+    authorization: Option<&str>,
+    body: &str,
+) -> anyhow::Result<()> {
+    let mut client = Client::wrap(EspHttpConnection::new(&Configuration {
+        crt_bundle_attach: Some(esp_crt_bundle_attach),
+        ..Default::default()
+    })?);
+    let length = body.len().to_string();
+    let mut headers = vec![
+        ("Content-Type", content_type),
+        ("Content-Length", length.as_str()),
+    ];
+    if let Some(authorization) = authorization {
+        headers.push(("Authorization", authorization));
+    }
+    let mut request = client.post(url, &headers)?;
+    request.write_all(body.as_bytes())?;
+    request.flush()?;
+    let response = request.submit()?;
+    let status = response.status();
+    if !(200..300).contains(&status) {
+        anyhow::bail!("http status {}", status);
+    }
+    Ok(())
+}
+
+/// Downloads the body of the url into memory, calling progress with the
+/// bytes received so far and the total when the server reports it
+pub fn download(
+    url: &str,
+    max_size: usize,
+    mut progress: impl FnMut(usize, Option<usize>),
+) -> anyhow::Result<Vec<u8>> {
+    let mut client = Client::wrap(EspHttpConnection::new(&Configuration {
+        crt_bundle_attach: Some(esp_crt_bundle_attach),
+        ..Default::default()
+    })?);
+    let mut response = client.get(url)?.submit()?;
+    let status = response.status();
+    if !(200..300).contains(&status) {
+        anyhow::bail!("http status {}", status);
+    }
+    let total = response
+        .header("Content-Length")
+        .and_then(|length| length.parse().ok());
+    if total.is_some_and(|total| total > max_size) {
+        anyhow::bail!("file too big {:?}", total);
+    }
+
+    let initial = total.unwrap_or(0);
+    heap::reserve(initial, "download")?;
+    let mut body = Vec::with_capacity(initial);
+    let mut buf = [0; 1024];
+    loop {
+        let read = response.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        if body.len() + read > max_size {
+            anyhow::bail!("file bigger than {} bytes", max_size);
+        }
+        // Growing allocates the doubled buffer before the old one is freed
+        if body.len() + read > body.capacity() {
+            heap::reserve((body.len() + read).max(body.capacity() * 2), "download")?;
+        }
+        body.extend_from_slice(&buf[..read]);
+        progress(body.len(), total);
+    }
+    Ok(body)
+}
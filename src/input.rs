@@ -0,0 +1,188 @@
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant, SystemTime};
+use std::{mem, thread};
+
+use esp_idf_svc::hal::gpio::{AnyInputPin, PinDriver, Pull};
+use serde::{Deserialize, Serialize};
+
+use crate::adc::AdcChannel;
+use crate::alert::{Alert, Category, Severity};
+use crate::config::EolConfig;
+use crate::mqtt::Outbox;
+use crate::walktest::{Observation, WalkTest};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What a dry contact input is wired to
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Motion,
+    /// Request to exit button
+    Exit,
+    /// Door contact, active while the door is open
+    Contact,
+    /// Contact of the second door, only set from its own section
+    #[serde(skip_deserializing)]
+    SecondContact,
+    /// Fire alarm panel relay, active while the alarm sounds
+    Fire,
+    /// Enclosure or reader tamper switch, active when opened
+    Tamper,
+    /// Any other contact, only published
+    Auxiliary,
+}
+
+/// Debounced state change of an input
+#[derive(Serialize, Debug)]
+pub struct InputEvent {
+    pub role: Role,
+    pub active: bool,
+    pub timestamp: SystemTime,
+}
+
+/// Reading of a supervised line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Line {
+    /// Within the bands of the resistors, true when active
+    Contact(bool),
+    Shorted,
+    Cut,
+}
+
+impl Line {
+    fn read(mv: u16, eol: &EolConfig, active_low: bool) -> Self {
+        if mv < eol.short_mv {
+            Line::Shorted
+        } else if mv >= eol.cut_mv {
+            Line::Cut
+        } else {
+            Line::Contact((mv < eol.split_mv) == active_low)
+        }
+    }
+}
+
+fn send_event(role: Role, active: bool, event_tx: &Sender<InputEvent>, walk_test: &WalkTest) {
+    log::info!("Input {:?} active: {}", role, active);
+    walk_test.observe(Observation::Input { role, active });
+    let event = InputEvent {
+        role,
+        active,
+        timestamp: SystemTime::now(),
+    };
+    if let Err(e) = event_tx.send(event) {
+        log::error!("error sending input event: {}", e);
+    }
+}
+
+/// Spawns a thread that polls `pin` and sends an event every time its
+/// state stays unchanged for at least `debounce`.
+/// Active low inputs are pulled up, active high inputs are pulled down.
+/// Returns the state read before the thread starts.
+pub fn setup_input(
+    pin: AnyInputPin,
+    role: Role,
+    active_low: bool,
+    debounce: Duration,
+    event_tx: Sender<InputEvent>,
+    walk_test: WalkTest,
+) -> anyhow::Result<bool> {
+    let mut driver = PinDriver::input(pin)?;
+    driver.set_pull(if active_low { Pull::Up } else { Pull::Down })?;
+    let initial = driver.is_high() != active_low;
+
+    thread::spawn(move || {
+        let mut state = initial;
+        let mut candidate = state;
+        let mut changed_at = Instant::now();
+        loop {
+            let level = driver.is_high() != active_low;
+            if level != candidate {
+                candidate = level;
+                changed_at = Instant::now();
+            } else if candidate != state && changed_at.elapsed() >= debounce {
+                state = candidate;
+                send_event(role, state, &event_tx, &walk_test);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    });
+
+    Ok(initial)
+}
+
+/// Same as [`setup_input`] for a line supervised with end-of-line resistors.
+/// A shorted or cut line keeps the last contact state and raises a hardware
+/// fault, critical for the door contact, the tamper and the fire input, until
+/// it is restored. A faulty fire line is active so the doors fail safe.
+/// Returns the state read before the thread starts, inactive when faulty
+/// except for the fire input.
+#[allow(clippy::too_many_arguments)]
+pub fn setup_supervised_input(
+    channel: AdcChannel,
+    eol: EolConfig,
+    role: Role,
+    active_low: bool,
+    debounce: Duration,
+    event_tx: Sender<InputEvent>,
+    alert_tx: Outbox<Alert>,
+    walk_test: WalkTest,
+) -> anyhow::Result<bool> {
+    let initial = match Line::read(channel.read_mv()?, &eol, active_low) {
+        Line::Contact(active) => active,
+        Line::Shorted | Line::Cut => role == Role::Fire,
+    };
+    let severity = match role {
+        Role::Contact | Role::SecondContact | Role::Tamper | Role::Fire => Severity::Critical,
+        _ => Severity::Warning,
+    };
+
+    thread::spawn(move || {
+        let mut state = initial;
+        // A line faulty from the start is alerted once debounced
+        let mut line = Line::Contact(state);
+        let mut candidate = line;
+        let mut changed_at = Instant::now();
+        loop {
+            let reading = match channel.read_mv() {
+                Ok(mv) => Line::read(mv, &eol, active_low),
+                Err(e) => {
+                    log::warn!("error reading the {:?} input: {}", role, e);
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+            };
+            if reading != candidate {
+                candidate = reading;
+                changed_at = Instant::now();
+            } else if candidate != line && changed_at.elapsed() >= debounce {
+                let previous = mem::replace(&mut line, candidate);
+                let fault = match line {
+                    Line::Contact(_) if matches!(previous, Line::Contact(_)) => None,
+                    Line::Contact(_) => Some((Severity::Info, "restored")),
+                    Line::Shorted => Some((severity, "shorted")),
+                    Line::Cut => Some((severity, "cut")),
+                };
+                if let Some((severity, condition)) = fault {
+                    let detail = format!("{:?} input line {}", role, condition);
+                    let alert = Alert::new(severity, Category::HardwareFault, detail);
+                    if let Err(e) = alert_tx.send(alert) {
+                        log::error!("error sending alert: {}", e);
+                    }
+                }
+                let active = match line {
+                    Line::Contact(active) => active,
+                    Line::Shorted | Line::Cut if role == Role::Fire => true,
+                    Line::Shorted | Line::Cut => state,
+                };
+                if active != state {
+                    state = active;
+                    send_event(role, state, &event_tx, &walk_test);
+                }
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    });
+
+    Ok(initial)
+}
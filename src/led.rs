@@ -0,0 +1,100 @@
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+use esp_idf_svc::hal::gpio::{AnyOutputPin, PinDriver};
+
+use crate::config::LedConfig;
+use crate::power;
+use crate::schedule::{Mode, Scheduler};
+use crate::stale::Uplink;
+
+/// Alternating on and off durations in milliseconds, starting with on.
+/// Each pattern lasts 3 seconds and is repeated while the status holds.
+const HEALTHY: &[u64] = &[100, 2900];
+const OFFLINE: &[u64] = &[100, 200, 100, 2600];
+const LOCKED_DOWN: &[u64] = &[250, 250, 250, 250, 250, 250, 250, 250, 250, 250, 250, 250];
+/// Led kept on by the wiring test
+const TEST_PULSE: Duration = Duration::from_secs(1);
+
+/// Status shown on the reader led so users and guards can see it at the door
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    /// Slow single blink
+    Healthy,
+    /// Double blink while the broker is unreachable
+    Offline,
+    /// Fast blink during a lockdown, takes precedence over offline
+    LockedDown,
+}
+
+impl Status {
+    fn pattern(&self) -> &'static [u64] {
+        match self {
+            Status::Healthy => HEALTHY,
+            Status::Offline => OFFLINE,
+            Status::LockedDown => LOCKED_DOWN,
+        }
+    }
+}
+
+/// Spawns the thread blinking the led line of the reader.
+/// Returns the sender lighting it for the wiring test.
+pub fn setup_status_led(
+    config: &LedConfig,
+    scheduler: Scheduler,
+    uplink: Uplink,
+) -> anyhow::Result<Sender<()>> {
+    let mut driver = PinDriver::output(unsafe { AnyOutputPin::new(config.pin) })?;
+    let active_low = config.active_low;
+    let mut set = move |on: bool| {
+        let result = if on == active_low {
+            driver.set_low()
+        } else {
+            driver.set_high()
+        };
+        if let Err(e) = result {
+            log::warn!("error driving the reader led: {}", e);
+        }
+    };
+    set(false);
+
+    let (test_tx, test_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut current = Status::Healthy;
+        loop {
+            let status = if scheduler.mode() == Mode::LockedDown {
+                Status::LockedDown
+            } else if !uplink.connected() {
+                Status::Offline
+            } else {
+                Status::Healthy
+            };
+            if status != current {
+                log::info!("Reader led status {:?}", status);
+                current = status;
+            }
+            // The healthy blink is left out to save power, problems still show
+            let dark = status == Status::Healthy && power::low_power();
+            let mut tested = false;
+            for (i, duration) in status.pattern().iter().enumerate() {
+                set(i % 2 == 0 && !dark);
+                let duration = Duration::from_millis(*duration);
+                match test_rx.recv_timeout(duration) {
+                    Ok(()) => {
+                        tested = true;
+                        break;
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => thread::sleep(duration),
+                }
+            }
+            if tested {
+                set(true);
+                thread::sleep(TEST_PULSE);
+            }
+            set(false);
+        }
+    });
+    Ok(test_tx)
+}
@@ -1,179 +1,406 @@
 // Reference: https://docs.espressif.com/projects/esp-idf/en/latest/esp32/api-reference/system/freertos.html
 
+mod access;
+mod adc;
+mod alarm;
+mod alert;
+mod api;
+mod atecc;
+mod audio;
+mod audit;
+mod auth;
+mod backup;
+mod boot;
+mod card;
+mod certs;
+mod channel;
+mod command;
 mod config;
+mod console;
+mod cron;
+mod crypto;
+mod discovery;
 mod door;
+mod duress;
+mod exit;
+mod fallback;
+mod feedback;
+mod heap;
+mod http_client;
+mod input;
+mod led;
+mod management;
+mod modbus;
 mod mqtt;
 mod network;
+mod ota;
+mod output;
+mod peer;
+mod power;
+mod privacy;
+mod protocol;
+mod ratelimit;
+mod readout;
+mod reboot;
+mod reload;
+mod rules;
+mod scan;
+mod schedule;
+mod selftest;
+mod sink;
+mod smartconfig;
+mod stale;
+mod storage;
+mod sync;
+mod temporary;
+mod twin;
 mod user;
+mod vms;
+mod walktest;
+mod watchdog;
+mod webhook;
 mod wiegand;
+mod wiring;
+mod zone;
 
-use config::DoorsysConfig;
-use doorsys_protocol::{Audit, CodeType};
+use access::Access;
+use adc::Adc;
+use alarm::{ForcedOpen, HeldOpen};
+use alert::{Alert, Category, Severity};
+use anyhow::Context;
+use audio::Audio;
+use audit::{AuditChain, AuditQueue, MqttSink, PendingSink};
+use auth::{Authenticator, ReplayGuard, MQTT_FLOOR_KEY};
+use backup::StateBackup;
+use boot::{BootProgress, Progress, Stage};
+use card::Normalizer;
+use certs::CertStore;
+use channel::Channels;
+use command::Executor;
+use config::{
+    ContactConfig, DoorsysConfig, EolConfig, HealthConfig, InfluxConfig, SecondDoorConfig,
+    SecurityConfig, Settings, WiegandConfig,
+};
+use cron::Jobs;
+use crypto::Secret;
+use door::{
+    Burst, CurrentSense, Door, DoorCommand, DoorMode, DoorStatus, EmergencyEvent, Latency,
+    Position, Sensor, SensorStats,
+};
+use duress::Duress;
 use esp_idf_svc::eventloop::EspSystemEventLoop;
-use esp_idf_svc::hal::gpio::{InputPin, Output, OutputPin, PinDriver};
+use esp_idf_svc::hal::gpio::{AnyInputPin, AnyOutputPin, InputPin, OutputPin, Pin};
 use esp_idf_svc::hal::prelude::Peripherals;
 use esp_idf_svc::mqtt::client::QoS;
 use esp_idf_svc::nvs::EspDefaultNvsPartition;
 use esp_idf_svc::sys::{
     esp, gpio_install_isr_service, heap_caps_get_free_size, heap_caps_get_largest_free_block,
-    heap_caps_get_minimum_free_size, heap_caps_get_total_size, nvs_get_stats, ESP_INTR_FLAG_IRAM,
+    heap_caps_get_minimum_free_size, heap_caps_get_total_size, ESP_INTR_FLAG_IRAM,
     MALLOC_CAP_DEFAULT,
 };
 use esp_idf_svc::systime::EspSystemTime;
-use mqtt::MqttClient;
-use std::mem;
-use std::ptr;
-use std::sync::mpsc::{self, Receiver, Sender};
+use exit::ExitButton;
+use feedback::Feedback;
+use input::{InputEvent, Role};
+use management::ManagementLog;
+use mqtt::{MqttClient, Outbox, Router, Stamped};
+use power::Power;
+use ratelimit::OpenLimit;
+use reload::SettingsBus;
+use rules::Rules;
+use scan::ScanGuard;
+use schedule::{Holidays, LocalTime, Mode, Scheduler, TimeWindow};
+use selftest::Relay;
+use stale::{StaleGuard, Uplink};
+use std::fmt::{self, Write};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
 use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
-use std::{thread, time::Duration};
-use wiegand::Packet;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use temporary::TemporaryCodes;
+use vms::Vms;
+use watchdog::{Heartbeat, Watchdog};
+use webhook::{Kind, Notifier};
+use wiegand::{FrameTiming, IsrCheck, IsrStats, Packet, UnknownPackets, UnknownReport};
+use wiring::{InputLine, WiringTest};
+use zone::Zone;
 
 use crate::user::UserDB;
-use crate::wiegand::Reader;
+use crate::wiegand::{Reader, ReaderOptions};
 
-const MAX_PIN_LENGTH: usize = 8;
 const PIN_TIMEOUT: Duration = Duration::from_secs(10);
-const STAR_KEY: u8 = 0x0A;
-const HASH_KEY: u8 = 0x0B;
-const DOOR_OPEN_DELAY: Duration = Duration::from_secs(4);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(5);
+/// Room for every health line with long tags, grown only if ever exceeded
+const HEALTH_BUFFER_SIZE: usize = 1024;
+const HEALTH_INTERVAL: Duration = Duration::from_secs(60);
+/// Time from power-on the door must be working by, whatever the network does
+const DOOR_READY_DEADLINE: Duration = Duration::from_secs(5);
+/// Relay, reader data lines and feedback of the first door
+const FIRST_DOOR_PINS: [i32; 4] = [10, 4, 5, 7];
 
-fn setup_door(pin: impl OutputPin, door_rx: Receiver<()>) -> anyhow::Result<()> {
-    let mut door = door::Door::new(pin)?;
+/// Optional behaviors of the door thread
+struct DoorOptions {
+    /// Index of the door, picks its contact settings
+    door: u8,
+    unlock: Duration,
+    relock_on_close: bool,
+    burst: Option<Burst>,
+    held_open: Option<HeldOpen>,
+    forced_open: Option<ForcedOpen>,
+    /// Told about the grants releasing the first card in unlock windows
+    scheduler: Scheduler,
+    /// New settings for the timings above and the relay rest time
+    settings_rx: Receiver<Arc<Settings>>,
+}
 
-    thread::spawn(move || loop {
-        door_rx.recv().unwrap();
-        if let Err(e) = door.open() {
-            log::error!("error: {}", e);
-        }
-        // Drain the queue while the door is open
-        while door_rx.recv_timeout(DOOR_OPEN_DELAY).is_ok() {}
-        if let Err(e) = door.close() {
-            log::error!("error: {}", e);
-        }
-    });
+fn relay_min_off(settings: &Settings) -> Duration {
+    settings.relay.as_ref().map_or(Duration::ZERO, |config| {
+        Duration::from_millis(config.min_off_ms)
+    })
+}
 
-    Ok(())
+fn unlock_time(settings: &Settings) -> Duration {
+    let config = settings.door.clone().unwrap_or_default();
+    Duration::from_secs(config.unlock_secs)
 }
 
-/// Plays a sound when the door opens or the code is invalid
-fn keypad_feedback(
-    success: bool,
-    pin: &mut PinDriver<'_, impl OutputPin, Output>,
-) -> anyhow::Result<()> {
-    for _ in 0..8 {
-        if success {
-            pin.set_low()?;
-        } else {
-            pin.toggle()?;
-        }
-        thread::sleep(Duration::from_millis(100));
+fn door_contact(settings: &Settings, door: u8) -> Option<&ContactConfig> {
+    match door {
+        0 => settings.contact.as_ref(),
+        _ => settings
+            .second_door
+            .as_ref()
+            .and_then(|config| config.contact.as_ref()),
     }
-    pin.set_high()?;
-    Ok(())
 }
 
-/// Converts a key press sequence into an integer
-fn keys_to_int(keys: &[u8]) -> i32 {
-    keys.iter()
-        .cloned()
-        .rev()
-        .enumerate()
-        .fold(0, |acc, (i, num)| acc + 10i32.pow(i as u32) * num as i32)
+fn relocks_on_close(settings: &Settings, door: u8) -> bool {
+    door_contact(settings, door).is_some_and(|config| config.relock_on_close)
 }
 
-/// Setup the wiegand reader and spawns a thread to read incoming packets
-fn setup_reader(
-    door_tx: Sender<()>,
-    user_db: UserDB,
-    audit_tx: Sender<Audit>,
-    d0_gpio: impl InputPin,
-    d1_gpio: impl InputPin,
-    signal_pin: impl OutputPin,
-) -> anyhow::Result<()> {
-    let mut signal_driver = PinDriver::output_od(signal_pin)?;
-    signal_driver.set_high()?;
+/// Checks the second door doesn't reuse a gpio of the first door or its own
+fn check_second_door_pins(settings: &Settings, config: &SecondDoorConfig) -> anyhow::Result<()> {
+    let first_door = FIRST_DOOR_PINS
+        .into_iter()
+        .chain(settings.contact.as_ref().map(|config| config.pin))
+        .chain(settings.exit.as_ref().map(|config| config.pin));
+    let mut used: Vec<i32> = first_door.collect();
+    for pin in config.pins() {
+        if used.contains(&pin) {
+            anyhow::bail!("gpio{} of the second door is already in use", pin);
+        }
+        used.push(pin);
+    }
+    Ok(())
+}
 
+fn setup_door(
+    mut door: Door<'static, impl OutputPin>,
+    door_rx: Receiver<DoorCommand>,
+    mut current_sense: Option<CurrentSense>,
+    alert_tx: Outbox<Alert>,
+    heartbeat: Heartbeat,
+    options: DoorOptions,
+) -> anyhow::Result<()> {
+    let DoorOptions {
+        door: index,
+        mut unlock,
+        mut relock_on_close,
+        mut burst,
+        mut held_open,
+        mut forced_open,
+        scheduler,
+        settings_rx,
+    } = options;
     thread::spawn(move || {
-        let (_reader, channel) =
-            Reader::new(d0_gpio, d1_gpio).expect("Error initializing wiegand reader");
-
-        let mut keys = Vec::with_capacity(MAX_PIN_LENGTH);
-
-        // Reads the queue in a loop.
-        // If a pin sequence is not entered in PIN_TIMEOUT time
-        // it will be cancelled
+        let mut mode = DoorMode::Normal;
+        // Deadline to close the door after a momentary open
+        let mut close_at: Option<Instant> = None;
+        // Set when the door is opened while momentarily unlocked
+        let mut passed = false;
+        // Set while consecutive grants keep the door unlocked
+        let mut bursting = false;
+        // Decode time of the credential that unlocked the door,
+        // until the door is opened or locked again
+        let mut granted_at: Option<Instant> = None;
+        // Set while the fire panel releases the door, nothing locks it again
+        let mut fire = false;
+        // Open requested while the relay rests, with the decode time of
+        // the credential. Deferred so the loop keeps serving the contact.
+        let mut open_at: Option<(Instant, Option<Instant>)> = None;
         loop {
-            match channel.recv_timeout(PIN_TIMEOUT) {
-                Ok(Packet::Key { key }) => {
-                    if key == HASH_KEY {
-                        let pin = keys_to_int(&keys);
-                        let success = user_db.contains(pin);
-                        log::info!("Valid pin {}: {}", pin, success);
-                        if success {
-                            door_tx.send(()).unwrap();
+            heartbeat.beat();
+            if let Some(settings) = settings_rx.try_iter().last() {
+                log::info!("Reloading the door settings");
+                door.set_min_off(relay_min_off(&settings));
+                unlock = unlock_time(&settings);
+                relock_on_close = relocks_on_close(&settings, index);
+                burst = settings.burst.as_ref().map(Burst::new);
+                // Adding or removing the alarm section needs a restart
+                if let (Some(held_open), Some(config)) = (&mut held_open, &settings.alarm) {
+                    held_open.set_limit(Duration::from_secs(config.held_open_secs));
+                }
+                if let (Some(forced_open), Some(config)) = (&mut forced_open, &settings.alarm) {
+                    forced_open.set_grace(Duration::from_secs(config.forced_open_secs));
+                }
+            }
+            let held = mode == DoorMode::HeldOpen || fire;
+            // The held open count is paused during a scheduled unlock and a fire
+            let held_open_at = held_open
+                .as_ref()
+                .and_then(|held_open| held_open.deadline())
+                .filter(|_| !held);
+            let timeout = close_at
+                .into_iter()
+                .chain(held_open_at)
+                .chain(open_at.map(|(deadline, _)| deadline))
+                .min()
+                .map_or(HEARTBEAT_INTERVAL, |deadline| {
+                    deadline.saturating_duration_since(Instant::now())
+                });
+            let unlocked = held || close_at.is_some();
+            match door_rx.recv_timeout(timeout) {
+                Ok(DoorCommand::Open | DoorCommand::Grant { .. }) if fire => {}
+                Ok(DoorCommand::Open | DoorCommand::Grant { .. })
+                    if mode == DoorMode::LockedDown =>
+                {
+                    log::warn!("Door locked down, ignoring the open request");
+                }
+                Ok(command @ (DoorCommand::Open | DoorCommand::Grant { .. })) => {
+                    let decoded_at = match command {
+                        DoorCommand::Grant {
+                            decoded_at,
+                            arrival,
+                        } => {
+                            if arrival {
+                                scheduler.credential_presented();
+                            }
+                            Some(decoded_at)
                         }
-                        let audit = Audit {
-                            code: pin,
-                            code_type: CodeType::Pin,
-                            timestamp: SystemTime::now(),
-                            success,
-                        };
-                        if let Err(e) = audit_tx.send(audit) {
-                            log::error!("error sending audit record: {}", e);
+                        _ => None,
+                    };
+                    if !unlocked {
+                        if let Some(rest_until) = door.resting_until() {
+                            if open_at.is_none() {
+                                log::info!("Relay resting, deferring the open");
+                                open_at = Some((rest_until, decoded_at));
+                            }
+                            continue;
                         }
-                        keys.clear();
-                        if let Err(e) = keypad_feedback(success, &mut signal_driver) {
-                            log::warn!("error playing feedback: {}", e);
+                        passed = false;
+                        granted_at =
+                            grant_door(&mut door, &mut current_sense, &alert_tx, decoded_at);
+                    }
+                    let burst_hold = burst.as_mut().and_then(|burst| burst.open());
+                    if burst_hold.is_some() && !bursting {
+                        log::info!("Burst of grants, keeping the door unlocked");
+                    }
+                    bursting |= burst_hold.is_some();
+                    // Keeps the door open while requests keep coming
+                    if !held {
+                        close_at = Some(Instant::now() + burst_hold.unwrap_or(unlock));
+                    }
+                }
+                Ok(DoorCommand::Mode(new_mode)) if new_mode == mode => {}
+                Ok(DoorCommand::Mode(new_mode)) if fire => {
+                    // Not even a lockdown relocks the door, applied once cleared
+                    log::warn!("Door released by the fire panel, {:?} postponed", new_mode);
+                    mode = new_mode;
+                }
+                Ok(DoorCommand::Mode(new_mode)) => {
+                    log::info!("Door mode changed from {:?} to {:?}", mode, new_mode);
+                    mode = new_mode;
+                    open_at = None;
+                    if mode == DoorMode::HeldOpen {
+                        if !unlocked {
+                            open_door(&mut door, &mut current_sense, &alert_tx);
                         }
-                    } else if key == STAR_KEY {
-                        log::info!("Cancel sequence");
-                        keys.clear();
-                        if let Err(e) = keypad_feedback(false, &mut signal_driver) {
-                            log::warn!("error playing feedback: {}", e);
+                        close_at = None;
+                    } else if held || mode == DoorMode::LockedDown {
+                        // A lockdown also cuts a momentary open short
+                        close_at = None;
+                        bursting = false;
+                        granted_at = None;
+                        if unlocked {
+                            close_door(&mut door, &alert_tx);
                         }
-                    } else if keys.len() == MAX_PIN_LENGTH {
-                        log::warn!("pin sequence is too big {:?}", keys);
-                        keys.clear();
-                        if let Err(e) = keypad_feedback(false, &mut signal_driver) {
-                            log::warn!("error playing feedback: {}", e);
+                        if let (Some(held_open), true) = (&mut held_open, held) {
+                            held_open.restart();
                         }
-                    } else {
-                        keys.push(key);
                     }
                 }
-                Ok(Packet::Card { rfid }) => {
-                    let success = user_db.contains(rfid);
-                    log::info!("Valid rfid {}: {}", rfid, success);
-                    if success {
-                        door_tx.send(()).unwrap();
+                Ok(DoorCommand::FireRelease(active)) if active == fire => {}
+                Ok(DoorCommand::FireRelease(true)) => {
+                    log::warn!("Fire alarm, releasing the door");
+                    fire = true;
+                    open_at = None;
+                    close_at = None;
+                    bursting = false;
+                    granted_at = None;
+                    if !unlocked {
+                        open_door(&mut door, &mut current_sense, &alert_tx);
                     }
-                    let audit = Audit {
-                        code: rfid,
-                        code_type: CodeType::Fob,
-                        timestamp: SystemTime::now(),
-                        success,
-                    };
-                    if let Err(e) = audit_tx.send(audit) {
-                        log::error!("error sending audit record: {}", e);
+                }
+                Ok(DoorCommand::FireRelease(false)) => {
+                    log::info!("Fire alarm cleared, door back to {:?}", mode);
+                    fire = false;
+                    if mode != DoorMode::HeldOpen {
+                        close_door(&mut door, &alert_tx);
+                        if let Some(held_open) = &mut held_open {
+                            held_open.restart();
+                        }
+                    }
+                }
+                Ok(DoorCommand::Contact(true)) => {
+                    passed = close_at.is_some();
+                    if let Some(decoded_at) = granted_at.take() {
+                        door::record_contact_latency(decoded_at.elapsed());
                     }
-                    if let Err(e) = keypad_feedback(success, &mut signal_driver) {
-                        log::warn!("error playing feedback: {}", e);
+                    if let Some(held_open) = &mut held_open {
+                        held_open.contact(true);
+                    }
+                    if let Some(forced_open) = &mut forced_open {
+                        forced_open.contact(true, unlocked, door.locked_at());
                     }
-                    keys.clear();
                 }
-                Ok(Packet::Unknown { bits, data }) => {
-                    log::warn!("pattern not recognized bits: {}, data: {:02X?}", bits, data);
+                Ok(DoorCommand::Contact(false)) => {
+                    if let Some(held_open) = &mut held_open {
+                        held_open.contact(false);
+                    }
+                    if let Some(forced_open) = &mut forced_open {
+                        forced_open.contact(false, unlocked, door.locked_at());
+                    }
+                    // Relocks as soon as the door closes behind the user
+                    if relock_on_close && passed && close_at.is_some() && !bursting {
+                        log::info!("Door closed, relocking");
+                        passed = false;
+                        close_at = None;
+                        granted_at = None;
+                        close_door(&mut door, &alert_tx);
+                    }
                 }
-                Err(_e) => {
-                    if !keys.is_empty() {
-                        log::warn!("incomplete pin sequence {:?}", keys);
-                        keys.clear();
-                        if let Err(e) = keypad_feedback(false, &mut signal_driver) {
-                            log::warn!("error playing feedback: {}", e);
-                        }
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Some((_, decoded_at)) =
+                        open_at.filter(|(deadline, _)| *deadline <= Instant::now())
+                    {
+                        open_at = None;
+                        passed = false;
+                        granted_at =
+                            grant_door(&mut door, &mut current_sense, &alert_tx, decoded_at);
+                        close_at = Some(Instant::now() + unlock);
+                    }
+                    if close_at.is_some_and(|deadline| deadline <= Instant::now()) {
+                        close_at = None;
+                        bursting = false;
+                        granted_at = None;
+                        close_door(&mut door, &alert_tx);
+                    }
+                    if let (Some(held_open), false) = (&mut held_open, held) {
+                        held_open.check();
                     }
                 }
+                Err(e) => panic!("door channel closed: {}", e),
             }
         }
     });
@@ -181,90 +408,525 @@ fn setup_reader(
     Ok(())
 }
 
-/// Publishes mqtt audit events
-fn setup_audit_publiher(
-    device_id: &str,
-    mqtt_client: Arc<Mutex<MqttClient>>,
-    audit_rx: Receiver<Audit>,
+/// Energizes the relay, returns when it happened or None if it failed
+fn open_door(
+    door: &mut Door<'_, impl OutputPin>,
+    current_sense: &mut Option<CurrentSense>,
+    alert_tx: &Outbox<Alert>,
+) -> Option<Instant> {
+    if let Err(e) = door.open() {
+        relay_fault(alert_tx, e);
+        return None;
+    }
+    let energized_at = Instant::now();
+    if let Some(sense) = current_sense {
+        if let Err(e) = sense.check() {
+            let alert = Alert::new(
+                Severity::Warning,
+                Category::HardwareFault,
+                format!("strike fault: {}", e),
+            );
+            if let Err(e) = alert_tx.send(alert) {
+                log::error!("error sending alert: {}", e);
+            }
+        }
+    }
+    Some(energized_at)
+}
+
+/// Opens the door for a request, returns the decode time of the credential
+/// that caused it so the contact latency can be measured
+fn grant_door(
+    door: &mut Door<'_, impl OutputPin>,
+    current_sense: &mut Option<CurrentSense>,
+    alert_tx: &Outbox<Alert>,
+    decoded_at: Option<Instant>,
+) -> Option<Instant> {
+    let energized_at = open_door(door, current_sense, alert_tx)?;
+    let decoded_at = decoded_at?;
+    door::record_relay_latency(energized_at - decoded_at);
+    Some(decoded_at)
+}
+
+fn close_door(door: &mut Door<'_, impl OutputPin>, alert_tx: &Outbox<Alert>) {
+    if let Err(e) = door.close() {
+        relay_fault(alert_tx, e);
+    }
+}
+
+/// The door may be stuck locked or unlocked, someone has to check it
+fn relay_fault(alert_tx: &Outbox<Alert>, e: anyhow::Error) {
+    let alert = Alert::new(
+        Severity::Critical,
+        Category::ActuatorFault,
+        format!("relay fault: {}", e),
+    );
+    if let Err(e) = alert_tx.send(alert) {
+        log::error!("error sending alert: {}", e);
+    }
+}
+
+/// Evaluates the schedule periodically and switches the mode of the doors,
+/// held open while an unlock window or override is active and locked down
+/// with the lockdown
+fn setup_scheduler(scheduler: Scheduler, door_txs: Vec<Sender<DoorCommand>>) {
+    thread::spawn(move || {
+        let mut current = Mode::Normal;
+        loop {
+            let mode = scheduler.mode();
+            if mode != current {
+                log::info!("Schedule mode changed from {:?} to {:?}", current, mode);
+                current = mode;
+                for door_tx in &door_txs {
+                    if let Err(e) = door_tx.send(DoorCommand::Mode(mode.into())) {
+                        log::error!("error sending door command: {}", e);
+                    }
+                }
+            }
+            thread::sleep(SCHEDULE_INTERVAL);
+        }
+    });
+}
+
+/// Pulses the chime output if one is configured
+fn ring_chime(chime_tx: &Option<Sender<()>>) {
+    log::info!("Ringing chime");
+    if let Some(chime_tx) = chime_tx {
+        if let Err(e) = chime_tx.send(()) {
+            log::error!("error ringing chime: {}", e);
+        }
+    }
+}
+
+/// Setup the wiegand reader and spawns a thread to read incoming packets.
+/// Between packets the thread checks the interrupts still follow the lines,
+/// an alert is raised when the handlers had to be registered again.
+/// The reader stage is reached once the interrupts are registered.
+#[allow(clippy::too_many_arguments)]
+fn setup_reader(
+    mut access: Access,
+    d0_gpio: impl InputPin,
+    d1_gpio: impl InputPin,
+    config: WiegandConfig,
+    timing_tx: Option<Outbox<FrameTiming>>,
+    unknown_tx: Outbox<UnknownReport>,
+    alert_tx: Outbox<Alert>,
+    heartbeat: Heartbeat,
+    boot: Option<BootProgress>,
+) -> anyhow::Result<()> {
+    let window = Duration::from_secs(config.unknown_report_minutes * 60);
+    let mut unknown = UnknownPackets::new(window, unknown_tx);
+    let isr_check = Duration::from_secs(config.isr_check_seconds);
+    // The reader lives in its thread, a failure to start it is sent back
+    let (started_tx, started_rx) = mpsc::sync_channel(1);
+    thread::spawn(move || {
+        let options = ReaderOptions {
+            pull: config.pull,
+            edge: config.edge,
+            completion: config.completion,
+            dedup_window: Duration::from_millis(config.dedup_ms),
+            timing_tx,
+        };
+        let mut packets = match Reader::new(d0_gpio, d1_gpio, options) {
+            Ok(packets) => packets,
+            Err(e) => {
+                let _ = started_tx.send(Err(e));
+                return;
+            }
+        };
+        let _ = started_tx.send(Ok(()));
+        if let Some(boot) = boot {
+            boot.reached(Stage::Reader);
+        }
+        let mut last_check = Instant::now();
+
+        // Reads the queue in a loop.
+        // If a pin sequence is not entered in PIN_TIMEOUT time
+        // it will be cancelled
+        loop {
+            heartbeat.beat();
+            match packets.recv_timeout(PIN_TIMEOUT) {
+                Ok(Packet::Key { key }) => access.key(key),
+                Ok(Packet::Card { rfid }) => access.card(rfid),
+                Ok(Packet::Unknown { bits, data }) => unknown.record(bits, data),
+                Err(_e) => access.timeout(),
+            }
+            unknown.flush();
+            if !isr_check.is_zero() && last_check.elapsed() >= isr_check {
+                last_check = Instant::now();
+                let alert = match packets.check_interrupts() {
+                    Ok(IsrCheck::Healthy) => continue,
+                    Ok(IsrCheck::Reregistered(fault)) => Alert::new(
+                        Severity::Warning,
+                        Category::HardwareFault,
+                        format!(
+                            "reader interrupts stopped ({:?}), handlers registered again",
+                            fault
+                        ),
+                    ),
+                    Err(e) => Alert::new(
+                        Severity::Critical,
+                        Category::HardwareFault,
+                        format!("reader interrupts stopped and can't be registered: {}", e),
+                    ),
+                };
+                if let Err(e) = alert_tx.send(alert) {
+                    log::error!("error sending alert: {}", e);
+                }
+            }
+        }
+    });
+
+    started_rx
+        .recv()?
+        .context("error initializing wiegand reader")
+}
+
+/// Releases every door while the fire panel is asserted and publishes it
+fn release_doors(
+    release_txs: &[Sender<DoorCommand>],
+    released: bool,
+    emergency_tx: &Outbox<EmergencyEvent>,
+) {
+    for door_tx in release_txs {
+        if let Err(e) = door_tx.send(DoorCommand::FireRelease(released)) {
+            log::error!("error sending door command: {}", e);
+        }
+    }
+    let emergency = EmergencyEvent {
+        released,
+        timestamp: SystemTime::now(),
+    };
+    if let Err(e) = emergency_tx.send(emergency) {
+        log::error!("error sending emergency event: {}", e);
+    }
+}
+
+/// Reacts to input events and forwards them to be published
+#[allow(clippy::too_many_arguments)]
+fn setup_input_events(
+    event_rx: Receiver<InputEvent>,
+    motion_tx: Outbox<InputEvent>,
+    motion_trigger_tx: Option<Sender<()>>,
+    mut exit_button: Option<ExitButton>,
+    door_tx: Sender<DoorCommand>,
+    second_door_tx: Option<Sender<DoorCommand>>,
+    sensor: Option<Sensor>,
+    input_tx: Outbox<InputEvent>,
+    alert_tx: Outbox<Alert>,
+    notifier: Notifier,
+    release_txs: Vec<Sender<DoorCommand>>,
+    emergency_tx: Outbox<EmergencyEvent>,
 ) {
-    let topic = format!("doorsys/audit/{device_id}");
     thread::spawn(move || {
-        for audit in audit_rx {
-            match postcard::to_allocvec(&audit) {
-                Ok(buffer) => {
-                    if let Err(e) = mqtt_client.lock().unwrap().enqueue(
-                        &topic,
-                        QoS::AtLeastOnce,
-                        false,
-                        &buffer,
-                    ) {
-                        log::error!("error sending audit: {}", e);
+        for event in event_rx {
+            match event.role {
+                Role::Contact => {
+                    if let Some(sensor) = &sensor {
+                        sensor.update(event.active);
+                    }
+                    if let Err(e) = door_tx.send(DoorCommand::Contact(event.active)) {
+                        log::error!("error sending door command: {}", e);
                     }
                 }
-                Err(e) => {
-                    log::error!("error encoding audit: {}", e);
+                Role::SecondContact => {
+                    let sent = second_door_tx
+                        .as_ref()
+                        .map(|door_tx| door_tx.send(DoorCommand::Contact(event.active)));
+                    if let Some(Err(e)) = sent {
+                        log::error!("error sending door command: {}", e);
+                    }
+                }
+                Role::Exit => {
+                    if let Some(exit_button) = &mut exit_button {
+                        exit_button.event(&event);
+                    }
+                }
+                Role::Motion => {
+                    if let (true, Some(trigger_tx)) = (event.active, &motion_trigger_tx) {
+                        if let Err(e) = trigger_tx.send(()) {
+                            log::error!("error triggering motion output: {}", e);
+                        }
+                    }
+                    if let Err(e) = motion_tx.send(event) {
+                        log::error!("error sending motion event: {}", e);
+                    }
+                }
+                Role::Fire | Role::Tamper | Role::Auxiliary => {
+                    if event.role == Role::Fire {
+                        release_doors(&release_txs, event.active, &emergency_tx);
+                    }
+                    if let (Role::Tamper, true) = (event.role, event.active) {
+                        let alert = Alert::new(
+                            Severity::Critical,
+                            Category::Security,
+                            "tamper switch opened",
+                        );
+                        if let Err(e) = alert_tx.send(alert) {
+                            log::error!("error sending alert: {}", e);
+                        }
+                        notifier.notify(Kind::Tamper, "tamper switch opened");
+                    }
+                    if let Err(e) = input_tx.send(event) {
+                        log::error!("error sending input event: {}", e);
+                    }
                 }
             }
         }
     });
 }
 
-/// Starts the health check thread
-fn health_check(net_id: &str, mqtt_client: Arc<Mutex<MqttClient>>) -> anyhow::Result<()> {
+/// Health reporting detail by time of day
+struct HealthSchedule {
+    verbose_windows: Vec<TimeWindow>,
+    verbose_interval: Duration,
+    minimal_interval: Duration,
+    holidays: Holidays,
+}
+
+impl HealthSchedule {
+    fn new(config: &HealthConfig, holidays: Holidays) -> Self {
+        HealthSchedule {
+            verbose_windows: config.verbose_windows.clone(),
+            verbose_interval: Duration::from_secs(config.verbose_minutes.max(1) * 60),
+            minimal_interval: Duration::from_secs(config.minimal_minutes.max(1) * 60),
+            holidays,
+        }
+    }
+
+    /// Verbose inside the windows and while the clock is not synchronized
+    fn verbose(&self) -> bool {
+        let Some(now) = LocalTime::now() else {
+            return true;
+        };
+        self.verbose_windows
+            .iter()
+            .any(|window| window.contains(&now, &self.holidays))
+    }
+}
+
+/// Writes the health metrics into the buffer, one influx line per metric.
+/// Minimal reports only carry the heap.
+fn write_health(
+    body: &mut String,
+    tags: &str,
+    user_db: &UserDB,
+    time: u128,
+    verbose: bool,
+) -> fmt::Result {
+    unsafe {
+        let total = heap_caps_get_total_size(MALLOC_CAP_DEFAULT);
+        let free = heap_caps_get_free_size(MALLOC_CAP_DEFAULT);
+        let minimum = heap_caps_get_minimum_free_size(MALLOC_CAP_DEFAULT);
+        let largest_free = heap_caps_get_largest_free_block(MALLOC_CAP_DEFAULT);
+        let fragmentation = command::fragmentation(free, largest_free);
+        writeln!(body, "heap,{tags} free={free},total={total},minimum={minimum},largest_free={largest_free},fragmentation={fragmentation} {time}")?;
+    }
+    if !verbose {
+        return Ok(());
+    }
+
+    match storage::nvs_stats() {
+        Ok(stats) => {
+            let used = stats.used_entries;
+            let free = stats.free_entries;
+            let total = stats.total_entries;
+            writeln!(
+                body,
+                "nvs,{tags} used={used},free={free},total={total} {time}"
+            )?;
+            let wear = storage::wear();
+            writeln!(
+                body,
+                "nvs_wear,{tags} user_db_bytes={},config_bytes={},other_bytes={},erase_cycles={:.2},lifetime_used={:.4} {time}",
+                wear.user_db,
+                wear.config,
+                wear.other,
+                wear.erase_cycles(total),
+                wear.lifetime_used(total)
+            )?;
+        }
+        Err(e) => log::error!("error reading nvs stats: {}", e),
+    }
+
+    let stats = user_db.stats();
+    writeln!(
+        body,
+        "userdb,{tags} codes={},lookups={},avg_us={},max_us={},contended={} {time}",
+        stats.codes, stats.lookups, stats.avg_us, stats.max_us, stats.contended
+    )?;
+
+    let isr = IsrStats::take();
+    writeln!(
+        body,
+        "wiegand,{tags} isr_calls={},spurious={},overflows={},max_edge_gap_us={},duplicates={} {time}",
+        isr.calls, isr.spurious, isr.overflows, isr.max_edge_gap_us, isr.duplicates
+    )?;
+
+    let (relay, contact) = Latency::take();
+    writeln!(
+        body,
+        "door_latency,{tags} grants={},relay_last_ms={},relay_avg_ms={},relay_max_ms={},opens={},contact_last_ms={},contact_avg_ms={},contact_max_ms={},low_power={} {time}",
+        relay.count,
+        relay.last_ms,
+        relay.avg_ms(),
+        relay.max_ms,
+        contact.count,
+        contact.last_ms,
+        contact.avg_ms(),
+        contact.max_ms,
+        power::low_power()
+    )?;
+
+    let sensor = SensorStats::take();
+    if let Some(position) = sensor.position {
+        writeln!(
+            body,
+            "door_sensor,{tags} open={},openings={} {time}",
+            position == Position::Open,
+            sensor.openings
+        )?;
+    }
+    Ok(())
+}
+
+/// Starts the health check thread.
+/// Metrics are published to doorsys/status unless an influx endpoint is configured.
+/// Without a schedule every metric is reported each minute.
+fn health_check(
+    net_id: &str,
+    site: Option<&str>,
+    mqtt_client: Arc<Mutex<MqttClient>>,
+    influx: Option<&InfluxConfig>,
+    user_db: UserDB,
+    heartbeat: Heartbeat,
+    schedule: Option<HealthSchedule>,
+) -> anyhow::Result<()> {
     let systime = EspSystemTime {};
 
     let mqtt_client = mqtt_client.clone();
 
-    let net_id = net_id.to_owned();
     let version = built_info::GIT_VERSION.unwrap_or("");
+    let tags = match site {
+        Some(site) => format!("host={net_id},site={site},version={version}"),
+        None => format!("host={net_id},version={version}"),
+    };
+    let status_topic = mqtt::topic("status");
+    let influx = influx.map(|config| (config.url.clone(), config.authorization.clone()));
+
+    // Allocated once and reused so the health loop doesn't fragment the heap
+    let mut body = String::with_capacity(HEALTH_BUFFER_SIZE);
+
+    let mut last_report: Option<Instant> = None;
 
     thread::spawn(move || loop {
-        let time = systime.now().as_nanos();
-        let heap = unsafe {
-            let total = heap_caps_get_total_size(MALLOC_CAP_DEFAULT);
-            let free = heap_caps_get_free_size(MALLOC_CAP_DEFAULT);
-            let minimum = heap_caps_get_minimum_free_size(MALLOC_CAP_DEFAULT);
-            let largest_free = heap_caps_get_largest_free_block(MALLOC_CAP_DEFAULT);
-            format!("heap,host={net_id},version={version} free={free},total={total},minimum={minimum},largest_free={largest_free} {time}")
+        heartbeat.beat();
+        let (verbose, interval) = match &schedule {
+            Some(schedule) if schedule.verbose() => (true, schedule.verbose_interval),
+            Some(schedule) => (false, schedule.minimal_interval),
+            None => (true, HEALTH_INTERVAL),
         };
-        log::info!("{}", heap);
-        if let Err(e) = mqtt_client.lock().unwrap().publish(
-            "doorsys/status",
-            QoS::AtMostOnce,
-            false,
-            heap.as_bytes(),
-        ) {
-            log::warn!("mqtt publish error: {}", e);
-        }
-
-        let nvs = unsafe {
-            let mut stats = mem::MaybeUninit::uninit();
-            if let Err(e) = esp!(nvs_get_stats(ptr::null(), stats.as_mut_ptr())) {
-                format!("error: {}", e)
-            } else {
-                let stats = stats.assume_init();
-                let used = stats.used_entries;
-                let free = stats.free_entries;
-                let total = stats.total_entries;
-                format!("nvs,host={net_id},version={version} used={used},free={free},total={total} {time}")
+        let interval = power::health_interval(interval);
+        if last_report.is_some_and(|last| last.elapsed() < interval) {
+            thread::sleep(HEALTH_INTERVAL);
+            continue;
+        }
+        last_report = Some(Instant::now());
+
+        body.clear();
+        let time = systime.now().as_nanos();
+        if let Err(e) = write_health(&mut body, &tags, &user_db, time, verbose) {
+            log::error!("error formatting health metrics: {}", e);
+        }
+        for line in body.lines() {
+            log::info!("{}", line);
+        }
+
+        match &influx {
+            Some((url, authorization)) => {
+                if let Err(e) =
+                    http_client::post(url, "text/plain", authorization.as_deref(), body.trim_end())
+                {
+                    log::warn!("influx write error: {}", e);
+                }
+            }
+            None => {
+                for line in body.lines() {
+                    if let Err(e) = mqtt_client.lock().unwrap().publish(
+                        &status_topic,
+                        QoS::AtMostOnce,
+                        false,
+                        line.as_bytes(),
+                    ) {
+                        log::warn!("mqtt publish error: {}", e);
+                    }
+                }
             }
-        };
-        log::info!("{}", nvs);
-        if let Err(e) = mqtt_client.lock().unwrap().publish(
-            "doorsys/status",
-            QoS::AtMostOnce,
-            false,
-            nvs.as_bytes(),
-        ) {
-            log::warn!("mqtt publish error: {}", e);
         }
 
-        thread::sleep(Duration::from_secs(60));
+        thread::sleep(HEALTH_INTERVAL);
     });
 
     Ok(())
 }
 
+/// Opens the user database with the fallback snapshot when the stored one
+/// can't be loaded, or blank without one, along with the alert detail
+fn load_fallback(nvs_part: EspDefaultNvsPartition) -> anyhow::Result<(UserDB, String)> {
+    let snapshot = fallback::read().unwrap_or_else(|e| {
+        log::error!("Error reading the fallback snapshot: {:?}", e);
+        None
+    });
+    match snapshot {
+        Some(snapshot) => {
+            let detail = format!(
+                "user database corrupted, running from the fallback snapshot of {} codes taken at {}",
+                snapshot.codes.len(),
+                snapshot.taken
+            );
+            let user_db = UserDB::from_snapshot(nvs_part, snapshot.codes, snapshot.credentials)?;
+            Ok((user_db, detail))
+        }
+        None => {
+            let user_db = UserDB::from_snapshot(nvs_part, Default::default(), Default::default())?;
+            Ok((
+                user_db,
+                "user database corrupted and no fallback snapshot, starting blank".to_string(),
+            ))
+        }
+    }
+}
+
+fn operator_secret(security: Option<&SecurityConfig>) -> anyhow::Result<Option<Secret>> {
+    match security {
+        Some(config) => Secret::from_settings(config.secret_slot, config.secret.as_deref()),
+        None => Ok(None),
+    }
+}
+
+fn admin_secret(security: Option<&SecurityConfig>) -> anyhow::Result<Option<Secret>> {
+    match security {
+        Some(config) => {
+            Secret::from_settings(config.admin_secret_slot, config.admin_secret.as_deref())
+        }
+        None => Ok(None),
+    }
+}
+
+/// Starts a subsystem that is not needed to open the door,
+/// a failure is logged instead of stopping the boot
+fn optional<T>(name: &str, result: anyhow::Result<T>) -> Option<T> {
+    result
+        .map_err(|e| {
+            log::error!(
+                "error starting the {}, continuing without it: {:?}",
+                name,
+                e
+            )
+        })
+        .ok()
+}
+
 fn main() -> anyhow::Result<()> {
     // It is necessary to call this function once. Otherwise some patches to the runtime
     // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
@@ -286,6 +948,22 @@ fn main() -> anyhow::Result<()> {
         log::info!("Git version: {version} ({hash}) dirty: {dirty}");
     }
 
+    let (boot_tx, boot_rx) = mqtt::outbox();
+    let boot = BootProgress::new(boot_tx);
+    match run(boot.clone(), boot_rx) {
+        // Returning an error restarts the device, which would only
+        // interrupt a door that is already working offline
+        Err(e) if boot.has_reached(Stage::Door) => {
+            log::error!("Startup failed, the door keeps working offline: {:?}", e);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Boots the door first, with the reader, the door and the user database
+/// only depending on the flash, then brings the network up
+fn run(boot: BootProgress, boot_rx: Receiver<Stamped<Progress>>) -> anyhow::Result<()> {
     // Installs the generic GPIO interrupt handler which will
     // be used later on by the wiegand reader.
     esp!(unsafe { gpio_install_isr_service(ESP_INTR_FLAG_IRAM as i32) })?;
@@ -296,41 +974,921 @@ fn main() -> anyhow::Result<()> {
 
     let mut doorsys_config = DoorsysConfig::new(nvs_part.clone())?;
 
-    let user_db = UserDB::new(nvs_part.clone())?;
+    let settings = doorsys_config.read_settings().unwrap_or_else(|e| {
+        log::error!("Error reading settings, using defaults: {}", e);
+        Default::default()
+    });
+
+    boot.reached(Stage::Nvs);
+
+    let ota_config = settings.ota.clone().unwrap_or_default();
+    optional(
+        "firmware rollback",
+        ota::setup_rollback(
+            boot.clone(),
+            Duration::from_secs(ota_config.confirm_minutes * 60),
+        ),
+    );
+
+    if settings.privacy {
+        privacy::enable();
+    }
+
+    if let Some(site) = &settings.site {
+        mqtt::set_site(site);
+    }
+
+    if let Some(tz) = &settings.timezone {
+        schedule::set_timezone(tz);
+    }
+
+    let (user_db, degraded) = match UserDB::new(nvs_part.clone()) {
+        Ok(user_db) => (user_db, None),
+        Err(e) => {
+            log::error!("Error loading the user database: {:?}", e);
+            let (user_db, detail) = load_fallback(nvs_part.clone())?;
+            (user_db, Some(detail))
+        }
+    };
+    boot.reached(Stage::UserDb {
+        codes: user_db.count(),
+    });
+    let temporary_codes = TemporaryCodes::new(nvs_part.clone())?;
+    let channels = Channels::new(nvs_part.clone(), settings.channels.as_ref())?;
+    let holidays = Holidays::new(nvs_part.clone())?;
+    let rules = Rules::new(nvs_part.clone(), holidays.clone())?;
+    let mut scheduler = Scheduler::new(nvs_part.clone(), holidays.clone())?;
+    if settings.first_card_in {
+        scheduler = scheduler.with_first_card_in();
+    }
 
     log::info!("Starting application");
 
+    let watchdog = Watchdog::default();
+    if let Some(config) = &settings.watchdog {
+        let pin = unsafe { AnyOutputPin::new(config.pin) };
+        optional(
+            "watchdog",
+            watchdog.start(pin, Duration::from_millis(config.strobe_ms)),
+        );
+    }
+
+    let supervised = settings.exit.as_ref().is_some_and(|c| c.eol.is_some())
+        || settings.contact.as_ref().is_some_and(|c| c.eol.is_some())
+        || settings
+            .second_door
+            .as_ref()
+            .and_then(|c| c.contact.as_ref())
+            .is_some_and(|c| c.eol.is_some())
+        || settings.inputs.iter().any(|c| c.eol.is_some());
+    // Shared by the strike current sense and the supervised inputs
+    let adc = (settings.strike.is_some() || supervised)
+        .then(|| optional("adc", Adc::new(peripherals.adc1)))
+        .flatten();
+    let current_sense = settings
+        .strike
+        .as_ref()
+        .zip(adc.as_ref())
+        .and_then(|(config, adc)| {
+            optional(
+                "current sense",
+                adc.channel(CurrentSense::GPIO)
+                    .map(|channel| CurrentSense::new(channel, config)),
+            )
+        });
+
+    let (webhook_tx, webhook_rx) = mpsc::channel();
+    let notifier = match &settings.webhook {
+        Some(config) => Notifier::new(config, webhook_tx),
+        None => Notifier::default(),
+    };
+
+    let (alert_tx, alert_rx) = mqtt::outbox();
+    heap::set_alert_tx(alert_tx.clone());
+    match degraded {
+        Some(detail) => {
+            // Users may be locked out or let in from an old copy
+            let alert = Alert::new(Severity::Critical, Category::StorageFault, detail);
+            if let Err(e) = alert_tx.send(alert) {
+                log::error!("error sending alert: {}", e);
+            }
+        }
+        // A snapshot of the database restored from it would only repeat it
+        None => fallback::setup_snapshots(user_db.clone()),
+    }
+    let (alarm_event_tx, alarm_event_rx) = mqtt::outbox();
+    let audio = settings
+        .audio
+        .as_ref()
+        .and_then(|config| {
+            optional(
+                "audio module",
+                audio::setup_audio(peripherals.uart1, config),
+            )
+        })
+        .unwrap_or_default();
+    let (zone_tx, zone_rx) = mpsc::channel();
+    let zone = settings
+        .zone
+        .as_ref()
+        .map(|config| Zone::new(config, zone_tx))
+        .unwrap_or_default();
+    let alarm = alarm::setup_alarm(
+        settings.alarm.as_ref().unwrap_or(&Default::default()),
+        alert_tx.clone(),
+        alarm_event_tx,
+        notifier.clone(),
+        audio.clone(),
+        zone.clone(),
+    )?;
+    let settings_bus = SettingsBus::default();
+    let feedback_tx = feedback::setup_feedback(
+        peripherals.pins.gpio7.into(),
+        settings.feedback.as_ref(),
+        audio,
+        settings_bus.subscribe(),
+    )?;
+    let (walk_tx, walk_rx) = mqtt::outbox();
+    let walk_test = walktest::setup_walk_test(walk_tx, feedback_tx.clone());
     let (door_tx, door_rx) = mpsc::channel();
-    setup_door(peripherals.pins.gpio10, door_rx)?;
+    let (state_tx, state_rx) = mqtt::outbox();
+    let door_status = DoorStatus::default();
+    setup_door(
+        Door::new(peripherals.pins.gpio10, door_status.clone(), state_tx)?
+            .with_min_off(relay_min_off(&settings))
+            .with_walk_test(walk_test.clone()),
+        door_rx,
+        current_sense,
+        alert_tx.clone(),
+        watchdog.register("door", HEARTBEAT_INTERVAL * 3),
+        DoorOptions {
+            door: 0,
+            unlock: unlock_time(&settings),
+            relock_on_close: relocks_on_close(&settings, 0),
+            burst: settings.burst.as_ref().map(Burst::new),
+            held_open: settings.alarm.as_ref().map(|config| {
+                HeldOpen::new(Duration::from_secs(config.held_open_secs), alarm.clone())
+            }),
+            forced_open: settings.alarm.as_ref().map(|config| {
+                ForcedOpen::new(Duration::from_secs(config.forced_open_secs), alarm.clone())
+            }),
+            scheduler: scheduler.clone(),
+            settings_rx: settings_bus.subscribe(),
+        },
+    )?;
+    let (second_state_tx, second_state_rx) = mqtt::outbox();
+    let second_door_tx = settings.second_door.as_ref().and_then(|config| {
+        let (door_tx, door_rx) = mpsc::channel();
+        let door = check_second_door_pins(&settings, config).and_then(|_| {
+            Door::new(
+                unsafe { AnyOutputPin::new(config.relay_pin) },
+                DoorStatus::default(),
+                second_state_tx,
+            )
+        });
+        // The alarms need the contact to tell the door is open
+        let alarm_config = settings.alarm.as_ref().filter(|_| config.contact.is_some());
+        let started = door.and_then(|door| {
+            setup_door(
+                door.with_min_off(relay_min_off(&settings))
+                    .with_walk_test(walk_test.clone()),
+                door_rx,
+                None,
+                alert_tx.clone(),
+                watchdog.register("second door", HEARTBEAT_INTERVAL * 3),
+                DoorOptions {
+                    door: 1,
+                    unlock: unlock_time(&settings),
+                    relock_on_close: relocks_on_close(&settings, 1),
+                    burst: settings.burst.as_ref().map(Burst::new),
+                    held_open: alarm_config.map(|config| {
+                        HeldOpen::new(Duration::from_secs(config.held_open_secs), alarm.clone())
+                    }),
+                    forced_open: alarm_config.map(|config| {
+                        ForcedOpen::new(Duration::from_secs(config.forced_open_secs), alarm.clone())
+                    }),
+                    scheduler: scheduler.clone(),
+                    settings_rx: settings_bus.subscribe(),
+                },
+            )
+        });
+        optional("second door", started).map(|_| door_tx)
+    });
+
+    let chime_tx = settings.chime.as_ref().and_then(|config| {
+        optional(
+            "chime",
+            output::setup_pulse(
+                unsafe { AnyOutputPin::new(config.pin) },
+                Duration::from_millis(config.pulse_ms),
+            ),
+        )
+    });
 
     let (audit_tx, audit_rx) = mpsc::channel();
-    setup_reader(
+    let (event_tx, event_rx) = mpsc::channel();
+    let (motion_tx, motion_rx) = mqtt::outbox();
+    let (input_tx, input_rx) = mqtt::outbox();
+    // Inputs with end-of-line resistors are read over the adc
+    let start_input = |pin, role, active_low, debounce_ms, eol: Option<&EolConfig>| {
+        let debounce = Duration::from_millis(debounce_ms);
+        match eol {
+            Some(eol) => adc
+                .as_ref()
+                .context("no adc for the supervised input")
+                .and_then(|adc| adc.channel(pin))
+                .and_then(|channel| {
+                    input::setup_supervised_input(
+                        channel,
+                        eol.clone(),
+                        role,
+                        active_low,
+                        debounce,
+                        event_tx.clone(),
+                        alert_tx.clone(),
+                        walk_test.clone(),
+                    )
+                }),
+            None => input::setup_input(
+                unsafe { AnyInputPin::new(pin) },
+                role,
+                active_low,
+                debounce,
+                event_tx.clone(),
+                walk_test.clone(),
+            ),
+        }
+    };
+    let mut motion_trigger_tx = None;
+    if let Some(config) = &settings.motion {
+        optional(
+            "motion input",
+            start_input(
+                config.pin,
+                Role::Motion,
+                config.active_low,
+                config.debounce_ms,
+                None,
+            ),
+        );
+        if let Some(pin) = config.trigger_pin {
+            motion_trigger_tx = optional(
+                "motion trigger",
+                output::setup_pulse(
+                    unsafe { AnyOutputPin::new(pin) },
+                    Duration::from_millis(config.trigger_ms),
+                ),
+            );
+        }
+    }
+    let mut exit_button = None;
+    if let Some(config) = &settings.exit {
+        optional(
+            "exit button",
+            start_input(
+                config.pin,
+                Role::Exit,
+                config.active_low,
+                config.debounce_ms,
+                config.eol.as_ref(),
+            ),
+        );
+        exit_button = Some(ExitButton::new(
+            config,
+            door_tx.clone(),
+            scheduler.clone(),
+            audit_tx.clone(),
+        ));
+    }
+    let (position_tx, position_rx) = mqtt::outbox();
+    let mut sensor = None;
+    if let Some(config) = &settings.contact {
+        let contact = optional(
+            "door contact",
+            start_input(
+                config.pin,
+                Role::Contact,
+                config.active_low,
+                config.debounce_ms,
+                config.eol.as_ref(),
+            ),
+        );
+        if let Some(open) = contact {
+            sensor = Some(Sensor::new(open, position_tx));
+        }
+    }
+    if let Some(config) = settings
+        .second_door
+        .as_ref()
+        .filter(|_| second_door_tx.is_some())
+        .and_then(|config| config.contact.as_ref())
+    {
+        optional(
+            "second door contact",
+            start_input(
+                config.pin,
+                Role::SecondContact,
+                config.active_low,
+                config.debounce_ms,
+                config.eol.as_ref(),
+            ),
+        );
+    }
+    let mut release_txs = vec![door_tx.clone()];
+    release_txs.extend(second_door_tx.clone());
+    let (emergency_tx, emergency_rx) = mqtt::outbox();
+    for config in &settings.inputs {
+        let active = optional(
+            "input",
+            start_input(
+                config.pin,
+                config.role,
+                config.active_low,
+                config.debounce_ms,
+                config.eol.as_ref(),
+            ),
+        );
+        // A fire alarm already sounding releases the doors right away
+        if let (Role::Fire, Some(true)) = (config.role, active) {
+            release_doors(&release_txs, true, &emergency_tx);
+        }
+    }
+    setup_input_events(
+        event_rx,
+        motion_tx,
+        motion_trigger_tx,
+        exit_button,
         door_tx.clone(),
-        user_db.clone(),
-        audit_tx,
+        second_door_tx.clone(),
+        sensor,
+        input_tx,
+        alert_tx.clone(),
+        notifier.clone(),
+        release_txs,
+        emergency_tx,
+    );
+
+    let uplink = Uplink::new(nvs_part.clone())?;
+    let audit_config = settings.audit.clone().unwrap_or_default();
+    let audit_queue = AuditQueue::new(&audit_config);
+    // Available before the network so audits can be pulled while it is down
+    console::setup_console_commands(audit_queue.clone(), sink::archive_path(&audit_config));
+    let (grant_tx, grant_rx) = mqtt::outbox();
+    let grant_output = settings.grant.as_ref().and_then(|config| {
+        let pin = config.pin?;
+        optional(
+            "grant output",
+            output::setup_pulse(
+                unsafe { AnyOutputPin::new(pin) },
+                Duration::from_millis(config.pulse_ms),
+            ),
+        )
+    });
+    let floor_outputs = settings.elevator.as_ref().and_then(|config| {
+        let pins = config
+            .pins
+            .iter()
+            .map(|pin| unsafe { AnyOutputPin::new(*pin) })
+            .collect();
+        optional(
+            "floor outputs",
+            output::setup_outputs(pins, Duration::from_millis(config.pulse_ms)),
+        )
+    });
+    let (vms_tx, vms_rx) = mqtt::outbox();
+    let vms_output = settings.vms.as_ref().and_then(|config| {
+        let pin = config.pin?;
+        optional(
+            "vms output",
+            output::setup_pulse(
+                unsafe { AnyOutputPin::new(pin) },
+                Duration::from_millis(config.pulse_ms),
+            ),
+        )
+    });
+    // Every reader decides for its own door with the same database and outputs
+    let new_access = |door: u8, door_tx: Sender<DoorCommand>, feedback_tx: Sender<Feedback>| {
+        let mut access = Access::new(
+            user_db.clone(),
+            rules.clone(),
+            scheduler.clone(),
+            door_tx,
+            audit_tx.clone(),
+            chime_tx.clone(),
+            feedback_tx,
+        )
+        .with_door(door)
+        .with_temporary_codes(temporary_codes.clone())
+        .with_channels(channels.clone())
+        .with_alarm(alarm.clone())
+        .with_walk_test(walk_test.clone())
+        .with_readout(uplink.clone())
+        .with_audit_queue(audit_queue.clone());
+        if let Some(config) = &settings.scan_guard {
+            access = access.with_scan_guard(ScanGuard::new(config, alert_tx.clone()));
+        }
+        if let Some(config) = &settings.card {
+            access = access.with_normalizer(Normalizer::new(config));
+        }
+        if let Some(config) = &settings.pin {
+            access = access.with_pin_length(config);
+        }
+        if let Some(config) = &settings.stale {
+            access = access.with_stale_guard(StaleGuard::new(config, uplink.clone()));
+        }
+        if settings.grant.as_ref().is_some_and(|config| config.publish) {
+            access = access.with_grant_publisher(grant_tx.clone());
+        }
+        if let Some(grant_output) = &grant_output {
+            access = access.with_grant_output(grant_output.clone());
+        }
+        if let Some(floor_outputs) = &floor_outputs {
+            access = access.with_floor_outputs(floor_outputs.clone());
+        }
+        if settings.vms.is_some() {
+            let mut vms = Vms::new(vms_tx.clone());
+            if let Some(vms_output) = &vms_output {
+                vms = vms.with_output(vms_output.clone());
+            }
+            access = access.with_vms(vms);
+        }
+        if let Some(config) = &settings.duress {
+            let duress = Duress::new(config, alert_tx.clone()).with_zone(zone.clone());
+            access = access.with_duress(duress);
+        }
+        access
+    };
+    let access = new_access(0, door_tx.clone(), feedback_tx.clone());
+    let second_access = settings
+        .second_door
+        .as_ref()
+        .zip(second_door_tx.clone())
+        .map(|(config, door_tx)| {
+            let feedback_tx = config
+                .feedback_pin
+                .and_then(|pin| {
+                    optional(
+                        "second reader feedback",
+                        feedback::setup_feedback(
+                            unsafe { AnyOutputPin::new(pin) },
+                            settings.feedback.as_ref(),
+                            Audio::default(),
+                            settings_bus.subscribe(),
+                        ),
+                    )
+                })
+                .unwrap_or_else(|| feedback_tx.clone());
+            new_access(1, door_tx, feedback_tx)
+        });
+    let wiegand_config = settings.wiegand.clone().unwrap_or_default();
+    let (timing_tx, timing_rx) = mqtt::outbox();
+    let (unknown_tx, unknown_rx) = mqtt::outbox();
+    let timing_tx = wiegand_config.capture.then_some(timing_tx);
+    let reader_pins = [peripherals.pins.gpio4.pin(), peripherals.pins.gpio5.pin()];
+    let idle_level = wiegand_config.edge.idle_level();
+    let input_lines = settings
+        .motion
+        .iter()
+        .map(|config| (Role::Motion, config.pin, config.active_low))
+        // Supervised lines are analog, their faults are alerted instead
+        .chain(
+            settings
+                .exit
+                .iter()
+                .filter(|config| config.eol.is_none())
+                .map(|config| (Role::Exit, config.pin, config.active_low)),
+        )
+        .chain(
+            settings
+                .contact
+                .iter()
+                .filter(|config| config.eol.is_none())
+                .map(|config| (Role::Contact, config.pin, config.active_low)),
+        )
+        .chain(
+            settings
+                .second_door
+                .iter()
+                .filter_map(|config| config.contact.as_ref())
+                .filter(|config| config.eol.is_none())
+                .map(|config| (Role::SecondContact, config.pin, config.active_low)),
+        )
+        .chain(
+            settings
+                .inputs
+                .iter()
+                .filter(|config| config.eol.is_none())
+                .map(|config| (config.role, config.pin, config.active_low)),
+        )
+        .map(|(role, pin, active_low)| InputLine {
+            role,
+            pin,
+            active_low,
+        })
+        .collect();
+    let led_tx = match &settings.led {
+        Some(config) => Some(led::setup_status_led(
+            config,
+            scheduler.clone(),
+            uplink.clone(),
+        )?),
+        None => None,
+    };
+    let (wiring_tx, wiring_rx) = mqtt::outbox();
+    let wiring = WiringTest::new(door_tx.clone(), feedback_tx.clone(), reader_pins, wiring_tx)
+        .with_chime(chime_tx.clone())
+        .with_grant_output(grant_output)
+        .with_floor_outputs(floor_outputs)
+        .with_led(led_tx)
+        .with_sounder(alarm.clone())
+        .with_inputs(input_lines);
+    if let (Some(config), Some(access)) = (&settings.second_door, second_access) {
+        optional(
+            "second reader",
+            setup_reader(
+                access,
+                unsafe { AnyInputPin::new(config.d0_pin) },
+                unsafe { AnyInputPin::new(config.d1_pin) },
+                wiegand_config.clone(),
+                timing_tx.clone(),
+                unknown_tx.clone(),
+                alert_tx.clone(),
+                watchdog.register("second reader", PIN_TIMEOUT * 3),
+                None,
+            ),
+        );
+    }
+    setup_reader(
+        access,
         peripherals.pins.gpio4,
         peripherals.pins.gpio5,
-        peripherals.pins.gpio7,
+        wiegand_config,
+        timing_tx,
+        unknown_tx,
+        alert_tx.clone(),
+        watchdog.register("reader", PIN_TIMEOUT * 3),
+        Some(boot.clone()),
     )?;
 
-    let net_id = network::setup_wireless(
+    // Started before anything that can fail once the door works, audits are
+    // queued and written to the other sinks even when the broker never comes
+    let net_id = network::create_net_id()?;
+    log::info!("Device net_id: {net_id}");
+    let mqtt_sink = PendingSink::default();
+    let mut audit_sinks = vec![audit::setup_audit_sink(
+        mqtt_sink.clone(),
+        audit_queue.clone(),
+    )];
+    audit_sinks.extend(sink::setup_sinks(&net_id, &audit_config, peripherals.spi2));
+    audit::setup_audit_publisher(notifier, audit_rx, audit_sinks);
+    if let Some(config) = &settings.webhook {
+        webhook::setup_webhook(&net_id, config, webhook_rx);
+    }
+    let alert_relay = alert::setup_alert_relay(alert_rx);
+    boot.reached(Stage::Door);
+    if boot.elapsed() > DOOR_READY_DEADLINE {
+        log::warn!("Door ready after {:?}, later than expected", boot.elapsed());
+    }
+
+    let online = network::setup_wireless(
         peripherals.modem,
         sysloop.clone(),
         nvs_part.clone(),
         &mut doorsys_config,
+        settings.provisioning.as_ref(),
     )?;
+    if online {
+        boot.reached(Stage::Wifi);
+    }
+    let power_config = settings.power.clone().unwrap_or_default();
+    let power = Power::new(&power_config);
+    if power_config.low_power {
+        optional("low power profile", power.set(true));
+    }
 
-    let mqtt_client = mqtt::setup_mqtt(
+    let mut door_txs = vec![door_tx.clone()];
+    door_txs.extend(second_door_tx);
+    setup_scheduler(scheduler.clone(), door_txs.clone());
+    if let Some(config) = &settings.reboot {
+        reboot::setup_managed_reboot(
+            config,
+            door_status.clone(),
+            scheduler.clone(),
+            audit_queue.clone(),
+        );
+    }
+
+    let (cmd_tx, cmd_rx) = mpsc::channel();
+    let (sync_status_tx, sync_status_rx) = mqtt::outbox();
+    let (query_tx, query_rx) = mqtt::outbox();
+    let (memory_tx, memory_rx) = mqtt::outbox();
+    let (backup_tx, backup_rx) = mqtt::outbox();
+    let (resend_tx, resend_rx) = mpsc::channel();
+    let (management_tx, management_rx) = mqtt::outbox();
+    let management = ManagementLog::new(management_tx);
+    if let Some(config) = &settings.api {
+        api::setup_api(config, scheduler.clone(), cmd_tx.clone(), audit_tx.clone())?;
+    }
+    if let Some(config) = &settings.modbus {
+        modbus::setup_modbus(
+            config,
+            door_status.clone(),
+            scheduler.clone(),
+            door_tx.clone(),
+            audit_tx,
+        )?;
+    }
+    let (reported_tx, reported_rx) = mqtt::outbox();
+    let twin_tx = twin::setup_twin(
+        nvs_part.clone(),
+        rules.clone(),
+        holidays.clone(),
+        scheduler.clone(),
+        reported_tx,
+        management.clone(),
+    )?;
+    let peer_tx = match &settings.peer {
+        Some(config) => Some(peer::setup_peers(
+            config,
+            &net_id,
+            nvs_part.clone(),
+            user_db.clone(),
+            scheduler.clone(),
+            management.clone(),
+        )?),
+        None => None,
+    };
+    let jobs = Jobs::new(nvs_part.clone())?;
+    let cert_store = CertStore::new(nvs_part.clone())?;
+    let (certificates, cert_trial) = cert_store.load()?;
+    cron::setup_cron(jobs.clone(), cmd_tx.clone());
+    let health_schedule = settings
+        .health
+        .as_ref()
+        .map(|config| HealthSchedule::new(config, holidays.clone()));
+    command::setup_commands(
+        cmd_rx,
+        Executor {
+            user_db: user_db.clone(),
+            rules,
+            holidays,
+            scheduler,
+            jobs,
+            door_txs,
+            chime_tx,
+            sync_tx: sync::setup_sync(user_db.clone(), sync_status_tx, management.clone()),
+            query_tx,
+            memory_tx,
+            temporary_codes,
+            channels,
+            resend_tx,
+            alarm,
+            management: management.clone(),
+            cert_store: cert_store.clone(),
+            wiring,
+            walk_test,
+            config_store: Mutex::new(DoorsysConfig::new(nvs_part.clone())?),
+            settings_bus,
+            power,
+            firmware_key: doorsys_config.read_firmware_key()?,
+            backup: Arc::new(StateBackup::new(
+                nvs_part.clone(),
+                admin_secret(settings.security.as_ref())?,
+                backup_tx,
+            )),
+            open_limit: Mutex::new(OpenLimit::new(
+                &settings.remote_open.clone().unwrap_or_default(),
+                alert_tx.clone(),
+            )),
+        },
+    );
+
+    if !online {
+        // Left offline by the provisioning timeout, the door keeps working
+        // from the threads already started with the credentials in flash
+        log::warn!("Running offline, the broker is not used");
+        return Ok(());
+    }
+
+    let security = settings.security.as_ref();
+    let secret = operator_secret(security)?;
+    let admin_secret = admin_secret(security)?;
+    let mut router = Router::new(
         &net_id,
         user_db.clone(),
-        &doorsys_config.read_mqtt_configs()?,
+        cmd_tx,
+        twin_tx,
+        Authenticator::new(secret, admin_secret).with_floor(nvs_part.clone(), MQTT_FLOOR_KEY)?,
+        ReplayGuard::new(nvs_part.clone())?,
+        alert_tx.clone(),
+    )
+    .with_feedback(feedback_tx)
+    .with_management(management)
+    .with_broadcast_jitter(Duration::from_secs(
+        settings.broadcast.clone().unwrap_or_default().jitter_secs,
+    ));
+    if let Some(peer_tx) = peer_tx {
+        router = router.with_peers(peer_tx);
+    }
+    if let Some(config) = &settings.zone {
+        router = router.with_zone(&config.id);
+    }
+    let mut mqtt_config = doorsys_config.read_mqtt_configs()?;
+    if mqtt_config.url == discovery::AUTO {
+        let config = settings.discovery.clone().unwrap_or_default();
+        mqtt_config.url = discovery::broker_url(&config)?;
+    }
+    let mqtt_client = mqtt::setup_mqtt(
+        &net_id,
+        &mqtt_config,
+        settings.security.as_ref(),
+        router,
+        uplink.clone(),
+        boot.clone(),
+        certificates.as_ref(),
     )?;
+    if cert_trial {
+        cert_store.confirm(uplink.clone());
+    }
+    if let Some(config) = &settings.zone {
+        zone::setup_zone_publisher(
+            &net_id,
+            &config.id,
+            operator_secret(security)?,
+            mqtt_client.clone(),
+            zone_rx,
+        );
+    }
+
+    mqtt_sink.attach(MqttSink::new(
+        &net_id,
+        mqtt_client.clone(),
+        AuditChain::new(nvs_part.clone(), settings.site.clone())?,
+        uplink,
+    ));
+    optional(
+        "storage watch",
+        storage::setup_storage_watch(
+            &settings.storage.clone().unwrap_or_default(),
+            nvs_part.clone(),
+            user_db.clone(),
+            alert_tx.clone(),
+        ),
+    );
+    alert::setup_alert_publisher(&net_id, mqtt_client.clone(), alert_relay);
+    management::setup_management_publisher(&net_id, mqtt_client.clone(), management_rx);
+    mqtt::setup_publisher(
+        mqtt::topic(&format!("door/{net_id}")),
+        true,
+        mqtt_client.clone(),
+        state_rx,
+    );
+    mqtt::setup_publisher(
+        mqtt::topic(&format!("door/{net_id}/position")),
+        true,
+        mqtt_client.clone(),
+        position_rx,
+    );
+    mqtt::setup_publisher(
+        mqtt::topic(&format!("emergency/{net_id}")),
+        true,
+        mqtt_client.clone(),
+        emergency_rx,
+    );
+    mqtt::setup_publisher(
+        mqtt::topic(&format!("door/{net_id}/1")),
+        true,
+        mqtt_client.clone(),
+        second_state_rx,
+    );
+    mqtt::setup_publisher(
+        mqtt::topic(&format!("grant/{net_id}")),
+        false,
+        mqtt_client.clone(),
+        grant_rx,
+    );
+    mqtt::setup_publisher(
+        mqtt::topic(&format!("backup/{net_id}")),
+        false,
+        mqtt_client.clone(),
+        backup_rx,
+    );
+    mqtt::setup_publisher(
+        mqtt::topic(&format!("vms/{net_id}")),
+        false,
+        mqtt_client.clone(),
+        vms_rx,
+    );
+    mqtt::setup_publisher(
+        mqtt::topic(&format!("motion/{net_id}")),
+        false,
+        mqtt_client.clone(),
+        motion_rx,
+    );
+    mqtt::setup_publisher(
+        mqtt::topic(&format!("input/{net_id}")),
+        false,
+        mqtt_client.clone(),
+        input_rx,
+    );
+    mqtt::setup_publisher(
+        mqtt::topic(&format!("alarm/{net_id}")),
+        false,
+        mqtt_client.clone(),
+        alarm_event_rx,
+    );
+    mqtt::setup_publisher(
+        mqtt::topic(&format!("diag/{net_id}")),
+        false,
+        mqtt_client.clone(),
+        timing_rx,
+    );
+    mqtt::setup_publisher(
+        mqtt::topic(&format!("diag/{net_id}/unknown")),
+        false,
+        mqtt_client.clone(),
+        unknown_rx,
+    );
+    mqtt::setup_publisher(
+        mqtt::topic(&format!("sync/{net_id}")),
+        false,
+        mqtt_client.clone(),
+        sync_status_rx,
+    );
+    mqtt::setup_publisher(
+        mqtt::topic(&format!("query/{net_id}")),
+        false,
+        mqtt_client.clone(),
+        query_rx,
+    );
+    mqtt::setup_publisher(
+        mqtt::topic(&format!("diag/{net_id}/memory")),
+        false,
+        mqtt_client.clone(),
+        memory_rx,
+    );
+    mqtt::setup_publisher(
+        mqtt::topic(&format!("walktest/{net_id}")),
+        false,
+        mqtt_client.clone(),
+        walk_rx,
+    );
+    mqtt::setup_publisher(
+        mqtt::topic(&format!("wiring/{net_id}")),
+        false,
+        mqtt_client.clone(),
+        wiring_rx,
+    );
+    mqtt::setup_publisher(
+        mqtt::topic(&format!("twin/{net_id}/reported")),
+        true,
+        mqtt_client.clone(),
+        reported_rx,
+    );
 
-    setup_audit_publiher(&net_id, mqtt_client.clone(), audit_rx);
+    let relay = settings
+        .selftest
+        .as_ref()
+        .is_some_and(|config| config.relay)
+        .then(|| Relay {
+            door_tx: door_tx.clone(),
+            status: door_status,
+        });
+    let (selftest_tx, selftest_rx) = mqtt::outbox();
+    selftest::setup_selftest(
+        nvs_part.clone(),
+        user_db.clone(),
+        relay,
+        reader_pins,
+        idle_level,
+        selftest_tx,
+    );
+    mqtt::setup_publisher(
+        mqtt::topic(&format!("selftest/{net_id}")),
+        true,
+        mqtt_client.clone(),
+        selftest_rx,
+    );
+    mqtt::setup_publisher(
+        mqtt::topic(&format!("boot/{net_id}")),
+        true,
+        mqtt_client.clone(),
+        boot_rx,
+    );
+    mqtt::setup_resend(mqtt_client.clone(), resend_rx);
 
-    health_check(&net_id, mqtt_client.clone())?;
+    health_check(
+        &net_id,
+        settings.site.as_deref(),
+        mqtt_client.clone(),
+        settings.influx.as_ref(),
+        user_db,
+        watchdog.register("health", Duration::from_secs(180)),
+        health_schedule,
+    )?;
 
     log::info!("Application fully functional");
+    boot.reached(Stage::Ready);
 
     Ok(())
 }
@@ -0,0 +1,118 @@
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use serde::Serialize;
+
+use crate::auth::Level;
+use crate::mqtt::{self, MqttClient, Outbox, Stamped};
+
+/// Who requested a management change
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// Mqtt message signed with the operator secret
+    Operator,
+    /// Mqtt message signed with the admin secret, or any message
+    /// when signing is disabled
+    Admin,
+    Http,
+    /// Scheduled job
+    Cron,
+    /// Desired state of the device twin
+    Twin,
+    /// Another controller of the site
+    Peer,
+    /// Lockdown requested by another controller of the zone
+    Zone,
+}
+
+impl From<Level> for Origin {
+    fn from(level: Level) -> Self {
+        match level {
+            Level::Operator => Origin::Operator,
+            Level::Admin => Origin::Admin,
+        }
+    }
+}
+
+/// What was changed
+#[derive(Serialize, Debug, Clone, Copy)]
+pub enum Change {
+    Users,
+    Rules,
+    Holidays,
+    UnlockSchedule,
+    Overrides,
+    Lockdown,
+    Jobs,
+    Credential,
+    TemporaryCode,
+    Channels,
+    FactoryReset,
+    Certificates,
+    Settings,
+    Firmware,
+    Power,
+    /// Whole state restored from a backup
+    Restore,
+    DoorMode,
+}
+
+/// Published to doorsys/management/{device_id} for every change to the
+/// configuration or the user database, a compliance trail kept apart
+/// from the door activity
+#[derive(Serialize, Debug)]
+pub struct ManagementEvent {
+    pub origin: Origin,
+    pub change: Change,
+    /// Entries in the new configuration, or users affected
+    pub count: usize,
+    pub error: Option<String>,
+    pub timestamp: SystemTime,
+}
+
+/// Handle used by the subsystems to record the changes.
+/// Does nothing when created with default.
+#[derive(Clone, Default)]
+pub struct ManagementLog {
+    tx: Option<Outbox<ManagementEvent>>,
+}
+
+impl ManagementLog {
+    pub fn new(tx: Outbox<ManagementEvent>) -> Self {
+        ManagementLog { tx: Some(tx) }
+    }
+
+    pub fn record(
+        &self,
+        origin: Origin,
+        change: Change,
+        count: usize,
+        result: &anyhow::Result<()>,
+    ) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        let event = ManagementEvent {
+            origin,
+            change,
+            count,
+            error: result.as_ref().err().map(|e| e.to_string()),
+            timestamp: SystemTime::now(),
+        };
+        log::info!("Management change {:?}", event);
+        if let Err(e) = tx.send(event) {
+            log::error!("error sending management event: {}", e);
+        }
+    }
+}
+
+/// Publishes the management events to doorsys/management/{device_id}
+pub fn setup_management_publisher(
+    device_id: &str,
+    mqtt_client: Arc<Mutex<MqttClient>>,
+    management_rx: Receiver<Stamped<ManagementEvent>>,
+) {
+    let topic = mqtt::topic(&format!("management/{device_id}"));
+    mqtt::setup_publisher(topic, false, mqtt_client, management_rx);
+}
@@ -0,0 +1,164 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::Sender;
+use std::thread;
+
+use crate::audit::{Action, AuditEvent, Source};
+use crate::config::ModbusConfig;
+use crate::door::{DoorCommand, DoorState, DoorStatus};
+use crate::schedule::{Mode, Scheduler};
+
+const READ_COILS: u8 = 0x01;
+const READ_DISCRETE_INPUTS: u8 = 0x02;
+const WRITE_SINGLE_COIL: u8 = 0x05;
+
+const ILLEGAL_FUNCTION: u8 = 0x01;
+const ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+const ILLEGAL_DATA_VALUE: u8 = 0x03;
+
+/// Coil 0, reads as the relay state and opens the door when set
+const UNLOCK_COIL: u16 = 0;
+
+const COIL_ON: u16 = 0xFF00;
+const COIL_OFF: u16 = 0x0000;
+
+/// Minimal Modbus TCP server exposing the door to building automation
+struct Server {
+    door_status: DoorStatus,
+    scheduler: Scheduler,
+    door_tx: Sender<DoorCommand>,
+    audit_tx: Sender<AuditEvent>,
+    allow_writes: bool,
+}
+
+/// Starts the Modbus TCP server, connections are served one at a time
+pub fn setup_modbus(
+    config: &ModbusConfig,
+    door_status: DoorStatus,
+    scheduler: Scheduler,
+    door_tx: Sender<DoorCommand>,
+    audit_tx: Sender<AuditEvent>,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", config.port))?;
+    let server = Server {
+        door_status,
+        scheduler,
+        door_tx,
+        audit_tx,
+        allow_writes: config.allow_writes,
+    };
+    log::info!("Modbus server listening on port {}", config.port);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = server.serve(stream) {
+                        log::warn!("modbus connection closed: {}", e);
+                    }
+                }
+                Err(e) => log::error!("modbus accept error: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+impl Server {
+    fn serve(&self, mut stream: TcpStream) -> anyhow::Result<()> {
+        let mut header = [0; 7];
+        loop {
+            stream.read_exact(&mut header)?;
+            // Length covers the unit id, which is part of the header, and the pdu
+            let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+            if !(2..=253).contains(&length) {
+                anyhow::bail!("invalid length {}", length);
+            }
+            let mut pdu = vec![0; length - 1];
+            stream.read_exact(&mut pdu)?;
+
+            let response = match self.process(&pdu) {
+                Ok(response) => response,
+                Err(code) => vec![pdu[0] | 0x80, code],
+            };
+            let mut frame = Vec::with_capacity(7 + response.len());
+            frame.extend_from_slice(&header[..4]);
+            frame.extend_from_slice(&(response.len() as u16 + 1).to_be_bytes());
+            frame.push(header[6]);
+            frame.extend_from_slice(&response);
+            stream.write_all(&frame)?;
+        }
+    }
+
+    /// Handles a request pdu returning the response or an exception code
+    fn process(&self, pdu: &[u8]) -> Result<Vec<u8>, u8> {
+        if pdu.len() != 5 {
+            return Err(ILLEGAL_DATA_VALUE);
+        }
+        let function = pdu[0];
+        let address = u16::from_be_bytes([pdu[1], pdu[2]]);
+        let value = u16::from_be_bytes([pdu[3], pdu[4]]);
+        match function {
+            READ_COILS => {
+                let coils = [self.door_status.unlocked()];
+                read_bits(function, &coils, address, value)
+            }
+            READ_DISCRETE_INPUTS => {
+                // Relay state followed by the lockdown and relay fault flags
+                let inputs = [
+                    self.door_status.unlocked(),
+                    self.scheduler.mode() == Mode::LockedDown,
+                    self.door_status.state() == DoorState::Fault,
+                ];
+                read_bits(function, &inputs, address, value)
+            }
+            WRITE_SINGLE_COIL => {
+                if address != UNLOCK_COIL {
+                    return Err(ILLEGAL_DATA_ADDRESS);
+                }
+                if value != COIL_ON && value != COIL_OFF {
+                    return Err(ILLEGAL_DATA_VALUE);
+                }
+                if value == COIL_ON {
+                    self.audit(self.allow_writes);
+                    if !self.allow_writes {
+                        log::warn!("modbus writes are disabled");
+                        return Err(ILLEGAL_FUNCTION);
+                    }
+                    log::info!("Modbus open");
+                    if let Err(e) = self.door_tx.send(DoorCommand::Open) {
+                        log::error!("error sending door command: {}", e);
+                    }
+                }
+                // The response to a write is an echo of the request
+                Ok(pdu.to_vec())
+            }
+            _ => Err(ILLEGAL_FUNCTION),
+        }
+    }
+
+    fn audit(&self, success: bool) {
+        let event = AuditEvent::remote(Source::Modbus, Action::Open, success);
+        if let Err(e) = self.audit_tx.send(event) {
+            log::error!("error sending audit record: {}", e);
+        }
+    }
+}
+
+/// Packs the requested range of bits, least significant bit first
+fn read_bits(function: u8, bits: &[bool], address: u16, quantity: u16) -> Result<Vec<u8>, u8> {
+    let start = address as usize;
+    let end = start + quantity as usize;
+    if quantity == 0 || end > bits.len() {
+        return Err(ILLEGAL_DATA_ADDRESS);
+    }
+    let byte_count = (quantity as usize).div_ceil(8);
+    let mut response = vec![0; 2 + byte_count];
+    response[0] = function;
+    response[1] = byte_count as u8;
+    for (i, bit) in bits[start..end].iter().enumerate() {
+        if *bit {
+            response[2 + i / 8] |= 1 << (i % 8);
+        }
+    }
+    Ok(response)
+}
@@ -1,35 +1,145 @@
-use std::sync::{mpsc, Arc, Mutex};
+use std::collections::BTreeMap;
+use std::mem;
+use std::sync::mpsc::{self, Receiver, SendError, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 use doorsys_protocol::UserAction;
 use esp_idf_svc::mqtt::client::{
     Details, EspMqttClient, EventPayload, MqttClientConfiguration, QoS,
 };
+use esp_idf_svc::sys::esp_random;
+use esp_idf_svc::tls::X509;
+use serde::Serialize;
 
-use crate::config::MqttConfig;
+use crate::alert::{Alert, Category, Severity};
+use crate::auth::{Authenticator, Level, ReplayGuard};
+use crate::boot::{BootProgress, Stage};
+use crate::certs::Certificates;
+use crate::command::{BroadcastMessage, Command, CommandMessage};
+use crate::config::{MqttConfig, SecurityConfig};
+use crate::feedback::Feedback;
+use crate::heap;
+use crate::management::{Change, ManagementLog, Origin};
+use crate::privacy::Redacted;
+use crate::protocol::{self, Birth, ServerCapabilities, PROTOCOL_VERSION};
+use crate::schedule::LocalTime;
+use crate::stale::Uplink;
 use crate::user::UserDB;
+use crate::zone::{self, ZoneLockdown};
+
+/// Received messages waiting for the router, keeps the client callback short
+const RX_QUEUE_SIZE: usize = 8;
+
+static SITE: OnceLock<String> = OnceLock::new();
+/// Last payload of every retained topic, kept to resend them on request
+static RETAINED: Mutex<BTreeMap<String, Vec<u8>>> = Mutex::new(BTreeMap::new());
+
+/// Sets the site id included in every topic so devices from multiple
+/// buildings can share the same broker. Must be called before any topic is built.
+pub fn set_site(site: &str) {
+    if SITE.set(site.to_owned()).is_err() {
+        log::warn!("site already set");
+    }
+}
+
+/// Builds the full topic, doorsys/{path} or doorsys/{site}/{path} when a site is set
+pub fn topic(path: &str) -> String {
+    match SITE.get() {
+        Some(site) => format!("doorsys/{site}/{path}"),
+        None => format!("doorsys/{path}"),
+    }
+}
 
 pub type MqttClient = EspMqttClient<'static>;
 
+/// Envelope of every message sent through a publisher.
+/// The message comes first so backends unaware of the envelope can still decode it.
+#[derive(Serialize, Debug)]
+pub struct Stamped<T> {
+    pub message: T,
+    /// When the message was created, not delayed by a stalled connection
+    pub captured: SystemTime,
+    /// When the message was handed to the mqtt client,
+    /// only once the clock is synchronized
+    pub published: Option<SystemTime>,
+}
+
+/// Sending side of a publisher, stamps every message with its capture time
+pub struct Outbox<T>(Sender<Stamped<T>>);
+
+impl<T> Clone for Outbox<T> {
+    fn clone(&self) -> Self {
+        Outbox(self.0.clone())
+    }
+}
+
+impl<T> Outbox<T> {
+    pub fn send(&self, message: T) -> Result<(), SendError<Stamped<T>>> {
+        self.0.send(Stamped {
+            message,
+            captured: SystemTime::now(),
+            published: None,
+        })
+    }
+}
+
+/// Creates the channel feeding a publisher
+pub fn outbox<T>() -> (Outbox<T>, Receiver<Stamped<T>>) {
+    let (tx, rx) = mpsc::channel();
+    (Outbox(tx), rx)
+}
+
 /// Creates a new mqtt client and setup the book keeping
 /// the background thread to receive and process incoming messages
 pub fn setup_mqtt(
     net_id: &str,
-    user_db: UserDB,
     config: &MqttConfig,
+    security: Option<&SecurityConfig>,
+    router: Router,
+    uplink: Uplink,
+    boot: BootProgress,
+    certificates: Option<&Certificates>,
 ) -> anyhow::Result<Arc<Mutex<MqttClient>>> {
+    // Installed certificates take precedence over the one in the settings
+    let client_certificate = certificates
+        .and_then(|certificates| certificates.client_cert.as_deref())
+        .or_else(|| security.and_then(|security| security.client_cert.as_deref()))
+        .map(leak_pem);
     let mqtt_config = MqttClientConfiguration {
         client_id: Some(net_id),
         username: Some(&config.username),
         password: Some(&config.password),
         disable_clean_session: true,
+        client_certificate,
+        private_key: certificates
+            .and_then(|certificates| certificates.private_key.as_deref())
+            .map(leak_pem),
+        server_certificate: certificates
+            .and_then(|certificates| certificates.ca.as_deref())
+            .map(leak_pem),
+        use_secure_element: security.is_some_and(|security| security.secure_element),
         ..Default::default()
     };
 
     let (conn_sender, conn_receiver) = mpsc::channel();
 
+    let mut topics = vec![
+        router.user_topic.clone(),
+        router.cmd_topic.clone(),
+        router.twin_topic.clone(),
+        router.broadcast_topic.clone(),
+        router.server_topic.clone(),
+    ];
+    topics.extend(router.zone_topic.clone());
+    let birth_topic = topic(&format!("birth/{net_id}"));
+
+    let rx_tx = setup_router(router);
     let mut shared_buffer = Vec::new();
     let mut shared_topic = String::new();
+    // Set when the heap can't take a chunked payload, its chunks are skipped
+    let mut dropping = false;
     let client = EspMqttClient::new_cb(&config.url, &mqtt_config, move |event| {
         match event.payload() {
             EventPayload::Received {
@@ -44,89 +154,504 @@ pub fn setup_mqtt(
                     details,
                     data.len()
                 );
-                let (topic, data) = match details {
+                let message = match details {
                     Details::InitialChunk(init) => {
-                        shared_buffer = Vec::with_capacity(init.total_data_size);
+                        shared_buffer = Vec::new();
+                        dropping = heap::reserve(init.total_data_size, "mqtt payload").is_err();
+                        if dropping {
+                            return;
+                        }
+                        shared_buffer.reserve_exact(init.total_data_size);
                         shared_buffer.extend_from_slice(data);
                         shared_topic = String::from(topic.unwrap());
                         return;
                     }
-                    Details::SubsequentChunk(_sub) => {
+                    Details::SubsequentChunk(sub) => {
+                        if dropping {
+                            dropping = sub.current_data_offset + data.len() < sub.total_data_size;
+                            return;
+                        }
                         shared_buffer.extend_from_slice(data);
                         if shared_buffer.len() != shared_buffer.capacity() {
                             return;
                         }
-                        (&*shared_topic, &*shared_buffer)
+                        (mem::take(&mut shared_topic), mem::take(&mut shared_buffer))
                     }
-                    Details::Complete => (topic.unwrap(), data),
+                    Details::Complete => (String::from(topic.unwrap()), data.to_vec()),
                 };
-                route_message(topic, data, &user_db);
+                dispatch(&rx_tx, message);
             }
             EventPayload::Connected(session) => {
                 log::info!("Connected session = {session}");
+                uplink.set_connected(true);
+                boot.reached(Stage::Mqtt);
                 conn_sender.send(()).unwrap();
             }
+            EventPayload::Disconnected => {
+                log::warn!("mqtt disconnected");
+                uplink.set_connected(false);
+            }
             EventPayload::Error(e) => log::error!("from mqtt: {:?}", e),
             event => log::info!("mqtt event: {:?}", event),
         }
     })?;
     let client = Arc::new(Mutex::new(client));
 
-    subscriber_thread(client.clone(), conn_receiver);
+    subscriber_thread(client.clone(), conn_receiver, topics, birth_topic);
 
     Ok(client)
 }
 
+/// Spawns the thread routing the received messages so the decoding and
+/// handling, such as a bulk user update, don't delay the client keepalives
+fn setup_router(mut router: Router) -> SyncSender<(String, Vec<u8>)> {
+    let (rx_tx, rx_rx) = mpsc::sync_channel::<(String, Vec<u8>)>(RX_QUEUE_SIZE);
+    thread::spawn(move || {
+        for (topic, data) in rx_rx {
+            router.route(&topic, &data);
+        }
+    });
+    rx_tx
+}
+
+/// Queues a received message. When the queue is full the client waits
+/// for room instead of dropping it, messages are acknowledged on receipt.
+fn dispatch(rx_tx: &SyncSender<(String, Vec<u8>)>, message: (String, Vec<u8>)) {
+    let result = match rx_tx.try_send(message) {
+        Err(TrySendError::Full(message)) => {
+            log::warn!("receive queue full, waiting for the router");
+            rx_tx.send(message)
+        }
+        Err(TrySendError::Disconnected(message)) => Err(SendError(message)),
+        Ok(()) => Ok(()),
+    };
+    if let Err(e) = result {
+        log::error!("error dispatching received message: {}", e);
+    }
+}
+
+/// The certificates must outlive the client, which lives until reboot
+fn leak_pem(pem: &str) -> X509<'static> {
+    let pem: &'static str = Box::leak(format!("{pem}\0").into_boxed_str());
+    X509::pem_until_nul(pem.as_bytes())
+}
+
+/// Spawns a thread that encodes every message received on the
+/// channel and enqueues it to the given topic
+pub fn setup_publisher<T: Serialize + Send + 'static>(
+    topic: String,
+    retain: bool,
+    mqtt_client: Arc<Mutex<MqttClient>>,
+    rx: Receiver<Stamped<T>>,
+) {
+    thread::spawn(move || {
+        for mut msg in rx {
+            if LocalTime::now().is_some() {
+                msg.published = Some(SystemTime::now());
+            }
+            match postcard::to_allocvec(&msg) {
+                Ok(buffer) => {
+                    if let Err(e) = mqtt_client.lock().unwrap().enqueue(
+                        &topic,
+                        QoS::AtLeastOnce,
+                        retain,
+                        &buffer,
+                    ) {
+                        log::error!("error publishing to {}: {}", topic, e);
+                    }
+                    if retain {
+                        RETAINED.lock().unwrap().insert(topic.clone(), buffer);
+                    }
+                }
+                Err(e) => {
+                    log::error!("error encoding message for {}: {}", topic, e);
+                }
+            }
+        }
+    });
+}
+
+/// Publishes again the last message of every retained topic
+/// whenever a request is received, e.g. after the backend is rebuilt
+pub fn setup_resend(mqtt_client: Arc<Mutex<MqttClient>>, resend_rx: Receiver<()>) {
+    thread::spawn(move || {
+        for () in resend_rx {
+            let retained = RETAINED.lock().unwrap().clone();
+            log::info!("Resending {} retained topics", retained.len());
+            for (topic, buffer) in retained {
+                if let Err(e) =
+                    mqtt_client
+                        .lock()
+                        .unwrap()
+                        .enqueue(&topic, QoS::AtLeastOnce, true, &buffer)
+                {
+                    log::error!("error resending {}: {}", topic, e);
+                }
+            }
+        }
+    });
+}
+
 fn subscriber_thread(
     client: Arc<Mutex<EspMqttClient<'static>>>,
     conn_receiver: mpsc::Receiver<()>,
+    topics: Vec<String>,
+    birth_topic: String,
 ) {
     thread::spawn(move || {
         while conn_receiver.recv().is_ok() {
-            let topic = "doorsys/user";
-            match client.lock().unwrap().subscribe(topic, QoS::AtLeastOnce) {
-                Ok(id) => log::info!("Subscribed to {topic} {id}"),
-                Err(e) => log::error!("Failed to subscribe to topic {topic}: {e}"),
+            for topic in &topics {
+                match client.lock().unwrap().subscribe(topic, QoS::AtLeastOnce) {
+                    Ok(id) => log::info!("Subscribed to {topic} {id}"),
+                    Err(e) => log::error!("Failed to subscribe to topic {topic}: {e}"),
+                };
+            }
+            let birth = match postcard::to_allocvec(&Birth::now()) {
+                Ok(birth) => birth,
+                Err(e) => {
+                    log::error!("error encoding birth message: {}", e);
+                    continue;
+                }
             };
+            if let Err(e) =
+                client
+                    .lock()
+                    .unwrap()
+                    .enqueue(&birth_topic, QoS::AtLeastOnce, true, &birth)
+            {
+                log::error!("error sending birth message: {}", e);
+            }
         }
     });
 }
 
-fn route_message(topic: &str, data: &[u8], user_db: &UserDB) {
-    match topic {
-        "doorsys/user" => process_user_message(data, user_db),
-        _ => log::warn!("unknown topic {}", topic),
-    };
+/// Authenticates the messages received from the broker
+/// and dispatches them to their handlers
+pub struct Router {
+    user_db: UserDB,
+    user_topic: String,
+    cmd_topic: String,
+    cmd_tx: Sender<(Origin, Command)>,
+    twin_topic: String,
+    twin_tx: Sender<Vec<u8>>,
+    auth: Authenticator,
+    replay_guard: ReplayGuard,
+    alert_tx: Outbox<Alert>,
+    feedback_tx: Option<Sender<Feedback>>,
+    peer_tx: Option<Sender<Vec<u8>>>,
+    management: ManagementLog,
+    broadcast_topic: String,
+    /// Longest random delay before running a broadcast command
+    broadcast_jitter: Duration,
+    server_topic: String,
+    net_id: String,
+    zone_topic: Option<String>,
 }
 
-fn process_user_message(data: &[u8], user_db: &UserDB) {
-    match postcard::from_bytes(data) {
-        Ok(UserAction::Add(code)) => {
-            log::info!("Adding code {}", code);
-            if let Err(e) = user_db.add(code) {
-                log::error!("Error adding new code {}", e);
+impl Router {
+    pub fn new(
+        net_id: &str,
+        user_db: UserDB,
+        cmd_tx: Sender<(Origin, Command)>,
+        twin_tx: Sender<Vec<u8>>,
+        auth: Authenticator,
+        replay_guard: ReplayGuard,
+        alert_tx: Outbox<Alert>,
+    ) -> Self {
+        Router {
+            user_db,
+            user_topic: topic("user"),
+            cmd_topic: topic(&format!("cmd/{net_id}")),
+            cmd_tx,
+            twin_topic: topic(&format!("twin/{net_id}/desired")),
+            twin_tx,
+            auth,
+            replay_guard,
+            alert_tx,
+            feedback_tx: None,
+            peer_tx: None,
+            management: ManagementLog::default(),
+            broadcast_topic: topic("broadcast"),
+            broadcast_jitter: Duration::ZERO,
+            server_topic: topic("server"),
+            net_id: net_id.to_owned(),
+            zone_topic: None,
+        }
+    }
+
+    /// Confirms on the keypad when new users are added
+    pub fn with_feedback(mut self, feedback_tx: Sender<Feedback>) -> Self {
+        self.feedback_tx = Some(feedback_tx);
+        self
+    }
+
+    /// Gossips the user updates to the other controllers
+    pub fn with_peers(mut self, peer_tx: Sender<Vec<u8>>) -> Self {
+        self.peer_tx = Some(peer_tx);
+        self
+    }
+
+    /// Records the user updates in the management trail
+    pub fn with_management(mut self, management: ManagementLog) -> Self {
+        self.management = management;
+        self
+    }
+
+    /// Spreads the broadcast commands of the site over the window
+    /// so the devices don't all publish or reconnect at once
+    pub fn with_broadcast_jitter(mut self, jitter: Duration) -> Self {
+        self.broadcast_jitter = jitter;
+        self
+    }
+
+    /// Locks the door down when another controller of the zone asks for it
+    pub fn with_zone(mut self, zone_id: &str) -> Self {
+        self.zone_topic = Some(zone::zone_topic(zone_id));
+        self
+    }
+
+    fn alert(&self, detail: String) {
+        if let Err(e) = self
+            .alert_tx
+            .send(Alert::new(Severity::Info, Category::Security, detail))
+        {
+            log::error!("error sending alert: {}", e);
+        }
+    }
+
+    fn route(&mut self, topic: &str, data: &[u8]) {
+        if topic == self.twin_topic {
+            self.process_desired_state(data);
+            return;
+        }
+        if topic == self.server_topic {
+            self.process_server_capabilities(data);
+            return;
+        }
+        let zone = self.zone_topic.as_deref() == Some(topic);
+        if topic != self.user_topic
+            && topic != self.cmd_topic
+            && topic != self.broadcast_topic
+            && !zone
+        {
+            log::warn!("unknown topic {}", topic);
+            return;
+        }
+        let (level, payload) = match self.auth.verify(topic, data) {
+            Ok(verified) => verified,
+            Err(e) => {
+                self.alert(format!("rejected message on {}: {}", topic, e));
+                return;
             }
+        };
+        if topic == self.user_topic {
+            self.process_user_message(level, payload);
+        } else if topic == self.broadcast_topic {
+            self.process_broadcast_message(level, payload);
+        } else if zone {
+            self.process_zone_message(payload);
+        } else {
+            self.process_command_message(level, payload);
         }
-        Ok(UserAction::Del(code)) => {
-            log::info!("Deleting code {}", code);
-            if let Err(e) = user_db.delete(code) {
-                log::error!("Error deleting code {}", e);
+    }
+
+    fn process_desired_state(&self, data: &[u8]) {
+        // Clearing the retained message delivers an empty payload
+        if data.is_empty() {
+            return;
+        }
+        match self.auth.verify_retained(&self.twin_topic, data) {
+            Ok((Level::Admin, payload)) => {
+                if let Err(e) = self.twin_tx.send(payload.to_vec()) {
+                    log::error!("Error dispatching desired state {}", e);
+                }
             }
+            Ok((level, _)) => self.alert(format!(
+                "desired state requires admin level, got {:?}",
+                level
+            )),
+            Err(e) => self.alert(format!("rejected desired state: {}", e)),
+        }
+    }
+
+    fn process_server_capabilities(&self, data: &[u8]) {
+        // Clearing the retained message delivers an empty payload
+        if data.is_empty() {
+            return;
         }
-        Ok(UserAction::Replace { old, new }) => {
-            log::info!("Replacing code {} with {}", old, new);
-            if let Err(e) = user_db.replace(old, new) {
-                log::error!("Error replacing code {}", e);
+        // A forged downgrade would take the audits off the chain
+        match self.auth.verify_retained(&self.server_topic, data) {
+            Ok((Level::Admin, payload)) => {
+                match postcard::from_bytes::<ServerCapabilities>(payload) {
+                    Ok(capabilities) => protocol::set_server_protocol(capabilities.protocol),
+                    Err(e) => log::error!("decoding error: {}", e),
+                }
             }
+            Ok((level, _)) => self.alert(format!(
+                "server capabilities require admin level, got {:?}",
+                level
+            )),
+            Err(e) => self.alert(format!("rejected server capabilities: {}", e)),
         }
-        Ok(UserAction::Bulk(codes)) => {
-            log::info!("Bulk adding codes {}", codes.len());
-            if let Err(e) = user_db.bulk(codes) {
-                log::error!("Error bulk inserting codes {}", e);
+    }
+
+    fn process_user_message(&self, level: Level, data: &[u8]) {
+        if heap::reserve(data.len() * heap::DECODE_EXPANSION, "user update").is_err() {
+            return;
+        }
+        match postcard::from_bytes(data) {
+            // Bulk replaces the whole database so it can wipe every user
+            Ok(UserAction::Bulk(_)) if level < Level::Admin => {
+                self.alert(String::from("bulk user update requires admin level"));
+            }
+            Ok(action) => {
+                let added = matches!(action, UserAction::Add(_) | UserAction::Replace { .. });
+                // Bulk updates don't fit in a datagram, peers get them from the broker
+                let gossip = !matches!(action, UserAction::Bulk(_));
+                if !process_user_action(action, &self.user_db, level.into(), &self.management) {
+                    return;
+                }
+                if let (true, Some(peer_tx)) = (gossip, &self.peer_tx) {
+                    if let Err(e) = peer_tx.send(data.to_vec()) {
+                        log::error!("error gossiping user action: {}", e);
+                    }
+                }
+                if let (true, Some(feedback_tx)) = (added, &self.feedback_tx) {
+                    if let Err(e) = feedback_tx.send(Feedback::Enrollment) {
+                        log::warn!("error playing feedback: {}", e);
+                    }
+                }
+            }
+            Err(e) => {
+                log::error!("decoding error: {}", e);
+            }
+        }
+    }
+
+    fn process_command_message(&mut self, level: Level, data: &[u8]) {
+        match postcard::from_bytes::<CommandMessage>(data) {
+            Ok(msg) => {
+                log::info!("Command received {:?}", msg);
+                if level < msg.command.level() {
+                    self.alert(format!(
+                        "command {:?} requires {:?} level",
+                        msg.command,
+                        msg.command.level()
+                    ));
+                    return;
+                }
+                if let Err(e) = self.replay_guard.check(msg.counter) {
+                    self.alert(format!("rejected command {:?}: {}", msg.command, e));
+                    return;
+                }
+                if let Err(e) = self.cmd_tx.send((level.into(), msg.command)) {
+                    log::error!("Error dispatching command {}", e);
+                }
+            }
+            Err(e) => {
+                log::error!("decoding error: {}", e);
+                // Most likely a command added after this firmware
+                if let Some(server) =
+                    protocol::server_protocol().filter(|server| *server > PROTOCOL_VERSION)
+                {
+                    self.alert(format!(
+                        "command not understood, backend protocol {} is newer than {}",
+                        server, PROTOCOL_VERSION
+                    ));
+                }
+            }
+        };
+    }
+
+    fn process_broadcast_message(&mut self, level: Level, data: &[u8]) {
+        let msg = match postcard::from_bytes::<BroadcastMessage>(data) {
+            Ok(msg) => msg,
+            Err(e) => {
+                log::error!("decoding error: {}", e);
+                return;
+            }
+        };
+        log::info!("Broadcast received {:?}", msg);
+        let command = Command::from(msg.command);
+        if level < command.level() {
+            self.alert(format!(
+                "broadcast {:?} requires {:?} level",
+                msg.command,
+                command.level()
+            ));
+            return;
+        }
+        if let Err(e) = self.replay_guard.check_broadcast(msg.counter) {
+            self.alert(format!("rejected broadcast {:?}: {}", msg.command, e));
+            return;
+        }
+        let jitter_ms = self.broadcast_jitter.as_millis() as u32;
+        let delay = match jitter_ms {
+            0 => Duration::ZERO,
+            jitter_ms => Duration::from_millis((unsafe { esp_random() } % jitter_ms) as u64),
+        };
+        log::info!("Running broadcast {:?} in {:?}", command, delay);
+        let command = Command::Deferred(Instant::now() + delay, Box::new(command));
+        if let Err(e) = self.cmd_tx.send((level.into(), command)) {
+            log::error!("Error dispatching broadcast {}", e);
+        }
+    }
+
+    /// The lockdown stays until it is cleared by hand, like any other
+    fn process_zone_message(&self, data: &[u8]) {
+        let msg = match postcard::from_bytes::<ZoneLockdown>(data) {
+            Ok(msg) => msg,
+            Err(e) => {
+                log::error!("decoding error: {}", e);
+                return;
             }
+        };
+        if msg.device == self.net_id {
+            return;
+        }
+        log::warn!(
+            "Zone lockdown requested by {} after {:?}",
+            msg.device,
+            msg.trigger
+        );
+        if let Err(e) = self.cmd_tx.send((Origin::Zone, Command::SetLockdown(true))) {
+            log::error!("Error dispatching zone lockdown {}", e);
+        }
+    }
+}
+
+/// Applies the user action returning true if it succeeded
+pub fn process_user_action(
+    action: UserAction,
+    user_db: &UserDB,
+    origin: Origin,
+    management: &ManagementLog,
+) -> bool {
+    let count = match &action {
+        UserAction::Bulk(codes) => codes.len(),
+        _ => 1,
+    };
+    let result = match action {
+        UserAction::Add(code) => {
+            log::info!("Adding code {}", Redacted(code));
+            user_db.add(code)
         }
-        Err(e) => {
-            log::error!("decoding error: {}", e);
+        UserAction::Del(code) => {
+            log::info!("Deleting code {}", Redacted(code));
+            user_db.delete(code)
+        }
+        UserAction::Replace { old, new } => {
+            log::info!("Replacing code {} with {}", Redacted(old), Redacted(new));
+            user_db.replace(old, new)
+        }
+        UserAction::Bulk(codes) => {
+            log::info!("Bulk adding codes {}", codes.len());
+            user_db.bulk(codes)
         }
     };
+    if let Err(e) = &result {
+        log::error!("Error updating users {}", e);
+    }
+    management.record(origin, Change::Users, count, &result);
+    result.is_ok()
 }
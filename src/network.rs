@@ -1,42 +1,77 @@
 use std::ffi::CStr;
 use std::{thread, time::Duration};
 
-use crate::config::DoorsysConfig;
+use crate::config::{DoorsysConfig, ProvisioningConfig};
 
 use esp_idf_svc::eventloop::{EspEventLoop, System};
 use esp_idf_svc::hal::modem::Modem;
 use esp_idf_svc::nvs::{EspNvsPartition, NvsDefault};
 use esp_idf_svc::sntp::EspSntp;
-use esp_idf_svc::sys::CONFIG_LWIP_LOCAL_HOSTNAME;
-use esp_idf_svc::wifi::{BlockingWifi, Configuration, EspWifi, WifiDeviceId};
+use esp_idf_svc::sys::{
+    esp, esp_mac_type_t_ESP_MAC_WIFI_STA, esp_read_mac, esp_restart, CONFIG_LWIP_LOCAL_HOSTNAME,
+};
+use esp_idf_svc::wifi::{BlockingWifi, Configuration, EspWifi};
+use serde::Deserialize;
 
 const RECONNECT_COOLDOWN: Duration = Duration::from_secs(5);
 
+/// What to do when nobody provisions the device before the timeout
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeoutAction {
+    /// Connects with the wifi credentials of the stored configuration,
+    /// restarts when there are none
+    #[default]
+    Retry,
+    Restart,
+    /// Gives up on the network, the door keeps working with the
+    /// credentials already in flash
+    Offline,
+}
+
 /// Setup the wifi and spawns the reconnect thread.
 /// If no previous wifi configuration is found, it will start in
-/// AP mode and launch the configuration server and wait for connections.
+/// AP mode and launch the configuration server and wait for connections,
+/// for as long as the provisioning timeout allows.
+/// Returns false when the device was left offline.
 pub fn setup_wireless(
     modem: Modem,
     sysloop: EspEventLoop<System>,
     nvs_part: EspNvsPartition<NvsDefault>,
     doorsys_config: &mut DoorsysConfig,
-) -> anyhow::Result<String> {
+    provisioning: Option<&ProvisioningConfig>,
+) -> anyhow::Result<bool> {
     let mut wifi = BlockingWifi::wrap(
         EspWifi::new(modem, sysloop.clone(), Some(nvs_part.clone()))?,
         sysloop,
     )?;
 
-    let net_id = create_net_id(&wifi)?;
-    log::info!("Device net_id: {net_id}");
-
     wifi.start()?;
     log::info!("Wifi started");
 
+    let timeout = provisioning.map(|config| Duration::from_secs(config.timeout_minutes * 60));
     if let Ok(Configuration::Client(config)) = wifi.get_configuration() {
         log::info!("Existing wifi config: {:?}", config);
-    } else {
+    } else if !doorsys_config.apply_provisioned(&mut wifi, timeout)? {
         log::warn!("No wifi config found.");
-        doorsys_config.run_config_server(&mut wifi)?;
+        if !doorsys_config.run_config_server(&mut wifi, timeout)? {
+            let action = provisioning
+                .map(|config| config.on_timeout)
+                .unwrap_or_default();
+            log::warn!("Provisioning timed out, {:?}", action);
+            match action {
+                TimeoutAction::Retry => {
+                    if !doorsys_config.connect_stored(&mut wifi)? {
+                        unsafe { esp_restart() };
+                    }
+                }
+                TimeoutAction::Restart => unsafe { esp_restart() },
+                TimeoutAction::Offline => {
+                    wifi.stop()?;
+                    return Ok(false);
+                }
+            }
+        }
     }
 
     connect_wifi_loop(&mut wifi);
@@ -54,7 +89,7 @@ pub fn setup_wireless(
         }
     });
 
-    Ok(net_id)
+    Ok(true)
 }
 
 fn connect_wifi(wifi: &mut BlockingWifi<EspWifi>) -> anyhow::Result<()> {
@@ -81,9 +116,11 @@ fn connect_wifi_loop(wifi: &mut BlockingWifi<EspWifi>) {
 }
 
 /// Creates a unique identifier for this device based on local hostname
-/// plus last 3 octets of the mac address
-fn create_net_id(wifi: &BlockingWifi<EspWifi>) -> anyhow::Result<String> {
-    let mac = wifi.wifi().get_mac(WifiDeviceId::Sta)?;
+/// plus last 3 octets of the mac address, read from the efuses so it is
+/// known before wifi starts
+pub fn create_net_id() -> anyhow::Result<String> {
+    let mut mac = [0u8; 6];
+    esp!(unsafe { esp_read_mac(mac.as_mut_ptr(), esp_mac_type_t_ESP_MAC_WIFI_STA) })?;
     let mac_id = mac
         .iter()
         .skip(3)
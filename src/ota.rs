@@ -0,0 +1,219 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use embedded_svc::http::client::{Client, Response};
+use esp_idf_svc::http::client::{Configuration, EspHttpConnection};
+use esp_idf_svc::io::{Read, Write};
+use esp_idf_svc::ota::{EspOta, EspOtaUpdate, SlotState};
+use esp_idf_svc::sys::{esp_crt_bundle_attach, esp_restart};
+
+use crate::boot::{BootProgress, Stage};
+use crate::crypto::{self, Sha256};
+use crate::heap;
+use crate::management::{Change, ManagementLog, Origin};
+
+/// Stages a new image must reach before it is kept
+const HEALTHY: [Stage; 4] = [Stage::Door, Stage::Reader, Stage::Wifi, Stage::Mqtt];
+const HEALTH_POLL: Duration = Duration::from_secs(5);
+const RESTART_DELAY: Duration = Duration::from_secs(2);
+/// Start of the header of a signed image
+const SIGNED_MAGIC: &[u8; 4] = b"DSFW";
+const MAX_SIGNATURE_SIZE: usize = 512;
+/// Version, size and digest of the image, see [`Manifest`]
+const MANIFEST_SIZE: usize = 42;
+
+/// Signed part of the header, checked before anything is written to flash
+struct Manifest {
+    /// Major, minor and patch, must be newer than the running firmware
+    version: [u16; 3],
+    size: usize,
+    digest: [u8; 32],
+}
+
+impl Manifest {
+    fn parse(bytes: &[u8; MANIFEST_SIZE]) -> Self {
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let mut digest = [0; 32];
+        digest.copy_from_slice(&bytes[10..]);
+        Manifest {
+            version: [u16_at(0), u16_at(2), u16_at(4)],
+            size: u32::from_le_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]) as usize,
+            digest,
+        }
+    }
+}
+
+/// Version of the running firmware, pre-release suffixes are ignored
+fn running_version() -> [u16; 3] {
+    let mut version = [0; 3];
+    let release = crate::built_info::PKG_VERSION
+        .split('-')
+        .next()
+        .unwrap_or("");
+    for (part, number) in version.iter_mut().zip(release.split('.')) {
+        *part = number.parse().unwrap_or(0);
+    }
+    version
+}
+
+fn read_header(
+    response: &mut Response<&mut EspHttpConnection>,
+    buf: &mut [u8],
+) -> anyhow::Result<()> {
+    response
+        .read_exact(buf)
+        .map_err(|e| anyhow::anyhow!("error reading image header: {:?}", e))
+}
+
+/// Reads the header in front of the image: the magic, the signature length
+/// as a little endian u16, the manifest and the signature of its SHA-256.
+/// Returns the manifest once the signature checks and the image is newer.
+fn read_manifest(
+    response: &mut Response<&mut EspHttpConnection>,
+    public_key: &str,
+) -> anyhow::Result<Manifest> {
+    let mut header = [0; 6];
+    read_header(response, &mut header)?;
+    if &header[..4] != SIGNED_MAGIC {
+        anyhow::bail!("image is not signed");
+    }
+    let len = u16::from_le_bytes([header[4], header[5]]) as usize;
+    if len > MAX_SIGNATURE_SIZE {
+        anyhow::bail!("signature of {} bytes too big", len);
+    }
+    let mut manifest = [0; MANIFEST_SIZE];
+    read_header(response, &mut manifest)?;
+    let mut signature = vec![0; len];
+    read_header(response, &mut signature)?;
+    crypto::verify_signature(public_key, &crypto::sha256(&manifest)?, &signature)?;
+    let manifest = Manifest::parse(&manifest);
+    let running = running_version();
+    if manifest.version <= running {
+        anyhow::bail!(
+            "image version {:?} is not newer than {:?}",
+            manifest.version,
+            running
+        );
+    }
+    Ok(manifest)
+}
+
+/// Writes the image to the slot while hashing it, returns the size and digest
+fn copy(
+    response: &mut Response<&mut EspHttpConnection>,
+    update: &mut EspOtaUpdate<'_>,
+) -> anyhow::Result<(usize, [u8; 32])> {
+    let mut hasher = Sha256::new()?;
+    let mut buf = [0; 1024];
+    let mut written = 0;
+    loop {
+        let read = response.read(&mut buf)?;
+        if read == 0 {
+            return Ok((written, hasher.finish()?));
+        }
+        hasher.update(&buf[..read])?;
+        update.write_all(&buf[..read])?;
+        written += read;
+    }
+}
+
+/// Streams the image at the url to the next ota slot, the image never fits
+/// in memory. Nothing is written unless the signed manifest checks with the
+/// public key and is newer, the slot becomes the boot one only if the image
+/// matches the size and digest of the manifest. Returns the size of the image.
+fn install(url: &str, public_key: &str) -> anyhow::Result<usize> {
+    // The image is streamed to flash, only the TLS session takes heap
+    heap::reserve(heap::TLS_SESSION, "firmware download")?;
+    let mut client = Client::wrap(EspHttpConnection::new(&Configuration {
+        crt_bundle_attach: Some(esp_crt_bundle_attach),
+        ..Default::default()
+    })?);
+    let mut response = client.get(url)?.submit()?;
+    let status = response.status();
+    if !(200..300).contains(&status) {
+        anyhow::bail!("http status {}", status);
+    }
+    let manifest = read_manifest(&mut response, public_key)?;
+    log::info!("Installing signed firmware {:?}", manifest.version);
+    let mut ota = EspOta::new()?;
+    let mut update = ota.initiate_update()?;
+    let copied = copy(&mut response, &mut update).and_then(|(written, digest)| {
+        if written != manifest.size || digest != manifest.digest {
+            anyhow::bail!("image does not match its manifest");
+        }
+        Ok(written)
+    });
+    match copied {
+        Ok(written) => {
+            update.complete()?;
+            Ok(written)
+        }
+        Err(e) => {
+            if let Err(e) = update.abort() {
+                log::error!("error aborting firmware update: {}", e);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Downloads and installs the firmware in the background then restarts
+/// into it. The door keeps working from the current image meanwhile.
+/// Without a public key every image is rejected.
+pub fn update(url: String, public_key: Option<String>, origin: Origin, management: ManagementLog) {
+    thread::spawn(move || {
+        log::info!("Updating firmware from {}", url);
+        let result = match &public_key {
+            Some(public_key) => install(&url, public_key),
+            None => Err(anyhow::anyhow!("no public key to verify the image")),
+        };
+        let size = *result.as_ref().unwrap_or(&0);
+        let result = result.map(|_| ());
+        if let Err(e) = &result {
+            log::error!("Error updating firmware {:?}", e);
+        }
+        management.record(origin, Change::Firmware, size, &result);
+        if result.is_ok() {
+            log::warn!("Restarting into the new firmware");
+            thread::sleep(RESTART_DELAY);
+            unsafe { esp_restart() };
+        }
+    });
+}
+
+/// Keeps a new image only once the door, wifi and mqtt are up. Until then
+/// the bootloader rolls back to the previous image if it crashes or
+/// restarts, and after the timeout it is rolled back on purpose. Images
+/// already kept and the factory one are left alone.
+pub fn setup_rollback(boot: BootProgress, timeout: Duration) -> anyhow::Result<()> {
+    let slot = EspOta::new()?.get_running_slot()?;
+    if !matches!(slot.state, SlotState::Unverified) {
+        return Ok(());
+    }
+    log::warn!(
+        "Running new firmware from {}, keeping it once healthy",
+        slot.label
+    );
+    thread::spawn(move || {
+        let started = Instant::now();
+        while !HEALTHY.iter().all(|stage| boot.has_reached(*stage)) {
+            if started.elapsed() > timeout {
+                log::error!("New firmware not healthy, rolling back");
+                match EspOta::new() {
+                    Ok(mut ota) => {
+                        let e = ota.mark_running_slot_invalid_and_reboot();
+                        log::error!("error rolling back firmware: {}", e);
+                    }
+                    Err(e) => log::error!("error rolling back firmware: {}", e),
+                }
+                return;
+            }
+            thread::sleep(HEALTH_POLL);
+        }
+        match EspOta::new().and_then(|mut ota| ota.mark_running_slot_valid()) {
+            Ok(()) => log::info!("New firmware healthy, rollback cancelled"),
+            Err(e) => log::error!("error confirming firmware: {}", e),
+        }
+    });
+    Ok(())
+}
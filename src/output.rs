@@ -0,0 +1,71 @@
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::Duration;
+
+use esp_idf_svc::hal::gpio::{AnyOutputPin, PinDriver};
+
+/// Outputs addressable by a 16 bit mask
+const MAX_OUTPUTS: usize = 16;
+
+/// Spawns a thread that drives `pin` high for `width` every time
+/// a message is received on the returned channel
+pub fn setup_pulse(pin: AnyOutputPin, width: Duration) -> anyhow::Result<Sender<()>> {
+    let mut driver = PinDriver::output(pin)?;
+    driver.set_low()?;
+
+    let (pulse_tx, pulse_rx) = mpsc::channel();
+    thread::spawn(move || {
+        for () in pulse_rx {
+            if let Err(e) = driver.set_high() {
+                log::error!("error setting output: {}", e);
+            }
+            thread::sleep(width);
+            if let Err(e) = driver.set_low() {
+                log::error!("error resetting output: {}", e);
+            }
+        }
+    });
+
+    Ok(pulse_tx)
+}
+
+/// Spawns a thread driving a bank of outputs, such as the floor relays of an
+/// elevator. Every received mask drives high for `width` the outputs whose
+/// bit is set, bit 0 being the first pin. Bits without a pin are ignored.
+pub fn setup_outputs(pins: Vec<AnyOutputPin>, width: Duration) -> anyhow::Result<Sender<u16>> {
+    if pins.len() > MAX_OUTPUTS {
+        log::warn!("only the first {} outputs are driven", MAX_OUTPUTS);
+    }
+    let mut drivers = pins
+        .into_iter()
+        .take(MAX_OUTPUTS)
+        .map(|pin| {
+            let mut driver = PinDriver::output(pin)?;
+            driver.set_low()?;
+            Ok(driver)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let (mask_tx, mask_rx) = mpsc::channel::<u16>();
+    thread::spawn(move || {
+        for mask in mask_rx {
+            for (i, driver) in drivers.iter_mut().enumerate() {
+                if mask & (1 << i) != 0 {
+                    if let Err(e) = driver.set_high() {
+                        log::error!("error setting output {}: {}", i, e);
+                    }
+                }
+            }
+            thread::sleep(width);
+            for (i, driver) in drivers.iter_mut().enumerate() {
+                if mask & (1 << i) != 0 {
+                    if let Err(e) = driver.set_low() {
+                        log::error!("error resetting output {}: {}", i, e);
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(mask_tx)
+}
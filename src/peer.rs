@@ -0,0 +1,200 @@
+use std::net::UdpSocket;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use doorsys_protocol::UserAction;
+use esp_idf_svc::nvs::{EspNvsPartition, NvsDefault};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{self, Authenticator};
+use crate::config::PeerConfig;
+use crate::crypto::{self, Secret};
+use crate::management::{Change, ManagementLog, Origin};
+use crate::mqtt;
+use crate::schedule::{LocalTime, Mode, Scheduler};
+use crate::user::UserDB;
+
+/// Bound to every signature so peer messages can't be replayed as mqtt ones
+const PEER_TOPIC: &str = "doorsys/peer";
+const LOCKDOWN_INTERVAL: Duration = Duration::from_secs(2);
+const MAX_DATAGRAM_SIZE: usize = 1024;
+
+/// Change gossiped to the other controllers on the same network
+#[derive(Serialize, Deserialize, Debug)]
+enum PeerBody {
+    /// Postcard encoded user action, as received on the user topic
+    User(Vec<u8>),
+    Lockdown(bool),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct PeerMessage {
+    origin: String,
+    body: PeerBody,
+}
+
+/// Lockdown state last applied from a peer, changes caused
+/// by peers are not gossiped back to avoid loops
+type PeerLockdown = Arc<Mutex<Option<bool>>>;
+
+/// Starts gossiping user updates and the lockdown state to the peers
+/// over udp so a site stays consistent while the broker is unreachable.
+/// Returns the channel where applied user actions are forwarded.
+pub fn setup_peers(
+    config: &PeerConfig,
+    net_id: &str,
+    nvs_part: EspNvsPartition<NvsDefault>,
+    user_db: UserDB,
+    scheduler: Scheduler,
+    management: ManagementLog,
+) -> anyhow::Result<Sender<Vec<u8>>> {
+    let key = crypto::decode_hex(&config.key)?;
+    let socket = UdpSocket::bind(("0.0.0.0", config.port))?;
+    socket.set_broadcast(true)?;
+    let targets = if config.peers.is_empty() {
+        vec![format!("255.255.255.255:{}", config.port)]
+    } else {
+        config
+            .peers
+            .iter()
+            .map(|peer| format!("{}:{}", peer, config.port))
+            .collect()
+    };
+    let peer_lockdown = PeerLockdown::default();
+
+    let (body_tx, body_rx) = mpsc::channel();
+    let sender = socket.try_clone()?;
+    let origin = net_id.to_owned();
+    let secret = Secret::Key(key.clone());
+    thread::spawn(move || {
+        for body in body_rx {
+            let message = PeerMessage {
+                origin: origin.clone(),
+                body,
+            };
+            let signed = postcard::to_allocvec(&message)
+                .map_err(anyhow::Error::from)
+                .and_then(|payload| auth::sign(&secret, PEER_TOPIC, &payload));
+            match signed {
+                Ok(datagram) => {
+                    for target in &targets {
+                        if let Err(e) = sender.send_to(&datagram, target) {
+                            log::warn!("error sending to peer {}: {}", target, e);
+                        }
+                    }
+                }
+                Err(e) => log::error!("error encoding peer message: {}", e),
+            }
+        }
+    });
+
+    let origin = net_id.to_owned();
+    let auth = Authenticator::new(Some(Secret::Key(key)), None)
+        .with_floor(nvs_part, auth::PEER_FLOOR_KEY)?;
+    let lockdown = peer_lockdown.clone();
+    let peer_scheduler = scheduler.clone();
+    thread::spawn(move || {
+        receive(
+            socket,
+            auth,
+            &origin,
+            &user_db,
+            &peer_scheduler,
+            &lockdown,
+            &management,
+        )
+    });
+
+    let lockdown_tx = body_tx.clone();
+    thread::spawn(move || {
+        let mut current = scheduler.mode() == Mode::LockedDown;
+        loop {
+            thread::sleep(LOCKDOWN_INTERVAL);
+            let locked_down = scheduler.mode() == Mode::LockedDown;
+            if locked_down == current {
+                continue;
+            }
+            current = locked_down;
+            if *peer_lockdown.lock().unwrap() == Some(locked_down) {
+                continue;
+            }
+            if let Err(e) = lockdown_tx.send(PeerBody::Lockdown(locked_down)) {
+                log::error!("error sending lockdown to peers: {}", e);
+            }
+        }
+    });
+
+    let (user_tx, user_rx) = mpsc::channel::<Vec<u8>>();
+    thread::spawn(move || {
+        for payload in user_rx {
+            if let Err(e) = body_tx.send(PeerBody::User(payload)) {
+                log::error!("error sending user action to peers: {}", e);
+            }
+        }
+    });
+
+    Ok(user_tx)
+}
+
+fn receive(
+    socket: UdpSocket,
+    mut auth: Authenticator,
+    origin: &str,
+    user_db: &UserDB,
+    scheduler: &Scheduler,
+    peer_lockdown: &PeerLockdown,
+    management: &ManagementLog,
+) {
+    let mut buf = [0; MAX_DATAGRAM_SIZE];
+    loop {
+        let (len, addr) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) => {
+                log::warn!("peer receive error: {}", e);
+                continue;
+            }
+        };
+        // Replays can only be told apart by their timestamp, which
+        // means nothing until sntp has set the clock
+        if LocalTime::now().is_none() {
+            log::warn!("ignoring peer message from {}, clock not set", addr);
+            continue;
+        }
+        let payload = match auth.verify(PEER_TOPIC, &buf[..len]) {
+            Ok((_, payload)) => payload,
+            Err(e) => {
+                log::warn!("rejected peer message from {}: {}", addr, e);
+                continue;
+            }
+        };
+        let message: PeerMessage = match postcard::from_bytes(payload) {
+            Ok(message) => message,
+            Err(e) => {
+                log::warn!("error decoding peer message from {}: {}", addr, e);
+                continue;
+            }
+        };
+        // Broadcasts are received by the sender too
+        if message.origin == origin {
+            continue;
+        }
+        log::info!("Peer message from {}: {:?}", message.origin, message.body);
+        match message.body {
+            PeerBody::User(data) => match postcard::from_bytes::<UserAction>(&data) {
+                Ok(action) => {
+                    mqtt::process_user_action(action, user_db, Origin::Peer, management);
+                }
+                Err(e) => log::warn!("error decoding peer user action: {}", e),
+            },
+            PeerBody::Lockdown(lockdown) => {
+                *peer_lockdown.lock().unwrap() = Some(lockdown);
+                if scheduler.set_lockdown(lockdown) {
+                    let count = usize::from(lockdown);
+                    management.record(Origin::Peer, Change::Lockdown, count, &Ok(()));
+                }
+            }
+        }
+    }
+}
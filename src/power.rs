@@ -0,0 +1,76 @@
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+use esp_idf_svc::sys::{
+    esp, esp_pm_config_t, esp_pm_configure, esp_wifi_set_ps, wifi_ps_type_t_WIFI_PS_MAX_MODEM,
+    wifi_ps_type_t_WIFI_PS_MIN_MODEM,
+};
+
+use crate::config::PowerConfig;
+use crate::door::Latency;
+
+const NORMAL_CPU_MHZ: i32 = 160;
+/// The cpu is scaled down to this while idle in the low power profile
+const IDLE_CPU_MHZ: i32 = 40;
+
+static LOW_POWER: AtomicBool = AtomicBool::new(false);
+static LOW_POWER_HEALTH_SECS: AtomicU64 = AtomicU64::new(0);
+
+/// Switches between the normal and the low power profile
+/// for installs running on batteries
+#[derive(Clone)]
+pub struct Power {
+    cpu_mhz: i32,
+}
+
+impl Power {
+    pub fn new(config: &PowerConfig) -> Self {
+        LOW_POWER_HEALTH_SECS.store(config.health_minutes.max(1) * 60, Ordering::Relaxed);
+        Power {
+            cpu_mhz: config.cpu_mhz.clamp(IDLE_CPU_MHZ, NORMAL_CPU_MHZ),
+        }
+    }
+
+    /// Applies the cpu frequency and the wifi power save of the profile.
+    /// The latency figures restart so they only cover the new profile.
+    pub fn set(&self, low_power: bool) -> anyhow::Result<()> {
+        let (max_freq_mhz, min_freq_mhz, ps) = if low_power {
+            (self.cpu_mhz, IDLE_CPU_MHZ, wifi_ps_type_t_WIFI_PS_MAX_MODEM)
+        } else {
+            (
+                NORMAL_CPU_MHZ,
+                NORMAL_CPU_MHZ,
+                wifi_ps_type_t_WIFI_PS_MIN_MODEM,
+            )
+        };
+        let config = esp_pm_config_t {
+            max_freq_mhz,
+            min_freq_mhz,
+            // Light sleep would delay the reader interrupts
+            light_sleep_enable: false,
+        };
+        esp!(unsafe { esp_pm_configure(&config as *const esp_pm_config_t as *const c_void) })?;
+        if LOW_POWER.swap(low_power, Ordering::Relaxed) != low_power {
+            log::info!("Low power profile {}", low_power);
+            Latency::take();
+        }
+        esp!(unsafe { esp_wifi_set_ps(ps) })?;
+        Ok(())
+    }
+}
+
+pub fn low_power() -> bool {
+    LOW_POWER.load(Ordering::Relaxed)
+}
+
+/// Health report interval stretched by the low power profile
+pub fn health_interval(interval: Duration) -> Duration {
+    if low_power() {
+        interval.max(Duration::from_secs(
+            LOW_POWER_HEALTH_SECS.load(Ordering::Relaxed),
+        ))
+    } else {
+        interval
+    }
+}
@@ -0,0 +1,68 @@
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+/// Revision of the messages exchanged with the backend, bumped whenever one
+/// changes in a way older backends can't decode.
+/// 1: bare doorsys-protocol audits on doorsys/audit/{device_id}
+/// 2: audits chained in audit records
+/// 3: audit records tagged with the door and remote opens addressing it
+/// 4: gaps in the audit records marked with their own action
+pub const PROTOCOL_VERSION: u16 = 4;
+
+/// Protocol announced by the backend, zero until it does
+static SERVER_PROTOCOL: AtomicU16 = AtomicU16::new(0);
+
+/// Published retained to doorsys/birth/{device_id} on every connection so
+/// the backend knows what the device speaks before sending anything
+#[derive(Serialize, Debug)]
+pub struct Birth {
+    pub protocol: u16,
+    pub firmware: &'static str,
+    pub timestamp: SystemTime,
+}
+
+impl Birth {
+    pub fn now() -> Self {
+        Birth {
+            protocol: PROTOCOL_VERSION,
+            firmware: crate::built_info::PKG_VERSION,
+            timestamp: SystemTime::now(),
+        }
+    }
+}
+
+/// Published retained to doorsys/server by the backend
+#[derive(Deserialize, Debug)]
+pub struct ServerCapabilities {
+    pub protocol: u16,
+}
+
+pub fn set_server_protocol(protocol: u16) {
+    let previous = SERVER_PROTOCOL.swap(protocol, Ordering::Relaxed);
+    if previous != protocol {
+        log::info!(
+            "Backend speaks protocol {}, device {}",
+            protocol,
+            PROTOCOL_VERSION
+        );
+    }
+}
+
+/// Protocol both ends understand, the device one until the backend announces
+/// its own. Backends that never announce are assumed to be up to date.
+pub fn negotiated() -> u16 {
+    match SERVER_PROTOCOL.load(Ordering::Relaxed) {
+        0 => PROTOCOL_VERSION,
+        server => server.min(PROTOCOL_VERSION),
+    }
+}
+
+/// Protocol announced by the backend, if any
+pub fn server_protocol() -> Option<u16> {
+    match SERVER_PROTOCOL.load(Ordering::Relaxed) {
+        0 => None,
+        server => Some(server),
+    }
+}
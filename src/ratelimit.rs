@@ -0,0 +1,64 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::alert::{Alert, Category, Severity};
+use crate::config::RemoteOpenConfig;
+use crate::mqtt::Outbox;
+
+/// Caps the remote opens within a sliding window, so a misbehaving or
+/// compromised backend can't keep pulsing the relay
+pub struct OpenLimit {
+    max_opens: usize,
+    window: Duration,
+    opens: VecDeque<Instant>,
+    /// Set once the excess is alerted, cleared when opens are allowed again
+    alerted: bool,
+    alert_tx: Outbox<Alert>,
+}
+
+impl OpenLimit {
+    pub fn new(config: &RemoteOpenConfig, alert_tx: Outbox<Alert>) -> Self {
+        OpenLimit {
+            max_opens: config.max_opens,
+            window: Duration::from_secs(config.window_secs),
+            opens: VecDeque::with_capacity(config.max_opens),
+            alerted: false,
+            alert_tx,
+        }
+    }
+
+    /// True when the open may go ahead, the excess is
+    /// alerted once for every burst of rejections
+    pub fn allow(&mut self) -> bool {
+        let now = Instant::now();
+        while self
+            .opens
+            .front()
+            .is_some_and(|open| now.duration_since(*open) > self.window)
+        {
+            self.opens.pop_front();
+        }
+        if self.opens.len() < self.max_opens {
+            self.opens.push_back(now);
+            self.alerted = false;
+            return true;
+        }
+
+        log::warn!("Remote open rejected, rate limit reached");
+        if !self.alerted {
+            self.alerted = true;
+            let detail = format!(
+                "more than {} remote opens in {}s, rejecting until it slows down",
+                self.max_opens,
+                self.window.as_secs()
+            );
+            if let Err(e) =
+                self.alert_tx
+                    .send(Alert::new(Severity::Critical, Category::Security, detail))
+            {
+                log::error!("error sending alert: {}", e);
+            }
+        }
+        false
+    }
+}
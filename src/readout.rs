@@ -0,0 +1,60 @@
+use std::mem;
+
+use esp_idf_svc::sys::{esp_err_t, esp_wifi_sta_get_ap_info, wifi_ap_record_t, ESP_OK};
+
+use crate::feedback::FeedbackPattern;
+use crate::stale::Uplink;
+use crate::user::UserDB;
+
+const CONNECTED: &[u16] = &[800];
+const DISCONNECTED: &[u16] = &[100, 100, 100, 100, 100];
+const DIGIT: &[u16] = &[150];
+const DIGIT_GAP: u16 = 250;
+/// Silence between the groups of the readout
+const GROUP_GAP: u16 = 1200;
+
+/// Status read out on the keypad buzzer so a guard can tell a network
+/// problem from a door problem without any tools
+#[derive(Debug, Clone, Copy)]
+pub struct Readout {
+    pub wifi: bool,
+    pub mqtt: bool,
+    pub users: usize,
+}
+
+impl Readout {
+    pub fn take(user_db: &UserDB, uplink: &Uplink) -> Self {
+        let mut ap_info: wifi_ap_record_t = unsafe { mem::zeroed() };
+        // Only succeeds while associated to an access point
+        let wifi = unsafe { esp_wifi_sta_get_ap_info(&mut ap_info) } == ESP_OK as esp_err_t;
+        Readout {
+            wifi,
+            mqtt: uplink.connected(),
+            users: user_db.count(),
+        }
+    }
+
+    /// Digits in the user count, 0 for an empty database
+    fn magnitude(&self) -> usize {
+        self.users
+            .checked_ilog10()
+            .map_or(0, |log| log as usize + 1)
+    }
+
+    /// Wifi then mqtt, a long beep when connected and three short ones when
+    /// not, then a short beep per digit of the user count, e.g. three for
+    /// hundreds of users and none for an empty database
+    pub fn pattern(&self) -> FeedbackPattern {
+        let mut durations = Vec::new();
+        for connected in [self.wifi, self.mqtt] {
+            let group = if connected { CONNECTED } else { DISCONNECTED };
+            durations.extend_from_slice(group);
+            durations.push(GROUP_GAP);
+        }
+        for _ in 0..self.magnitude() {
+            durations.extend_from_slice(DIGIT);
+            durations.push(DIGIT_GAP);
+        }
+        FeedbackPattern(durations)
+    }
+}
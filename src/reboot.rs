@@ -0,0 +1,68 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::sys::esp_restart;
+
+use crate::audit::AuditQueue;
+use crate::config::RebootConfig;
+use crate::door::{DoorState, DoorStatus};
+use crate::schedule::{LocalTime, Mode, Scheduler};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const FLUSH_POLL: Duration = Duration::from_secs(1);
+/// Time given to the mqtt client to send the last audits
+const RESTART_DELAY: Duration = Duration::from_secs(2);
+
+/// Spawns the thread rebooting the device once in every maintenance window.
+/// The reboot waits while the door is unlocked and is skipped if it stays
+/// unlocked for the whole window. Pending audits are flushed first.
+pub fn setup_managed_reboot(
+    config: &RebootConfig,
+    door_status: DoorStatus,
+    scheduler: Scheduler,
+    audit_queue: AuditQueue,
+) {
+    let days = config.days;
+    let start = config.minute;
+    let window = config.window_minutes;
+    let flush_timeout = Duration::from_secs(config.flush_secs);
+    let booted = Instant::now();
+    thread::spawn(move || {
+        let mut waiting = false;
+        loop {
+            thread::sleep(CHECK_INTERVAL);
+            let Some(now) = LocalTime::now() else {
+                continue;
+            };
+            let elapsed = (now.minutes + 24 * 60 - start) % (24 * 60);
+            let in_window = days & (1 << now.weekday) != 0 && elapsed < window;
+            // Started within the window, most likely by this reboot
+            let rebooted = booted.elapsed() < Duration::from_secs(window as u64 * 60);
+            if !in_window || rebooted {
+                if waiting {
+                    log::warn!("Managed reboot skipped, the door stayed unlocked");
+                    waiting = false;
+                }
+                continue;
+            }
+            if door_status.state() == DoorState::Unlocked || scheduler.mode() == Mode::Unlocked {
+                if !waiting {
+                    log::info!("Managed reboot waiting for the door to lock");
+                    waiting = true;
+                }
+                continue;
+            }
+
+            log::warn!("Managed reboot, flushing the pending audits");
+            let flush_started = Instant::now();
+            while !audit_queue.is_empty() && flush_started.elapsed() < flush_timeout {
+                thread::sleep(FLUSH_POLL);
+            }
+            if !audit_queue.is_empty() {
+                log::warn!("Rebooting with audits still queued");
+            }
+            thread::sleep(RESTART_DELAY);
+            unsafe { esp_restart() };
+        }
+    });
+}
@@ -0,0 +1,27 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use crate::config::Settings;
+
+/// Internal bus announcing new settings to the running subsystems. Each one
+/// applies the sections it owns without a restart, so the reader and the
+/// mqtt session are kept. Sections read only at boot take effect on the
+/// next restart.
+#[derive(Clone, Default)]
+pub struct SettingsBus(Arc<Mutex<Vec<Sender<Arc<Settings>>>>>);
+
+impl SettingsBus {
+    pub fn subscribe(&self) -> Receiver<Arc<Settings>> {
+        let (tx, rx) = mpsc::channel();
+        self.0.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Hands the settings to every subscriber, the ones gone are dropped
+    pub fn publish(&self, settings: Settings) {
+        let settings = Arc::new(settings);
+        let mut subscribers = self.0.lock().unwrap();
+        subscribers.retain(|tx| tx.send(settings.clone()).is_ok());
+        log::info!("New settings sent to {} subsystems", subscribers.len());
+    }
+}
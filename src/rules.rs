@@ -0,0 +1,94 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+use serde::{Deserialize, Serialize};
+
+use crate::schedule::{Holidays, LocalTime, TimeWindow};
+use crate::storage::{self, Area};
+
+const NVS_KEY: &str = "rules";
+
+/// Credentials that must be presented to open the door
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Requirement {
+    /// Either a valid card or a valid pin
+    Any,
+    CardOnly,
+    PinOnly,
+    /// A valid card followed by a valid pin
+    CardAndPin,
+}
+
+impl Requirement {
+    /// Requirement that satisfies both, a card and a pin when they differ
+    fn stricter(self, other: Requirement) -> Requirement {
+        match (self, other) {
+            (Requirement::Any, other) => other,
+            (requirement, Requirement::Any) => requirement,
+            (a, b) if a == b => a,
+            _ => Requirement::CardAndPin,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Rule {
+    pub window: TimeWindow,
+    pub requirement: Requirement,
+}
+
+/// Set of rules evaluated at decision time. The first rule whose window
+/// matches the current local time decides which credentials are required.
+/// If no rule matches any valid credential is accepted. While the clock is
+/// not synchronized the strictest of the rules applies so a two factor door
+/// never falls back to a single credential.
+#[derive(Clone)]
+pub struct Rules(Arc<Mutex<RulesData>>);
+
+struct RulesData {
+    nvs: EspNvs<NvsDefault>,
+    rules: Vec<Rule>,
+    holidays: Holidays,
+}
+
+impl Rules {
+    pub fn new(nvs_part: EspNvsPartition<NvsDefault>, holidays: Holidays) -> anyhow::Result<Self> {
+        let nvs = EspNvs::new(nvs_part, "doorsys", true)?;
+        let blob_size = nvs.blob_len(NVS_KEY)?.unwrap_or(0);
+        let mut buf = vec![0; blob_size];
+        let rules = match nvs.get_raw(NVS_KEY, &mut buf)? {
+            Some(slice) => postcard::from_bytes(slice).context("error decoding rules")?,
+            None => Vec::new(),
+        };
+        log::info!("Loaded {} access rules", rules.len());
+        Ok(Rules(Arc::new(Mutex::new(RulesData {
+            nvs,
+            rules,
+            holidays,
+        }))))
+    }
+
+    /// Replaces the current rules and persists them to flash
+    pub fn set(&self, rules: Vec<Rule>) -> anyhow::Result<()> {
+        let mut data = self.0.lock().unwrap();
+        let buf = postcard::to_allocvec(&rules).context("encoding failure")?;
+        data.nvs.set_raw(NVS_KEY, &buf).context("nvs failure")?;
+        storage::record_write(Area::Config, buf.len());
+        data.rules = rules;
+        Ok(())
+    }
+
+    pub fn requirement(&self) -> Requirement {
+        let data = self.0.lock().unwrap();
+        let Some(now) = LocalTime::now() else {
+            return data.rules.iter().fold(Requirement::Any, |strictest, rule| {
+                strictest.stricter(rule.requirement)
+            });
+        };
+        data.rules
+            .iter()
+            .find(|rule| rule.window.contains(&now, &data.holidays))
+            .map_or(Requirement::Any, |rule| rule.requirement)
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::alert::{Alert, Category, Severity};
+use crate::config::ScanGuardConfig;
+use crate::mqtt::Outbox;
+
+/// Watches the rate of unknown cards to detect someone emulating
+/// wiegand to brute force card numbers
+pub struct ScanGuard {
+    max_denials: usize,
+    window: Duration,
+    lockout: Duration,
+    denials: VecDeque<Instant>,
+    /// End of the window where audits are flagged as suspicious
+    suspicious_until: Option<Instant>,
+    locked_until: Option<Instant>,
+    alert_tx: Outbox<Alert>,
+}
+
+impl ScanGuard {
+    pub fn new(config: &ScanGuardConfig, alert_tx: Outbox<Alert>) -> Self {
+        ScanGuard {
+            max_denials: config.max_denials,
+            window: Duration::from_secs(config.window_secs),
+            lockout: Duration::from_secs(config.lockout_secs),
+            denials: VecDeque::with_capacity(config.max_denials),
+            suspicious_until: None,
+            locked_until: None,
+            alert_tx,
+        }
+    }
+
+    /// Records a card that is not in the database
+    pub fn denied(&mut self) {
+        let now = Instant::now();
+        while self
+            .denials
+            .front()
+            .is_some_and(|denial| now.duration_since(*denial) > self.window)
+        {
+            self.denials.pop_front();
+        }
+        self.denials.push_back(now);
+        if self.denials.len() < self.max_denials {
+            return;
+        }
+
+        let suspicious = self.suspicious();
+        self.suspicious_until = Some(now + self.window.max(self.lockout));
+        if suspicious {
+            return;
+        }
+        let mut detail = format!(
+            "{} unknown cards in {}s",
+            self.denials.len(),
+            self.window.as_secs()
+        );
+        if !self.lockout.is_zero() {
+            self.locked_until = Some(now + self.lockout);
+            detail.push_str(&format!(", reader locked for {}s", self.lockout.as_secs()));
+        }
+        if let Err(e) =
+            self.alert_tx
+                .send(Alert::new(Severity::Critical, Category::Security, detail))
+        {
+            log::error!("error sending alert: {}", e);
+        }
+    }
+
+    /// True while a scan is being detected
+    pub fn suspicious(&self) -> bool {
+        self.suspicious_until
+            .is_some_and(|deadline| Instant::now() < deadline)
+    }
+
+    /// True while cards must be ignored
+    pub fn locked(&self) -> bool {
+        self.locked_until
+            .is_some_and(|deadline| Instant::now() < deadline)
+    }
+}
@@ -0,0 +1,493 @@
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+use esp_idf_svc::sys::{localtime_r, time_t, tzset};
+use serde::{Deserialize, Serialize};
+
+use crate::door::DoorMode;
+use crate::storage::{self, Area};
+
+const HOLIDAYS_KEY: &str = "holidays";
+const HOLIDAY_BIT: u8 = 1 << 7;
+
+/// Anything before this year means sntp has not synchronized the clock yet
+const MIN_VALID_YEAR: i32 = 2024;
+
+/// Sets the POSIX timezone used to convert the system time to local time
+/// e.g. "EST5EDT,M3.2.0,M11.1.0"
+pub fn set_timezone(tz: &str) {
+    std::env::set_var("TZ", tz);
+    unsafe { tzset() };
+}
+
+/// Broken down local time used to evaluate schedules
+#[derive(Debug, Clone, Copy)]
+pub struct LocalTime {
+    pub year: i32,
+    pub month: u8,
+    pub day: u8,
+    /// Days since sunday
+    pub weekday: u8,
+    /// Minutes since midnight
+    pub minutes: u16,
+}
+
+impl LocalTime {
+    /// Returns the current local time or None if the clock is not synchronized
+    pub fn now() -> Option<Self> {
+        Self::from_system_time(SystemTime::now())
+    }
+
+    pub fn from_system_time(time: SystemTime) -> Option<Self> {
+        let secs = time.duration_since(UNIX_EPOCH).ok()?.as_secs() as time_t;
+        let tm = unsafe {
+            let mut tm = mem::MaybeUninit::uninit();
+            if localtime_r(&secs, tm.as_mut_ptr()).is_null() {
+                return None;
+            }
+            tm.assume_init()
+        };
+        let year = tm.tm_year + 1900;
+        if year < MIN_VALID_YEAR {
+            return None;
+        }
+        Some(LocalTime {
+            year,
+            month: tm.tm_mon as u8 + 1,
+            day: tm.tm_mday as u8,
+            weekday: tm.tm_wday as u8,
+            minutes: (tm.tm_hour * 60 + tm.tm_min) as u16,
+        })
+    }
+
+    /// Year, month and day
+    pub fn date(&self) -> (i32, u8, u8) {
+        (self.year, self.month, self.day)
+    }
+
+    /// Last minute of the previous day
+    fn day_before(&self) -> LocalTime {
+        let (year, month, day) = match (self.month, self.day) {
+            (1, 1) => (self.year - 1, 12, 31),
+            (month, 1) => (self.year, month - 1, days_in_month(self.year, month - 1)),
+            (month, day) => (self.year, month, day - 1),
+        };
+        LocalTime {
+            year,
+            month,
+            day,
+            weekday: (self.weekday + 6) % 7,
+            minutes: 24 * 60 - 1,
+        }
+    }
+}
+
+/// Weekly recurring window of time.
+/// Days is a bit mask where bit 0 is sunday and bit 6 is saturday.
+/// Bit 7 selects holidays, on a holiday only windows with this bit
+/// set match regardless of the day of the week.
+/// Start and end are minutes since midnight, windows where end is
+/// before start wrap around midnight and belong to the day they start.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TimeWindow {
+    pub days: u8,
+    pub start: u16,
+    pub end: u16,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, now: &LocalTime, holidays: &Holidays) -> bool {
+        let (inside, day) = if self.start <= self.end {
+            let inside = self.start <= now.minutes && now.minutes < self.end;
+            (inside, *now)
+        } else if now.minutes >= self.start {
+            (true, *now)
+        } else {
+            // After midnight the window started the day before,
+            // it is a holiday window if that day was one
+            (now.minutes < self.end, now.day_before())
+        };
+        if !inside {
+            return false;
+        }
+        let day_bit = if holidays.holiday(&day).is_some() {
+            HOLIDAY_BIT
+        } else {
+            1 << day.weekday
+        };
+        self.days & day_bit != 0
+    }
+
+    /// True after midnight in a window that started the day before
+    fn past_midnight(&self, now: &LocalTime) -> bool {
+        self.start > self.end && now.minutes < self.end
+    }
+}
+
+fn days_in_month(year: i32, month: u8) -> u8 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// When a holiday happens
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Recurrence {
+    /// A single date
+    Date { year: i32, month: u8, day: u8 },
+    /// Same day every year
+    Yearly { month: u8, day: u8 },
+    /// Nth weekday of the month every year, 5 means the last one
+    /// e.g. the first monday of september
+    NthWeekday { month: u8, weekday: u8, nth: u8 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Holiday {
+    pub name: String,
+    pub recurrence: Recurrence,
+}
+
+impl Holiday {
+    fn matches(&self, now: &LocalTime) -> bool {
+        match self.recurrence {
+            Recurrence::Date { year, month, day } => {
+                now.year == year && now.month == month && now.day == day
+            }
+            Recurrence::Yearly { month, day } => now.month == month && now.day == day,
+            Recurrence::NthWeekday {
+                month,
+                weekday,
+                nth,
+            } => {
+                let last = now.day + 7 > days_in_month(now.year, now.month);
+                now.month == month
+                    && now.weekday == weekday
+                    && ((now.day - 1) / 7 + 1 == nth || (nth == 5 && last))
+            }
+        }
+    }
+}
+
+/// Holiday calendar persisted in nvs and consulted by the schedules
+#[derive(Clone)]
+pub struct Holidays(Arc<Mutex<HolidaysData>>);
+
+struct HolidaysData {
+    nvs: EspNvs<NvsDefault>,
+    holidays: Vec<Holiday>,
+}
+
+impl Holidays {
+    pub fn new(nvs_part: EspNvsPartition<NvsDefault>) -> anyhow::Result<Self> {
+        let nvs = EspNvs::new(nvs_part, "doorsys", true)?;
+        let blob_size = nvs.blob_len(HOLIDAYS_KEY)?.unwrap_or(0);
+        let mut buf = vec![0; blob_size];
+        let holidays = match nvs.get_raw(HOLIDAYS_KEY, &mut buf)? {
+            Some(slice) => postcard::from_bytes(slice).context("error decoding holidays")?,
+            None => Vec::new(),
+        };
+        log::info!("Loaded {} holidays", holidays.len());
+        Ok(Holidays(Arc::new(Mutex::new(HolidaysData {
+            nvs,
+            holidays,
+        }))))
+    }
+
+    /// Replaces the holiday calendar and persists it to flash
+    pub fn set(&self, holidays: Vec<Holiday>) -> anyhow::Result<()> {
+        let mut data = self.0.lock().unwrap();
+        let buf = postcard::to_allocvec(&holidays).context("encoding failure")?;
+        data.nvs
+            .set_raw(HOLIDAYS_KEY, &buf)
+            .context("nvs failure")?;
+        storage::record_write(Area::Config, buf.len());
+        data.holidays = holidays;
+        Ok(())
+    }
+
+    /// Returns the name of the holiday happening today if any
+    pub fn holiday(&self, now: &LocalTime) -> Option<String> {
+        let data = self.0.lock().unwrap();
+        data.holidays
+            .iter()
+            .find(|holiday| holiday.matches(now))
+            .map(|holiday| holiday.name.clone())
+    }
+}
+
+const UNLOCK_KEY: &str = "unlock";
+const STATE_KEY: &str = "op_state";
+const ARRIVED_KEY: &str = "arrived_on";
+
+/// Operating mode derived from the schedule and overrides
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Door unlocks with valid credentials
+    Normal,
+    /// Door is kept unlocked
+    Unlocked,
+    /// Every credential is rejected
+    LockedDown,
+}
+
+/// One off window that takes precedence over the normal schedule
+/// e.g. unlocked from 18:00 to 21:00 for an event
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Override {
+    pub mode: Mode,
+    pub start: SystemTime,
+    pub end: SystemTime,
+}
+
+/// Runtime state kept across reboots
+#[derive(Serialize, Deserialize, Default)]
+struct OperationalState {
+    lockdown: bool,
+    overrides: Vec<Override>,
+    held_open: bool,
+}
+
+/// Layout written before the hold was kept, still read so an update
+/// doesn't lift a lockdown
+#[derive(Deserialize)]
+struct LegacyState {
+    lockdown: bool,
+    overrides: Vec<Override>,
+}
+
+impl From<LegacyState> for OperationalState {
+    fn from(state: LegacyState) -> Self {
+        OperationalState {
+            lockdown: state.lockdown,
+            overrides: state.overrides,
+            held_open: false,
+        }
+    }
+}
+
+/// Auto unlock schedule plus temporary overrides layered on top of it.
+/// The unlock windows, the overrides, the lockdown and the hold are persisted
+/// in nvs so a reboot doesn't lift them, overrides still expire on their own.
+#[derive(Clone)]
+pub struct Scheduler(Arc<Mutex<SchedulerData>>);
+
+struct SchedulerData {
+    nvs: EspNvs<NvsDefault>,
+    unlock_windows: Vec<TimeWindow>,
+    overrides: Vec<Override>,
+    lockdown: bool,
+    held_open: bool,
+    holidays: Holidays,
+    /// Unlock windows only apply once a valid credential was presented
+    /// the same day
+    first_card_in: bool,
+    /// Day the first credential was presented, kept across reboots
+    arrived_on: Option<(i32, u8, u8)>,
+}
+
+impl Scheduler {
+    pub fn new(nvs_part: EspNvsPartition<NvsDefault>, holidays: Holidays) -> anyhow::Result<Self> {
+        let nvs = EspNvs::new(nvs_part, "doorsys", true)?;
+        let blob_size = nvs.blob_len(UNLOCK_KEY)?.unwrap_or(0);
+        let mut buf = vec![0; blob_size];
+        let unlock_windows = match nvs.get_raw(UNLOCK_KEY, &mut buf)? {
+            Some(slice) => postcard::from_bytes(slice).context("error decoding unlock schedule")?,
+            None => Vec::new(),
+        };
+        log::info!("Loaded {} unlock windows", unlock_windows.len());
+        let blob_size = nvs.blob_len(STATE_KEY)?.unwrap_or(0);
+        let mut buf = vec![0; blob_size];
+        let state = match nvs.get_raw(STATE_KEY, &mut buf)? {
+            Some(slice) => postcard::from_bytes(slice)
+                .or_else(|_| postcard::from_bytes::<LegacyState>(slice).map(Into::into))
+                .unwrap_or_else(|e| {
+                    log::error!("error decoding operational state: {}", e);
+                    OperationalState::default()
+                }),
+            None => OperationalState::default(),
+        };
+        log::info!(
+            "Restored lockdown {}, hold {} and {} overrides",
+            state.lockdown,
+            state.held_open,
+            state.overrides.len()
+        );
+        let mut buf = [0; 16];
+        let arrived_on = match nvs.get_raw(ARRIVED_KEY, &mut buf)? {
+            Some(slice) => postcard::from_bytes(slice).ok(),
+            None => None,
+        };
+        Ok(Scheduler(Arc::new(Mutex::new(SchedulerData {
+            nvs,
+            unlock_windows,
+            overrides: state.overrides,
+            lockdown: state.lockdown,
+            held_open: state.held_open,
+            holidays,
+            first_card_in: false,
+            arrived_on,
+        }))))
+    }
+
+    /// Holds the unlock windows back every day until someone with a valid
+    /// credential arrives, so the building stays locked on snow days
+    pub fn with_first_card_in(self) -> Self {
+        self.0.lock().unwrap().first_card_in = true;
+        self
+    }
+
+    /// Records a valid credential, releasing the unlock windows of the day
+    pub fn credential_presented(&self) {
+        let mut data = self.0.lock().unwrap();
+        if !data.first_card_in {
+            return;
+        }
+        let Some(local) = LocalTime::now() else {
+            return;
+        };
+        let today = local.date();
+        if data.arrived_on == Some(today) {
+            return;
+        }
+        log::info!("First credential of the day, releasing the unlock windows");
+        data.arrived_on = Some(today);
+        // A reboot later in the day keeps the windows released
+        let result = postcard::to_allocvec(&today)
+            .context("encoding failure")
+            .and_then(|buf| {
+                data.nvs.set_raw(ARRIVED_KEY, &buf).context("nvs failure")?;
+                storage::record_write(Area::Config, buf.len());
+                Ok(())
+            });
+        if let Err(e) = result {
+            log::error!("error persisting the first credential: {:?}", e);
+        }
+    }
+
+    /// Replaces the auto unlock schedule and persists it to flash
+    pub fn set_unlock_windows(&self, windows: Vec<TimeWindow>) -> anyhow::Result<()> {
+        let mut data = self.0.lock().unwrap();
+        let buf = postcard::to_allocvec(&windows).context("encoding failure")?;
+        data.nvs.set_raw(UNLOCK_KEY, &buf).context("nvs failure")?;
+        storage::record_write(Area::Config, buf.len());
+        data.unlock_windows = windows;
+        Ok(())
+    }
+
+    pub fn add_override(&self, window: Override) {
+        let mut data = self.0.lock().unwrap();
+        let now = SystemTime::now();
+        data.overrides.retain(|o| o.end > now);
+        data.overrides.push(window);
+        data.persist();
+    }
+
+    pub fn clear_overrides(&self) {
+        let mut data = self.0.lock().unwrap();
+        if !data.overrides.is_empty() {
+            data.overrides.clear();
+            data.persist();
+        }
+    }
+
+    /// Locks the door down until released, regardless of overrides.
+    /// Returns true if the lockdown state changed.
+    pub fn set_lockdown(&self, lockdown: bool) -> bool {
+        let mut data = self.0.lock().unwrap();
+        // Avoids a flash write when the twin applies the same state again
+        if data.lockdown == lockdown {
+            return false;
+        }
+        data.lockdown = lockdown;
+        data.persist();
+        true
+    }
+
+    /// Sets the door mode until changed again, normal lifts the lockdown
+    /// and the hold and leaves the door to the schedule.
+    /// Returns true if the mode changed.
+    pub fn set_door_mode(&self, mode: DoorMode) -> bool {
+        let mut data = self.0.lock().unwrap();
+        let lockdown = mode == DoorMode::LockedDown;
+        let held_open = mode == DoorMode::HeldOpen;
+        // Avoids a flash write when the same mode is sent again
+        if data.lockdown == lockdown && data.held_open == held_open {
+            return false;
+        }
+        data.lockdown = lockdown;
+        data.held_open = held_open;
+        data.persist();
+        true
+    }
+
+    /// Evaluates the current mode. A lockdown takes precedence over
+    /// everything, then a remote hold, then the most recent active
+    /// override wins, otherwise the auto unlock windows are checked,
+    /// with first card in only after the first credential of the day
+    /// or, past midnight, of the day the window started.
+    pub fn mode(&self) -> Mode {
+        let now = SystemTime::now();
+        let mut data = self.0.lock().unwrap();
+        if data.lockdown {
+            return Mode::LockedDown;
+        }
+        if data.held_open {
+            return Mode::Unlocked;
+        }
+        data.overrides.retain(|o| o.end > now);
+        if let Some(active) = data.overrides.iter().rev().find(|o| o.start <= now) {
+            return active.mode;
+        }
+        let Some(local) = LocalTime::from_system_time(now) else {
+            return Mode::Normal;
+        };
+        let arrived_on = data.arrived_on.filter(|_| data.first_card_in);
+        let yesterday = arrived_on.and_then(|_| {
+            let before_midnight = Duration::from_secs((local.minutes as u64 + 1) * 60);
+            LocalTime::from_system_time(now - before_midnight).map(|day| day.date())
+        });
+        let arrived = |window: &TimeWindow| {
+            !data.first_card_in
+                || arrived_on == Some(local.date())
+                || (window.past_midnight(&local) && arrived_on == yesterday)
+        };
+        let unlocked = data
+            .unlock_windows
+            .iter()
+            .any(|window| window.contains(&local, &data.holidays) && arrived(window));
+        if unlocked {
+            Mode::Unlocked
+        } else {
+            Mode::Normal
+        }
+    }
+}
+
+impl SchedulerData {
+    /// Writes the lockdown, the hold and the overrides to flash.
+    /// Failures are only logged, the state still applies until a reboot.
+    fn persist(&mut self) {
+        let state = OperationalState {
+            lockdown: self.lockdown,
+            overrides: self.overrides.clone(),
+            held_open: self.held_open,
+        };
+        let result = postcard::to_allocvec(&state)
+            .context("encoding failure")
+            .and_then(|buf| {
+                self.nvs.set_raw(STATE_KEY, &buf).context("nvs failure")?;
+                storage::record_write(Area::Config, buf.len());
+                Ok(())
+            });
+        if let Err(e) = result {
+            log::error!("error persisting operational state: {:?}", e);
+        }
+    }
+}
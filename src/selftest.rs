@@ -0,0 +1,105 @@
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+use esp_idf_svc::sys::gpio_get_level;
+use serde::Serialize;
+
+use crate::door::{DoorCommand, DoorState, DoorStatus};
+use crate::mqtt::Outbox;
+use crate::schedule::LocalTime;
+use crate::user::UserDB;
+
+/// How long to wait for sntp before failing the time check
+const TIME_TIMEOUT: Duration = Duration::from_secs(60);
+/// Time given to the door thread to drive the relay
+const RELAY_SETTLE: Duration = Duration::from_millis(500);
+
+/// Result of the boot self-test published to doorsys/selftest/{device_id}
+#[derive(Serialize, Debug)]
+pub struct SelfTest {
+    pub passed: bool,
+    pub nvs: bool,
+    /// Number of codes loaded from flash
+    pub codes: usize,
+    /// None when the relay test is disabled
+    pub relay: Option<bool>,
+    /// Both reader lines are at their idle level
+    pub reader: bool,
+    /// The clock was synchronized
+    pub time: bool,
+}
+
+/// Relay under test, it is only toggled when enabled in the settings
+pub struct Relay {
+    pub door_tx: Sender<DoorCommand>,
+    pub status: DoorStatus,
+}
+
+/// Runs the self-test in the background once the device is up
+pub fn setup_selftest(
+    nvs_part: EspNvsPartition<NvsDefault>,
+    user_db: UserDB,
+    relay: Option<Relay>,
+    reader_pins: [i32; 2],
+    idle_level: i32,
+    result_tx: Outbox<SelfTest>,
+) {
+    thread::spawn(move || {
+        let nvs = check_nvs(nvs_part);
+        let codes = user_db.stats().codes;
+        let relay = relay.map(|relay| check_relay(&relay));
+        let reader = reader_pins
+            .iter()
+            .all(|pin| unsafe { gpio_get_level(*pin) } == idle_level);
+        let time = wait_for_time();
+
+        let passed = nvs && relay.unwrap_or(true) && reader && time;
+        let result = SelfTest {
+            passed,
+            nvs,
+            codes,
+            relay,
+            reader,
+            time,
+        };
+        if passed {
+            log::info!("Self-test passed: {:?}", result);
+        } else {
+            log::error!("Self-test failed: {:?}", result);
+        }
+        if let Err(e) = result_tx.send(result) {
+            log::error!("error sending self-test result: {}", e);
+        }
+    });
+}
+
+fn check_nvs(nvs_part: EspNvsPartition<NvsDefault>) -> bool {
+    let result = EspNvs::new(nvs_part, "doorsys", true).and_then(|nvs| nvs.contains("codes"));
+    if let Err(e) = &result {
+        log::error!("self-test nvs error: {}", e);
+    }
+    result.is_ok()
+}
+
+/// Opens the door momentarily and checks the relay driver didn't fail
+fn check_relay(relay: &Relay) -> bool {
+    if let Err(e) = relay.door_tx.send(DoorCommand::Open) {
+        log::error!("error sending door command: {}", e);
+        return false;
+    }
+    thread::sleep(RELAY_SETTLE);
+    relay.status.state() != DoorState::Fault
+}
+
+fn wait_for_time() -> bool {
+    let start = Instant::now();
+    while LocalTime::now().is_none() {
+        if start.elapsed() > TIME_TIMEOUT {
+            return false;
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+    true
+}
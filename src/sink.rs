@@ -0,0 +1,253 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::mem;
+use std::net::UdpSocket;
+use std::sync::mpsc::Sender;
+
+use anyhow::Context;
+use esp_idf_svc::fs::fatfs::Fatfs;
+use esp_idf_svc::hal::gpio::AnyIOPin;
+use esp_idf_svc::hal::sd::spi::SdSpiHostDriver;
+use esp_idf_svc::hal::sd::{SdCardConfiguration, SdCardDriver};
+use esp_idf_svc::hal::spi::config::DriverConfig;
+use esp_idf_svc::hal::spi::{Dma, SpiDriver, SPI2};
+use esp_idf_svc::io::vfs::MountedFatfs;
+
+use crate::audit::{self, AuditEvent, AuditQueue, AuditSink, OverflowPolicy};
+use crate::config::{AuditConfig, SinkConfig};
+use crate::http_client;
+use crate::privacy::{self, Redacted};
+use crate::webhook::escape;
+
+const SD_MOUNT_POINT: &str = "/sdcard";
+/// Authpriv facility, where access control messages belong
+const SYSLOG_FACILITY: u8 = 10;
+const SYSLOG_INFO: u8 = 6;
+const SYSLOG_WARNING: u8 = 4;
+
+/// Sends every audit as a syslog message over udp. Nothing tells
+/// if the message arrived, errors only come from the local stack.
+pub struct SyslogSink {
+    socket: UdpSocket,
+    server: (String, u16),
+    hostname: String,
+}
+
+impl SyslogSink {
+    pub fn new(device_id: &str, host: &str, port: u16) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        Ok(SyslogSink {
+            socket,
+            server: (host.to_owned(), port),
+            hostname: device_id.to_owned(),
+        })
+    }
+}
+
+impl AuditSink for SyslogSink {
+    fn name(&self) -> &'static str {
+        "syslog"
+    }
+
+    fn write(&mut self, event: &AuditEvent) -> anyhow::Result<()> {
+        let severity = if event.audit.success {
+            SYSLOG_INFO
+        } else {
+            SYSLOG_WARNING
+        };
+        // The audit carries its own time, the header one is left out
+        let message = format!(
+            "<{}>1 - {} doorsys - audit - {:?} {} from {:?} with {} {} on door {} at {}{}{}",
+            SYSLOG_FACILITY * 8 + severity,
+            self.hostname,
+            event.action,
+            if event.audit.success {
+                "granted"
+            } else {
+                "denied"
+            },
+            event.source,
+            event.code_type(),
+            Redacted(event.audit.code),
+            event.door,
+            event.unix_time(),
+            if event.suspicious { " suspicious" } else { "" },
+            if event.stale { " stale" } else { "" },
+        );
+        let (host, port) = &self.server;
+        self.socket
+            .send_to(message.as_bytes(), (host.as_str(), *port))?;
+        Ok(())
+    }
+}
+
+/// Posts every audit as json, one request per audit
+pub struct WebhookSink {
+    device_id: String,
+    url: String,
+    authorization: Option<String>,
+}
+
+impl AuditSink for WebhookSink {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn write(&mut self, event: &AuditEvent) -> anyhow::Result<()> {
+        let body = format!(
+            r#"{{"device":"{}","action":"{:?}","source":"{:?}","code_type":"{}","code":{},"success":{},"suspicious":{},"stale":{},"correlation_id":{},"door":{},"timestamp":{}}}"#,
+            escape(&self.device_id),
+            event.action,
+            event.source,
+            event.code_type(),
+            // Left to the mqtt audits, posted to a third party otherwise
+            if privacy::enabled() {
+                "null".to_owned()
+            } else {
+                event.audit.code.to_string()
+            },
+            event.audit.success,
+            event.suspicious,
+            event.stale,
+            event
+                .correlation_id
+                .map_or_else(|| "null".to_owned(), |id| id.to_string()),
+            event.door,
+            event.unix_time()
+        );
+        http_client::post(
+            &self.url,
+            "application/json",
+            self.authorization.as_deref(),
+            &body,
+        )
+    }
+}
+
+/// Appends every audit as a csv line to a file on the sd card
+pub struct SdCardSink {
+    path: String,
+    /// Reopened on the next write after an error, e.g. the card was swapped
+    file: Option<File>,
+}
+
+impl SdCardSink {
+    /// Mounts the card, it stays mounted for as long as the device runs
+    pub fn new(
+        spi: SPI2,
+        sclk: i32,
+        mosi: i32,
+        miso: i32,
+        cs: i32,
+        file: &str,
+    ) -> anyhow::Result<Self> {
+        let spi_driver = SpiDriver::new(
+            spi,
+            unsafe { AnyIOPin::new(sclk) },
+            unsafe { AnyIOPin::new(mosi) },
+            Some(unsafe { AnyIOPin::new(miso) }),
+            &DriverConfig::default().dma(Dma::Auto(4096)),
+        )?;
+        let host = SdSpiHostDriver::new(
+            spi_driver,
+            Some(unsafe { AnyIOPin::new(cs) }),
+            AnyIOPin::none(),
+            AnyIOPin::none(),
+            AnyIOPin::none(),
+            None,
+        )?;
+        let card = SdCardDriver::new_spi(host, &SdCardConfiguration::new())?;
+        let mounted = MountedFatfs::mount(Fatfs::new_sdcard(0, card)?, SD_MOUNT_POINT, 2)
+            .context("error mounting the sd card")?;
+        mem::forget(mounted);
+        Ok(SdCardSink {
+            path: archive_file(file),
+            file: None,
+        })
+    }
+
+    fn open(&mut self) -> anyhow::Result<&mut File> {
+        if self.file.is_none() {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            if file.metadata()?.len() == 0 {
+                writeln!(file, "{}", audit::CSV_HEADER)?;
+            }
+            self.file = Some(file);
+        }
+        Ok(self.file.as_mut().unwrap())
+    }
+}
+
+impl AuditSink for SdCardSink {
+    fn name(&self) -> &'static str {
+        "sd card"
+    }
+
+    fn write(&mut self, event: &AuditEvent) -> anyhow::Result<()> {
+        let result = self.open().and_then(|file| {
+            writeln!(file, "{}", event.csv())?;
+            file.flush()?;
+            Ok(())
+        });
+        if result.is_err() {
+            self.file = None;
+        }
+        result
+    }
+}
+
+fn archive_file(file: &str) -> String {
+    format!("{SD_MOUNT_POINT}/{file}")
+}
+
+/// Path of the csv file written by the sd card sink, if there is one
+pub fn archive_path(config: &AuditConfig) -> Option<String> {
+    config.sinks.iter().find_map(|sink| match sink {
+        SinkConfig::SdCard { file, .. } => Some(archive_file(file)),
+        _ => None,
+    })
+}
+
+/// Starts the additional sinks of the settings. One that fails
+/// to start is logged and left out, the others still run.
+pub fn setup_sinks(device_id: &str, config: &AuditConfig, spi: SPI2) -> Vec<Sender<AuditEvent>> {
+    let mut spi = Some(spi);
+    let mut senders = Vec::with_capacity(config.sinks.len());
+    for sink in &config.sinks {
+        // Extra sinks never hold back grants, only mqtt may
+        let queue = AuditQueue::with_capacity(config.queue_size, OverflowPolicy::DropOldest);
+        let sender = match sink {
+            SinkConfig::Syslog { host, port } => SyslogSink::new(device_id, host, *port)
+                .map(|sink| audit::setup_audit_sink(sink, queue)),
+            SinkConfig::Webhook { url, authorization } => {
+                let sink = WebhookSink {
+                    device_id: device_id.to_owned(),
+                    url: url.clone(),
+                    authorization: authorization.clone(),
+                };
+                Ok(audit::setup_audit_sink(sink, queue))
+            }
+            SinkConfig::SdCard {
+                sclk_pin,
+                mosi_pin,
+                miso_pin,
+                cs_pin,
+                file,
+            } => spi
+                .take()
+                .context("only one sd card sink is supported")
+                .and_then(|spi| {
+                    SdCardSink::new(spi, *sclk_pin, *mosi_pin, *miso_pin, *cs_pin, file)
+                })
+                .map(|sink| audit::setup_audit_sink(sink, queue)),
+        };
+        match sender {
+            Ok(sender) => senders.push(sender),
+            Err(e) => log::error!("error starting audit sink: {:?}", e),
+        }
+    }
+    senders
+}
@@ -0,0 +1,114 @@
+use std::ffi::c_void;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Mutex;
+use std::time::Duration;
+use std::{ptr, thread};
+
+use esp_idf_svc::sys::{
+    esp, esp_event_base_t, esp_event_handler_register, esp_event_handler_unregister,
+    esp_smartconfig_set_type, esp_smartconfig_start, esp_smartconfig_stop,
+    smartconfig_event_got_ssid_pswd_t, smartconfig_event_t,
+    smartconfig_event_t_SC_EVENT_GOT_SSID_PSWD, smartconfig_event_t_SC_EVENT_SEND_ACK_DONE,
+    smartconfig_start_config_t, smartconfig_type_t_SC_TYPE_ESPTOUCH, ESP_EVENT_ANY_ID, SC_EVENT,
+};
+
+/// Time the app is given to hear back from the device once it joined the network
+const ACK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Wifi credentials sent by the EspTouch phone app
+pub struct Credentials {
+    pub ssid: String,
+    pub password: String,
+}
+
+enum Event {
+    Credentials(Credentials),
+    /// The app was told the device joined the network
+    AckDone,
+}
+
+static EVENTS: Mutex<Option<Sender<Event>>> = Mutex::new(None);
+
+/// Text of a nul padded C string
+fn c_string(bytes: &[u8]) -> String {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
+
+unsafe extern "C" fn on_event(
+    _arg: *mut c_void,
+    _base: esp_event_base_t,
+    id: i32,
+    data: *mut c_void,
+) {
+    let event = match id as smartconfig_event_t {
+        smartconfig_event_t_SC_EVENT_GOT_SSID_PSWD => {
+            let got = &*(data as *const smartconfig_event_got_ssid_pswd_t);
+            Event::Credentials(Credentials {
+                ssid: c_string(&got.ssid),
+                password: c_string(&got.password),
+            })
+        }
+        smartconfig_event_t_SC_EVENT_SEND_ACK_DONE => Event::AckDone,
+        _ => return,
+    };
+    if let Some(tx) = EVENTS.lock().unwrap().as_ref() {
+        let _ = tx.send(event);
+    }
+}
+
+fn stop() {
+    unsafe {
+        if let Err(e) = esp!(esp_smartconfig_stop()) {
+            log::warn!("error stopping smartconfig: {}", e);
+        }
+        esp_event_handler_unregister(SC_EVENT, ESP_EVENT_ANY_ID, Some(on_event));
+    }
+    EVENTS.lock().unwrap().take();
+}
+
+/// Listens for the credentials broadcast by the EspTouch app, wifi must be
+/// started in station mode. Returns None when nothing arrived before the
+/// timeout. Once credentials arrive SmartConfig keeps running in the
+/// background until the app is told the device joined the network.
+pub fn receive_credentials(timeout: Option<Duration>) -> anyhow::Result<Option<Credentials>> {
+    let (tx, rx) = mpsc::channel();
+    *EVENTS.lock().unwrap() = Some(tx);
+    let config = smartconfig_start_config_t {
+        enable_log: false,
+        esp_touch_v2_enable_crypt: false,
+        esp_touch_v2_key: ptr::null_mut(),
+    };
+    unsafe {
+        esp!(esp_event_handler_register(
+            SC_EVENT,
+            ESP_EVENT_ANY_ID,
+            Some(on_event),
+            ptr::null_mut()
+        ))?;
+        esp!(esp_smartconfig_set_type(
+            smartconfig_type_t_SC_TYPE_ESPTOUCH
+        ))?;
+        esp!(esp_smartconfig_start(&config))?;
+    }
+    log::info!("Waiting for SmartConfig credentials");
+
+    let received = match timeout {
+        Some(timeout) => rx.recv_timeout(timeout).ok(),
+        None => rx.recv().ok(),
+    };
+    let Some(Event::Credentials(credentials)) = received else {
+        log::warn!("No SmartConfig credentials received");
+        stop();
+        return Ok(None);
+    };
+    log::info!("SmartConfig credentials for {}", credentials.ssid);
+    thread::spawn(move || {
+        match rx.recv_timeout(ACK_TIMEOUT) {
+            Ok(_) => log::info!("SmartConfig acknowledged"),
+            Err(_) => log::warn!("SmartConfig not acknowledged"),
+        }
+        stop();
+    });
+    Ok(Some(credentials))
+}
@@ -0,0 +1,162 @@
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+use serde::Deserialize;
+
+use crate::config::StaleConfig;
+use crate::schedule::LocalTime;
+use crate::storage::{self, Area};
+
+const SEEN_KEY: &str = "uplink_seen";
+/// How often the time last connected is written while connected
+const SEEN_INTERVAL: Duration = Duration::from_secs(3600);
+
+struct State {
+    offline_since: Option<Instant>,
+    connected_once: bool,
+    /// Wall time the broker was last seen connected, kept across reboots
+    seen: Option<u64>,
+    seen_written: Option<Instant>,
+    nvs: Option<EspNvs<NvsDefault>>,
+}
+
+/// Tracks the connection to the broker, the user database
+/// can't be kept up to date while it is down
+#[derive(Clone)]
+pub struct Uplink(Arc<Mutex<State>>);
+
+/// Starts offline, the clock starts at boot
+impl Default for Uplink {
+    fn default() -> Self {
+        Uplink(Arc::new(Mutex::new(State {
+            offline_since: Some(Instant::now()),
+            connected_once: false,
+            seen: None,
+            seen_written: None,
+            nvs: None,
+        })))
+    }
+}
+
+impl Uplink {
+    /// Keeps the wall time last connected in nvs so the offline time
+    /// survives a reboot
+    pub fn new(nvs_part: EspNvsPartition<NvsDefault>) -> anyhow::Result<Self> {
+        let nvs = EspNvs::new(nvs_part, "doorsys", true)?;
+        let uplink = Uplink::default();
+        {
+            let mut state = uplink.0.lock().unwrap();
+            state.seen = nvs.get_u64(SEEN_KEY)?;
+            state.nvs = Some(nvs);
+        }
+        Ok(uplink)
+    }
+
+    pub fn set_connected(&self, connected: bool) {
+        let mut state = self.0.lock().unwrap();
+        if connected {
+            state.offline_since = None;
+            state.connected_once = true;
+            Self::touch(&mut state, false);
+        } else if state.offline_since.is_none() {
+            Self::touch(&mut state, true);
+            state.offline_since = Some(Instant::now());
+        }
+    }
+
+    pub fn connected(&self) -> bool {
+        let mut state = self.0.lock().unwrap();
+        let connected = state.offline_since.is_none();
+        if connected {
+            Self::touch(&mut state, false);
+        }
+        connected
+    }
+
+    /// Time since the broker was last seen, counted across reboots once
+    /// the clock is set. Unknown before the first connection without it,
+    /// which reads as offline for ever
+    pub fn offline_for(&self) -> Duration {
+        let state = self.0.lock().unwrap();
+        let Some(since) = state.offline_since else {
+            return Duration::ZERO;
+        };
+        let since_seen = state
+            .seen
+            .filter(|_| LocalTime::now().is_some())
+            .and_then(|seen| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH + Duration::from_secs(seen))
+                    .ok()
+            });
+        match since_seen {
+            Some(offline) => offline.max(since.elapsed()),
+            None if state.connected_once => since.elapsed(),
+            None => Duration::MAX,
+        }
+    }
+
+    /// Records now as the time last connected, at most every
+    /// SEEN_INTERVAL unless forced, once the clock is set
+    fn touch(state: &mut State, force: bool) {
+        if LocalTime::now().is_none() {
+            return;
+        }
+        let due = state
+            .seen_written
+            .map_or(true, |written| written.elapsed() >= SEEN_INTERVAL);
+        if !force && !due {
+            return;
+        }
+        let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) else {
+            return;
+        };
+        state.seen = Some(now.as_secs());
+        state.seen_written = Some(Instant::now());
+        if let Some(nvs) = &mut state.nvs {
+            match nvs.set_u64(SEEN_KEY, now.as_secs()) {
+                Ok(_) => storage::record_write(Area::Other, mem::size_of::<u64>()),
+                Err(e) => log::error!("Error saving the time last connected: {e}"),
+            }
+        }
+    }
+}
+
+/// Access policy once the user database is considered stale
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StalePolicy {
+    /// Only flags the audits
+    #[default]
+    Flag,
+    /// Any valid credential opens the door regardless of the access rules
+    Relaxed,
+    /// Temporary codes are rejected, permanent codes keep working
+    PermanentOnly,
+    /// Every credential is rejected
+    DenyAll,
+}
+
+/// Decides when the user database is stale and which policy applies
+pub struct StaleGuard {
+    uplink: Uplink,
+    after: Duration,
+    policy: StalePolicy,
+}
+
+impl StaleGuard {
+    pub fn new(config: &StaleConfig, uplink: Uplink) -> Self {
+        StaleGuard {
+            uplink,
+            after: Duration::from_secs(config.after_hours * 3600),
+            policy: config.policy,
+        }
+    }
+
+    /// Policy in effect, none while the database is fresh
+    pub fn policy(&self) -> Option<StalePolicy> {
+        (self.uplink.offline_for() >= self.after).then_some(self.policy)
+    }
+}
@@ -0,0 +1,171 @@
+use std::sync::Mutex;
+use std::time::Duration;
+use std::{mem, ptr, thread};
+
+use anyhow::Context;
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+use esp_idf_svc::sys::{esp, nvs_get_stats, nvs_stats_t, EspError};
+use serde::{Deserialize, Serialize};
+
+use crate::alert::{Alert, Category, Severity};
+use crate::config::StorageConfig;
+use crate::mqtt::Outbox;
+use crate::user::UserDB;
+
+const WEAR_KEY: &str = "nvs_wear";
+/// Bytes taken by an nvs entry
+const ENTRY_SIZE: u64 = 32;
+/// Erase cycles the flash is rated for
+const FLASH_ENDURANCE: f32 = 100_000.0;
+
+/// Subsystem writing to nvs
+#[derive(Debug, Clone, Copy)]
+pub enum Area {
+    UserDb,
+    /// Settings, rules, schedules and the rest of the configuration
+    Config,
+    /// Audit chain and command counter
+    Other,
+}
+
+/// Bytes written to nvs since the device was first flashed
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Wear {
+    pub user_db: u64,
+    pub config: u64,
+    pub other: u64,
+}
+
+impl Wear {
+    pub fn total(&self) -> u64 {
+        self.user_db + self.config + self.other
+    }
+
+    /// Erase cycles consumed by every sector, assuming nvs spreads the
+    /// writes evenly over the partition
+    pub fn erase_cycles(&self, total_entries: usize) -> f32 {
+        match total_entries as u64 * ENTRY_SIZE {
+            0 => 0.0,
+            size => self.total() as f32 / size as f32,
+        }
+    }
+
+    /// Share of the rated flash endurance used, in percent
+    pub fn lifetime_used(&self, total_entries: usize) -> f32 {
+        self.erase_cycles(total_entries) * 100.0 / FLASH_ENDURANCE
+    }
+}
+
+static WEAR: Mutex<Wear> = Mutex::new(Wear {
+    user_db: 0,
+    config: 0,
+    other: 0,
+});
+
+/// Accounts a successful nvs write
+pub fn record_write(area: Area, bytes: usize) {
+    let mut wear = WEAR.lock().unwrap();
+    let bytes = bytes as u64;
+    match area {
+        Area::UserDb => wear.user_db += bytes,
+        Area::Config => wear.config += bytes,
+        Area::Other => wear.other += bytes,
+    }
+}
+
+/// Bytes written so far, including the ones before this boot
+pub fn wear() -> Wear {
+    *WEAR.lock().unwrap()
+}
+
+/// Adds the totals persisted by the previous boots to the counters
+fn load_wear(nvs: &EspNvs<NvsDefault>) -> anyhow::Result<()> {
+    let mut buf = [0; 32];
+    if let Some(slice) = nvs.get_raw(WEAR_KEY, &mut buf)? {
+        let stored: Wear = postcard::from_bytes(slice).context("error decoding nvs wear")?;
+        let mut wear = WEAR.lock().unwrap();
+        wear.user_db += stored.user_db;
+        wear.config += stored.config;
+        wear.other += stored.other;
+    }
+    Ok(())
+}
+
+fn save_wear(nvs: &mut EspNvs<NvsDefault>, wear: &Wear) -> anyhow::Result<()> {
+    let buf = postcard::to_allocvec(wear).context("encoding failure")?;
+    nvs.set_raw(WEAR_KEY, &buf).context("nvs failure")?;
+    record_write(Area::Other, buf.len());
+    Ok(())
+}
+
+/// Usage of the default nvs partition
+pub fn nvs_stats() -> Result<nvs_stats_t, EspError> {
+    unsafe {
+        let mut stats = mem::MaybeUninit::uninit();
+        esp!(nvs_get_stats(ptr::null(), stats.as_mut_ptr()))?;
+        Ok(stats.assume_init())
+    }
+}
+
+/// Spawns the thread watching the free nvs entries. Below the limit the user
+/// database is written again so nvs can reclaim the pages holding its old
+/// copies and, if that is not enough, an alert is raised since a full nvs
+/// makes the next user sync fail. The alert is raised again only after the
+/// free entries recover. The bytes written to nvs are persisted on every
+/// check where they changed.
+pub fn setup_storage_watch(
+    config: &StorageConfig,
+    nvs_part: EspNvsPartition<NvsDefault>,
+    user_db: UserDB,
+    alert_tx: Outbox<Alert>,
+) -> anyhow::Result<()> {
+    let min_free_entries = config.min_free_entries;
+    let interval = Duration::from_secs(config.check_minutes.max(1) * 60);
+    let mut nvs = EspNvs::new(nvs_part, "doorsys", true)?;
+    if let Err(e) = load_wear(&nvs) {
+        log::error!("error loading nvs wear: {:?}", e);
+    }
+    thread::spawn(move || {
+        let mut alerted = false;
+        let mut saved = wear();
+        loop {
+            let current = wear();
+            if current != saved {
+                match save_wear(&mut nvs, &current) {
+                    // Includes the bytes of this save so it isn't saved again
+                    Ok(()) => saved = wear(),
+                    Err(e) => log::error!("error saving nvs wear: {:?}", e),
+                }
+            }
+            match nvs_stats() {
+                Ok(stats) if stats.free_entries >= min_free_entries => alerted = false,
+                // Compacting again would only wear the flash
+                Ok(_) if alerted => {}
+                Ok(stats) => {
+                    log::warn!(
+                        "Only {} free nvs entries, compacting the user database",
+                        stats.free_entries
+                    );
+                    if let Err(e) = user_db.rewrite() {
+                        log::error!("error compacting the user database: {}", e);
+                    }
+                    let free_entries = nvs_stats().map_or(0, |stats| stats.free_entries);
+                    if free_entries < min_free_entries {
+                        alerted = true;
+                        let detail = format!(
+                            "nvs nearly full, {} free entries after compaction",
+                            free_entries
+                        );
+                        let alert = Alert::new(Severity::Warning, Category::StorageFault, detail);
+                        if let Err(e) = alert_tx.send(alert) {
+                            log::error!("error sending alert: {}", e);
+                        }
+                    }
+                }
+                Err(e) => log::error!("error reading nvs stats: {}", e),
+            }
+            thread::sleep(interval);
+        }
+    });
+    Ok(())
+}
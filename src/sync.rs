@@ -0,0 +1,95 @@
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+use serde::{Deserialize, Serialize};
+
+use crate::crypto;
+use crate::heap;
+use crate::http_client;
+use crate::management::{Change, ManagementLog, Origin};
+use crate::mqtt::Outbox;
+use crate::user::UserDB;
+
+/// Big enough for a bit over 25k codes
+const MAX_FILE_SIZE: usize = 128 * 1024;
+/// Progress is reported every time this many percent is downloaded
+const PROGRESS_STEP: usize = 10;
+
+/// Location of a credential file replacing the whole user database.
+/// The file holds the same postcard encoded list of codes as a bulk update.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SyncRequest {
+    pub url: String,
+    /// SHA-256 of the file
+    pub sha256: [u8; 32],
+}
+
+/// Published to doorsys/sync/{device_id} while a sync is running
+#[derive(Serialize, Debug)]
+pub enum SyncStatus {
+    Downloading {
+        received: usize,
+        total: Option<usize>,
+    },
+    Applied {
+        codes: usize,
+    },
+    Failed(String),
+}
+
+/// Spawns the thread that downloads and applies the credential files.
+/// Downloads run one at a time, away from the command thread.
+pub fn setup_sync(
+    user_db: UserDB,
+    status_tx: Outbox<SyncStatus>,
+    management: ManagementLog,
+) -> Sender<(Origin, SyncRequest)> {
+    let (sync_tx, sync_rx) = mpsc::channel::<(Origin, SyncRequest)>();
+    thread::spawn(move || {
+        for (origin, request) in sync_rx {
+            log::info!("Syncing users from {}", request.url);
+            let status = match sync_users(&request, &user_db, &status_tx) {
+                Ok(codes) => {
+                    management.record(origin, Change::Users, codes, &Ok(()));
+                    SyncStatus::Applied { codes }
+                }
+                Err(e) => {
+                    let status = SyncStatus::Failed(e.to_string());
+                    management.record(origin, Change::Users, 0, &Err(e));
+                    status
+                }
+            };
+            log::info!("User sync finished {:?}", status);
+            if let Err(e) = status_tx.send(status) {
+                log::error!("error sending sync status: {}", e);
+            }
+        }
+    });
+    sync_tx
+}
+
+fn sync_users(
+    request: &SyncRequest,
+    user_db: &UserDB,
+    status_tx: &Outbox<SyncStatus>,
+) -> anyhow::Result<usize> {
+    let mut next_step = 0;
+    let file = http_client::download(&request.url, MAX_FILE_SIZE, |received, total| {
+        let percent = total.map_or(0, |total| received * 100 / total.max(1));
+        if percent >= next_step {
+            next_step = percent + PROGRESS_STEP;
+            if let Err(e) = status_tx.send(SyncStatus::Downloading { received, total }) {
+                log::error!("error sending sync status: {}", e);
+            }
+        }
+    })?;
+    if !crypto::constant_time_eq(&crypto::sha256(&file)?, &request.sha256) {
+        anyhow::bail!("checksum mismatch");
+    }
+    heap::reserve(file.len() * heap::DECODE_EXPANSION, "user sync")?;
+    let codes: Vec<i32> = postcard::from_bytes(&file)?;
+    let len = codes.len();
+    // The database is persisted as a single blob so it is either fully replaced or untouched
+    user_db.bulk(codes)?;
+    Ok(len)
+}
@@ -0,0 +1,104 @@
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+use serde::{Deserialize, Serialize};
+
+use crate::schedule::LocalTime;
+use crate::storage::{self, Area};
+
+const TEMPORARY_KEY: &str = "temp_codes";
+
+/// Single use code generated by the backend, e.g. for couriers
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TemporaryCode {
+    pub code: i32,
+    /// Unix time in seconds after which the code is no longer valid
+    pub expires: u64,
+}
+
+/// Temporary codes persisted in nvs, kept apart from the permanent users.
+/// Codes are removed once used or expired.
+#[derive(Clone)]
+pub struct TemporaryCodes(Arc<Mutex<TemporaryData>>);
+
+struct TemporaryData {
+    nvs: EspNvs<NvsDefault>,
+    codes: Vec<TemporaryCode>,
+}
+
+impl TemporaryData {
+    fn persist(&mut self) -> anyhow::Result<()> {
+        let buf = postcard::to_allocvec(&self.codes).context("encoding failure")?;
+        self.nvs
+            .set_raw(TEMPORARY_KEY, &buf)
+            .context("nvs failure")?;
+        storage::record_write(Area::Config, buf.len());
+        Ok(())
+    }
+
+    /// Drops the expired codes, returns true if any was removed
+    fn purge(&mut self, now: u64) -> bool {
+        let len = self.codes.len();
+        self.codes.retain(|temp| temp.expires > now);
+        self.codes.len() != len
+    }
+}
+
+/// Current unix time, only once the clock is synchronized
+/// as expiration can't be verified before that
+fn now() -> Option<u64> {
+    LocalTime::now()?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+    Some(now.as_secs())
+}
+
+impl TemporaryCodes {
+    pub fn new(nvs_part: EspNvsPartition<NvsDefault>) -> anyhow::Result<Self> {
+        let nvs = EspNvs::new(nvs_part, "doorsys", true)?;
+        let blob_size = nvs.blob_len(TEMPORARY_KEY)?.unwrap_or(0);
+        let mut buf = vec![0; blob_size];
+        let codes = match nvs.get_raw(TEMPORARY_KEY, &mut buf)? {
+            Some(slice) => postcard::from_bytes(slice).context("error decoding temporary codes")?,
+            None => Vec::new(),
+        };
+        log::info!("Loaded {} temporary codes", codes.len());
+        Ok(TemporaryCodes(Arc::new(Mutex::new(TemporaryData {
+            nvs,
+            codes,
+        }))))
+    }
+
+    /// Installs a new code replacing any other with the same number
+    pub fn add(&self, temp: TemporaryCode) -> anyhow::Result<()> {
+        let mut data = self.0.lock().unwrap();
+        if let Some(now) = now() {
+            data.purge(now);
+        }
+        data.codes.retain(|other| other.code != temp.code);
+        data.codes.push(temp);
+        data.persist()
+    }
+
+    /// Checks if the code is installed and not expired
+    pub fn contains(&self, code: i32) -> bool {
+        let Some(now) = now() else {
+            return false;
+        };
+        let mut data = self.0.lock().unwrap();
+        if data.purge(now) {
+            if let Err(e) = data.persist() {
+                log::error!("error removing expired temporary codes: {}", e);
+            }
+        }
+        data.codes.iter().any(|temp| temp.code == code)
+    }
+
+    /// Removes the code after it was used
+    pub fn consume(&self, code: i32) -> anyhow::Result<()> {
+        let mut data = self.0.lock().unwrap();
+        data.codes.retain(|temp| temp.code != code);
+        data.persist()
+    }
+}
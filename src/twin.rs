@@ -0,0 +1,166 @@
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+use anyhow::Context;
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+use serde::{Deserialize, Serialize};
+
+use crate::crypto;
+use crate::management::{Change, ManagementLog, Origin};
+use crate::mqtt::Outbox;
+use crate::rules::{Rule, Rules};
+use crate::schedule::{Holiday, Holidays, Scheduler, TimeWindow};
+use crate::storage::{self, Area};
+
+const TWIN_KEY: &str = "twin";
+
+/// Configuration the backend wants the device to have, published retained to
+/// doorsys/twin/{device_id}/desired. Sections left empty are not changed.
+#[derive(Deserialize, Debug)]
+pub struct DesiredState {
+    /// Must never decrease, older versions are rejected
+    pub version: u64,
+    pub rules: Option<Vec<Rule>>,
+    pub holidays: Option<Vec<Holiday>>,
+    pub unlock_schedule: Option<Vec<TimeWindow>>,
+    pub lockdown: bool,
+}
+
+/// Outcome of applying the desired state, published retained to
+/// doorsys/twin/{device_id}/reported
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReportedState {
+    pub version: u64,
+    /// SHA-256 of the desired state payload that was applied
+    pub hash: [u8; 32],
+    pub error: Option<String>,
+}
+
+/// Applies the desired state to the device. The last applied version is
+/// persisted so the same state is not written to flash again on every
+/// connection and older versions can't roll the configuration back.
+struct Twin {
+    nvs: EspNvs<NvsDefault>,
+    reported: Option<ReportedState>,
+    rules: Rules,
+    holidays: Holidays,
+    scheduler: Scheduler,
+    management: ManagementLog,
+}
+
+impl Twin {
+    fn apply(&mut self, payload: &[u8]) -> anyhow::Result<ReportedState> {
+        let desired: DesiredState = postcard::from_bytes(payload)?;
+        let hash = crypto::sha256(payload)?;
+        log::info!("Desired state version {}", desired.version);
+
+        // Checked before anything is touched, a stale retained twin
+        // must not lift a lockdown
+        if let Some(reported) = &self.reported {
+            if desired.version < reported.version {
+                anyhow::bail!(
+                    "desired version {} is older than {}",
+                    desired.version,
+                    reported.version
+                );
+            }
+        }
+
+        // Applied even if nothing else changed, another interface
+        // may have changed the lockdown since
+        if self.scheduler.set_lockdown(desired.lockdown) {
+            let count = usize::from(desired.lockdown);
+            self.management
+                .record(Origin::Twin, Change::Lockdown, count, &Ok(()));
+        }
+
+        if let Some(reported) = &self.reported {
+            if reported.hash == hash && reported.error.is_none() {
+                log::info!("Desired state already applied");
+                return Ok(reported.clone());
+            }
+        }
+
+        let mut error = None;
+        if let Some(rules) = desired.rules {
+            let count = rules.len();
+            let result = self.rules.set(rules);
+            if let Err(e) = &result {
+                error = Some(format!("rules: {}", e));
+            }
+            self.management
+                .record(Origin::Twin, Change::Rules, count, &result);
+        }
+        if let Some(holidays) = desired.holidays {
+            let count = holidays.len();
+            let result = self.holidays.set(holidays);
+            if let Err(e) = &result {
+                error = Some(format!("holidays: {}", e));
+            }
+            self.management
+                .record(Origin::Twin, Change::Holidays, count, &result);
+        }
+        if let Some(windows) = desired.unlock_schedule {
+            let count = windows.len();
+            let result = self.scheduler.set_unlock_windows(windows);
+            if let Err(e) = &result {
+                error = Some(format!("unlock schedule: {}", e));
+            }
+            self.management
+                .record(Origin::Twin, Change::UnlockSchedule, count, &result);
+        }
+
+        let reported = ReportedState {
+            version: desired.version,
+            hash,
+            error,
+        };
+        let buf = postcard::to_allocvec(&reported).context("encoding failure")?;
+        self.nvs.set_raw(TWIN_KEY, &buf).context("nvs failure")?;
+        storage::record_write(Area::Config, buf.len());
+        self.reported = Some(reported.clone());
+        Ok(reported)
+    }
+}
+
+/// Spawns the thread applying the desired states received from the router
+/// and returns the channel used to feed it the authenticated payloads
+pub fn setup_twin(
+    nvs_part: EspNvsPartition<NvsDefault>,
+    rules: Rules,
+    holidays: Holidays,
+    scheduler: Scheduler,
+    reported_tx: Outbox<ReportedState>,
+    management: ManagementLog,
+) -> anyhow::Result<Sender<Vec<u8>>> {
+    let nvs = EspNvs::new(nvs_part, "doorsys", true)?;
+    let blob_size = nvs.blob_len(TWIN_KEY)?.unwrap_or(0);
+    let mut buf = vec![0; blob_size];
+    let reported = match nvs.get_raw(TWIN_KEY, &mut buf)? {
+        Some(slice) => Some(postcard::from_bytes(slice).context("error decoding twin")?),
+        None => None,
+    };
+    let mut twin = Twin {
+        nvs,
+        reported,
+        rules,
+        holidays,
+        scheduler,
+        management,
+    };
+
+    let (desired_tx, desired_rx) = mpsc::channel::<Vec<u8>>();
+    thread::spawn(move || {
+        for payload in desired_rx {
+            match twin.apply(&payload) {
+                Ok(reported) => {
+                    if let Err(e) = reported_tx.send(reported) {
+                        log::error!("error sending reported state: {}", e);
+                    }
+                }
+                Err(e) => log::error!("Error applying desired state {}", e),
+            }
+        }
+    });
+    Ok(desired_tx)
+}
@@ -1,95 +1,326 @@
 use std::{
-    collections::BTreeSet,
-    sync::{Arc, Mutex},
+    collections::{BTreeMap, BTreeSet},
+    mem,
+    sync::atomic::{AtomicU32, Ordering},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Instant,
 };
 
 use anyhow::Context;
+use arc_swap::ArcSwap;
 use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+use serde::{Deserialize, Serialize};
 
-const NVS_NAMESPACE: &str = "codes";
+use crate::storage::{self, Area};
+
+/// The users are kept under one of two pairs of keys, the slot key
+/// tells which one so a rewrite can move them to fresh entries
+const SLOT_KEY: &str = "user_slot";
+const SLOTS: [Slot; 2] = [
+    Slot {
+        codes: "codes",
+        credentials: "credentials",
+    },
+    Slot {
+        codes: "codes_b",
+        credentials: "credentials_b",
+    },
+];
+
+struct Slot {
+    codes: &'static str,
+    credentials: &'static str,
+}
+
+/// Optional data attached to a code
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Credential {
+    /// Identifier of the user in the backend
+    pub user_id: Option<u32>,
+    /// Floor or zone forwarded to secondary systems on a grant,
+    /// credentials with a route also pulse the grant output.
+    /// With elevator outputs it is the mask of the floors allowed.
+    pub route: Option<u16>,
+}
 
 /// Abstraction to encapsulate the persistent database of users
 #[derive(Clone)]
-pub struct UserDB(Arc<Mutex<UserData>>);
+pub struct UserDB(Arc<Shared>);
+
+struct Shared {
+    data: Mutex<UserData>,
+    /// Read only copy of the codes used for access decisions. It is swapped
+    /// only after the changes are persisted and reading it never waits, so a
+    /// long nvs write never delays a lookup at the door.
+    snapshot: ArcSwap<BTreeSet<i32>>,
+    credentials: ArcSwap<BTreeMap<i32, Credential>>,
+    lookups: AtomicU32,
+    lookup_us: AtomicU32,
+    max_lookup_us: AtomicU32,
+    contended: AtomicU32,
+}
 
 /// The database of users is BTreeSet of i32s that is persistet to
 /// flash memory everytime it is changed. On reset the memory structure
 /// will be loaded from flash again.
 struct UserData {
     nvs: EspNvs<NvsDefault>,
+    slot: u8,
     codes: BTreeSet<i32>,
+    credentials: BTreeMap<i32, Credential>,
+}
+
+/// Lookup metrics since the last time they were read
+pub struct UserStats {
+    pub codes: usize,
+    pub lookups: u32,
+    pub avg_us: u32,
+    pub max_us: u32,
+    /// Times a change had to wait for another one to finish
+    pub contended: u32,
 }
 
 fn persist(data: &mut UserData) -> anyhow::Result<()> {
+    write_slot(data, data.slot)
+}
+
+fn write_slot(data: &mut UserData, slot: u8) -> anyhow::Result<()> {
+    let slot = &SLOTS[slot as usize];
     let buf = postcard::to_allocvec(&data.codes).context("encoding failure")?;
+    data.nvs.set_raw(slot.codes, &buf).context("nvs failure")?;
+    storage::record_write(Area::UserDb, buf.len());
+    let buf = postcard::to_allocvec(&data.credentials).context("encoding failure")?;
     data.nvs
-        .set_raw(NVS_NAMESPACE, &buf)
+        .set_raw(slot.credentials, &buf)
         .context("nvs failure")?;
+    storage::record_write(Area::UserDb, buf.len());
+    Ok(())
+}
+
+fn remove_slot(data: &mut UserData, slot: u8) -> anyhow::Result<()> {
+    let slot = &SLOTS[slot as usize];
+    data.nvs.remove(slot.codes).context("nvs failure")?;
+    data.nvs.remove(slot.credentials).context("nvs failure")?;
     Ok(())
 }
 
+/// Kept as a u64 like every other number, the backups only carry those
+fn load_slot(nvs: &EspNvs<NvsDefault>) -> anyhow::Result<u8> {
+    match nvs.get_u64(SLOT_KEY)? {
+        Some(slot) if (slot as usize) < SLOTS.len() => Ok(slot as u8),
+        Some(slot) => anyhow::bail!("invalid user slot {}", slot),
+        None => Ok(0),
+    }
+}
+
+fn load_credentials(
+    nvs: &EspNvs<NvsDefault>,
+    slot: &Slot,
+) -> anyhow::Result<BTreeMap<i32, Credential>> {
+    let blob_size = nvs.blob_len(slot.credentials)?.unwrap_or(0);
+    let mut buf = vec![0; blob_size];
+    match nvs.get_raw(slot.credentials, &mut buf)? {
+        Some(slice) => Ok(postcard::from_bytes(slice)?),
+        None => Ok(BTreeMap::new()),
+    }
+}
+
+fn load(
+    nvs: &EspNvs<NvsDefault>,
+    slot: u8,
+) -> anyhow::Result<(BTreeSet<i32>, BTreeMap<i32, Credential>)> {
+    let slot = &SLOTS[slot as usize];
+    let blob_size = nvs.blob_len(slot.codes)?.unwrap_or(0);
+    let mut buf = vec![0; blob_size];
+    let maybe_blob = nvs
+        .get_raw(slot.codes, &mut buf)
+        .context("error loading nvs")?;
+
+    let codes = match maybe_blob {
+        Some(slice) => {
+            let codes: BTreeSet<i32> =
+                postcard::from_bytes(slice).context("error deconding blob")?;
+            log::info!(
+                "Loaded {} codes from flash ({} bytes)",
+                codes.len(),
+                slice.len()
+            );
+            codes
+        }
+        None => {
+            log::warn!("No codes found, starting blank");
+            BTreeSet::new()
+        }
+    };
+    let credentials = load_credentials(nvs, slot).context("error loading credentials")?;
+    Ok((codes, credentials))
+}
+
 impl UserDB {
     pub fn new(nvs_part: EspNvsPartition<NvsDefault>) -> anyhow::Result<Self> {
         let nvs = EspNvs::new(nvs_part, "doorsys", true)?;
-        let blob_size = nvs.blob_len(NVS_NAMESPACE)?.unwrap_or(0);
-        let mut buf = vec![0; blob_size];
-        let maybe_blob = nvs
-            .get_raw(NVS_NAMESPACE, &mut buf)
-            .context("error loading nvs")?;
-
-        match maybe_blob {
-            Some(slice) => {
-                let codes = postcard::from_bytes(slice).context("error deconding blob")?;
-                let data = UserData { nvs, codes };
-
-                log::info!(
-                    "Loaded {} codes from flash ({} bytes)",
-                    data.codes.len(),
-                    slice.len()
-                );
-                Ok(UserDB(Arc::new(Mutex::new(data))))
-            }
-            None => {
-                log::warn!("No codes found, starting blank");
-                Ok(UserDB(Arc::new(Mutex::new(UserData {
-                    nvs,
-                    codes: BTreeSet::new(),
-                }))))
-            }
-        }
+        let slot = load_slot(&nvs)?;
+        let (codes, credentials) = load(&nvs, slot)?;
+        Ok(UserDB::with_data(nvs, slot, codes, credentials))
+    }
+
+    /// Opens the database with users read elsewhere when the stored ones
+    /// can't be loaded. They replace the stored ones on the next change.
+    pub fn from_snapshot(
+        nvs_part: EspNvsPartition<NvsDefault>,
+        codes: BTreeSet<i32>,
+        credentials: BTreeMap<i32, Credential>,
+    ) -> anyhow::Result<Self> {
+        let nvs = EspNvs::new(nvs_part, "doorsys", true)?;
+        let slot = load_slot(&nvs).unwrap_or(0);
+        Ok(UserDB::with_data(nvs, slot, codes, credentials))
+    }
+
+    fn with_data(
+        nvs: EspNvs<NvsDefault>,
+        slot: u8,
+        codes: BTreeSet<i32>,
+        credentials: BTreeMap<i32, Credential>,
+    ) -> Self {
+        UserDB(Arc::new(Shared {
+            snapshot: ArcSwap::from_pointee(codes.clone()),
+            credentials: ArcSwap::from_pointee(credentials.clone()),
+            data: Mutex::new(UserData {
+                nvs,
+                slot,
+                codes,
+                credentials,
+            }),
+            lookups: AtomicU32::new(0),
+            lookup_us: AtomicU32::new(0),
+            max_lookup_us: AtomicU32::new(0),
+            contended: AtomicU32::new(0),
+        }))
+    }
+
+    /// Copy of every code and its data
+    pub fn export(&self) -> (BTreeSet<i32>, BTreeMap<i32, Credential>) {
+        (
+            (**self.0.snapshot.load()).clone(),
+            (**self.0.credentials.load()).clone(),
+        )
     }
 
     pub fn add(&self, code: i32) -> anyhow::Result<()> {
-        let mut data = self.0.lock().unwrap();
-        data.codes.insert(code);
-        persist(&mut data)?;
-        Ok(())
+        self.update(|data| {
+            data.codes.insert(code);
+        })
     }
 
+    /// Replaces every code, the data of the codes that remain is kept
     pub fn bulk(&self, codes: Vec<i32>) -> anyhow::Result<()> {
-        let mut data = self.0.lock().unwrap();
-        data.codes = BTreeSet::from_iter(codes);
-        persist(&mut data)?;
-        Ok(())
+        self.update(|data| {
+            data.codes = BTreeSet::from_iter(codes);
+            let codes = &data.codes;
+            data.credentials.retain(|code, _| codes.contains(code));
+        })
     }
 
+    /// Replaces the code keeping its data
     pub fn replace(&self, old: i32, new: i32) -> anyhow::Result<()> {
-        let mut data = self.0.lock().unwrap();
-        data.codes.remove(&old);
-        data.codes.insert(new);
-        persist(&mut data)?;
-        Ok(())
+        self.update(|data| {
+            data.codes.remove(&old);
+            data.codes.insert(new);
+            if let Some(credential) = data.credentials.remove(&old) {
+                data.credentials.insert(new, credential);
+            }
+        })
+    }
+
+    /// Attaches data to an existing code
+    pub fn set_credential(&self, code: i32, credential: Credential) -> anyhow::Result<()> {
+        if !self.0.snapshot.load().contains(&code) {
+            anyhow::bail!("code not found");
+        }
+        self.update(|data| {
+            data.credentials.insert(code, credential);
+        })
+    }
+
+    pub fn credential(&self, code: i32) -> Option<Credential> {
+        self.0.credentials.load().get(&code).cloned()
     }
 
     pub fn contains(&self, code: i32) -> bool {
-        let data = self.0.lock().unwrap();
-        data.codes.contains(&code)
+        let start = Instant::now();
+        let found = self.0.snapshot.load().contains(&code);
+
+        let elapsed = start.elapsed().as_micros() as u32;
+        self.0.lookups.fetch_add(1, Ordering::Relaxed);
+        self.0.lookup_us.fetch_add(elapsed, Ordering::Relaxed);
+        self.0.max_lookup_us.fetch_max(elapsed, Ordering::Relaxed);
+        found
     }
 
     pub fn delete(&self, code: i32) -> anyhow::Result<()> {
-        let mut data = self.0.lock().unwrap();
-        data.codes.remove(&code);
-        persist(&mut data)?;
-        Ok(())
+        self.update(|data| {
+            data.codes.remove(&code);
+            data.credentials.remove(&code);
+        })
+    }
+
+    /// Number of codes in the database
+    pub fn count(&self) -> usize {
+        self.0.snapshot.load().len()
+    }
+
+    /// Returns the lookup metrics and starts a new interval
+    pub fn stats(&self) -> UserStats {
+        let codes = self.count();
+        let lookups = self.0.lookups.swap(0, Ordering::Relaxed);
+        let lookup_us = self.0.lookup_us.swap(0, Ordering::Relaxed);
+        UserStats {
+            codes,
+            lookups,
+            avg_us: lookup_us.checked_div(lookups).unwrap_or(0),
+            max_us: self.0.max_lookup_us.swap(0, Ordering::Relaxed),
+            contended: self.0.contended.swap(0, Ordering::Relaxed),
+        }
+    }
+
+    /// Writes the blobs again under the other slot so they are packed in the
+    /// newest pages and the pages holding the old chunks can be reclaimed.
+    /// The previous copy is only removed once the slot key points to the new
+    /// one, so it is safe on power loss.
+    pub fn rewrite(&self) -> anyhow::Result<()> {
+        let mut data = self.lock(&self.0.data);
+        let current = data.slot;
+        let next = (current + 1) % SLOTS.len() as u8;
+        // Leftovers of an interrupted rewrite would be kept as they are
+        remove_slot(&mut data, next)?;
+        write_slot(&mut data, next)?;
+        data.nvs
+            .set_u64(SLOT_KEY, next as u64)
+            .context("nvs failure")?;
+        storage::record_write(Area::UserDb, mem::size_of::<u64>());
+        data.slot = next;
+        remove_slot(&mut data, current)
+    }
+
+    /// Applies the change, persists it and then publishes the new snapshot.
+    /// Like before the change is kept in memory even if it fails to persist.
+    fn update(&self, change: impl FnOnce(&mut UserData)) -> anyhow::Result<()> {
+        let mut data = self.lock(&self.0.data);
+        change(&mut *data);
+        let result = persist(&mut data);
+        self.0.snapshot.store(Arc::new(data.codes.clone()));
+        self.0.credentials.store(Arc::new(data.credentials.clone()));
+        result
+    }
+
+    /// Locks the mutex counting how often it was already taken
+    fn lock<'a, T>(&self, mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+        match mutex.try_lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                self.0.contended.fetch_add(1, Ordering::Relaxed);
+                mutex.lock().unwrap()
+            }
+        }
     }
 }
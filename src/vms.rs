@@ -0,0 +1,67 @@
+use std::sync::mpsc::Sender;
+use std::time::SystemTime;
+
+use esp_idf_svc::sys::esp_random;
+use serde::Serialize;
+
+use crate::audit::Source;
+use crate::mqtt::Outbox;
+
+/// Why the video management system is asked for a snapshot
+#[derive(Serialize, Debug, Clone, Copy)]
+pub enum Reason {
+    Deny,
+    Duress,
+}
+
+/// Published to doorsys/vms/{device_id} so the video management system can
+/// bookmark the footage. The audit record carries the same correlation id.
+#[derive(Serialize, Debug)]
+pub struct SnapshotTrigger {
+    pub correlation_id: u32,
+    pub reason: Reason,
+    pub source: Source,
+    pub timestamp: SystemTime,
+}
+
+/// Second stage verification by the cameras on denials and duress
+pub struct Vms {
+    trigger_tx: Outbox<SnapshotTrigger>,
+    output_tx: Option<Sender<()>>,
+}
+
+impl Vms {
+    pub fn new(trigger_tx: Outbox<SnapshotTrigger>) -> Self {
+        Vms {
+            trigger_tx,
+            output_tx: None,
+        }
+    }
+
+    /// Also pulses an output wired to the camera or the recorder
+    pub fn with_output(mut self, output_tx: Sender<()>) -> Self {
+        self.output_tx = Some(output_tx);
+        self
+    }
+
+    /// Pulses the output and publishes the trigger,
+    /// returns the correlation id for the audit record
+    pub fn trigger(&self, reason: Reason, source: Source) -> u32 {
+        if let Some(output_tx) = &self.output_tx {
+            if let Err(e) = output_tx.send(()) {
+                log::error!("error pulsing vms output: {}", e);
+            }
+        }
+        let correlation_id = unsafe { esp_random() };
+        let trigger = SnapshotTrigger {
+            correlation_id,
+            reason,
+            source,
+            timestamp: SystemTime::now(),
+        };
+        if let Err(e) = self.trigger_tx.send(trigger) {
+            log::error!("error sending snapshot trigger: {}", e);
+        }
+        correlation_id
+    }
+}
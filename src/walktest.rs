@@ -0,0 +1,144 @@
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::Serialize;
+
+use crate::audit::Action;
+use crate::door::DoorState;
+use crate::feedback::Feedback;
+use crate::input::Role;
+use crate::mqtt::Outbox;
+
+/// The walk test ends on its own after this long
+const WALK_TEST_DURATION: Duration = Duration::from_secs(15 * 60);
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Anything that happened at the door during the walk test
+#[derive(Serialize, Debug, Clone, Copy)]
+pub enum Observation {
+    /// Seconds until the test ends on its own
+    Started {
+        remaining_secs: u64,
+    },
+    Ended,
+    /// The key or card read, left out in privacy mode
+    Key(Option<u8>),
+    Card(Option<i32>),
+    /// Outcome of a credential, as audited
+    Decision {
+        action: Action,
+        success: bool,
+    },
+    Input {
+        role: Role,
+        active: bool,
+    },
+    Relay(DoorState),
+}
+
+/// Published to doorsys/walktest/{device_id} for every observation
+#[derive(Serialize, Debug)]
+pub struct WalkEvent {
+    pub observation: Observation,
+    pub timestamp: SystemTime,
+}
+
+/// Installer mode where every credential read, input change and relay
+/// actuation is signaled on the buzzer and published in detail, so a whole
+/// door can be checked from the keypad. Does nothing when created with default.
+#[derive(Clone, Default)]
+pub struct WalkTest {
+    until: Arc<Mutex<Option<Instant>>>,
+    tx: Option<Outbox<WalkEvent>>,
+    feedback_tx: Option<Sender<Feedback>>,
+}
+
+impl WalkTest {
+    /// Starts the test or restarts its time if already running
+    pub fn start(&self) {
+        if self.tx.is_none() {
+            return;
+        }
+        log::info!("Walk test started");
+        *self.until.lock().unwrap() = Some(Instant::now() + WALK_TEST_DURATION);
+        self.send(Observation::Started {
+            remaining_secs: WALK_TEST_DURATION.as_secs(),
+        });
+    }
+
+    pub fn stop(&self) {
+        if self.until.lock().unwrap().take().is_some() {
+            log::info!("Walk test ended");
+            self.send(Observation::Ended);
+        }
+    }
+
+    pub fn toggle(&self) {
+        if self.active() {
+            self.stop();
+        } else {
+            self.start();
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.until
+            .lock()
+            .unwrap()
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    /// Publishes the observation while the test runs
+    pub fn observe(&self, observation: Observation) {
+        if self.active() {
+            self.send(observation);
+        }
+    }
+
+    /// Key presses already beep on the keypad, everything else gets
+    /// the walk test pattern on top of the regular feedback
+    fn send(&self, observation: Observation) {
+        log::info!("Walk test {:?}", observation);
+        if let (Some(feedback_tx), false) = (
+            &self.feedback_tx,
+            matches!(observation, Observation::Key(_)),
+        ) {
+            if let Err(e) = feedback_tx.send(Feedback::WalkTest) {
+                log::error!("error sending feedback: {}", e);
+            }
+        }
+        if let Some(tx) = &self.tx {
+            let event = WalkEvent {
+                observation,
+                timestamp: SystemTime::now(),
+            };
+            if let Err(e) = tx.send(event) {
+                log::error!("error sending walk test event: {}", e);
+            }
+        }
+    }
+}
+
+/// Creates the walk test and spawns the thread ending it once its time is up
+pub fn setup_walk_test(walk_tx: Outbox<WalkEvent>, feedback_tx: Sender<Feedback>) -> WalkTest {
+    let walk_test = WalkTest {
+        until: Arc::default(),
+        tx: Some(walk_tx),
+        feedback_tx: Some(feedback_tx),
+    };
+    let expiry = walk_test.clone();
+    thread::spawn(move || loop {
+        thread::sleep(EXPIRY_CHECK_INTERVAL);
+        let expired = expiry
+            .until
+            .lock()
+            .unwrap()
+            .is_some_and(|until| Instant::now() >= until);
+        if expired {
+            expiry.stop();
+        }
+    });
+    walk_test
+}
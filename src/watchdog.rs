@@ -0,0 +1,78 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::gpio::{AnyOutputPin, PinDriver};
+
+/// Handle given to a supervised task so it can report it is still alive
+#[derive(Clone)]
+pub struct Heartbeat(Arc<Mutex<Instant>>);
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        *self.0.lock().unwrap() = Instant::now();
+    }
+}
+
+struct Task {
+    name: &'static str,
+    timeout: Duration,
+    last_beat: Arc<Mutex<Instant>>,
+}
+
+/// Supervisor for the external hardware watchdog.
+/// The watchdog pin is only strobed while every registered task has
+/// reported a heartbeat within its timeout. If any of them gets stuck
+/// the strobing stops and the watchdog IC power cycles the board.
+#[derive(Clone, Default)]
+pub struct Watchdog(Arc<Mutex<Vec<Task>>>);
+
+impl Watchdog {
+    /// Registers a new task that must call `beat` at least once every `timeout`
+    pub fn register(&self, name: &'static str, timeout: Duration) -> Heartbeat {
+        let last_beat = Arc::new(Mutex::new(Instant::now()));
+        self.0.lock().unwrap().push(Task {
+            name,
+            timeout,
+            last_beat: last_beat.clone(),
+        });
+        Heartbeat(last_beat)
+    }
+
+    /// Returns the name of the first task that missed its heartbeat
+    fn stalled_task(&self) -> Option<&'static str> {
+        let tasks = self.0.lock().unwrap();
+        tasks
+            .iter()
+            .find(|task| task.last_beat.lock().unwrap().elapsed() > task.timeout)
+            .map(|task| task.name)
+    }
+
+    /// Spawns the thread that strobes the watchdog pin every `period`
+    pub fn start(&self, pin: AnyOutputPin, period: Duration) -> anyhow::Result<()> {
+        let mut driver = PinDriver::output(pin)?;
+        let watchdog = self.clone();
+
+        thread::spawn(move || {
+            let mut stalled = false;
+            loop {
+                match watchdog.stalled_task() {
+                    Some(name) => {
+                        if !stalled {
+                            log::error!("task {} stopped responding, halting watchdog", name);
+                            stalled = true;
+                        }
+                    }
+                    None => {
+                        if let Err(e) = driver.toggle() {
+                            log::warn!("error strobing watchdog: {}", e);
+                        }
+                    }
+                }
+                thread::sleep(period);
+            }
+        });
+
+        Ok(())
+    }
+}
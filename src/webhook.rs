@@ -0,0 +1,129 @@
+use std::sync::mpsc::{Receiver, Sender};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+
+use crate::config::WebhookConfig;
+use crate::http_client;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Events that can be forwarded to the webhook
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Kind {
+    /// Credential rejected
+    Deny,
+    /// Door opened under duress
+    Duress,
+    /// Door left open for too long
+    HeldOpen,
+    /// Enclosure or wiring tampered with
+    Tamper,
+    /// Door opened while locked, without a grant or exit request
+    ForcedOpen,
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::Deny => "deny",
+            Kind::Duress => "duress",
+            Kind::HeldOpen => "held-open",
+            Kind::Tamper => "tamper",
+            Kind::ForcedOpen => "forced-open",
+        }
+    }
+}
+
+pub struct Event {
+    kind: Kind,
+    detail: String,
+    timestamp: SystemTime,
+}
+
+/// Handle used by the subsystems to fire webhooks.
+/// Does nothing when webhooks are disabled or the kind was not selected.
+#[derive(Clone, Default)]
+pub struct Notifier {
+    events: Vec<Kind>,
+    tx: Option<Sender<Event>>,
+}
+
+impl Notifier {
+    pub fn new(config: &WebhookConfig, tx: Sender<Event>) -> Self {
+        Notifier {
+            events: config.events.clone(),
+            tx: Some(tx),
+        }
+    }
+
+    pub fn notify(&self, kind: Kind, detail: impl Into<String>) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        if !self.events.contains(&kind) {
+            return;
+        }
+        let event = Event {
+            kind,
+            detail: detail.into(),
+            timestamp: SystemTime::now(),
+        };
+        if let Err(e) = tx.send(event) {
+            log::error!("error sending webhook event: {}", e);
+        }
+    }
+}
+
+/// Spawns the thread that posts the events to the webhook url as json,
+/// retrying with an exponential backoff when the request fails
+pub fn setup_webhook(device_id: &str, config: &WebhookConfig, rx: Receiver<Event>) {
+    let device_id = device_id.to_owned();
+    let url = config.url.clone();
+    let authorization = config.authorization.clone();
+    let retries = config.retries;
+    thread::spawn(move || {
+        for event in rx {
+            let timestamp = event
+                .timestamp
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs());
+            let body = format!(
+                r#"{{"device":"{}","event":"{}","detail":"{}","timestamp":{}}}"#,
+                escape(&device_id),
+                event.kind.name(),
+                escape(&event.detail),
+                timestamp
+            );
+            let mut backoff = INITIAL_BACKOFF;
+            for attempt in 0..=retries {
+                match http_client::post(&url, "application/json", authorization.as_deref(), &body) {
+                    Ok(()) => break,
+                    Err(e) => {
+                        log::warn!("webhook attempt {} failed: {}", attempt + 1, e);
+                        if attempt < retries {
+                            thread::sleep(backoff);
+                            backoff *= 2;
+                        }
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Escapes a string to be embedded in json
+pub fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
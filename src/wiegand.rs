@@ -4,39 +4,126 @@ use std::{
     marker::PhantomPinned,
     pin::Pin,
     ptr,
-    sync::mpsc::{self, Receiver, Sender},
+    sync::atomic::{AtomicU32, Ordering},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    thread,
+    time::{Duration, Instant},
 };
 
 use esp_idf_svc::{
-    hal::gpio::InputPin,
+    hal::{gpio::InputPin, task::thread::ThreadSpawnConfiguration},
     sys::{
-        esp, esp_timer_create, esp_timer_create_args_t, esp_timer_delete,
-        esp_timer_dispatch_t_ESP_TIMER_TASK, esp_timer_handle_t, esp_timer_start_once,
-        esp_timer_stop, gpio_config, gpio_config_t, gpio_get_level,
-        gpio_int_type_t_GPIO_INTR_DISABLE, gpio_int_type_t_GPIO_INTR_NEGEDGE, gpio_isr_handler_add,
+        eNotifyAction_eIncrement, esp, esp_timer_create, esp_timer_create_args_t, esp_timer_delete,
+        esp_timer_dispatch_t_ESP_TIMER_ISR, esp_timer_dispatch_t_ESP_TIMER_TASK,
+        esp_timer_get_time, esp_timer_handle_t, esp_timer_isr_dispatch_need_yield,
+        esp_timer_start_once, esp_timer_stop, gpio_config, gpio_config_t, gpio_get_level,
+        gpio_install_isr_service, gpio_int_type_t, gpio_int_type_t_GPIO_INTR_DISABLE,
+        gpio_int_type_t_GPIO_INTR_NEGEDGE, gpio_int_type_t_GPIO_INTR_POSEDGE, gpio_isr_handler_add,
         gpio_isr_handler_remove, gpio_mode_t_GPIO_MODE_INPUT, gpio_reset_pin, gpio_set_intr_type,
+        ulTaskGenericNotifyTake, xTaskGenericNotifyFromISR, xTaskGetCurrentTaskHandle,
+        TaskHandle_t, ESP_ERR_INVALID_STATE, ESP_INTR_FLAG_IRAM,
     },
 };
 
+use serde::{Deserialize, Serialize};
+
+use crate::mqtt::Outbox;
+use crate::privacy::Redacted;
+
 const WIEGAND_TIMEOUT: u64 = 50000; // 50ms
 const BUFFER_SIZE: usize = 4;
+/// Above every application task but below the esp_timer task itself
+const COMPLETION_PRIORITY: u8 = 20;
+const COMPLETION_STACK_SIZE: usize = 4096;
+/// Gpio registers of the esp32c3. The status register latches every edge
+/// of a pin with its interrupt enabled until the interrupt service clears it.
+const GPIO_STATUS_REG: usize = 0x6000_4044;
+const GPIO_PIN0_REG: usize = 0x6000_4074;
+const GPIO_PIN_INT_TYPE_SHIFT: u32 = 7;
+const GPIO_PIN_INT_TYPE_MASK: u32 = 0x7;
+/// Far longer than the interrupt service takes to clear a pending edge
+/// or the frame completion to enable the interrupts again
+const ISR_GRACE: Duration = Duration::from_millis(20);
+
+// Interrupt statistics. The statics live in dram and are only written from
+// the interrupt, so plain loads and stores are enough and no atomic
+// read-modify-write helper has to be called from the iram handler.
+static ISR_CALLS: AtomicU32 = AtomicU32::new(0);
+static ISR_SPURIOUS: AtomicU32 = AtomicU32::new(0);
+static ISR_OVERFLOWS: AtomicU32 = AtomicU32::new(0);
+static MAX_EDGE_GAP_US: AtomicU32 = AtomicU32::new(0);
+/// Only written by the frame completion, which never runs concurrently
+static DUPLICATES: AtomicU32 = AtomicU32::new(0);
+
+#[inline(always)]
+fn increment(counter: &AtomicU32) {
+    counter.store(
+        counter.load(Ordering::Relaxed).wrapping_add(1),
+        Ordering::Relaxed,
+    );
+}
+
+/// Interrupt counters since boot and the longest gap between two edges
+/// of the same frame since the last call. A gap much longer than the
+/// 1 to 2ms a reader takes per bit means the interrupt was delayed.
+#[derive(Debug)]
+pub struct IsrStats {
+    pub calls: u32,
+    /// Interrupts with both lines at the same level
+    pub spurious: u32,
+    /// Edges received after the buffer was full
+    pub overflows: u32,
+    pub max_edge_gap_us: u32,
+    /// Frames dropped as a repeat of the previous one
+    pub duplicates: u32,
+}
+
+impl IsrStats {
+    /// Reads the counters and restarts the max edge gap
+    pub fn take() -> Self {
+        IsrStats {
+            calls: ISR_CALLS.load(Ordering::Relaxed),
+            spurious: ISR_SPURIOUS.load(Ordering::Relaxed),
+            overflows: ISR_OVERFLOWS.load(Ordering::Relaxed),
+            max_edge_gap_us: MAX_EDGE_GAP_US.swap(0, Ordering::Relaxed),
+            duplicates: DUPLICATES.load(Ordering::Relaxed),
+        }
+    }
+}
 
 #[link_section = ".iram0.text"]
 unsafe extern "C" fn wiegand_interrupt<D0: InputPin, D1: InputPin>(arg: *mut c_void) {
     let reader = &mut *(arg as *mut Reader<D0, D1>);
+    increment(&ISR_CALLS);
     let d0 = gpio_get_level(reader.d0_gpio.pin());
     let d1 = gpio_get_level(reader.d1_gpio.pin());
     if d0 == d1 {
+        increment(&ISR_SPURIOUS);
         return;
     }
     // Overflow
-    if reader.bits > reader.data.len() * 8 {
+    if reader.bits >= reader.data.len() * 8 {
+        increment(&ISR_OVERFLOWS);
         return;
     }
 
     esp_timer_stop(reader.timer);
 
-    let value = if d0 == 0 { 0 } else { 0x80 };
+    let now = esp_timer_get_time();
+    if reader.last_edge != 0 {
+        let gap = (now - reader.last_edge) as u32;
+        if gap > MAX_EDGE_GAP_US.load(Ordering::Relaxed) {
+            MAX_EDGE_GAP_US.store(gap, Ordering::Relaxed);
+        }
+    }
+    reader.last_edge = now;
+
+    if reader.timing_tx.is_some() {
+        reader.timing.record(now);
+    }
+
+    // A pulse on d0 is a zero
+    let value = if d0 == reader.active_level { 0 } else { 0x80 };
     reader.data[reader.bits / 8] |= value >> (reader.bits % 8);
     reader.bits += 1;
 
@@ -45,14 +132,208 @@ unsafe extern "C" fn wiegand_interrupt<D0: InputPin, D1: InputPin>(arg: *mut c_v
 
 unsafe extern "C" fn timer_interrupt<D0: InputPin, D1: InputPin>(arg: *mut c_void) {
     let reader = &mut *(arg as *mut Reader<D0, D1>);
-    reader.stop();
+    reader.complete();
+}
+
+/// Timer callback dispatched from the isr, it only wakes the completion task
+#[link_section = ".iram0.text"]
+unsafe extern "C" fn timer_isr<D0: InputPin, D1: InputPin>(arg: *mut c_void) {
+    let reader = &*(arg as *const Reader<D0, D1>);
+    let mut woken = 0;
+    xTaskGenericNotifyFromISR(
+        reader.completion_task,
+        0,
+        0,
+        eNotifyAction_eIncrement,
+        ptr::null_mut(),
+        &mut woken,
+    );
+    if woken != 0 {
+        esp_timer_isr_dispatch_need_yield();
+    }
+}
+
+/// Where the frame is finalized once the lines go quiet
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Completion {
+    /// Shared esp_timer task, competing with every other timer callback
+    #[default]
+    Timer,
+    /// The timer fires from the isr and wakes a dedicated high priority task.
+    /// Requires CONFIG_ESP_TIMER_SUPPORTS_ISR_DISPATCH_METHOD.
+    Task,
+}
+
+/// Internal resistor applied to the data lines
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LinePull {
+    #[default]
+    Up,
+    Down,
+    /// For readers with push-pull outputs or external resistors
+    None,
+}
+
+/// Edge that starts a pulse on the data lines
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Edge {
+    /// Lines idle high and pulse low, as in the standard
+    #[default]
+    Negedge,
+    /// Lines idle low and pulse high, found on inverted readers
+    Posedge,
+}
+
+impl Edge {
+    fn intr_type(&self) -> gpio_int_type_t {
+        match self {
+            Edge::Negedge => gpio_int_type_t_GPIO_INTR_NEGEDGE,
+            Edge::Posedge => gpio_int_type_t_GPIO_INTR_POSEDGE,
+        }
+    }
+
+    /// Level of the line during a pulse
+    fn active_level(&self) -> i32 {
+        match self {
+            Edge::Negedge => 0,
+            Edge::Posedge => 1,
+        }
+    }
+
+    /// Level of the line between pulses
+    pub fn idle_level(&self) -> i32 {
+        1 - self.active_level()
+    }
+}
+
+/// Edge timestamps of the frame being received, in microseconds
+struct Timing {
+    first: i64,
+    last: i64,
+    min_gap: i64,
+    max_gap: i64,
+}
+
+impl Timing {
+    const fn new() -> Self {
+        Timing {
+            first: 0,
+            last: 0,
+            min_gap: i64::MAX,
+            max_gap: 0,
+        }
+    }
+
+    /// Called from the interrupt for every bit, inlined so it runs from iram
+    #[inline(always)]
+    fn record(&mut self, now: i64) {
+        if self.first == 0 {
+            self.first = now;
+        } else {
+            let gap = now - self.last;
+            self.min_gap = self.min_gap.min(gap);
+            self.max_gap = self.max_gap.max(gap);
+        }
+        self.last = now;
+    }
+
+    fn summary(&self, bits: usize) -> FrameTiming {
+        let duration_us = (self.last - self.first) as u32;
+        let gaps = bits.saturating_sub(1) as u32;
+        FrameTiming {
+            bits,
+            min_gap_us: if gaps == 0 { 0 } else { self.min_gap as u32 },
+            avg_gap_us: duration_us.checked_div(gaps).unwrap_or(0),
+            max_gap_us: self.max_gap as u32,
+            duration_us,
+        }
+    }
+}
+
+/// Bit timing of the last frame, used to diagnose long or noisy cables.
+/// Readers usually send a bit every 1 to 2ms.
+#[derive(Serialize, Debug)]
+pub struct FrameTiming {
+    pub bits: usize,
+    pub min_gap_us: u32,
+    pub avg_gap_us: u32,
+    pub max_gap_us: u32,
+    /// Time between the first and last bit
+    pub duration_us: u32,
+}
 
-    let packet = Packet::new(reader.bits, reader.data);
+/// Unknown packets aggregated over a reporting window
+#[derive(Serialize, Debug)]
+pub struct UnknownReport {
+    pub count: u32,
+    /// Bit length of the last unknown packet
+    pub last_bits: usize,
+    pub window_secs: u64,
+}
+
+/// Rate limits the reports of unknown packets so a noisy line
+/// doesn't flood the logs and the broker
+pub struct UnknownPackets {
+    window: Duration,
+    started: Option<Instant>,
+    count: u32,
+    last_bits: usize,
+    report_tx: Outbox<UnknownReport>,
+}
 
-    if let Err(e) = reader.reader_tx.send(packet) {
-        log::error!("send error {}", e);
+impl UnknownPackets {
+    pub fn new(window: Duration, report_tx: Outbox<UnknownReport>) -> Self {
+        UnknownPackets {
+            window,
+            started: None,
+            count: 0,
+            last_bits: 0,
+            report_tx,
+        }
+    }
+
+    /// Only the first packet of each window is logged in full
+    pub fn record(&mut self, bits: usize, data: [u8; BUFFER_SIZE]) {
+        if self.started.is_none() {
+            log::warn!(
+                "pattern not recognized bits: {}, data: {:02X?}",
+                bits,
+                Redacted(data)
+            );
+            self.started = Some(Instant::now());
+        }
+        self.count += 1;
+        self.last_bits = bits;
+    }
+
+    /// Sends the aggregated report once the window is over,
+    /// it must be called periodically
+    pub fn flush(&mut self) {
+        if !self
+            .started
+            .is_some_and(|started| started.elapsed() >= self.window)
+        {
+            return;
+        }
+        log::warn!(
+            "{} unknown packets in the last {}s",
+            self.count,
+            self.window.as_secs()
+        );
+        let report = UnknownReport {
+            count: self.count,
+            last_bits: self.last_bits,
+            window_secs: self.window.as_secs(),
+        };
+        if let Err(e) = self.report_tx.send(report) {
+            log::error!("error sending unknown packet report: {}", e);
+        }
+        self.started = None;
+        self.count = 0;
     }
-    reader.reset();
 }
 
 /// Check parity bits 25 (even) and 0 (odd)
@@ -103,7 +384,11 @@ pub enum Packet {
 
 impl Packet {
     fn new(bits: usize, data: [u8; BUFFER_SIZE]) -> Self {
-        log::info!("data received; bits: {}, data: {:02X?}", bits, data);
+        log::info!(
+            "data received; bits: {}, data: {:02X?}",
+            bits,
+            Redacted(data)
+        );
         match bits {
             4 => Self::Key { key: data[0] >> 4 },
             26 => {
@@ -133,6 +418,114 @@ impl Packet {
     }
 }
 
+/// Line and frame options of the reader
+#[derive(Clone)]
+pub struct ReaderOptions {
+    pub pull: LinePull,
+    pub edge: Edge,
+    pub completion: Completion,
+    /// A frame identical to the previous one within the window is dropped,
+    /// zero disables it
+    pub dedup_window: Duration,
+    /// Receives the bit timing of every frame when present
+    pub timing_tx: Option<Outbox<FrameTiming>>,
+}
+
+impl Default for ReaderOptions {
+    fn default() -> Self {
+        ReaderOptions {
+            pull: LinePull::default(),
+            edge: Edge::default(),
+            completion: Completion::default(),
+            dedup_window: Duration::from_millis(100),
+            timing_tx: None,
+        }
+    }
+}
+
+/// Outcome of the interrupt self-check
+#[derive(Debug)]
+pub enum IsrCheck {
+    Healthy,
+    /// The handlers were registered again
+    Reregistered(IsrFault),
+}
+
+/// Why the interrupts were found stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsrFault {
+    /// An edge stayed latched without the interrupt service clearing it
+    Pending,
+    /// The interrupt of a line stayed disabled with no frame being completed
+    Disabled,
+}
+
+fn gpio_status() -> u32 {
+    unsafe { ptr::read_volatile(GPIO_STATUS_REG as *const u32) }
+}
+
+fn gpio_intr_enabled(pin: i32) -> bool {
+    let reg = (GPIO_PIN0_REG + 4 * pin as usize) as *const u32;
+    let value = unsafe { ptr::read_volatile(reg) };
+    (value >> GPIO_PIN_INT_TYPE_SHIFT) & GPIO_PIN_INT_TYPE_MASK != 0
+}
+
+/// Packets received by an initialized reader. The reader stays
+/// registered with the interrupts for as long as this is alive.
+pub struct Packets<D0: InputPin, D1: InputPin> {
+    reader: Pin<Box<Reader<D0, D1>>>,
+    reader_rx: Receiver<Packet>,
+}
+
+impl<D0: InputPin, D1: InputPin> Packets<D0, D1> {
+    /// Waits for the next packet up to `timeout`
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Packet, RecvTimeoutError> {
+        self.reader_rx.recv_timeout(timeout)
+    }
+
+    /// Checks the gpio peripheral, which keeps latching the edges of the
+    /// lines whatever happens to the handlers. An edge still pending after
+    /// the grace period was never serviced and an interrupt left disabled
+    /// outside a frame completion never fires again. Either way the handlers
+    /// are registered again, installing the interrupt service if it is
+    /// missing. It only takes a few register reads, the lines aren't sampled.
+    pub fn check_interrupts(&mut self) -> anyhow::Result<IsrCheck> {
+        let Some(fault) = self.fault() else {
+            return Ok(IsrCheck::Healthy);
+        };
+        // Both are fleeting while the interrupts work
+        thread::sleep(ISR_GRACE);
+        if self.fault() != Some(fault) {
+            return Ok(IsrCheck::Healthy);
+        }
+        log::warn!("Reader interrupts stopped ({:?}), registering again", fault);
+        let reader = unsafe { self.reader.as_mut().get_unchecked_mut() };
+        reader.reregister()?;
+        Ok(IsrCheck::Reregistered(fault))
+    }
+
+    fn fault(&self) -> Option<IsrFault> {
+        let (d0, d1) = (self.reader.d0_gpio.pin(), self.reader.d1_gpio.pin());
+        let pending = gpio_status() & (1 << d0 | 1 << d1);
+        if pending != 0 {
+            Some(IsrFault::Pending)
+        } else if !gpio_intr_enabled(d0) || !gpio_intr_enabled(d1) {
+            Some(IsrFault::Disabled)
+        } else {
+            None
+        }
+    }
+}
+
+impl<D0: InputPin, D1: InputPin> Iterator for Packets<D0, D1> {
+    type Item = Packet;
+
+    /// Blocks until the next packet arrives
+    fn next(&mut self) -> Option<Packet> {
+        self.reader_rx.recv().ok()
+    }
+}
+
 /// Wiegand reader
 /// This is the implementation the wiegand protocol using 2 gpio pins.
 /// The interrupt service must be installed as this code relies on interrupts
@@ -143,9 +536,7 @@ impl Packet {
 /// // Installs the generic GPIO interrupt handler
 /// esp!(unsafe { gpio_install_isr_service(ESP_INTR_FLAG_IRAM as i32) })?;
 ///
-/// let (_reader, channel) = Reader::new(d0, d1)?;
-/// loop {
-///     let packet = channel.recv()?;
+/// for packet in Reader::new(d0, d1, ReaderOptions::default())? {
 ///     // proccess packet
 /// }
 ///
@@ -157,11 +548,30 @@ pub struct Reader<D0: InputPin, D1: InputPin> {
     d1_gpio: D1,
     timer: esp_timer_handle_t,
     reader_tx: Sender<Packet>,
+    pull: LinePull,
+    intr_type: gpio_int_type_t,
+    active_level: i32,
+    completion: Completion,
+    /// Task woken by the timer when the completion runs in a dedicated task
+    completion_task: TaskHandle_t,
+    timing: Timing,
+    timing_tx: Option<Outbox<FrameTiming>>,
+    /// Time of the last edge of the current frame
+    last_edge: i64,
+    /// Identical frames completed within the window are sent once
+    dedup_window: Duration,
+    last_frame: Option<(usize, [u8; BUFFER_SIZE], Instant)>,
     _marker: PhantomPinned,
 }
 
 impl<D0: InputPin, D1: InputPin> Reader<D0, D1> {
-    pub fn new(d0_gpio: D0, d1_gpio: D1) -> anyhow::Result<(Pin<Box<Self>>, Receiver<Packet>)> {
+    /// Creates the reader with its interrupts registered and
+    /// returns the packets it receives
+    pub fn new(
+        d0_gpio: D0,
+        d1_gpio: D1,
+        options: ReaderOptions,
+    ) -> anyhow::Result<Packets<D0, D1>> {
         let (reader_tx, reader_rx) = mpsc::channel();
         let reader = Reader {
             d0_gpio,
@@ -170,22 +580,48 @@ impl<D0: InputPin, D1: InputPin> Reader<D0, D1> {
             bits: 0,
             timer: ptr::null_mut(),
             reader_tx,
+            pull: options.pull,
+            intr_type: options.edge.intr_type(),
+            active_level: options.edge.active_level(),
+            completion: options.completion,
+            completion_task: ptr::null_mut(),
+            timing: Timing::new(),
+            timing_tx: options.timing_tx,
+            last_edge: 0,
+            dedup_window: options.dedup_window,
+            last_frame: None,
             _marker: PhantomPinned,
         };
         let mut boxed = Box::pin(reader);
         let reader_ref = unsafe { boxed.as_mut().get_unchecked_mut() };
         Reader::init(reader_ref)?;
-        Ok((boxed, reader_rx))
+        Ok(Packets {
+            reader: boxed,
+            reader_rx,
+        })
     }
 
     fn init(&mut self) -> anyhow::Result<()> {
         let reader_ptr = self as *mut _ as *mut c_void;
 
+        let (callback, dispatch_method) = match self.completion {
+            Completion::Timer => (
+                timer_interrupt::<D0, D1> as unsafe extern "C" fn(*mut c_void),
+                esp_timer_dispatch_t_ESP_TIMER_TASK,
+            ),
+            Completion::Task => {
+                self.completion_task = self.spawn_completion_task()?;
+                (
+                    timer_isr::<D0, D1> as unsafe extern "C" fn(*mut c_void),
+                    esp_timer_dispatch_t_ESP_TIMER_ISR,
+                )
+            }
+        };
         let timer_config = esp_timer_create_args_t {
             name: CString::new("wiegand")?.into_raw(),
             arg: reader_ptr,
-            callback: Some(timer_interrupt::<D0, D1>),
-            dispatch_method: esp_timer_dispatch_t_ESP_TIMER_TASK,
+            callback: Some(callback),
+            dispatch_method,
             skip_unhandled_events: true,
         };
 
@@ -195,17 +631,22 @@ impl<D0: InputPin, D1: InputPin> Reader<D0, D1> {
         let io_conf = gpio_config_t {
             pin_bit_mask: (1 << self.d0_gpio.pin() | 1 << self.d1_gpio.pin()),
             mode: gpio_mode_t_GPIO_MODE_INPUT,
-            pull_up_en: true.into(),
-            pull_down_en: false.into(),
-            intr_type: gpio_int_type_t_GPIO_INTR_NEGEDGE,
+            pull_up_en: matches!(self.pull, LinePull::Up).into(),
+            pull_down_en: matches!(self.pull, LinePull::Down).into(),
+            intr_type: self.intr_type,
         };
 
-        unsafe {
-            // Writes the configuration to the registers
-            esp!(gpio_config(&io_conf))?;
+        // Writes the configuration to the registers
+        esp!(unsafe { gpio_config(&io_conf) })?;
+
+        self.register()
+    }
 
-            // Registers our function with the generic GPIO interrupt handler
-            // This assumes gpio_install_isr_service was called before
+    /// Registers our function with the generic GPIO interrupt handler
+    /// This assumes gpio_install_isr_service was called before
+    fn register(&mut self) -> anyhow::Result<()> {
+        let reader_ptr = self as *mut _ as *mut c_void;
+        unsafe {
             esp!(gpio_isr_handler_add(
                 self.d0_gpio.pin(),
                 Some(wiegand_interrupt::<D0, D1>),
@@ -217,10 +658,90 @@ impl<D0: InputPin, D1: InputPin> Reader<D0, D1> {
                 reader_ptr
             ))?;
         }
+        Ok(())
+    }
 
+    /// Drops any partial frame and registers the handlers from scratch,
+    /// installing the interrupt service first when it is gone
+    fn reregister(&mut self) -> anyhow::Result<()> {
+        self.stop();
+        unsafe {
+            gpio_isr_handler_remove(self.d0_gpio.pin());
+            gpio_isr_handler_remove(self.d1_gpio.pin());
+        }
+        let installed = unsafe { gpio_install_isr_service(ESP_INTR_FLAG_IRAM as i32) };
+        // Invalid state means the service was still installed
+        if installed != ESP_ERR_INVALID_STATE as i32 {
+            esp!(installed)?;
+            log::warn!("GPIO interrupt service installed again");
+        }
+        self.register()?;
+        self.reset();
         Ok(())
     }
 
+    /// Starts the task that finalizes the frames woken by the timer isr.
+    /// It blocks forever once the reader is dropped since the timer
+    /// is stopped, so it never touches the reader again.
+    fn spawn_completion_task(&mut self) -> anyhow::Result<TaskHandle_t> {
+        // Raw pointers aren't Send, the reader is pinned so the address is stable
+        let reader_addr = self as *mut Self as usize;
+        let (handle_tx, handle_rx) = mpsc::channel();
+        ThreadSpawnConfiguration {
+            priority: COMPLETION_PRIORITY,
+            ..Default::default()
+        }
+        .set()?;
+        let spawned = thread::Builder::new()
+            .stack_size(COMPLETION_STACK_SIZE)
+            .spawn(move || {
+                let handle = unsafe { xTaskGetCurrentTaskHandle() };
+                handle_tx.send(handle as usize).unwrap();
+                loop {
+                    if unsafe { ulTaskGenericNotifyTake(0, 1, u32::MAX) } > 0 {
+                        let reader = unsafe { &mut *(reader_addr as *mut Self) };
+                        reader.complete();
+                    }
+                }
+            });
+        ThreadSpawnConfiguration::default().set()?;
+        spawned?;
+        Ok(handle_rx.recv()? as TaskHandle_t)
+    }
+
+    /// Sends the frame received once the lines go quiet
+    fn complete(&mut self) {
+        self.stop();
+
+        if let Some(timing_tx) = &self.timing_tx {
+            if let Err(e) = timing_tx.send(self.timing.summary(self.bits)) {
+                log::error!("send error {}", e);
+            }
+        }
+
+        if self.is_repeated() {
+            log::debug!("Dropping repeated frame of {} bits", self.bits);
+            increment(&DUPLICATES);
+        } else {
+            let packet = Packet::new(self.bits, self.data);
+            if let Err(e) = self.reader_tx.send(packet) {
+                log::error!("send error {}", e);
+            }
+        }
+        self.reset();
+    }
+
+    /// Some readers send the same frame twice back to back. The window
+    /// restarts with every repeat so a burst collapses to one packet.
+    fn is_repeated(&mut self) -> bool {
+        let now = Instant::now();
+        let repeated = self.last_frame.is_some_and(|(bits, data, completed)| {
+            bits == self.bits && data == self.data && now - completed < self.dedup_window
+        });
+        self.last_frame = Some((self.bits, self.data, now));
+        repeated
+    }
+
     fn stop(&mut self) {
         unsafe {
             esp_timer_stop(self.timer);
@@ -231,11 +752,13 @@ impl<D0: InputPin, D1: InputPin> Reader<D0, D1> {
 
     fn reset(&mut self) {
         unsafe {
-            gpio_set_intr_type(self.d0_gpio.pin(), gpio_int_type_t_GPIO_INTR_NEGEDGE);
-            gpio_set_intr_type(self.d1_gpio.pin(), gpio_int_type_t_GPIO_INTR_NEGEDGE);
+            gpio_set_intr_type(self.d0_gpio.pin(), self.intr_type);
+            gpio_set_intr_type(self.d1_gpio.pin(), self.intr_type);
         }
         self.data = [0; BUFFER_SIZE];
         self.bits = 0;
+        self.timing = Timing::new();
+        self.last_edge = 0;
     }
 }
 
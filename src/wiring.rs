@@ -0,0 +1,204 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use esp_idf_svc::sys::gpio_get_level;
+use serde::Serialize;
+
+use crate::alarm::Alarm;
+use crate::door::DoorCommand;
+use crate::feedback::Feedback;
+use crate::input::Role;
+use crate::mqtt::Outbox;
+
+/// Pause after each output so it can be seen or heard on site
+const STEP_DELAY: Duration = Duration::from_secs(2);
+
+/// Outputs pulsed by the wiring test, in this order
+#[derive(Serialize, Debug, Clone, Copy)]
+pub enum Output {
+    Relay,
+    Buzzer,
+    Chime,
+    Grant,
+    /// Every floor output of the elevator bank at once
+    Floors,
+    /// Reader led
+    Led,
+    /// Local alarm sounder
+    Sounder,
+}
+
+/// Level read from a configured input
+#[derive(Serialize, Debug)]
+pub struct InputLevel {
+    pub role: Role,
+    pub pin: i32,
+    pub active: bool,
+}
+
+/// Result of the wiring test published to doorsys/wiring/{device_id}
+#[derive(Serialize, Debug)]
+pub struct WiringReport {
+    /// Outputs pulsed, the ones not configured are skipped
+    pub pulsed: Vec<Output>,
+    pub inputs: Vec<InputLevel>,
+    /// Raw levels of the d0 and d1 reader lines
+    pub reader: [i32; 2],
+    pub timestamp: SystemTime,
+}
+
+/// Input wired to the controller, read without taking over its pin
+#[derive(Debug, Clone, Copy)]
+pub struct InputLine {
+    pub role: Role,
+    pub pin: i32,
+    pub active_low: bool,
+}
+
+/// Everything driven or read by the installer wiring test
+#[derive(Clone)]
+pub struct WiringTest {
+    door_tx: Sender<DoorCommand>,
+    feedback_tx: Sender<Feedback>,
+    chime_tx: Option<Sender<()>>,
+    grant_tx: Option<Sender<()>>,
+    floor_tx: Option<Sender<u16>>,
+    led_tx: Option<Sender<()>>,
+    alarm: Option<Alarm>,
+    inputs: Vec<InputLine>,
+    reader_pins: [i32; 2],
+    report_tx: Outbox<WiringReport>,
+    running: Arc<AtomicBool>,
+}
+
+impl WiringTest {
+    pub fn new(
+        door_tx: Sender<DoorCommand>,
+        feedback_tx: Sender<Feedback>,
+        reader_pins: [i32; 2],
+        report_tx: Outbox<WiringReport>,
+    ) -> Self {
+        WiringTest {
+            door_tx,
+            feedback_tx,
+            chime_tx: None,
+            grant_tx: None,
+            floor_tx: None,
+            led_tx: None,
+            alarm: None,
+            inputs: Vec::new(),
+            reader_pins,
+            report_tx,
+            running: Arc::default(),
+        }
+    }
+
+    pub fn with_chime(mut self, chime_tx: Option<Sender<()>>) -> Self {
+        self.chime_tx = chime_tx;
+        self
+    }
+
+    pub fn with_grant_output(mut self, grant_tx: Option<Sender<()>>) -> Self {
+        self.grant_tx = grant_tx;
+        self
+    }
+
+    pub fn with_floor_outputs(mut self, floor_tx: Option<Sender<u16>>) -> Self {
+        self.floor_tx = floor_tx;
+        self
+    }
+
+    pub fn with_led(mut self, led_tx: Option<Sender<()>>) -> Self {
+        self.led_tx = led_tx;
+        self
+    }
+
+    pub fn with_sounder(mut self, alarm: Alarm) -> Self {
+        self.alarm = Some(alarm);
+        self
+    }
+
+    pub fn with_inputs(mut self, inputs: Vec<InputLine>) -> Self {
+        self.inputs = inputs;
+        self
+    }
+
+    /// Pulses every output one after the other in the background and then
+    /// reports the input levels. Ignored while a test is already running.
+    pub fn run(&self) {
+        if self.running.swap(true, Ordering::SeqCst) {
+            log::warn!("Wiring test already running");
+            return;
+        }
+        let test = self.clone();
+        thread::spawn(move || {
+            let report = test.sequence();
+            log::info!("Wiring test finished {:?}", report);
+            if let Err(e) = test.report_tx.send(report) {
+                log::error!("error sending wiring report: {}", e);
+            }
+            test.running.store(false, Ordering::SeqCst);
+        });
+    }
+
+    fn sequence(&self) -> WiringReport {
+        let mut pulsed = Vec::new();
+        let mut step = |output, sent: bool| {
+            if sent {
+                log::info!("Wiring test pulsed {:?}", output);
+                pulsed.push(output);
+                thread::sleep(STEP_DELAY);
+            }
+        };
+        step(Output::Relay, self.door_tx.send(DoorCommand::Open).is_ok());
+        step(
+            Output::Buzzer,
+            self.feedback_tx.send(Feedback::Grant).is_ok(),
+        );
+        step(
+            Output::Chime,
+            self.chime_tx.as_ref().is_some_and(|tx| tx.send(()).is_ok()),
+        );
+        step(
+            Output::Grant,
+            self.grant_tx.as_ref().is_some_and(|tx| tx.send(()).is_ok()),
+        );
+        step(
+            Output::Floors,
+            self.floor_tx
+                .as_ref()
+                .is_some_and(|tx| tx.send(u16::MAX).is_ok()),
+        );
+        step(
+            Output::Led,
+            self.led_tx.as_ref().is_some_and(|tx| tx.send(()).is_ok()),
+        );
+        step(
+            Output::Sounder,
+            self.alarm.as_ref().is_some_and(Alarm::test_sounder),
+        );
+
+        let inputs = self
+            .inputs
+            .iter()
+            .map(|line| {
+                let level = unsafe { gpio_get_level(line.pin) };
+                InputLevel {
+                    role: line.role,
+                    pin: line.pin,
+                    active: (level == 0) == line.active_low,
+                }
+            })
+            .collect();
+        let reader = self.reader_pins.map(|pin| unsafe { gpio_get_level(pin) });
+        WiringReport {
+            pulsed,
+            inputs,
+            reader,
+            timestamp: SystemTime::now(),
+        }
+    }
+}
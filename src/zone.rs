@@ -0,0 +1,100 @@
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use esp_idf_svc::mqtt::client::QoS;
+use serde::{Deserialize, Serialize};
+
+use crate::auth;
+use crate::config::ZoneConfig;
+use crate::crypto::Secret;
+use crate::mqtt::{self, MqttClient};
+
+/// Alerts of this controller that request the lockdown of its zone
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+    Duress,
+    ForcedOpen,
+}
+
+/// Published to doorsys/zone/{zone_id}, signed the same way as the commands
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ZoneLockdown {
+    /// Controller that raised the alert, it ignores its own request
+    pub device: String,
+    pub trigger: Trigger,
+}
+
+pub fn zone_topic(zone_id: &str) -> String {
+    mqtt::topic(&format!("zone/{zone_id}"))
+}
+
+/// Handle used by the duress pins and the alarms to lock down the zone.
+/// Does nothing when created with default or the trigger was not selected.
+#[derive(Clone, Default)]
+pub struct Zone {
+    triggers: Vec<Trigger>,
+    tx: Option<Sender<Trigger>>,
+}
+
+impl Zone {
+    pub fn new(config: &ZoneConfig, tx: Sender<Trigger>) -> Self {
+        Zone {
+            triggers: config.triggers.clone(),
+            tx: Some(tx),
+        }
+    }
+
+    pub fn request(&self, trigger: Trigger) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        if !self.triggers.contains(&trigger) {
+            return;
+        }
+        log::warn!("Requesting the lockdown of the zone after {:?}", trigger);
+        if let Err(e) = tx.send(trigger) {
+            log::error!("error sending zone lockdown: {}", e);
+        }
+    }
+}
+
+/// Publishes the lockdown requests of this controller to its zone,
+/// signed with the operator secret when one is set
+pub fn setup_zone_publisher(
+    net_id: &str,
+    zone_id: &str,
+    secret: Option<Secret>,
+    mqtt_client: Arc<Mutex<MqttClient>>,
+    rx: Receiver<Trigger>,
+) {
+    let topic = zone_topic(zone_id);
+    let device = net_id.to_owned();
+    thread::spawn(move || {
+        for trigger in rx {
+            let request = ZoneLockdown {
+                device: device.clone(),
+                trigger,
+            };
+            let payload = postcard::to_allocvec(&request).map_err(anyhow::Error::from);
+            let payload = match &secret {
+                Some(secret) => payload.and_then(|payload| auth::sign(secret, &topic, &payload)),
+                None => payload,
+            };
+            match payload {
+                Ok(payload) => {
+                    if let Err(e) = mqtt_client.lock().unwrap().enqueue(
+                        &topic,
+                        QoS::AtLeastOnce,
+                        false,
+                        &payload,
+                    ) {
+                        log::error!("error publishing to {}: {}", topic, e);
+                    }
+                }
+                Err(e) => log::error!("error encoding message for {}: {}", topic, e),
+            }
+        }
+    });
+}